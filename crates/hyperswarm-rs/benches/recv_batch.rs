@@ -0,0 +1,74 @@
+//! Benchmark: Batched socket reads
+//!
+//! Queues datagrams on a UDP socket on localhost, then times how fast a
+//! receive loop drains them:
+//! 1. With one `recv_from` per datagram
+//! 2. With `PacketTransport::recv_batch`, at a few batch sizes
+//!
+//! Only the draining is timed, so the figures are the receive path's own
+//! throughput, whatever the sender manages. Run with
+//! `cargo bench --bench recv_batch`. On Linux batches are read with one
+//! `recvmmsg` call each; elsewhere `recv_batch` falls back to `recv_from`,
+//! and every loop should measure about the same.
+
+use hyperswarm::packet::{PacketTransport, RecvBatch};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+/// Datagrams queued before each drain; fits the default receive buffer.
+const QUEUED: usize = 128;
+/// Queue-and-drain rounds per measurement.
+const ROUNDS: usize = 500;
+/// About the size of a KRPC query.
+const DATAGRAM_SIZE: usize = 200;
+/// Receive buffer per datagram, as the DHT uses.
+const SLOT_SIZE: usize = 2048;
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    runtime.block_on(async {
+        let single = datagrams_per_second(None).await;
+        println!("recv_from        {:>12.0} datagrams/s", single);
+        for batch_size in [8, 32, 128] {
+            let batched = datagrams_per_second(Some(batch_size)).await;
+            println!(
+                "recv_batch({:>3})  {:>12.0} datagrams/s  {:.2}x",
+                batch_size,
+                batched,
+                batched / single
+            );
+        }
+    });
+}
+
+/// Drain [`QUEUED`] waiting datagrams [`ROUNDS`] times, in batches of
+/// `batch_size` or with `recv_from` for `None`, and return the rate.
+async fn datagrams_per_second(batch_size: Option<usize>) -> f64 {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let target = socket.local_addr().unwrap();
+    let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let datagram = [0x42u8; DATAGRAM_SIZE];
+    let mut buf = [0u8; SLOT_SIZE];
+    let mut batch = RecvBatch::new(batch_size.unwrap_or(1), SLOT_SIZE);
+
+    let mut draining = Duration::ZERO;
+    for _ in 0..ROUNDS {
+        // Loopback queues each datagram before send_to returns
+        for _ in 0..QUEUED {
+            sender.send_to(&datagram, target).unwrap();
+        }
+        let started = Instant::now();
+        let mut received = 0;
+        while received < QUEUED {
+            received += match batch_size {
+                None => {
+                    socket.recv_from(&mut buf).await.unwrap();
+                    1
+                }
+                Some(_) => PacketTransport::recv_batch(&socket, &mut batch).await.unwrap(),
+            };
+        }
+        draining += started.elapsed();
+    }
+    (QUEUED * ROUNDS) as f64 / draining.as_secs_f64()
+}
@@ -20,6 +20,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             "dht.transmissionbt.com:6881".to_string(),
         ],
         bind_port: 0, // Let OS choose a port
+        ..Default::default()
     };
     
     let client = DhtClient::new(config).await?;
@@ -27,9 +28,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     println!("\nBootstrapping DHT...");
     match client.bootstrap().await {
-        Ok(_) => println!("Bootstrap completed successfully!"),
+        Ok(_) => println!("Bootstrapped into {} nodes", client.routing_table_len().await),
         Err(e) => println!("Bootstrap completed with some errors: {}", e),
     }
+    for (node_id, addr) in client.known_nodes().await.iter().take(5) {
+        let id: String = node_id.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("  {} at {}", id, addr);
+    }
     
     println!("\nDHT client is ready.");
     
@@ -26,6 +26,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             "dht.transmissionbt.com:6881".to_string(),
         ],
         bind_port: 0,
+        ..Default::default()
     };
     
     let dht_client = hyperswarm::dht::DhtClient::new(dht_config).await?;
@@ -34,7 +35,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Bootstrap the DHT
     println!("\nStep 2: Bootstrapping DHT...");
     match dht_client.bootstrap().await {
-        Ok(_) => println!("✓ DHT bootstrap completed"),
+        Ok(_) => println!("✓ DHT bootstrapped into {} nodes", dht_client.routing_table_len().await),
         Err(e) => println!("⚠ Bootstrap completed with some errors: {}", e),
     }
     
@@ -8,7 +8,8 @@
 //! cargo run --example topic_announce
 //! ```
 
-use hyperswarm::{Hyperswarm, SwarmConfig, Topic};
+use hyperswarm::{Hyperswarm, JoinOpts, SwarmConfig, Topic};
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -25,13 +26,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("\nTopic: {:02x?}", &topic.0[..8]);
     
     println!("\nJoining topic (announce + lookup)...");
-    match swarm.join(topic).await {
+    match swarm.join(topic, JoinOpts::default()).await {
         Ok(_) => println!("Successfully joined topic!"),
         Err(e) => println!("Join completed with result: {}", e),
     }
     
     println!("\nFlushing pending operations...");
-    swarm.flush().await?;
+    swarm.flush(Duration::from_secs(10)).await?;
     
     println!("\nShutting down...");
     swarm.destroy().await?;
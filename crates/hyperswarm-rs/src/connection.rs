@@ -0,0 +1,1397 @@
+//! Connection manager.
+//!
+//! A swarm establishes all of its peer connections over a single UDP socket.
+//! The manager owns that socket and a background task that reads it, routing
+//! each datagram to the connection for its source address. Punch packets
+//! from an unknown address start a new inbound connection, which is handed to
+//! [`ConnectionManager::accept`].
+//!
+//! Connecting is a two step process: a holepunch exchange opens the path
+//! (creating NAT bindings on both sides), then a Noise XX handshake runs over
+//! that same path and yields an [`EncryptedStream`]. While punching, a
+//! connection receives from every candidate address of the peer, and keeps
+//! only the one the punch settled on.
+//!
+//! Two peers connecting to each other at once end up with one connection.
+//! On a shared path the holepunch settles which of them initiates. Over
+//! different paths both connections complete, and since established
+//! connections are keyed by the peer's static public key, the second one is
+//! noticed: the connection initiated by the peer with the numerically lower
+//! key survives and the other is closed, on both ends alike.
+//!
+//! Connections made by the discovery layer, and inbound connections once
+//! [`ConnectionManager::connections`] has been taken, are delivered as
+//! [`PeerConnection`] events.
+//!
+//! Every connection holds a permit of a semaphore sized to `max_peers` from
+//! before its holepunch until its stream is dropped. Direct connects and
+//! inbound attempts fail when none is left, while discovery waits for one.
+//!
+//! An application policy set with [`ConnectionManager::set_authorize`] sees
+//! the static public key of every peer once its handshake completes, and
+//! connections to peers it rejects are closed before any traffic flows.
+//!
+//! Each established connection remembers the topics its peer was discovered
+//! under, so [`ConnectionManager::peers`] can list a topic's connections.
+//! An inbound connection gets the topic its peer names in its handshake
+//! payload, or one discovery finds the peer under once it is connected.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch, Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use zeroize::Zeroizing;
+
+use crate::dht::PeerAddress;
+use crate::holepunch::{self, Candidate, CandidateKind, HolepunchSession, PacketMagic};
+use crate::packet::PacketTransport;
+use crate::transport::{self, EncryptedStream, TransportConfig, TransportError};
+use crate::Topic;
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const ROUTE_QUEUE_SIZE: usize = 64; // datagrams buffered per connection
+const ACCEPT_QUEUE_SIZE: usize = 16; // inbound attempts waiting for accept()
+const CONNECTION_EVENT_QUEUE_SIZE: usize = 32; // established connections not yet received
+
+/// Datagram senders keyed by remote address. A connection being punched has
+/// one entry per candidate address of the peer.
+type Routes = Arc<std::sync::Mutex<HashMap<SocketAddr, RouteEntry>>>;
+/// Where the receive task sends datagrams from one remote address.
+type RouteSender = mpsc::Sender<(SocketAddr, Bytes)>;
+type IncomingAttempts = Arc<Mutex<mpsc::Receiver<(SocketAddr, Route)>>>;
+/// Noise static private key shared by every connection of a swarm.
+type StaticKey = Arc<Zeroizing<[u8; 32]>>;
+
+/// Source of [`Route`] ids.
+static NEXT_ROUTE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// An established connection to a peer.
+pub struct PeerConnection {
+    pub stream: EncryptedStream,
+    pub remote_addr: SocketAddr,
+    /// The topic the peer was discovered under; `None` for inbound connections.
+    pub topic: Option<Topic>,
+    /// Whether we initiated the connection.
+    pub initiator: bool,
+}
+
+/// A connected peer, as listed by [`ConnectionManager::peers`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// The address the connection exchanges datagrams with: the one the
+    /// holepunch settled on, which may differ from the announced address.
+    pub remote_addr: SocketAddr,
+    /// The peer's Noise static public key.
+    pub public_key: [u8; 32],
+    /// Whether we initiated the connection.
+    pub initiator: bool,
+    /// When the handshake completed.
+    pub connected_since: SystemTime,
+}
+
+/// Decides from a peer's static public key whether to keep its connection.
+pub type Authorize = Arc<dyn Fn([u8; 32]) -> bool + Send + Sync>;
+
+#[derive(Clone, Debug)]
+pub struct ConnectionConfig {
+    /// Upper bound on connections, counting those still being established.
+    /// Each one holds a permit until its stream is dropped.
+    pub max_peers: usize,
+    /// Applied to every connection's stream before its handshake, so its
+    /// `handshake_timeout` bounds the handshake too.
+    pub transport: TransportConfig,
+    /// Namespaces the holepunch packets of every connection, see
+    /// [`HolepunchSession::with_app_id`]. Punches tagged for another
+    /// application, or untagged ones when set, never start a connection.
+    pub app_id: Option<Vec<u8>>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConnectionError {
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("holepunch: {0}")]
+    Holepunch(#[from] holepunch::HolepunchError),
+    #[error("transport: {0}")]
+    Transport(#[from] crate::transport::TransportError),
+    #[error("peer limit of {0} connections reached")]
+    PeerLimit(usize),
+    #[error("already connected to {0}")]
+    AlreadyConnected(SocketAddr),
+    #[error("duplicate connection to {0}: the peer is connected over another path")]
+    Duplicate(SocketAddr),
+    #[error("connection manager closed")]
+    Closed,
+    #[error("peer not authorized")]
+    Unauthorized,
+}
+
+impl ConnectionError {
+    /// Why a connection attempt failed, for errors that say something about
+    /// the peer or the path to it; `None` for local errors such as I/O.
+    pub fn failure(&self) -> Option<ConnectionFailure> {
+        use holepunch::HolepunchError;
+
+        match self {
+            ConnectionError::Holepunch(HolepunchError::NoViableCandidates) => Some(ConnectionFailure::NoCandidates),
+            ConnectionError::Holepunch(HolepunchError::Timeout) => Some(ConnectionFailure::PunchTimeout),
+            ConnectionError::Holepunch(HolepunchError::AuthenticationFailed)
+            | ConnectionError::Transport(TransportError::PeerAuthenticationFailed) => {
+                Some(ConnectionFailure::PeerAuthFailed)
+            }
+            ConnectionError::Transport(TransportError::HandshakeIncomplete) => Some(ConnectionFailure::HandshakeTimeout),
+            ConnectionError::PeerLimit(_) => Some(ConnectionFailure::CapacityReached),
+            ConnectionError::Unauthorized => Some(ConnectionFailure::Unauthorized),
+            _ => None,
+        }
+    }
+}
+
+/// Why a connection attempt failed, as far as deciding what to try next goes.
+#[derive(thiserror::Error, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionFailure {
+    /// There was no address worth punching to. A fresh lookup may find some.
+    #[error("no viable candidates to connect to")]
+    NoCandidates,
+    /// No candidate answered the holepunch: the peer is gone or its NAT could
+    /// not be punched. Another candidate, or a relay, may still work.
+    #[error("holepunch timed out")]
+    PunchTimeout,
+    /// The path opened but the Noise handshake did not complete in time.
+    #[error("handshake timed out")]
+    HandshakeTimeout,
+    /// The peer is not who we expected: it presented another static key, or
+    /// does not hold the same pre-shared key. Retrying will not help.
+    #[error("peer authentication failed")]
+    PeerAuthFailed,
+    /// `max_peers` connections are already open; retry once one closes.
+    #[error("connection capacity reached")]
+    CapacityReached,
+    /// The peer authenticated, but the policy set with
+    /// [`ConnectionManager::set_authorize`] rejected its key.
+    #[error("peer not authorized")]
+    Unauthorized,
+}
+
+/// Where the receive task sends datagrams from one remote address.
+#[derive(Clone)]
+struct RouteEntry {
+    tx: RouteSender,
+    /// The [`Route`] the address belongs to.
+    id: u64,
+}
+
+/// Remove the entry for `addr` if it still belongs to route `id`.
+fn remove_route(map: &mut HashMap<SocketAddr, RouteEntry>, addr: &SocketAddr, id: u64) {
+    if map.get(addr).is_some_and(|entry| entry.id == id) {
+        map.remove(addr);
+    }
+}
+
+/// Datagrams from a connection's remote addresses, delivered by the manager's
+/// receive task.
+///
+/// Dropping the route unregisters it, after which datagrams from those
+/// addresses are treated as a new inbound connection attempt again, and
+/// releases the connection's permit. Once settled, the route is also closed
+/// by removing its entry.
+pub(crate) struct Route {
+    id: u64,
+    /// Addresses routed here; the first is the connection's own.
+    addrs: Vec<SocketAddr>,
+    /// For routing further candidate addresses here; dropped on settling.
+    tx: Option<RouteSender>,
+    rx: mpsc::Receiver<(SocketAddr, Bytes)>,
+    routes: Routes,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Route {
+    fn register(routes: &Routes, addr: SocketAddr, permit: OwnedSemaphorePermit) -> Result<(Self, RouteSender), ConnectionError> {
+        let mut map = routes.lock().expect("routes lock poisoned");
+        if map.contains_key(&addr) {
+            return Err(ConnectionError::AlreadyConnected(addr));
+        }
+        let id = NEXT_ROUTE_ID.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(ROUTE_QUEUE_SIZE);
+        map.insert(addr, RouteEntry { tx: tx.clone(), id });
+        Ok((
+            Self {
+                id,
+                addrs: vec![addr],
+                tx: Some(tx.clone()),
+                rx,
+                routes: routes.clone(),
+                _permit: permit,
+            },
+            tx,
+        ))
+    }
+
+    /// Also receive datagrams from `addr`, another candidate address of the
+    /// peer. Returns false if `addr` already belongs to a connection, or the
+    /// route has settled.
+    fn alias(&mut self, addr: SocketAddr) -> bool {
+        let Some(tx) = &self.tx else {
+            return false;
+        };
+        let mut map = self.routes.lock().expect("routes lock poisoned");
+        if map.contains_key(&addr) {
+            return false;
+        }
+        map.insert(addr, RouteEntry { tx: tx.clone(), id: self.id });
+        self.addrs.push(addr);
+        true
+    }
+
+    /// Keep only `addr`, the address the punch settled on, as the
+    /// connection's own.
+    fn settle(&mut self, addr: SocketAddr) {
+        // From now on the routing table holds the only sender
+        self.tx = None;
+        if !self.addrs.contains(&addr) {
+            return;
+        }
+        let mut map = self.routes.lock().expect("routes lock poisoned");
+        for other in self.addrs.iter().filter(|other| **other != addr) {
+            remove_route(&mut map, other, self.id);
+        }
+        self.addrs = vec![addr];
+    }
+}
+
+impl Drop for Route {
+    fn drop(&mut self) {
+        if let Ok(mut map) = self.routes.lock() {
+            for addr in &self.addrs {
+                remove_route(&mut map, addr, self.id);
+            }
+        }
+    }
+}
+
+/// A keyless holepunch session reading from `route`, its packets tagged
+/// with the configured application id.
+fn punch_session(socket: Arc<dyn PacketTransport>, route: Route, config: &ConnectionConfig) -> HolepunchSession {
+    let session = HolepunchSession::with_source(socket, PacketSource::Routed(route), None);
+    match &config.app_id {
+        Some(app_id) => session.with_app_id(app_id),
+        None => session,
+    }
+}
+
+/// Settle the route `source` reads from on `addr`, returning its id.
+fn settle_source(source: &mut PacketSource, addr: SocketAddr) -> Option<u64> {
+    match source {
+        PacketSource::Routed(route) => {
+            route.settle(addr);
+            Some(route.id)
+        }
+        PacketSource::Socket => None,
+    }
+}
+
+/// Counts operations in progress, so others can wait until there are none.
+#[derive(Clone)]
+pub(crate) struct InFlight(Arc<watch::Sender<usize>>);
+
+impl Default for InFlight {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(0).0))
+    }
+}
+
+impl InFlight {
+    /// Count one operation until the returned guard is dropped.
+    pub(crate) fn enter(&self) -> InFlightGuard {
+        self.0.send_modify(|count| *count += 1);
+        InFlightGuard(self.0.clone())
+    }
+
+    /// Wait until no operation is in progress.
+    pub(crate) async fn idle(&self) {
+        // The sender lives in `self`, so waiting cannot fail
+        let _ = self.0.subscribe().wait_for(|count| *count == 0).await;
+    }
+}
+
+/// One operation counted by an [`InFlight`].
+pub(crate) struct InFlightGuard(Arc<watch::Sender<usize>>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
+
+/// An established connection in the [`Registry`].
+struct PeerEntry {
+    addr: SocketAddr,
+    /// The [`Route`] the connection reads from; the connection is gone once
+    /// the route is.
+    route: u64,
+    /// Whether we initiated it.
+    initiator: bool,
+    /// Addresses of duplicate connections that were closed in its favour.
+    aliases: Vec<SocketAddr>,
+    /// Topics the peer was discovered under.
+    topics: Vec<Topic>,
+    connected_since: SystemTime,
+}
+
+impl PeerEntry {
+    /// Whether the entry is the peer at `addr`, over its own connection or
+    /// a duplicate closed in its favour.
+    fn is_at(&self, addr: &SocketAddr) -> bool {
+        self.addr == *addr || self.aliases.contains(addr)
+    }
+
+    fn add_topic(&mut self, topic: Topic) {
+        if !self.topics.contains(&topic) {
+            self.topics.push(topic);
+        }
+    }
+}
+
+/// Established connections keyed by the peer's static public key, to spot
+/// a second connection to the same peer.
+pub(crate) struct Registry {
+    /// Our static public key, the other half of the tiebreak.
+    local_key: [u8; 32],
+    routes: Routes,
+    peers: std::sync::Mutex<HashMap<[u8; 32], PeerEntry>>,
+    authorize: std::sync::RwLock<Option<Authorize>>,
+    /// Connections being set up, inbound or outbound, until they are
+    /// delivered or fail.
+    establishing: InFlight,
+}
+
+impl Registry {
+    fn new(local_key: [u8; 32], routes: Routes) -> Self {
+        Self {
+            local_key,
+            routes,
+            peers: std::sync::Mutex::new(HashMap::new()),
+            authorize: std::sync::RwLock::new(None),
+            establishing: InFlight::default(),
+        }
+    }
+
+    /// Whether the authorize policy, if there is one, accepts the peer with
+    /// static key `key`.
+    fn authorizes(&self, key: [u8; 32]) -> bool {
+        // Not holding the lock while application code runs
+        let authorize = self.authorize.read().expect("authorize lock poisoned").clone();
+        authorize.is_none_or(|authorize| authorize(key))
+    }
+
+    /// Close `stream` and fail with [`ConnectionError::Unauthorized`] if the
+    /// authorize policy rejects its peer, or the stream has no peer key to
+    /// judge, then record it as [`claim_stream`](Self::claim_stream) does.
+    async fn admit(&self, stream: &mut EncryptedStream, route: Option<u64>) -> Result<(), ConnectionError> {
+        let authorized = stream.remote_static_key().is_some_and(|key| self.authorizes(key));
+        if !authorized {
+            // Tell the peer rather than leave it to time out
+            let _ = stream.close().await;
+            return Err(ConnectionError::Unauthorized);
+        }
+        self.claim_stream(stream, route)
+    }
+
+    /// Record the connection to the peer with static key `key`, at `addr`
+    /// over route `route`. If the peer is connected already, the connection
+    /// initiated by whichever of us has the lower key is kept, or the newer
+    /// one if both were initiated by the same side. A replaced connection is
+    /// closed; if this one loses, [`ConnectionError::Duplicate`] is returned
+    /// and it should be dropped.
+    fn claim(&self, key: [u8; 32], addr: SocketAddr, initiator: bool, route: u64) -> Result<(), ConnectionError> {
+        let mut routes = self.routes.lock().expect("routes lock poisoned");
+        let mut peers = self.peers.lock().expect("peers lock poisoned");
+        peers.retain(|_, entry| routes.get(&entry.addr).is_some_and(|r| r.id == entry.route));
+
+        let mut aliases = Vec::new();
+        let mut topics = Vec::new();
+        if let Some(existing) = peers.get_mut(&key) {
+            let keep_new = existing.initiator == initiator || initiator == (self.local_key < key);
+            if !keep_new {
+                existing.aliases.push(addr);
+                return Err(ConnectionError::Duplicate(addr));
+            }
+            tracing::debug!("Closing duplicate connection to {} in favour of {}", existing.addr, addr);
+            // Without its entry the route, and the stream reading it, is closed
+            remove_route(&mut routes, &existing.addr, existing.route);
+            aliases = std::mem::take(&mut existing.aliases);
+            aliases.push(existing.addr);
+            topics = std::mem::take(&mut existing.topics);
+        }
+        peers.insert(
+            key,
+            PeerEntry {
+                addr,
+                route,
+                initiator,
+                aliases,
+                topics,
+                connected_since: SystemTime::now(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Record that the peer with static key `key` was discovered under
+    /// `topic`.
+    fn add_topic(&self, key: &[u8; 32], topic: Topic) {
+        if let Some(entry) = self.peers.lock().expect("peers lock poisoned").get_mut(key) {
+            entry.add_topic(topic);
+        }
+    }
+
+    /// Record that the peer connected at `addr` was discovered under
+    /// `topic`. Connections still being set up are left alone: until the
+    /// handshake completes, nothing vouches for who is at `addr`.
+    fn add_topic_at(&self, addr: SocketAddr, topic: Topic) {
+        let mut peers = self.peers.lock().expect("peers lock poisoned");
+        if let Some(entry) = peers.values_mut().find(|entry| entry.is_at(&addr)) {
+            entry.add_topic(topic);
+        }
+    }
+
+    /// Record the topic the peer of `stream` named in its handshake payload,
+    /// if it named one: it found us there, and the payload is bound to its
+    /// static key.
+    fn add_named_topic(&self, stream: &EncryptedStream) {
+        let named = <[u8; 32]>::try_from(stream.remote_handshake_payload());
+        if let (Some(key), Ok(topic)) = (stream.remote_static_key(), named) {
+            self.add_topic(&key, Topic(topic));
+        }
+    }
+
+    /// The established connections to peers discovered under `topic`.
+    fn peers(&self, topic: &Topic) -> Vec<PeerInfo> {
+        let routes = self.routes.lock().expect("routes lock poisoned");
+        let peers = self.peers.lock().expect("peers lock poisoned");
+        peers
+            .iter()
+            .filter(|(_, entry)| routes.get(&entry.addr).is_some_and(|r| r.id == entry.route))
+            .filter(|(_, entry)| entry.topics.contains(topic))
+            .map(|(key, entry)| PeerInfo {
+                remote_addr: entry.addr,
+                public_key: *key,
+                initiator: entry.initiator,
+                connected_since: entry.connected_since,
+            })
+            .collect()
+    }
+
+    /// Record `stream`, which reads from route `route`; see [`claim`](Self::claim).
+    fn claim_stream(&self, stream: &EncryptedStream, route: Option<u64>) -> Result<(), ConnectionError> {
+        match (stream.remote_static_key(), route) {
+            (Some(key), Some(route)) => self.claim(key, stream.remote_addr(), stream.is_initiator(), route),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether `addr` is the address of a duplicate connection that was
+    /// closed in favour of a connection that is still open.
+    fn is_alias(&self, routes: &HashMap<SocketAddr, RouteEntry>, addr: &SocketAddr) -> bool {
+        let peers = self.peers.lock().expect("peers lock poisoned");
+        peers
+            .values()
+            .any(|entry| entry.aliases.contains(addr) && routes.get(&entry.addr).is_some_and(|r| r.id == entry.route))
+    }
+}
+
+/// Where a holepunch session or encrypted stream reads its datagrams from.
+pub(crate) enum PacketSource {
+    /// Read the socket directly; the session is its only reader.
+    Socket,
+    /// Datagrams routed by a [`ConnectionManager`] sharing the socket.
+    Routed(Route),
+}
+
+impl PacketSource {
+    /// Receive the next datagram, like [`PacketTransport::recv_from`].
+    pub(crate) async fn recv_from(&mut self, socket: &dyn PacketTransport, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        std::future::poll_fn(|cx| self.poll_recv_from(socket, cx, buf)).await
+    }
+
+    /// Poll for the next datagram, like [`PacketTransport::poll_recv_from`].
+    pub(crate) fn poll_recv_from(
+        &mut self,
+        socket: &dyn PacketTransport,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<(usize, SocketAddr)>> {
+        match self {
+            PacketSource::Socket => {
+                let mut read_buf = ReadBuf::new(buf);
+                let addr = ready!(socket.poll_recv_from(cx, &mut read_buf))?;
+                Poll::Ready(Ok((read_buf.filled().len(), addr)))
+            }
+            PacketSource::Routed(route) => {
+                let (addr, data) = ready!(route.rx.poll_recv(cx)).ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "connection closed")
+                })?;
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                Poll::Ready(Ok((len, addr)))
+            }
+        }
+    }
+}
+
+/// Establishes and accepts peer connections over the swarm's UDP socket.
+pub struct ConnectionManager {
+    config: ConnectionConfig,
+    socket: Arc<dyn PacketTransport>,
+    routes: Routes,
+    /// One permit per connection, `max_peers` in all.
+    permits: Arc<Semaphore>,
+    registry: Arc<Registry>,
+    incoming: IncomingAttempts,
+    static_key: StaticKey,
+    events_tx: mpsc::Sender<PeerConnection>,
+    events_rx: std::sync::Mutex<Option<mpsc::Receiver<PeerConnection>>>,
+    tasks: std::sync::Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl ConnectionManager {
+    /// Bind the swarm socket and start routing datagrams.
+    ///
+    /// The manager generates a Noise static keypair that all of its
+    /// connections share.
+    pub async fn new(bind_addr: SocketAddr, config: ConnectionConfig) -> Result<Self, ConnectionError> {
+        let private_key = EncryptedStream::generate_private_key()?;
+        Self::bind(bind_addr, config, Arc::new(private_key)).await
+    }
+
+    /// Like [`new`](Self::new), but every connection uses `private_key` as its
+    /// Noise static key, so peers see the same identity across restarts.
+    pub async fn with_keypair(
+        bind_addr: SocketAddr,
+        config: ConnectionConfig,
+        private_key: [u8; 32],
+    ) -> Result<Self, ConnectionError> {
+        Self::bind(bind_addr, config, Arc::new(Zeroizing::new(private_key))).await
+    }
+
+    async fn bind(bind_addr: SocketAddr, config: ConnectionConfig, static_key: StaticKey) -> Result<Self, ConnectionError> {
+        config.transport.validate()?;
+        let public_key = transport::public_key_from_private(&static_key)?;
+        let socket: Arc<dyn PacketTransport> = Arc::new(UdpSocket::bind(bind_addr).await?);
+        let routes: Routes = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let permits = Arc::new(Semaphore::new(config.max_peers));
+        let (incoming_tx, incoming_rx) = mpsc::channel(ACCEPT_QUEUE_SIZE);
+        let (events_tx, events_rx) = mpsc::channel(CONNECTION_EVENT_QUEUE_SIZE);
+
+        let magic = config.app_id.as_deref().map_or_else(PacketMagic::default, PacketMagic::for_app);
+        let task = tokio::spawn(Self::recv_loop(
+            socket.clone(),
+            magic,
+            routes.clone(),
+            permits.clone(),
+            incoming_tx,
+        ));
+
+        Ok(Self {
+            config,
+            socket,
+            registry: Arc::new(Registry::new(public_key, routes.clone())),
+            routes,
+            permits,
+            incoming: Arc::new(Mutex::new(incoming_rx)),
+            static_key,
+            events_tx,
+            events_rx: std::sync::Mutex::new(Some(events_rx)),
+            tasks: std::sync::Mutex::new(vec![task]),
+        })
+    }
+
+    /// Take the stream of connection events.
+    ///
+    /// Returns `None` if it was already taken. Taking it also starts accepting
+    /// inbound connections in the background, each reported with `topic: None`
+    /// and `initiator: false`; [`ConnectionManager::accept`] should not be used
+    /// alongside it.
+    ///
+    /// The channel is bounded. When the receiver lags, delivering the next
+    /// event waits for room, which pauses discovery connects and inbound
+    /// handshakes until the application catches up. Dropping the receiver
+    /// drops further connections as they are established.
+    pub fn connections(&self) -> Option<mpsc::Receiver<PeerConnection>> {
+        let rx = self.events_rx.lock().expect("events lock poisoned").take()?;
+        let task = tokio::spawn(Self::accept_loop(
+            self.socket.clone(),
+            self.static_key.clone(),
+            self.config.clone(),
+            self.registry.clone(),
+            self.incoming.clone(),
+            self.events_tx.clone(),
+        ));
+        self.tasks.lock().expect("tasks lock poisoned").push(task);
+        Some(rx)
+    }
+
+    /// Whether a connection to `addr` is established or being set up, or
+    /// the peer there is connected over another path.
+    pub fn is_connected(&self, addr: &SocketAddr) -> bool {
+        let routes = self.routes.lock().expect("routes lock poisoned");
+        routes.contains_key(addr) || self.registry.is_alias(&routes, addr)
+    }
+
+    /// Keep only connections to peers whose static public key `authorize`
+    /// accepts, like an allowlist. It runs once each handshake completes,
+    /// inbound and outbound alike; a rejected connection is closed and fails
+    /// with [`ConnectionError::Unauthorized`]. Connections established
+    /// earlier are not checked again.
+    pub fn set_authorize(&self, authorize: impl Fn([u8; 32]) -> bool + Send + Sync + 'static) {
+        *self.registry.authorize.write().expect("authorize lock poisoned") = Some(Arc::new(authorize));
+    }
+
+    /// The Noise static public key our connections present.
+    pub fn public_key(&self) -> [u8; 32] {
+        self.registry.local_key
+    }
+
+    /// The key our published holepunch candidates are signed with, derived
+    /// from our Noise static key.
+    pub(crate) fn candidates_key(&self) -> ed25519_dalek::SigningKey {
+        crate::discovery::candidates::signing_key(&self.static_key)
+    }
+
+    /// Get the local address of the swarm socket
+    pub fn local_addr(&self) -> Result<SocketAddr, ConnectionError> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Wait until no connection is being set up: every connect and inbound
+    /// attempt in progress has been delivered or has failed. Attempts that
+    /// start meanwhile are waited for too.
+    pub async fn flush(&self) {
+        self.registry.establishing.idle().await;
+    }
+
+    /// The peers connected to us for `topic`: those we connected to after
+    /// discovering them there, and those that connected to us whom
+    /// discovery has found there since.
+    pub fn peers(&self, topic: &Topic) -> Vec<PeerInfo> {
+        self.registry.peers(topic)
+    }
+
+    /// Record that the peer connected at `addr` was discovered under
+    /// `topic`.
+    pub(crate) fn add_topic(&self, addr: SocketAddr, topic: Topic) {
+        self.registry.add_topic_at(addr, topic);
+    }
+
+    /// Number of connections, established or still being set up.
+    pub fn connection_count(&self) -> usize {
+        self.config.max_peers - self.permits.available_permits()
+    }
+
+    /// Reserve room for a connection, failing if `max_peers` are open.
+    fn try_permit(&self) -> Result<OwnedSemaphorePermit, ConnectionError> {
+        self.permits
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| ConnectionError::PeerLimit(self.config.max_peers))
+    }
+
+    /// Reserve room for a connection, waiting until one of the `max_peers`
+    /// open connections is dropped if need be.
+    pub(crate) async fn permit(&self) -> Result<OwnedSemaphorePermit, ConnectionError> {
+        self.permits.clone().acquire_owned().await.map_err(|_| ConnectionError::Closed)
+    }
+
+    /// Connect to `peer`: holepunch to its address, then run the Noise XX
+    /// handshake as initiator.
+    ///
+    /// If `peer.node_id` is known it is used as the expected static key of the
+    /// remote end, and the handshake fails if the peer presents another key.
+    pub async fn connect(&self, peer: &PeerAddress) -> Result<EncryptedStream, ConnectionError> {
+        self.connect_with_candidates(peer, Vec::new()).await
+    }
+
+    /// Like [`connect`](Self::connect), also punching the `candidates` the
+    /// peer published if its own address does not answer in time. The
+    /// connection continues on whichever address answers first. Candidates
+    /// that belong to another connection are skipped.
+    ///
+    /// Fails with [`ConnectionError::PeerLimit`] once `max_peers` connections
+    /// are open, and with [`ConnectionError::Duplicate`] if the peer turns
+    /// out to be connected over another path already and that connection
+    /// wins the tiebreak. If the peer is connecting to us at the same time
+    /// and wins the holepunch tiebreak, the stream is the one it initiates.
+    pub async fn connect_with_candidates(
+        &self,
+        peer: &PeerAddress,
+        candidates: Vec<Candidate>,
+    ) -> Result<EncryptedStream, ConnectionError> {
+        let permit = self.try_permit()?;
+        self.connect_with_permit(peer, candidates, permit, None).await
+    }
+
+    /// Connect as [`connect_with_candidates`](Self::connect_with_candidates)
+    /// does, counting the connection against `permit`. The peer is told it
+    /// was found under `topic`, if given, in our handshake payload.
+    pub(crate) async fn connect_with_permit(
+        &self,
+        peer: &PeerAddress,
+        candidates: Vec<Candidate>,
+        permit: OwnedSemaphorePermit,
+        topic: Option<Topic>,
+    ) -> Result<EncryptedStream, ConnectionError> {
+        let _establishing = self.registry.establishing.enter();
+        let (mut route, _) = Route::register(&self.routes, peer.addr, permit)?;
+        let announced = Candidate {
+            addr: peer.addr,
+            kind: CandidateKind::Wan,
+        };
+        let mut remote_candidates = vec![announced.clone()];
+        for candidate in candidates {
+            if candidate.addr != peer.addr && route.alias(candidate.addr) {
+                remote_candidates.push(candidate);
+            }
+        }
+
+        let mut session = punch_session(self.socket.clone(), route, &self.config);
+        // The announced address is the one nodes vouch for, so the published
+        // candidates are only punched once it stays silent
+        let punched = match session.initiate(vec![announced]).await {
+            Err(holepunch::HolepunchError::Timeout) if remote_candidates.len() > 1 => session.initiate(remote_candidates).await?,
+            result => result?,
+        };
+
+        let mut source = session.into_source();
+        let route = settle_source(&mut source, punched.addr);
+        let mut stream = EncryptedStream::with_source(self.socket.clone(), punched.addr, source, Some(&self.static_key))?
+            .with_config(self.config.transport.clone())?;
+        if let Some(topic) = topic {
+            stream = stream.with_handshake_payload(&topic.0)?;
+        }
+        if punched.initiator {
+            stream.handshake_initiator(peer.node_id).await?;
+        } else {
+            // The peer is connecting to us too, and initiates this connection
+            stream.handshake_responder(peer.node_id).await?;
+        }
+        self.registry.admit(&mut stream, route).await?;
+        // Met halfway by a peer connecting to us, which may name its topic
+        self.registry.add_named_topic(&stream);
+        Ok(stream)
+    }
+
+    /// Connect to a peer discovered under `topic` and report it as a
+    /// [`PeerConnection`] event.
+    ///
+    /// Unlike [`connect`](Self::connect), this waits for a connection to be
+    /// dropped when `max_peers` are already open.
+    pub async fn connect_discovered(&self, peer: &PeerAddress, topic: Topic) -> Result<(), ConnectionError> {
+        let permit = self.permit().await?;
+        let stream = self.connect_with_permit(peer, Vec::new(), permit, Some(topic)).await?;
+        self.deliver_discovered(stream, topic).await;
+        Ok(())
+    }
+
+    /// Report a connection made to a peer discovered under `topic` as a
+    /// [`PeerConnection`] event.
+    pub(crate) async fn deliver_discovered(&self, stream: EncryptedStream, topic: Topic) {
+        if let Some(key) = stream.remote_static_key() {
+            self.registry.add_topic(&key, topic);
+        }
+        let event = PeerConnection {
+            remote_addr: stream.remote_addr(),
+            initiator: stream.is_initiator(),
+            stream,
+            topic: Some(topic),
+        };
+        // Waits while the receiver lags; an error only means nobody listens anymore
+        let _ = self.events_tx.send(event).await;
+    }
+
+    /// Wait for the next inbound connection and complete it as responder.
+    pub async fn accept(&self) -> Result<EncryptedStream, ConnectionError> {
+        let (addr, route) = self
+            .incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(ConnectionError::Closed)?;
+        let _establishing = self.registry.establishing.enter();
+        Self::respond(
+            self.socket.clone(),
+            self.static_key.clone(),
+            self.config.clone(),
+            &self.registry,
+            addr,
+            route,
+        )
+        .await
+    }
+
+    /// Complete an inbound attempt from `addr` as holepunch and Noise responder.
+    async fn respond(
+        socket: Arc<dyn PacketTransport>,
+        static_key: StaticKey,
+        config: ConnectionConfig,
+        registry: &Registry,
+        addr: SocketAddr,
+        route: Route,
+    ) -> Result<EncryptedStream, ConnectionError> {
+        let remote_candidates = vec![Candidate {
+            addr,
+            kind: CandidateKind::Wan,
+        }];
+
+        let mut session = punch_session(socket.clone(), route, &config);
+        let punched = session.respond(remote_candidates).await?;
+
+        let mut source = session.into_source();
+        let route = settle_source(&mut source, punched.addr);
+        let mut stream =
+            EncryptedStream::with_source(socket, punched.addr, source, Some(&static_key))?.with_config(config.transport)?;
+        stream.handshake_responder(None).await?;
+        registry.admit(&mut stream, route).await?;
+        registry.add_named_topic(&stream);
+        Ok(stream)
+    }
+
+    /// Accept inbound connections and report them as events.
+    async fn accept_loop(
+        socket: Arc<dyn PacketTransport>,
+        static_key: StaticKey,
+        config: ConnectionConfig,
+        registry: Arc<Registry>,
+        incoming: IncomingAttempts,
+        events: mpsc::Sender<PeerConnection>,
+    ) {
+        loop {
+            let next = incoming.lock().await.recv().await;
+            let Some((addr, route)) = next else {
+                break;
+            };
+            // Handshake in its own task so one slow peer does not hold up the rest
+            let socket = socket.clone();
+            let events = events.clone();
+            let static_key = static_key.clone();
+            let config = config.clone();
+            let registry = registry.clone();
+            let establishing = registry.establishing.enter();
+            tokio::spawn(async move {
+                let _establishing = establishing;
+                match Self::respond(socket, static_key, config, &registry, addr, route).await {
+                    Ok(stream) => {
+                        let event = PeerConnection {
+                            remote_addr: stream.remote_addr(),
+                            stream,
+                            topic: None,
+                            initiator: false,
+                        };
+                        let _ = events.send(event).await;
+                    }
+                    Err(e) => tracing::debug!("Inbound connection from {} failed: {}", addr, e),
+                }
+            });
+        }
+    }
+
+    /// Sole reader of the swarm socket: hand each datagram to its route.
+    ///
+    /// Only punches under our `magic` start an inbound attempt. Attempts
+    /// beyond `max_peers` are dropped; the peer's punches keep coming for a
+    /// while, so one may still get in once a connection closes.
+    async fn recv_loop(
+        socket: Arc<dyn PacketTransport>,
+        magic: PacketMagic,
+        routes: Routes,
+        permits: Arc<Semaphore>,
+        incoming: mpsc::Sender<(SocketAddr, Route)>,
+    ) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    tracing::debug!("Swarm socket receive failed: {}", e);
+                    continue;
+                }
+            };
+            let data = Bytes::copy_from_slice(&buf[..len]);
+
+            let route = routes.lock().expect("routes lock poisoned").get(&from).cloned();
+            match route {
+                Some(entry) => {
+                    // Like the network itself, drop datagrams when the connection lags
+                    let _ = entry.tx.try_send((from, data));
+                }
+                None if magic.is_punch(&data, false) => {
+                    // Start of an inbound connection attempt
+                    let Ok(permit) = permits.clone().try_acquire_owned() else {
+                        tracing::debug!("Peer limit reached, dropping attempt from {}", from);
+                        continue;
+                    };
+                    match Route::register(&routes, from, permit) {
+                        Ok((route, tx)) => {
+                            let _ = tx.try_send((from, data));
+                            if incoming.try_send((from, route)).is_err() {
+                                tracing::debug!("Accept queue full, dropping attempt from {}", from);
+                            }
+                        }
+                        Err(e) => tracing::debug!("Rejecting inbound attempt from {}: {}", from, e),
+                    }
+                }
+                None => {
+                    // Not part of any connection
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionManager {
+    fn drop(&mut self) {
+        // Wake discovery waiting for room
+        self.permits.close();
+        if let Ok(tasks) = self.tasks.lock() {
+            for task in tasks.iter() {
+                task.abort();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn test_manager(max_peers: usize) -> ConnectionManager {
+        let config = ConnectionConfig {
+            max_peers,
+            transport: TransportConfig::default(),
+            app_id: None,
+        };
+        ConnectionManager::new("127.0.0.1:0".parse().unwrap(), config).await.unwrap()
+    }
+
+    fn register(manager: &ConnectionManager, addr: SocketAddr) -> Route {
+        Route::register(&manager.routes, addr, manager.try_permit().unwrap()).unwrap().0
+    }
+
+    #[tokio::test]
+    async fn test_route_unregisters_on_drop() {
+        let manager = test_manager(4).await;
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+
+        let route = register(&manager, addr);
+        assert_eq!(manager.connection_count(), 1);
+        assert!(matches!(
+            Route::register(&manager.routes, addr, manager.try_permit().unwrap()),
+            Err(ConnectionError::AlreadyConnected(_))
+        ));
+        // The rejected attempt gave its permit back
+        assert_eq!(manager.connection_count(), 1);
+
+        drop(route);
+        assert_eq!(manager.connection_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_candidate_aliases_settle_on_one_address() {
+        let manager = test_manager(4).await;
+        let peer: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let lan: SocketAddr = "192.168.1.5:9000".parse().unwrap();
+        let _other = register(&manager, "127.0.0.1:9001".parse().unwrap());
+
+        let mut route = register(&manager, peer);
+        assert!(route.alias(lan));
+        assert!(!route.alias("127.0.0.1:9001".parse().unwrap()), "belongs to another connection");
+        assert!(manager.is_connected(&lan));
+        assert_eq!(manager.connection_count(), 2);
+
+        route.settle(lan);
+        assert!(!manager.is_connected(&peer));
+        assert!(manager.is_connected(&lan));
+        assert_eq!(manager.connection_count(), 2);
+
+        drop(route);
+        assert!(!manager.is_connected(&lan));
+        assert_eq!(manager.connection_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stream_without_a_peer_key_is_not_admitted() {
+        let manager = test_manager(4).await;
+        let addr: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let mut stream = EncryptedStream::new(manager.socket.clone(), addr).await.unwrap();
+        assert!(matches!(manager.registry.admit(&mut stream, None).await, Err(ConnectionError::Unauthorized)));
+        assert!(!manager.is_connected(&addr));
+    }
+
+    #[tokio::test]
+    async fn test_transport_config_applies_to_connections() {
+        let config = |handshake_timeout| ConnectionConfig {
+            max_peers: 4,
+            transport: TransportConfig {
+                handshake_timeout,
+                ..Default::default()
+            },
+            app_id: None,
+        };
+        let bind_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let rejected = ConnectionManager::new(bind_addr, config(Duration::ZERO)).await;
+        assert!(matches!(rejected, Err(ConnectionError::Transport(TransportError::InvalidConfig(_)))));
+
+        // A peer that punches and then never handshakes is given up on after
+        // the configured timeout, not the default one
+        let manager = ConnectionManager::new(bind_addr, config(Duration::from_millis(300))).await.unwrap();
+        let peer = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let mut session = HolepunchSession::with_source(peer, PacketSource::Socket, None);
+        let candidate = Candidate {
+            addr: manager.local_addr().unwrap(),
+            kind: CandidateKind::Lan,
+        };
+        let started = tokio::time::Instant::now();
+        let (punched, accepted) = tokio::join!(session.initiate(vec![candidate]), manager.accept());
+        punched.unwrap();
+        assert!(matches!(accepted, Err(ConnectionError::Transport(TransportError::HandshakeIncomplete))), "got {:?}", accepted.err());
+        assert!(started.elapsed() < Duration::from_secs(5), "took {:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_keeps_connection_initiated_by_lower_key() {
+        let remote = [0x80; 32];
+        let ours: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+        let theirs: SocketAddr = "192.168.1.5:9000".parse().unwrap();
+
+        for (local, survivor) in [([0x10; 32], ours), ([0xf0; 32], theirs)] {
+            let mut manager = test_manager(4).await;
+            manager.registry = Arc::new(Registry::new(local, manager.routes.clone()));
+            let mut our_route = register(&manager, ours);
+            our_route.settle(ours);
+            let mut their_route = register(&manager, theirs);
+            their_route.settle(theirs);
+
+            manager.registry.claim(remote, ours, true, our_route.id).unwrap();
+            let claimed = manager.registry.claim(remote, theirs, false, their_route.id);
+            if survivor == ours {
+                assert!(matches!(claimed, Err(ConnectionError::Duplicate(addr)) if addr == theirs));
+                drop(their_route);
+                assert_eq!(our_route.rx.try_recv(), Err(mpsc::error::TryRecvError::Empty));
+            } else {
+                claimed.unwrap();
+                // Our connection was torn down
+                assert_eq!(our_route.rx.try_recv(), Err(mpsc::error::TryRecvError::Disconnected));
+            }
+            // Either way the peer still counts as connected at both addresses
+            assert!(manager.is_connected(&ours));
+            assert!(manager.is_connected(&theirs));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peers_are_listed_under_their_topics() {
+        let manager = test_manager(4).await;
+        let (joined, other) = (Topic::from_key(b"joined"), Topic::from_key(b"other"));
+        let (key, addr) = ([0x80; 32], "127.0.0.1:9000".parse().unwrap());
+        let lan: SocketAddr = "192.168.1.5:9000".parse().unwrap();
+
+        // Nothing vouches for a connection still being set up
+        let route = register(&manager, addr);
+        manager.add_topic(addr, joined);
+        manager.registry.claim(key, addr, false, route.id).unwrap();
+        assert!(manager.peers(&joined).is_empty());
+
+        // Found under a topic once connected
+        manager.add_topic(addr, joined);
+        manager.add_topic(lan, other);
+        let peers = manager.peers(&joined);
+        assert_eq!(peers.len(), 1);
+        assert_eq!((peers[0].remote_addr, peers[0].public_key, peers[0].initiator), (addr, key, false));
+        assert!(manager.peers(&other).is_empty(), "no connection at that address");
+
+        // Found under another once connected
+        manager.add_topic(addr, other);
+        assert_eq!(manager.peers(&other), peers);
+
+        drop(route);
+        assert!(manager.peers(&joined).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_respects_max_peers() {
+        let manager = test_manager(1).await;
+        let _held = register(&manager, "127.0.0.1:9000".parse().unwrap());
+
+        let peer = PeerAddress {
+            addr: "127.0.0.1:9001".parse().unwrap(),
+            node_id: None,
+            value: None,
+        };
+        let result = manager.connect(&peer).await;
+        assert!(matches!(result, Err(ConnectionError::PeerLimit(1))));
+        assert_eq!(result.err().and_then(|e| e.failure()), Some(ConnectionFailure::CapacityReached));
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_punch_is_a_punch_timeout() {
+        let manager = test_manager(4).await;
+        // Bound, but never answers
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let peer = PeerAddress {
+            addr: silent.local_addr().unwrap(),
+            node_id: None,
+            value: None,
+        };
+        let failure = manager.connect(&peer).await.err().and_then(|e| e.failure());
+        assert_eq!(failure, Some(ConnectionFailure::PunchTimeout));
+        assert_eq!(manager.connection_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_punching_no_candidates_is_no_candidates() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let mut session = HolepunchSession::with_transport(socket, [0u8; 32]);
+
+        let error = ConnectionError::from(session.initiate(Vec::new()).await.unwrap_err());
+        assert_eq!(error.failure(), Some(ConnectionFailure::NoCandidates));
+    }
+
+    #[test]
+    fn test_errors_classify_into_failures() {
+        use holepunch::HolepunchError;
+
+        let cases = [
+            (ConnectionError::from(HolepunchError::NoViableCandidates), Some(ConnectionFailure::NoCandidates)),
+            (HolepunchError::Timeout.into(), Some(ConnectionFailure::PunchTimeout)),
+            (HolepunchError::AuthenticationFailed.into(), Some(ConnectionFailure::PeerAuthFailed)),
+            (TransportError::HandshakeIncomplete.into(), Some(ConnectionFailure::HandshakeTimeout)),
+            (TransportError::PeerAuthenticationFailed.into(), Some(ConnectionFailure::PeerAuthFailed)),
+            (ConnectionError::PeerLimit(8), Some(ConnectionFailure::CapacityReached)),
+            (ConnectionError::Unauthorized, Some(ConnectionFailure::Unauthorized)),
+            // Not about the peer
+            (std::io::Error::other("local").into(), None),
+            (ConnectionError::Closed, None),
+        ];
+        for (error, failure) in cases {
+            assert_eq!(error.failure(), failure, "{}", error);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_discovery_connects_queue_at_max_peers() {
+        use std::time::Duration;
+
+        let manager = Arc::new(test_manager(2).await);
+        let mut events = manager.connections().unwrap();
+        let topic = Topic::from_key(b"queued");
+
+        // Three peers accepting in the background
+        let mut peers = Vec::new();
+        for _ in 0..3 {
+            let peer = test_manager(4).await;
+            let inbound = peer.connections().unwrap();
+            peers.push((peer, inbound));
+        }
+        for (peer, _) in &peers {
+            let manager = manager.clone();
+            let addr = PeerAddress {
+                addr: peer.local_addr().unwrap(),
+                node_id: None,
+                value: None,
+            };
+            tokio::spawn(async move { manager.connect_discovered(&addr, topic).await });
+        }
+
+        let first = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+        let _second = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+        assert_eq!(manager.connection_count(), 2);
+        // The third waits for room instead of failing or exceeding the limit
+        assert!(tokio::time::timeout(Duration::from_millis(500), events.recv()).await.is_err());
+        assert_eq!(manager.connection_count(), 2);
+
+        drop(first);
+        let third = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+        assert!(third.initiator);
+        assert_eq!(manager.connection_count(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_discovered_peer_presenting_another_key_is_rejected() {
+        use std::time::Duration;
+
+        let manager = test_manager(4).await;
+        let peer = test_manager(4).await;
+        let _inbound = peer.connections().unwrap();
+        assert_ne!(peer.public_key(), [0xde; 32]);
+
+        // The DHT said the peer's key is 0xde..; it presents its own
+        let addr = PeerAddress {
+            addr: peer.local_addr().unwrap(),
+            node_id: Some([0xde; 32]),
+            value: None,
+        };
+        let topic = Topic::from_key(b"pinned");
+        let result = tokio::time::timeout(Duration::from_secs(5), manager.connect_discovered(&addr, topic))
+            .await
+            .unwrap();
+        assert!(
+            matches!(result, Err(ConnectionError::Transport(TransportError::PeerAuthenticationFailed))),
+            "got {:?}",
+            result
+        );
+        assert_eq!(result.unwrap_err().failure(), Some(ConnectionFailure::PeerAuthFailed));
+        assert_eq!(manager.connection_count(), 0);
+        assert!(!manager.is_connected(&addr.addr));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_authorize_keeps_only_allowed_peers() {
+        use std::time::Duration;
+
+        let manager = test_manager(4).await;
+        let mut events = manager.connections().unwrap();
+        let allowed = test_manager(4).await;
+        let other = test_manager(4).await;
+        let allowed_key = allowed.public_key();
+        manager.set_authorize(move |key| key == allowed_key);
+
+        let addr = PeerAddress {
+            addr: manager.local_addr().unwrap(),
+            node_id: None,
+            value: None,
+        };
+        let mut allowed_stream = allowed.connect(&addr).await.unwrap();
+        // The other peer completes its handshake, then is closed on
+        let mut other_stream = other.connect(&addr).await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(5), other_stream.recv()).await.unwrap();
+        assert!(matches!(closed, Err(TransportError::Closed)), "got {:?}", closed);
+
+        let mut event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+        assert_eq!(event.stream.remote_static_key(), Some(allowed_key));
+        assert!(tokio::time::timeout(Duration::from_millis(300), events.recv()).await.is_err());
+        assert_eq!(manager.connection_count(), 1);
+
+        event.stream.send(Bytes::from_static(b"welcome")).await.unwrap();
+        assert_eq!(allowed_stream.recv().await.unwrap(), Bytes::from_static(b"welcome"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_unauthorized_outbound_connection_is_torn_down() {
+        let manager = test_manager(4).await;
+        let peer = test_manager(4).await;
+        let _inbound = peer.connections().unwrap();
+        manager.set_authorize(|_| false);
+
+        let addr = PeerAddress {
+            addr: peer.local_addr().unwrap(),
+            node_id: None,
+            value: None,
+        };
+        let result = manager.connect(&addr).await;
+        assert!(matches!(result, Err(ConnectionError::Unauthorized)));
+        assert_eq!(result.err().and_then(|e| e.failure()), Some(ConnectionFailure::Unauthorized));
+        assert_eq!(manager.connection_count(), 0);
+        assert!(!manager.is_connected(&addr.addr));
+    }
+
+    #[tokio::test]
+    async fn test_inbound_attempts_respect_max_peers() {
+        let manager = test_manager(1).await;
+        let _held = register(&manager, "127.0.0.1:9000".parse().unwrap());
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let mut session = HolepunchSession::with_source(socket, PacketSource::Socket, None);
+        let session_addr = session.local_addr().unwrap();
+
+        // Punches arrive but nobody answers them
+        let _ = tokio::time::timeout(
+            std::time::Duration::from_millis(300),
+            session.initiate(vec![Candidate {
+                addr: manager.local_addr().unwrap(),
+                kind: CandidateKind::Wan,
+            }]),
+        )
+        .await;
+
+        assert_eq!(manager.connection_count(), 1);
+        assert!(!manager.is_connected(&session_addr));
+    }
+
+    #[tokio::test]
+    async fn test_app_id_namespaces_connection_punches() {
+        let manager = ConnectionManager::new(
+            "127.0.0.1:0".parse().unwrap(),
+            ConnectionConfig {
+                max_peers: 4,
+                transport: TransportConfig::default(),
+                app_id: Some(b"app-a".to_vec()),
+            },
+        )
+        .await
+        .unwrap();
+        let manager_addr = manager.local_addr().unwrap();
+
+        // Our punches carry the tag
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = PeerAddress {
+            addr: peer.local_addr().unwrap(),
+            node_id: None,
+            value: None,
+        };
+        let connect = manager.connect(&target);
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        let received = async {
+            loop {
+                let (len, _) = peer.recv_from(&mut buf).await.unwrap();
+                if PacketMagic::for_app(b"app-a").is_punch(&buf[..len], false) {
+                    break;
+                }
+            }
+        };
+        tokio::select! {
+            _ = received => {}
+            _ = connect => panic!("no punch tagged for app-a"),
+        }
+
+        // Only punches under our tag start an inbound attempt
+        for (app_id, starts) in [(None, false), (Some(&b"app-b"[..]), false), (Some(&b"app-a"[..]), true)] {
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let session = HolepunchSession::with_source(socket, PacketSource::Socket, None);
+            let mut session = match app_id {
+                Some(app_id) => session.with_app_id(app_id),
+                None => session,
+            };
+            let session_addr = session.local_addr().unwrap();
+            let _ = tokio::time::timeout(
+                Duration::from_millis(300),
+                session.initiate(vec![Candidate {
+                    addr: manager_addr,
+                    kind: CandidateKind::Wan,
+                }]),
+            )
+            .await;
+            assert_eq!(manager.is_connected(&session_addr), starts, "app id {:?}", app_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unrelated_datagrams_do_not_start_connections() {
+        let manager = test_manager(4).await;
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        sender.send_to(b"not a holepunch packet", manager.local_addr().unwrap()).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        assert_eq!(manager.connection_count(), 0);
+    }
+}
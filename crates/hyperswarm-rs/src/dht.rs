@@ -5,19 +5,246 @@
 //! - bootstrapping into the routing table
 //! - announcing on a topic
 //! - looking up peers for a topic
+//! - storing and fetching small records (BEP 44, see [`storage`])
 
-use std::net::SocketAddr;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use futures::{Stream, StreamExt};
 use rand::Rng;
 
+use crate::metrics::{MetricsHandle, SwarmMetrics};
+use crate::packet::{PacketTransport, RecvBatch, DEFAULT_RECV_BATCH_SIZE, MAX_RECV_BATCH_SIZE};
 use crate::{protocol, Topic};
 
+pub mod mdns;
+pub mod node_id;
+mod observed;
+mod rate_limit;
+mod reachability;
+pub mod resolve;
+pub mod storage;
+
+use ed25519_dalek::SigningKey;
+use observed::ObservedAddresses;
+use rate_limit::QueryRateLimiter;
+use reachability::Contacts;
+use resolve::{Resolver, SystemResolver};
+use std::sync::atomic::{AtomicU64, Ordering};
+use storage::{ItemStore, MutableItem};
+use tokio_util::sync::CancellationToken;
+
 #[derive(Clone, Debug)]
 pub struct DhtConfig {
     pub bootstrap: Vec<String>,
+    /// Further places to find bootstrap nodes, tried after `bootstrap` and
+    /// combined with it. With both empty, the mainline DHT's routers are
+    /// used.
+    pub bootstrap_sources: Vec<BootstrapSource>,
+    /// Interface to bind. The unspecified `0.0.0.0` (the default) listens on
+    /// all of them. Any other IPv4 address pins the node to that interface,
+    /// and an IPv6 address makes it an IPv6-only node.
+    pub bind_addr: IpAddr,
     pub bind_port: u16,
+    /// Also bind an IPv6 socket (`[::]`) and take part in the IPv6 DHT.
+    /// If the host has no IPv6 support the client falls back to IPv4 only.
+    /// Only applies when `bind_addr` is `0.0.0.0`.
+    pub ipv6: bool,
+    /// Our public IP, if already known. The node id is then derived from it
+    /// as BEP 42 requires; otherwise it is fully random until
+    /// [`DhtClient::regenerate_node_id`] is called.
+    pub public_ip: Option<IpAddr>,
+    /// A fixed node id, e.g. derived from a persistent seed. Takes precedence
+    /// over `public_ip`.
+    pub node_id: Option<[u8; 20]>,
+    /// How long to wait for the response to a query to a node that has not
+    /// answered us before. Nodes that have are given four times their
+    /// average response time, at least `min_query_timeout`, doubled for
+    /// each query they let time out since, and at most twice `query_timeout`.
+    pub query_timeout: Duration,
+    /// Shortest wait for a node with a known response time.
+    pub min_query_timeout: Duration,
+    /// How long to wait for a bootstrap node's name to resolve.
+    pub bootstrap_dns_timeout: Duration,
+    /// How long to wait for a bootstrap node to answer its ping.
+    pub bootstrap_ping_timeout: Duration,
+    /// How often the least recently seen node of each routing-table bucket
+    /// is pinged to check it is still alive.
+    pub maintenance_interval: Duration,
+    /// Consecutive failed liveness pings after which a node is evicted.
+    pub max_node_failures: u32,
+    /// Incoming queries answered per second from any one IP; the rest are
+    /// dropped.
+    pub max_queries_per_ip: u32,
+    /// Incoming queries answered per second across all sources.
+    pub max_queries_total: u32,
+    /// Queries a lookup keeps in flight at once (Kademlia's alpha). Also
+    /// how many nodes an announce talks to at a time.
+    pub lookup_alpha: usize,
+    /// Size of the closest set (Kademlia's k): how many nodes we return to
+    /// `find_node` / `get_peers`, and how many closest candidates a lookup
+    /// must have queried to converge.
+    pub bucket_k: usize,
+    /// Queries of ours awaiting a response at once. Further ones fail with
+    /// [`DhtError::TooManyInFlight`] until some finish. At most 65536, the
+    /// number of distinct transaction ids.
+    pub max_in_flight_queries: usize,
+    /// How long [`DhtClient::lookup`] reuses a topic's peers before asking
+    /// the network again. Zero disables the cache.
+    pub peer_cache_ttl: Duration,
+    /// Datagrams each socket read may take at once, up to
+    /// [`MAX_RECV_BATCH_SIZE`]. On Linux waiting datagrams are read with one
+    /// `recvmmsg` call per batch; elsewhere, and on other transports, one
+    /// at a time regardless. 1 reads one at a time everywhere.
+    pub recv_batch_size: usize,
+    /// Remember which addresses we send to, so that
+    /// [`DhtClient::check_reachability`] can tell when a node we never
+    /// contacted reaches us. Off by default; without it the check always
+    /// answers [`Reachability::Unknown`].
+    pub detect_reachability: bool,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            bootstrap: Vec::new(),
+            bootstrap_sources: Vec::new(),
+            bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            bind_port: 0,
+            ipv6: true,
+            public_ip: None,
+            node_id: None,
+            query_timeout: DEFAULT_QUERY_TIMEOUT,
+            min_query_timeout: DEFAULT_MIN_QUERY_TIMEOUT,
+            bootstrap_dns_timeout: DEFAULT_BOOTSTRAP_DNS_TIMEOUT,
+            bootstrap_ping_timeout: DEFAULT_BOOTSTRAP_PING_TIMEOUT,
+            maintenance_interval: DEFAULT_MAINTENANCE_INTERVAL,
+            max_node_failures: DEFAULT_MAX_NODE_FAILURES,
+            max_queries_per_ip: DEFAULT_MAX_QUERIES_PER_IP,
+            max_queries_total: DEFAULT_MAX_QUERIES_TOTAL,
+            lookup_alpha: DEFAULT_LOOKUP_ALPHA,
+            bucket_k: DEFAULT_BUCKET_K,
+            max_in_flight_queries: DEFAULT_MAX_IN_FLIGHT_QUERIES,
+            peer_cache_ttl: DEFAULT_PEER_CACHE_TTL,
+            recv_batch_size: DEFAULT_RECV_BATCH_SIZE,
+            detect_reachability: false,
+        }
+    }
+}
+
+impl DhtConfig {
+    /// Reject settings the client cannot work with.
+    fn validate(&self) -> Result<(), DhtError> {
+        let timeouts = [
+            ("query_timeout", self.query_timeout),
+            ("min_query_timeout", self.min_query_timeout),
+            ("bootstrap_dns_timeout", self.bootstrap_dns_timeout),
+            ("bootstrap_ping_timeout", self.bootstrap_ping_timeout),
+            ("maintenance_interval", self.maintenance_interval),
+        ];
+        if let Some((name, _)) = timeouts.into_iter().find(|(_, timeout)| timeout.is_zero()) {
+            return Err(DhtError::InvalidConfig(format!("{} must be non-zero", name)));
+        }
+        let limits = [
+            ("max_node_failures", self.max_node_failures),
+            ("max_queries_per_ip", self.max_queries_per_ip),
+            ("max_queries_total", self.max_queries_total),
+        ];
+        if let Some((name, _)) = limits.into_iter().find(|(_, limit)| *limit == 0) {
+            return Err(DhtError::InvalidConfig(format!("{} must be non-zero", name)));
+        }
+        let sizes = [
+            ("lookup_alpha", self.lookup_alpha),
+            ("bucket_k", self.bucket_k),
+            ("max_in_flight_queries", self.max_in_flight_queries),
+            ("recv_batch_size", self.recv_batch_size),
+        ];
+        if let Some((name, _)) = sizes.into_iter().find(|(_, size)| *size == 0) {
+            return Err(DhtError::InvalidConfig(format!("{} must be non-zero", name)));
+        }
+        if self.max_in_flight_queries > MAX_TRANSACTION_IDS {
+            return Err(DhtError::InvalidConfig(format!(
+                "max_in_flight_queries must be at most {}",
+                MAX_TRANSACTION_IDS
+            )));
+        }
+        if self.recv_batch_size > MAX_RECV_BATCH_SIZE {
+            return Err(DhtError::InvalidConfig(format!(
+                "recv_batch_size must be at most {}",
+                MAX_RECV_BATCH_SIZE
+            )));
+        }
+        let mut invalid: Vec<&str> = self
+            .bootstrap
+            .iter()
+            .map(String::as_str)
+            .filter(|entry| !is_valid_bootstrap_entry(entry))
+            .collect();
+        for source in &self.bootstrap_sources {
+            match source {
+                BootstrapSource::Static(entries) => invalid.extend(
+                    entries.iter().map(String::as_str).filter(|entry| !is_valid_bootstrap_entry(entry)),
+                ),
+                BootstrapSource::Mdns { service_name } if !mdns::is_valid_service_name(service_name) => {
+                    invalid.push(service_name)
+                }
+                _ => {}
+            }
+        }
+        if !invalid.is_empty() {
+            return Err(DhtError::InvalidBootstrap(invalid.join(", ")));
+        }
+        Ok(())
+    }
+}
+
+/// Whether `entry` has the `host:port` form a bootstrap node needs: a
+/// socket address (IPv6 in brackets) or a host name with a port. Whether
+/// the name resolves is only known at bootstrap time.
+fn is_valid_bootstrap_entry(entry: &str) -> bool {
+    if entry.parse::<SocketAddr>().is_ok() {
+        return true;
+    }
+    let Some((host, port)) = entry.rsplit_once(':') else {
+        return false;
+    };
+    // A colon or bracket left in the host is a malformed IPv6 literal
+    !host.is_empty() && !host.contains([':', '[', ']']) && port.parse::<u16>().is_ok()
+}
+
+/// Where [`DhtClient::bootstrap`] looks for nodes to join through.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BootstrapSource {
+    /// `host:port` entries, as in [`DhtConfig::bootstrap`]. Every address a
+    /// name resolves to is tried, so one entry can be a DNS seed whose many
+    /// address records each point at a node.
+    Static(Vec<String>),
+    /// Nodes on the local networks answering a multicast DNS query for
+    /// `service_name`, e.g. [`mdns::DEFAULT_SERVICE_NAME`]. A client with
+    /// this source also answers such queries itself, so that nodes on one
+    /// LAN find each other.
+    Mdns { service_name: String },
+}
+
+/// Whether other nodes can reach us without us contacting them first, as
+/// found by [`DhtClient::check_reachability`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reachability {
+    /// A node we never sent to has queried our DHT port: announce a real
+    /// port and accept connections on it.
+    DirectlyReachable,
+    /// Nodes see us at another address than our own, and only the nodes we
+    /// sent to have got through, as behind a port-restricted NAT: peers have
+    /// to holepunch.
+    NatPortRestricted,
+    /// Nothing to go by yet, e.g. no node answered, or we are on a public
+    /// address nobody has queried so far.
+    Unknown,
 }
 
 #[derive(Clone, Debug)]
@@ -25,8 +252,14 @@ pub struct PeerAddress {
     pub addr: SocketAddr,
     /// Optional remote public key / node id if available.
     pub node_id: Option<[u8; 32]>,
+    /// Data the peer announced with [`DhtClient::announce_with_value`], if
+    /// any, such as a protocol version.
+    pub value: Option<Vec<u8>>,
 }
 
+/// Most bytes of data a peer can announce with itself.
+pub const MAX_ANNOUNCE_VALUE_SIZE: usize = 64;
+
 #[derive(thiserror::Error, Debug)]
 pub enum DhtError {
     #[error("io: {0}")]
@@ -35,56 +268,310 @@ pub enum DhtError {
     Protocol(#[from] protocol::ProtocolError),
     #[error("timeout")]
     Timeout,
+    /// The queried node answered with a KRPC error (`y` = `e`).
+    #[error("KRPC error {code}: {message}")]
+    KrpcError { code: i64, message: String },
+    /// A BEP 44 value that bencodes to more than [`storage::MAX_VALUE_SIZE`] bytes.
+    #[error("value too large: {0} bytes bencoded")]
+    ValueTooLarge(usize),
+    /// A BEP 44 salt longer than [`storage::MAX_SALT_SIZE`] bytes.
+    #[error("salt too large: {0} bytes")]
+    SaltTooLarge(usize),
+    /// Announce data longer than [`MAX_ANNOUNCE_VALUE_SIZE`] bytes.
+    #[error("announce value too large: {0} bytes")]
+    AnnounceValueTooLarge(usize),
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+    /// Bootstrap entries that are not `host:port`, comma separated.
+    #[error("invalid bootstrap entries: {0}")]
+    InvalidBootstrap(String),
+    /// A string that is not a node identity from [`DhtClient::identity`].
+    #[error("invalid identity: {0}")]
+    InvalidIdentity(String),
+    /// A node answered `get_peers` without the token needed to announce.
+    #[error("node did not provide a token")]
+    MissingToken,
+    /// The client was shut down.
+    #[error("DHT client shut down")]
+    Shutdown,
+    /// [`DhtConfig::max_in_flight_queries`] of our queries already await a
+    /// response.
+    #[error("too many queries in flight")]
+    TooManyInFlight,
     #[error("not implemented")]
     Unimplemented,
 }
 
 /// A minimal DHT client.
 ///
-/// Eventually this will manage:
-/// - node id / keypair
-/// - UDP socket
-/// - routing table
-/// - transaction ids and request/response matching
+/// Owns the UDP socket(s), node id and routing table. Background tasks spawned
+/// in [`DhtClient::new`] are the only readers of the sockets: they route
+/// responses to the waiting query by transaction id and hand incoming queries
+/// to a separate handler task. Another task keeps the routing table live.
+///
+/// The client owns those tasks and is not `Clone`; share it behind an `Arc`.
+/// Dropping it, or the last `Arc`, aborts the tasks as [`shutdown`] does,
+/// without waiting for them, and the sockets close once they have stopped.
+/// A socket passed to [`with_socket`] stays open as long as the caller
+/// holds it.
+///
+/// [`shutdown`]: DhtClient::shutdown
+/// [`with_socket`]: DhtClient::with_socket
 pub struct DhtClient {
-    socket: Arc<UdpSocket>,
-    node_id: [u8; 20],
+    sockets: DhtSockets,
+    node_id: SharedNodeId,
+    routing_table: Arc<Mutex<RoutingTable>>,
+    /// `DhtConfig::bootstrap` followed by `DhtConfig::bootstrap_sources`.
+    bootstrap_sources: Vec<BootstrapSource>,
+    /// Advertise this node to the `Mdns` bootstrap sources' queries.
+    mdns_responders: std::sync::Mutex<Vec<mdns::MdnsResponder>>,
+    /// Turns bootstrap entries into addresses.
+    resolver: Arc<dyn Resolver>,
+    bootstrap_dns_timeout: Duration,
+    bootstrap_ping_timeout: Duration,
+    max_node_failures: u32,
+    /// Default concurrency of lookups and announces.
+    lookup_alpha: usize,
+    /// Peers recently found per topic, with when the lookup finished.
+    peer_cache: std::sync::Mutex<HashMap<Topic, (Vec<PeerAddress>, Instant)>>,
+    peer_cache_ttl: Duration,
+    /// Sends our queries; shared with the maintenance task.
+    querier: Querier,
+    /// Incoming queries dropped by the rate limiter.
+    dropped_queries: Arc<AtomicU64>,
+    /// Incoming datagrams dropped as oversized or undecodable.
+    dropped_packets: Arc<AtomicU64>,
+    /// Background receive, query-handling and maintenance tasks.
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+/// Sends queries and waits for their responses.
+///
+/// Cheap to clone, so background tasks can query without borrowing the
+/// [`DhtClient`].
+#[derive(Clone)]
+struct Querier {
+    sockets: DhtSockets,
+    node_id: SharedNodeId,
     routing_table: Arc<Mutex<RoutingTable>>,
-    next_transaction_id: Arc<Mutex<u16>>,
-    bootstrap_nodes: Vec<String>,
+    /// Outstanding queries awaiting a response, keyed on the queried
+    /// address and transaction id.
+    pending: PendingQueries,
+    /// Bound on `pending`.
+    max_in_flight: usize,
+    query_timeout: Duration,
+    min_query_timeout: Duration,
+    /// Cancelled by [`DhtClient::shutdown`]; ends waiting queries early.
+    shutdown: CancellationToken,
+    /// Our address as reported by responders.
+    observed: Arc<std::sync::Mutex<ObservedAddresses>>,
+    /// Who we sent to and who queried us unasked, with
+    /// [`DhtConfig::detect_reachability`].
+    contacts: Option<SharedContacts>,
+    metrics: MetricsHandle,
+}
+
+/// Contacts shared between the querier and the receive loops.
+type SharedContacts = Arc<std::sync::Mutex<Contacts>>;
+
+/// This node's id, shared with the query handler so it can be regenerated.
+type SharedNodeId = Arc<std::sync::RwLock<[u8; 20]>>;
+
+/// Waiters for outstanding queries, keyed on the queried address and
+/// transaction id, so only that address can answer.
+type PendingQueries = Arc<Mutex<HashMap<(SocketAddr, Vec<u8>), oneshot::Sender<protocol::KrpcMessage>>>>;
+
+/// `addr` as responses from it are seen: IPv4-mapped IPv6 addresses are
+/// reported as plain IPv4, and the unspecified address answers from loopback.
+fn pending_addr(addr: SocketAddr) -> SocketAddr {
+    let ip = match addr.ip().to_canonical() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    SocketAddr::new(ip, addr.port())
+}
+
+/// A query received from another node, with the address it came from.
+type IncomingQuery = (SocketAddr, protocol::KrpcMessage);
+
+/// Announced peers per info hash.
+type PeerStore = Arc<Mutex<HashMap<[u8; 32], Vec<StoredPeer>>>>;
+
+/// A peer announced to us.
+struct StoredPeer {
+    addr: SocketAddr,
+    /// The data it announced with itself.
+    value: Option<Vec<u8>>,
+    /// When it last announced.
+    announced: Instant,
+}
+
+/// Reject BEP 44 values that nodes would refuse to store.
+fn check_value_size(value: &[u8]) -> Result<(), DhtError> {
+    match storage::encoded_len(value) {
+        len if len > storage::MAX_VALUE_SIZE => Err(DhtError::ValueTooLarge(len)),
+        _ => Ok(()),
+    }
 }
 
 /// Basic routing table for storing known nodes
 struct RoutingTable {
     nodes: Vec<NodeInfo>,
+    /// Size of the closest set handed out and converged on.
+    k: usize,
 }
 
 // Constants for routing table and protocol
 const MAX_ROUTING_TABLE_SIZE: usize = 100; // Simplified limit; full impl would use k-buckets
+/// Most routing-table nodes from one /24 (IPv4) or /48 (IPv6), so one
+/// attacker cannot fill the table with made-up ids pointing at itself.
+const MAX_NODES_PER_PREFIX: usize = 2;
 const MAX_KRPC_MESSAGE_SIZE: usize = 2048; // Typical UDP DHT message size
-const MAX_RESPONSE_ATTEMPTS: usize = 10; // Retries for matching transaction ID
+const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MIN_QUERY_TIMEOUT: Duration = Duration::from_millis(250);
+/// A node that has answered before gets this many times its average
+/// response time to answer a query.
+pub(crate) const RTT_TIMEOUT_FACTOR: u32 = 4;
+const RTT_EWMA_WEIGHT: u32 = 8; // A new response time counts for 1/8 of the average
+const RTT_MAX_BACKOFF: u32 = 8; // Doublings of a node's timeout after queries time out
+const DEFAULT_BOOTSTRAP_DNS_TIMEOUT: Duration = Duration::from_secs(2);
+// 500ms per node keeps total bootstrap time reasonable when a host
+// resolves to many dead addresses.
+const DEFAULT_BOOTSTRAP_PING_TIMEOUT: Duration = Duration::from_millis(500);
+const BOOTSTRAP_PING_CONCURRENCY: usize = 8; // Bootstrap addresses pinged at once
+const DEFAULT_MAINTENANCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const DEFAULT_MAX_NODE_FAILURES: u32 = 3;
+const DEFAULT_MAX_QUERIES_PER_IP: u32 = 10; // Incoming queries per second
+const DEFAULT_MAX_QUERIES_TOTAL: u32 = 1000; // Incoming queries per second
+const DEFAULT_MAX_IN_FLIGHT_QUERIES: usize = 4096; // Our queries awaiting a response
+const MAX_TRANSACTION_IDS: usize = 1 << 16; // Transaction ids are two bytes
+const DEFAULT_PEER_CACHE_TTL: Duration = Duration::from_secs(30);
+const REACHABILITY_PROBES: usize = 3; // Nodes asked by check_reachability
+const FLUSH_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const INCOMING_QUERY_QUEUE_SIZE: usize = 256; // Queries buffered for the handler task
+const KRPC_ERROR_PROTOCOL: i64 = 203; // BEP 5 error code
+const KRPC_ERROR_METHOD_UNKNOWN: i64 = 204; // BEP 5 error code
+const PEER_TTL: std::time::Duration = std::time::Duration::from_secs(30 * 60); // Announce lifetime
+const MAX_PEERS_PER_INFO_HASH: usize = 100; // Bound on stored peers per topic
+const TOKEN_SIZE: usize = 8; // Bytes in an announce token
+const TOKEN_ROTATION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5 * 60);
 
 // Constants for compact encoding formats (BEP 5)
 const COMPACT_PEER_INFO_SIZE_IPV4: usize = 6; // 4-byte IPv4 + 2-byte port
 const COMPACT_PEER_INFO_SIZE_IPV6: usize = 18; // 16-byte IPv6 + 2-byte port
 const COMPACT_NODE_INFO_SIZE: usize = 26; // 20-byte ID + 4-byte IPv4 + 2-byte port
+const COMPACT_NODE_INFO_SIZE_IPV6: usize = 38; // 20-byte ID + 16-byte IPv6 + 2-byte port (BEP 32)
+const PEER_KEY_SIZE: usize = 32; // Public key in front of a keyed peer (hyperdht)
+
+// `want` values (BEP 32)
+const WANT_IPV4: &str = "n4";
+const WANT_IPV6: &str = "n6";
+
+// Constants for iterative lookups (Kademlia)
+const DEFAULT_LOOKUP_ALPHA: usize = 3; // Concurrent queries per round
+const DEFAULT_LOOKUP_MAX_HOPS: usize = 8; // Upper bound on lookup rounds
+const DEFAULT_BUCKET_K: usize = 8; // Size of the closest set that must be queried to converge
+
+/// Tuning knobs for [`DhtClient::lookup_with`].
+#[derive(Clone, Debug)]
+pub struct LookupOptions {
+    /// Number of closest unqueried nodes to query in each round.
+    pub alpha: usize,
+    /// Maximum number of rounds before the lookup gives up converging.
+    pub max_hops: usize,
+    /// Stop early once this many peers have been found.
+    pub max_peers: Option<usize>,
+    /// Ask the network even if the topic's peers are cached.
+    pub force: bool,
+}
+
+impl Default for LookupOptions {
+    fn default() -> Self {
+        Self {
+            alpha: DEFAULT_LOOKUP_ALPHA,
+            max_hops: DEFAULT_LOOKUP_MAX_HOPS,
+            max_peers: None,
+            force: false,
+        }
+    }
+}
+
+/// What one node answered to `get_peers`, from [`DhtClient::get_peers_at`].
+#[derive(Clone, Debug)]
+pub struct GetPeersReply {
+    /// Peers the node stores for the topic.
+    pub peers: Vec<PeerAddress>,
+    /// Nodes it knows closer to the topic, as `(node id, address)` pairs.
+    pub nodes: Vec<([u8; 20], SocketAddr)>,
+    /// The token to announce to it with, if it gave one.
+    pub token: Option<Vec<u8>>,
+}
+
+/// Result of a single `get_peers` query.
+struct GetPeersResponse {
+    peers: Vec<PeerAddress>,
+    nodes: Vec<NodeInfo>,
+    token: Option<Vec<u8>>,
+}
 
 #[derive(Clone, Debug)]
 #[allow(dead_code)]
 struct NodeInfo {
     node_id: [u8; 20],
     addr: SocketAddr,
+    /// When the node last answered or queried us.
+    last_seen: Instant,
+    /// Liveness pings it has failed in a row.
+    failures: u32,
+    /// Moving average of how long it takes to answer our queries, once it
+    /// has answered one.
+    rtt: Option<Duration>,
+    /// Queries it let time out in a row; each doubles its next timeout.
+    timeouts: u32,
+}
+
+impl NodeInfo {
+    fn new(node_id: [u8; 20], addr: SocketAddr) -> Self {
+        Self {
+            node_id,
+            addr,
+            last_seen: Instant::now(),
+            failures: 0,
+            rtt: None,
+            timeouts: 0,
+        }
+    }
 }
 
 impl RoutingTable {
-    fn new() -> Self {
-        Self { nodes: Vec::new() }
+    fn new(k: usize) -> Self {
+        Self { nodes: Vec::new(), k }
     }
 
     fn add_node(&mut self, node_id: [u8; 20], addr: SocketAddr) {
         // Simple implementation: just add to the list
         // In a full implementation, this would use k-buckets
-        self.nodes.push(NodeInfo { node_id, addr });
+        let prefix = ip_prefix(addr.ip());
+        let from_prefix = self
+            .nodes
+            .iter()
+            .filter(|n| n.node_id != node_id && ip_prefix(n.addr.ip()) == prefix)
+            .count();
+        // Loopback is exempt, as from the rate limits: local nodes are ours
+        if from_prefix >= MAX_NODES_PER_PREFIX && !addr.ip().is_loopback() {
+            tracing::debug!("Not adding {}: {} nodes from its prefix already", addr, from_prefix);
+            return;
+        }
+        // A node seen again moves to the back as the most recently seen,
+        // keeping its response time
+        let (rtt, timeouts) = self
+            .nodes
+            .iter()
+            .find(|n| n.node_id == node_id)
+            .map_or((None, 0), |n| (n.rtt, n.timeouts));
+        self.nodes.retain(|n| n.node_id != node_id);
+        self.nodes.push(NodeInfo { rtt, timeouts, ..NodeInfo::new(node_id, addr) });
         
         // Keep the table size limited
         if self.nodes.len() > MAX_ROUTING_TABLE_SIZE {
@@ -92,389 +579,1822 @@ impl RoutingTable {
         }
     }
 
-    #[allow(dead_code)]
     fn get_nodes(&self, count: usize) -> Vec<NodeInfo> {
         self.nodes.iter().take(count).cloned().collect()
     }
-}
 
-impl DhtClient {
-    pub async fn new(config: DhtConfig) -> Result<Self, DhtError> {
-        // Bind UDP socket
-        let bind_addr = format!("0.0.0.0:{}", config.bind_port);
-        let socket = UdpSocket::bind(&bind_addr).await?;
-        
-        // Generate random node ID (20 bytes for mainline DHT compatibility)
-        let mut rng = rand::thread_rng();
-        let mut node_id = [0u8; 20];
-        rng.fill(&mut node_id);
-        
-        Ok(Self {
-            socket: Arc::new(socket),
-            node_id,
-            routing_table: Arc::new(Mutex::new(RoutingTable::new())),
-            next_transaction_id: Arc::new(Mutex::new(0)),
-            bootstrap_nodes: config.bootstrap,
-        })
+    /// Return up to `count` nodes ordered by XOR distance to `target`.
+    fn closest(&self, target: &[u8; 20], count: usize) -> Vec<NodeInfo> {
+        self.closest_where(target, count, |_| true)
     }
 
-    /// Join the DHT and populate the routing table from bootstrap nodes.
-    pub async fn bootstrap(&self) -> Result<(), DhtError> {
-        // Use configured bootstrap nodes
-        let bootstrap_nodes = if self.bootstrap_nodes.is_empty() {
-            // If no bootstrap nodes configured, use mainline DHT defaults
-            vec![
-                "router.bittorrent.com:6881".to_string(),
-                "dht.transmissionbt.com:6881".to_string(),
-                "router.utorrent.com:6881".to_string(),
-            ]
-        } else {
-            self.bootstrap_nodes.clone()
-        };
-        
-        for node_addr in bootstrap_nodes {
-            // Try to resolve and ping each bootstrap node
-            // Use a shorter timeout for DNS resolution
-            let timeout_result = tokio::time::timeout(
-                std::time::Duration::from_secs(2),
-                tokio::net::lookup_host(&node_addr)
-            ).await;
-            
-            match timeout_result {
-                Ok(Ok(mut addrs)) => {
-                    if let Some(addr) = addrs.next() {
-                        // Send ping to bootstrap node with timeout.
-                        // 500ms per node keeps total bootstrap time reasonable
-                        // when probing multiple nodes sequentially.
-                        let ping_timeout_result = tokio::time::timeout(
-                            std::time::Duration::from_millis(500),
-                            self.ping(addr)
-                        ).await;
-                        
-                        // Silently ignore errors and timeouts
-                        let _ = ping_timeout_result;
-                    }
-                }
-                _ => {
-                    // Silently skip nodes that can't be resolved or timeout
-                    continue;
-                }
-            }
-        }
-        
-        Ok(())
+    /// Like [`RoutingTable::closest`], restricted to nodes matching `keep`.
+    fn closest_where(&self, target: &[u8; 20], count: usize, keep: impl Fn(&NodeInfo) -> bool) -> Vec<NodeInfo> {
+        let mut nodes: Vec<NodeInfo> = self.nodes.iter().filter(|n| keep(n)).cloned().collect();
+        nodes.sort_by_key(|n| xor_distance(&n.node_id, target));
+        nodes.dedup_by_key(|n| n.node_id);
+        nodes.truncate(count);
+        nodes
     }
 
-    /// Send a ping query to a node
-    async fn ping(&self, addr: SocketAddr) -> Result<Vec<u8>, DhtError> {
-        let tx_id = self.get_transaction_id().await;
-        
-        let msg = protocol::KrpcMessage {
-            t: tx_id.clone(),
-            y: protocol::KrpcMessageType::Query,
-            q: Some(protocol::KrpcQueryKind::Ping),
-            a: Some(protocol::KrpcArgs {
-                id: Some(self.node_id.to_vec()),
-                ..Default::default()
-            }),
-            r: None,
-            e: None,
-        };
-        
-        self.send_krpc(addr, msg).await?;
-        
-        // Wait for response with timeout
-        let response = tokio::time::timeout(
-            std::time::Duration::from_secs(5),
-            self.recv_response(&tx_id)
-        )
-        .await
-        .map_err(|_| DhtError::Timeout)??;
-        
-        // Add responding node to routing table
-        if let Some(r) = &response.r {
-            if let Some(id) = &r.id {
-                if id.len() == 20 {
-                    let mut node_id = [0u8; 20];
-                    node_id.copy_from_slice(&id[..20]);
-                    let mut rt = self.routing_table.lock().await;
-                    rt.add_node(node_id, addr);
-                }
+    /// The least recently seen node of each bucket, buckets being the nodes
+    /// that share the same number of leading bits with `own_id`.
+    fn stalest_per_bucket(&self, own_id: &[u8; 20]) -> Vec<NodeInfo> {
+        let mut stalest: HashMap<u32, &NodeInfo> = HashMap::new();
+        for node in &self.nodes {
+            let bucket = bucket_index(own_id, &node.node_id);
+            let entry = stalest.entry(bucket).or_insert(node);
+            if node.last_seen < entry.last_seen {
+                *entry = node;
             }
         }
-        
-        Ok(response.r.and_then(|r| r.id).unwrap_or_default())
+        stalest.into_values().cloned().collect()
     }
 
-    /// Send a find_node query to locate nodes near a target
-    #[allow(dead_code)]
-    async fn find_node(&self, addr: SocketAddr, target: &[u8; 20]) -> Result<Vec<NodeInfo>, DhtError> {
-        let tx_id = self.get_transaction_id().await;
-        
-        let msg = protocol::KrpcMessage {
-            t: tx_id.clone(),
-            y: protocol::KrpcMessageType::Query,
-            q: Some(protocol::KrpcQueryKind::FindNode),
-            a: Some(protocol::KrpcArgs {
-                id: Some(self.node_id.to_vec()),
-                target: Some(target.to_vec()),
-                ..Default::default()
-            }),
-            r: None,
-            e: None,
-        };
-        
-        self.send_krpc(addr, msg).await?;
-        
-        // Wait for response with timeout
-        let response = tokio::time::timeout(
-            std::time::Duration::from_secs(5),
-            self.recv_response(&tx_id)
-        )
-        .await
-        .map_err(|_| DhtError::Timeout)??;
-        
-        // Parse compact node info from response
-        let mut nodes = Vec::new();
-        if let Some(r) = response.r {
-            if let Some(nodes_data) = r.nodes {
-                // Each node is 26 bytes: 20-byte ID + 4-byte IPv4 + 2-byte port (BEP 5)
-                for chunk in nodes_data.chunks(COMPACT_NODE_INFO_SIZE) {
-                    if chunk.len() == COMPACT_NODE_INFO_SIZE {
-                        let mut node_id = [0u8; 20];
-                        node_id.copy_from_slice(&chunk[0..20]);
-                        
-                        let ip = std::net::Ipv4Addr::new(
-                            chunk[20], chunk[21], chunk[22], chunk[23]
-                        );
-                        let port = u16::from_be_bytes([chunk[24], chunk[25]]);
-                        let addr = SocketAddr::new(std::net::IpAddr::V4(ip), port);
-                        
-                        nodes.push(NodeInfo { node_id, addr });
-                    }
-                }
-            }
-        }
-        
-        Ok(nodes)
+    /// The id of the node at `addr`, if it is in the table.
+    fn node_at(&self, addr: &SocketAddr) -> Option<[u8; 20]> {
+        self.nodes.iter().find(|n| &n.addr == addr).map(|n| n.node_id)
     }
 
-    /// Get peers for a given info hash (topic) from a node
-    async fn get_peers(&self, addr: SocketAddr, info_hash: &[u8; 32]) -> Result<(Vec<PeerAddress>, Option<Vec<u8>>), DhtError> {
-        let tx_id = self.get_transaction_id().await;
-        
-        let msg = protocol::KrpcMessage {
-            t: tx_id.clone(),
-            y: protocol::KrpcMessageType::Query,
-            q: Some(protocol::KrpcQueryKind::GetPeers),
-            a: Some(protocol::KrpcArgs {
-                id: Some(self.node_id.to_vec()),
-                info_hash: Some(info_hash.to_vec()),
-                ..Default::default()
-            }),
-            r: None,
-            e: None,
-        };
-        
-        self.send_krpc(addr, msg).await?;
-        
-        // Wait for response with timeout
-        let response = tokio::time::timeout(
-            std::time::Duration::from_secs(5),
-            self.recv_response(&tx_id)
-        )
-        .await
-        .map_err(|_| DhtError::Timeout)??;
-        
-        let mut peers = Vec::new();
-        let mut token = None;
-        
-        if let Some(r) = response.r {
-            // Extract token for announce_peer
-            token = r.token.clone();
-            
-            // Parse compact peer info from values field
-            // BEP 5 defines both IPv4 (6 bytes) and IPv6 (18 bytes) formats
-            if let Some(values) = r.values {
-                for value in values {
-                    if value.len() == COMPACT_PEER_INFO_SIZE_IPV4 {
-                        // IPv4: 4-byte IP + 2-byte port
-                        let ip = std::net::Ipv4Addr::new(
-                            value[0], value[1], value[2], value[3]
-                        );
-                        let port = u16::from_be_bytes([value[4], value[5]]);
-                        let addr = SocketAddr::new(std::net::IpAddr::V4(ip), port);
-                        
-                        peers.push(PeerAddress {
-                            addr,
-                            node_id: None,
-                        });
-                    } else if value.len() == COMPACT_PEER_INFO_SIZE_IPV6 {
-                        // IPv6: 16-byte IP + 2-byte port
-                        let mut ipv6_bytes = [0u8; 16];
-                        ipv6_bytes.copy_from_slice(&value[0..16]);
-                        let ip = std::net::Ipv6Addr::from(ipv6_bytes);
-                        let port = u16::from_be_bytes([value[16], value[17]]);
-                        let addr = SocketAddr::new(std::net::IpAddr::V6(ip), port);
-                        
-                        peers.push(PeerAddress {
-                            addr,
-                            node_id: None,
-                        });
-                    } else {
-                        // Unknown format, skip
-                        tracing::debug!("Skipping peer with unknown compact format length: {}", value.len());
-                    }
-                }
-            }
-        }
-        
-        Ok((peers, token))
+    /// The average response time of node `node_id`, if it has answered a
+    /// query before, and the queries it has let time out since.
+    fn rtt(&self, node_id: &[u8; 20]) -> Option<(Duration, u32)> {
+        let node = self.nodes.iter().find(|n| &n.node_id == node_id)?;
+        node.rtt.map(|rtt| (rtt, node.timeouts))
     }
 
-    /// Announce our presence for a topic to a specific node
-    async fn announce_peer(&self, addr: SocketAddr, info_hash: &[u8; 32], port: u16, token: Vec<u8>) -> Result<(), DhtError> {
-        let tx_id = self.get_transaction_id().await;
-        
-        let msg = protocol::KrpcMessage {
-            t: tx_id.clone(),
-            y: protocol::KrpcMessageType::Query,
-            q: Some(protocol::KrpcQueryKind::AnnouncePeer),
-            a: Some(protocol::KrpcArgs {
-                id: Some(self.node_id.to_vec()),
-                info_hash: Some(info_hash.to_vec()),
-                port: Some(port),
-                token: Some(token),
-                ..Default::default()
-            }),
-            r: None,
-            e: None,
-        };
-        
-        self.send_krpc(addr, msg).await?;
-        
-        // Wait for response with timeout
-        let _response = tokio::time::timeout(
-            std::time::Duration::from_secs(5),
-            self.recv_response(&tx_id)
-        )
-        .await
-        .map_err(|_| DhtError::Timeout)??;
-        
-        Ok(())
+    /// Fold a response time of node `node_id` into its average.
+    fn record_rtt(&mut self, node_id: &[u8; 20], rtt: Duration) {
+        if let Some(node) = self.nodes.iter_mut().find(|n| &n.node_id == node_id) {
+            node.rtt = Some(match node.rtt {
+                Some(average) => (average * (RTT_EWMA_WEIGHT - 1) + rtt) / RTT_EWMA_WEIGHT,
+                None => rtt,
+            });
+            node.timeouts = 0;
+        }
     }
 
-    /// Announce our presence for `topic`.
-    ///
-    /// In KRPC terms this often maps to `announce_peer` / topic announce.
-    /// This is a simplified implementation that announces to bootstrap nodes.
-    pub async fn announce(&self, topic: Topic, port: u16) -> Result<(), DhtError> {
-        // Convert topic (32 bytes) to info_hash format
-        let info_hash = topic.0;
-        
-        // Get nodes from routing table
-        let nodes = {
-            let rt = self.routing_table.lock().await;
-            rt.get_nodes(10)
-        };
-        
-        // If routing table is empty, bootstrap first
-        if nodes.is_empty() {
-            self.bootstrap().await?;
-        }
-        
-        // Get updated node list
-        let nodes = {
-            let rt = self.routing_table.lock().await;
-            rt.get_nodes(10)
-        };
-        
-        // Announce to each node in routing table
-        for node in nodes {
-            // First get token from get_peers
-            match self.get_peers(node.addr, &info_hash).await {
-                Ok((_, Some(token))) => {
-                    // Announce with the token
-                    if let Err(e) = self.announce_peer(node.addr, &info_hash, port, token).await {
-                        tracing::debug!("Failed to announce to node {}: {}", node.addr, e);
-                    }
-                }
-                Err(e) => {
-                    // Skip nodes that don't respond or don't provide a token
-                    tracing::debug!("Failed to get peers from node {}: {}", node.addr, e);
-                    continue;
-                }
-                _ => {
-                    tracing::debug!("Node {} did not provide a token", node.addr);
-                    continue;
-                }
+    /// Count a query node `node_id` let time out after `waited`: the wait
+    /// is folded into its average as a response time it at least took, and
+    /// its next timeout doubles.
+    fn record_timeout(&mut self, node_id: &[u8; 20], waited: Duration) {
+        if let Some(node) = self.nodes.iter_mut().find(|n| &n.node_id == node_id) {
+            if let Some(average) = node.rtt {
+                node.rtt = Some((average * (RTT_EWMA_WEIGHT - 1) + waited) / RTT_EWMA_WEIGHT);
             }
+            node.timeouts = (node.timeouts + 1).min(RTT_MAX_BACKOFF);
         }
-        
-        Ok(())
     }
 
-    /// Lookup peers for `topic`.
-    /// This is a simplified implementation that queries bootstrap nodes.
-    pub async fn lookup(&self, topic: Topic) -> Result<Vec<PeerAddress>, DhtError> {
-        // Convert topic (32 bytes) to info_hash format
-        let info_hash = topic.0;
-        
-        // Get nodes from routing table
-        let nodes = {
-            let rt = self.routing_table.lock().await;
-            rt.get_nodes(10)
+    /// Count a failed liveness ping, evicting the node once it has failed
+    /// `max_failures` times in a row. Returns whether it was evicted.
+    fn record_failure(&mut self, node_id: &[u8; 20], max_failures: u32) -> bool {
+        let Some(index) = self.nodes.iter().position(|n| &n.node_id == node_id) else {
+            return false;
         };
-        
-        // If routing table is empty, bootstrap first
-        if nodes.is_empty() {
-            self.bootstrap().await?;
-        }
-        
-        // Get updated node list
-        let nodes = {
-            let rt = self.routing_table.lock().await;
-            rt.get_nodes(10)
-        };
-        
-        let mut all_peers = Vec::new();
-        
-        // Query each node for peers
-        for node in nodes {
-            match self.get_peers(node.addr, &info_hash).await {
-                Ok((peers, _)) => {
-                    all_peers.extend(peers);
-                }
-                Err(_) => {
-                    // Skip nodes that don't respond
-                    continue;
-                }
-            }
+        self.nodes[index].failures += 1;
+        if self.nodes[index].failures < max_failures {
+            return false;
         }
-        
-        Ok(all_peers)
+        self.nodes.remove(index);
+        true
     }
+}
 
-    /// Flush in-flight queries.
-    pub async fn flush(&self) -> Result<(), DhtError> {
-        // TODO: wait for pending queries to resolve.
-        Ok(())
+/// Kademlia bucket of `node_id` as seen from `own_id`: the length of their
+/// common prefix in bits.
+fn bucket_index(own_id: &[u8; 20], node_id: &[u8; 20]) -> u32 {
+    let distance = xor_distance(own_id, node_id);
+    let zero_bytes = distance.iter().take_while(|&&b| b == 0).count();
+    match distance.get(zero_bytes) {
+        Some(byte) => zero_bytes as u32 * 8 + byte.leading_zeros(),
+        None => 160,
     }
+}
 
-    pub async fn shutdown(&self) -> Result<(), DhtError> {
-        // TODO: stop background tasks.
-        Ok(())
+/// The /24 (IPv4) or /48 (IPv6) network `ip` is in: `ip` with the host
+/// bits cleared.
+fn ip_prefix(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => IpAddr::V4(Ipv4Addr::from(v4.to_bits() & !0xFF)),
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(v6.to_bits() & !(u128::MAX >> 48))),
     }
+}
 
-    /// Get the local socket address
-    pub fn local_addr(&self) -> Result<SocketAddr, DhtError> {
-        Ok(self.socket.local_addr()?)
+/// XOR distance between two node ids (Kademlia metric).
+fn xor_distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut out = [0u8; 20];
+    for i in 0..20 {
+        out[i] = a[i] ^ b[i];
     }
+    out
+}
 
-    /// Get this node's ID (for testing)
-    pub fn node_id(&self) -> [u8; 20] {
-        self.node_id
-    }
+/// Map a 32-byte topic onto the 20-byte node id space used for routing.
+fn topic_target(topic: &Topic) -> [u8; 20] {
+    let mut target = [0u8; 20];
+    target.copy_from_slice(&topic.0[..20]);
+    target
+}
+
+/// Parse compact node info (BEP 5): 20-byte ID + 4-byte IPv4 + 2-byte port per node.
+fn parse_compact_nodes(data: &[u8]) -> Vec<NodeInfo> {
+    data.chunks_exact(COMPACT_NODE_INFO_SIZE)
+        .map(|chunk| {
+            let mut node_id = [0u8; 20];
+            node_id.copy_from_slice(&chunk[0..20]);
+            let ip = std::net::Ipv4Addr::new(chunk[20], chunk[21], chunk[22], chunk[23]);
+            let port = u16::from_be_bytes([chunk[24], chunk[25]]);
+            NodeInfo::new(node_id, SocketAddr::new(std::net::IpAddr::V4(ip), port))
+        })
+        .collect()
+}
+
+/// Parse compact IPv6 node info (BEP 32): 20-byte ID + 16-byte IPv6 + 2-byte port per node.
+fn parse_compact_nodes6(data: &[u8]) -> Vec<NodeInfo> {
+    data.chunks_exact(COMPACT_NODE_INFO_SIZE_IPV6)
+        .map(|chunk| {
+            let mut node_id = [0u8; 20];
+            node_id.copy_from_slice(&chunk[0..20]);
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&chunk[20..36]);
+            let port = u16::from_be_bytes([chunk[36], chunk[37]]);
+            NodeInfo::new(node_id, SocketAddr::new(std::net::IpAddr::V6(Ipv6Addr::from(ip)), port))
+        })
+        .collect()
+}
+
+/// Arguments of an `announce_peer` or `unannounce` query. Port 0 is sent
+/// as `implied_port` (BEP 5): use the port the query came from.
+fn announce_args(node_id: [u8; 20], info_hash: &[u8; 32], port: u16, token: Vec<u8>, value: Option<&[u8]>) -> protocol::KrpcArgs {
+    protocol::KrpcArgs {
+        id: Some(node_id.to_vec()),
+        info_hash: Some(info_hash.to_vec()),
+        port: Some(port),
+        implied_port: (port == 0).then_some(1),
+        token: Some(token),
+        value: value.map(<[u8]>::to_vec),
+        ..Default::default()
+    }
+}
+
+/// Parse a compact peer value (BEP 5): IPv4 (6 bytes) or IPv6 (18 bytes) + port.
+pub(crate) fn parse_compact_peer(value: &[u8]) -> Option<SocketAddr> {
+    if value.len() == COMPACT_PEER_INFO_SIZE_IPV4 {
+        // IPv4: 4-byte IP + 2-byte port
+        let ip = std::net::Ipv4Addr::new(value[0], value[1], value[2], value[3]);
+        let port = u16::from_be_bytes([value[4], value[5]]);
+        Some(SocketAddr::new(std::net::IpAddr::V4(ip), port))
+    } else if value.len() == COMPACT_PEER_INFO_SIZE_IPV6 {
+        // IPv6: 16-byte IP + 2-byte port
+        let mut ipv6_bytes = [0u8; 16];
+        ipv6_bytes.copy_from_slice(&value[0..16]);
+        let ip = std::net::Ipv6Addr::from(ipv6_bytes);
+        let port = u16::from_be_bytes([value[16], value[17]]);
+        Some(SocketAddr::new(std::net::IpAddr::V6(ip), port))
+    } else {
+        None
+    }
+}
+
+/// Parse a keyed peer value: a 32-byte public key followed by compact peer info.
+fn parse_keyed_peer(value: &[u8]) -> Option<PeerAddress> {
+    let (key, addr) = value.split_at_checked(PEER_KEY_SIZE)?;
+    Some(PeerAddress {
+        addr: parse_compact_peer(addr)?,
+        node_id: Some(key.try_into().ok()?),
+        value: None,
+    })
+}
+
+/// Encode IPv4 nodes as compact node info; IPv6 nodes are skipped.
+fn encode_compact_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(nodes.len() * COMPACT_NODE_INFO_SIZE);
+    for node in nodes {
+        if let SocketAddr::V4(v4) = node.addr {
+            out.extend_from_slice(&node.node_id);
+            out.extend_from_slice(&v4.ip().octets());
+            out.extend_from_slice(&v4.port().to_be_bytes());
+        }
+    }
+    out
+}
+
+/// Encode IPv6 nodes as compact `nodes6` info; IPv4 nodes are skipped.
+fn encode_compact_nodes6(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(nodes.len() * COMPACT_NODE_INFO_SIZE_IPV6);
+    for node in nodes {
+        if let SocketAddr::V6(v6) = node.addr {
+            out.extend_from_slice(&node.node_id);
+            out.extend_from_slice(&v6.ip().octets());
+            out.extend_from_slice(&v6.port().to_be_bytes());
+        }
+    }
+    out
+}
+
+/// The IPv4 and/or IPv6 socket of a node; at least one is bound.
+///
+/// Outgoing packets leave through the socket matching the destination's
+/// address family.
+#[derive(Clone)]
+struct DhtSockets {
+    v4: Option<Arc<dyn PacketTransport>>,
+    v6: Option<Arc<dyn PacketTransport>>,
+}
+
+impl DhtSockets {
+    /// Bind to `addr` on `port`. On `0.0.0.0`, also bind an IPv6-only socket
+    /// on the same port if `ipv6` is requested.
+    async fn bind(addr: IpAddr, port: u16, ipv6: bool) -> Result<Self, DhtError> {
+        if addr.is_ipv6() {
+            return Ok(Self {
+                v4: None,
+                v6: Some(Arc::new(Self::bind_v6(SocketAddr::new(addr, port))?)),
+            });
+        }
+        let v4 = UdpSocket::bind(SocketAddr::new(addr, port)).await?;
+        let v6 = if ipv6 && addr.is_unspecified() {
+            // Prefer the IPv4 port so the node is reachable on one port number
+            let v4_port = v4.local_addr()?.port();
+            let any = |port| SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
+            match Self::bind_v6(any(v4_port)).or_else(|_| Self::bind_v6(any(0))) {
+                Ok(socket) => Some(Arc::new(socket) as Arc<dyn PacketTransport>),
+                Err(e) => {
+                    tracing::debug!("IPv6 unavailable, running IPv4 only: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        Ok(Self {
+            v4: Some(Arc::new(v4)),
+            v6,
+        })
+    }
+
+    /// Use `transport` as the IPv4 socket of an IPv4-only node.
+    fn single(transport: Arc<dyn PacketTransport>) -> Result<Self, DhtError> {
+        if !transport.local_addr()?.is_ipv4() {
+            return Err(DhtError::InvalidConfig("transport needs an IPv4 address".to_string()));
+        }
+        Ok(Self {
+            v4: Some(transport),
+            v6: None,
+        })
+    }
+
+    fn bind_v6(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        // Keep IPv4 traffic on the dedicated IPv4 socket
+        socket.set_only_v6(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        UdpSocket::from_std(socket.into())
+    }
+
+    /// The socket able to reach `addr`, if any.
+    fn for_addr(&self, addr: &SocketAddr) -> Option<&Arc<dyn PacketTransport>> {
+        match addr {
+            SocketAddr::V4(_) => self.v4.as_ref(),
+            SocketAddr::V6(_) => self.v6.as_ref(),
+        }
+    }
+
+    async fn send_to(&self, data: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+        match self.for_addr(&addr) {
+            Some(socket) => socket.send_to(data, addr).await,
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::AddrNotAvailable,
+                "no socket bound for this address family",
+            )),
+        }
+    }
+
+    /// The `want` argument advertising which node families we can use.
+    fn want(&self) -> Vec<String> {
+        let mut want = Vec::new();
+        if self.v4.is_some() {
+            want.push(WANT_IPV4.to_string());
+        }
+        if self.v6.is_some() {
+            want.push(WANT_IPV6.to_string());
+        }
+        want
+    }
+
+    /// The IPv4 socket, or the IPv6 one of an IPv6-only node.
+    fn primary(&self) -> &Arc<dyn PacketTransport> {
+        self.v4
+            .as_ref()
+            .or(self.v6.as_ref())
+            .expect("a node binds at least one socket")
+    }
+
+    fn all(&self) -> impl Iterator<Item = &Arc<dyn PacketTransport>> {
+        self.v4.iter().chain(self.v6.as_ref())
+    }
+}
+
+/// Encode a peer address as a compact peer value (6 or 18 bytes).
+pub(crate) fn encode_compact_peer(addr: SocketAddr) -> Vec<u8> {
+    let mut out = match addr.ip() {
+        std::net::IpAddr::V4(ip) => ip.octets().to_vec(),
+        std::net::IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    out.extend_from_slice(&addr.port().to_be_bytes());
+    out
+}
+
+/// Rotating secret behind the announce tokens this node hands out.
+///
+/// A token is `blake2s(secret || client_ip)` truncated to [`TOKEN_SIZE`], so it
+/// is only usable from the address that requested it. The secret rotates every
+/// [`TOKEN_ROTATION_INTERVAL`]; tokens from the previous secret stay valid for
+/// one more interval so an announce racing a rotation is not rejected.
+struct TokenSecret {
+    current: [u8; 32],
+    previous: Option<[u8; 32]>,
+    rotated_at: Instant,
+}
+
+impl TokenSecret {
+    fn new() -> Self {
+        Self {
+            current: rand::thread_rng().gen(),
+            previous: None,
+            rotated_at: Instant::now(),
+        }
+    }
+
+    /// Rotate the secret if the current one has outlived its interval.
+    fn rotate_if_due(&mut self) {
+        let age = self.rotated_at.elapsed();
+        if age < TOKEN_ROTATION_INTERVAL {
+            return;
+        }
+        // After two idle intervals the old secret is past its grace period too
+        self.previous = (age < 2 * TOKEN_ROTATION_INTERVAL).then_some(self.current);
+        self.current = rand::thread_rng().gen();
+        self.rotated_at = Instant::now();
+    }
+
+    /// Token to hand out to a node at `ip`.
+    fn token_for(&mut self, ip: std::net::IpAddr) -> Vec<u8> {
+        self.rotate_if_due();
+        Self::derive(&self.current, ip)
+    }
+
+    /// Whether `token` was issued to `ip` under the current or previous secret.
+    fn is_valid(&mut self, token: &[u8], ip: std::net::IpAddr) -> bool {
+        self.rotate_if_due();
+        std::iter::once(self.current)
+            .chain(self.previous)
+            .any(|secret| Self::derive(&secret, ip) == token)
+    }
+
+    fn derive(secret: &[u8; 32], ip: std::net::IpAddr) -> Vec<u8> {
+        use blake2::{Blake2s256, Digest};
+
+        let mut hasher = Blake2s256::new();
+        hasher.update(secret);
+        match ip {
+            std::net::IpAddr::V4(v4) => hasher.update(v4.octets()),
+            std::net::IpAddr::V6(v6) => hasher.update(v6.octets()),
+        }
+        hasher.finalize()[..TOKEN_SIZE].to_vec()
+    }
+}
+
+/// Answers KRPC queries from other nodes so this node takes part in the DHT.
+struct QueryHandler {
+    sockets: DhtSockets,
+    node_id: SharedNodeId,
+    routing_table: Arc<Mutex<RoutingTable>>,
+    peer_store: PeerStore,
+    /// Issues `get_peers` tokens and checks them on `announce_peer`.
+    token_secret: Arc<Mutex<TokenSecret>>,
+    /// BEP 44 items stored for other nodes.
+    items: Mutex<ItemStore>,
+    /// Decides which incoming queries get an answer.
+    rate_limiter: Mutex<QueryRateLimiter>,
+    /// Incoming queries dropped by the rate limiter.
+    dropped_queries: Arc<AtomicU64>,
+}
+
+impl QueryHandler {
+    /// Answer queries until the channel closes.
+    async fn run(self, mut queries: mpsc::Receiver<IncomingQuery>) {
+        while let Some((addr, msg)) = queries.recv().await {
+            let Some(reply) = self.handle(addr, msg).await else {
+                continue;
+            };
+            match protocol::encode_krpc(&reply) {
+                Ok(data) => {
+                    if let Err(e) = self.sockets.send_to(&data, addr).await {
+                        tracing::debug!("Failed to answer query from {}: {}", addr, e);
+                    }
+                }
+                Err(e) => tracing::debug!("Failed to encode reply: {}", e),
+            }
+        }
+    }
+
+    /// Build the reply to a single query, or `None` to stay silent.
+    ///
+    /// Queries over the rate limit are dropped unanswered.
+    async fn handle(&self, addr: SocketAddr, mut msg: protocol::KrpcMessage) -> Option<protocol::KrpcMessage> {
+        if !self.rate_limiter.lock().await.allow(addr.ip()) {
+            self.dropped_queries.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        let t = std::mem::take(&mut msg.t);
+        let Ok(protocol::KrpcBody::Query { kind, args }) = msg.body() else {
+            return Some(Self::error(t, KRPC_ERROR_PROTOCOL, "Missing query or arguments"));
+        };
+        
+        // Nodes that query us are live: remember them
+        let Some(querier) = args.id.as_deref().and_then(|id| <[u8; 20]>::try_from(id).ok()) else {
+            return Some(Self::error(t, KRPC_ERROR_PROTOCOL, "Missing or invalid node id"));
+        };
+        self.routing_table.lock().await.add_node(querier, addr);
+        
+        let (want_v4, want_v6) = Self::wanted_families(args.want.as_deref(), &addr);
+        
+        let response = match kind {
+            protocol::KrpcQueryKind::Ping => protocol::KrpcResponse::default(),
+            protocol::KrpcQueryKind::FindNode => {
+                let Some(target) = args.target.as_deref().and_then(|t| <[u8; 20]>::try_from(t).ok()) else {
+                    return Some(Self::error(t, KRPC_ERROR_PROTOCOL, "Missing or invalid target"));
+                };
+                self.closest_nodes(&target, want_v4, want_v6).await
+            }
+            protocol::KrpcQueryKind::GetPeers => {
+                let Some(info_hash) = args.info_hash.as_deref().and_then(|h| <[u8; 32]>::try_from(h).ok()) else {
+                    return Some(Self::error(t, KRPC_ERROR_PROTOCOL, "Missing or invalid info_hash"));
+                };
+                let peers = self.stored_peer_values(&info_hash).await;
+                let mut response = if peers.is_empty() {
+                    let mut target = [0u8; 20];
+                    target.copy_from_slice(&info_hash[..20]);
+                    self.closest_nodes(&target, want_v4, want_v6).await
+                } else {
+                    let peer_values: Vec<protocol::PeerValue> = peers
+                        .iter()
+                        .filter_map(|(addr, value)| {
+                            let value = value.clone()?;
+                            Some(protocol::PeerValue { peer: encode_compact_peer(*addr), value })
+                        })
+                        .collect();
+                    protocol::KrpcResponse {
+                        values: Some(peers.into_iter().map(|(addr, _)| encode_compact_peer(addr)).collect()),
+                        peer_values: (!peer_values.is_empty()).then_some(peer_values),
+                        ..Default::default()
+                    }
+                };
+                response.token = Some(self.token_secret.lock().await.token_for(addr.ip()));
+                response
+            }
+            protocol::KrpcQueryKind::AnnouncePeer | protocol::KrpcQueryKind::Unannounce => {
+                let Some(info_hash) = args.info_hash.as_deref().and_then(|h| <[u8; 32]>::try_from(h).ok()) else {
+                    return Some(Self::error(t, KRPC_ERROR_PROTOCOL, "Missing or invalid info_hash"));
+                };
+                let port = match (args.implied_port, args.port) {
+                    (Some(1), _) => addr.port(),
+                    (_, Some(port)) => port,
+                    (_, None) => return Some(Self::error(t, KRPC_ERROR_PROTOCOL, "Missing port")),
+                };
+                if args.value.as_ref().is_some_and(|value| value.len() > MAX_ANNOUNCE_VALUE_SIZE) {
+                    return Some(Self::error(t, KRPC_ERROR_PROTOCOL, "Value too large"));
+                }
+                if !self.token_ok(args.token.as_deref(), &addr).await {
+                    return Some(Self::error(t, KRPC_ERROR_PROTOCOL, "Bad token"));
+                }
+                let peer = SocketAddr::new(addr.ip(), port);
+                if matches!(kind, protocol::KrpcQueryKind::Unannounce) {
+                    self.remove_peer(&info_hash, peer).await;
+                } else {
+                    self.store_peer(info_hash, peer, args.value.clone()).await;
+                }
+                protocol::KrpcResponse::default()
+            }
+            protocol::KrpcQueryKind::Get => {
+                let Some(target) = args.target.as_deref().and_then(|t| <[u8; 20]>::try_from(t).ok()) else {
+                    return Some(Self::error(t, KRPC_ERROR_PROTOCOL, "Missing or invalid target"));
+                };
+                let mut response = self.closest_nodes(&target, want_v4, want_v6).await;
+                response.token = Some(self.token_secret.lock().await.token_for(addr.ip()));
+                let mut items = self.items.lock().await;
+                if let Some(item) = items.get_mutable(&target) {
+                    response.k = Some(item.public_key.to_vec());
+                    response.seq = Some(item.seq);
+                    // A querier that already has this version does not need it again
+                    if args.seq.is_none_or(|seq| seq < item.seq) {
+                        response.v = Some(item.value);
+                        response.sig = Some(item.signature.to_vec());
+                    }
+                } else if let Some(value) = items.get_immutable(&target) {
+                    response.v = Some(value);
+                }
+                response
+            }
+            protocol::KrpcQueryKind::Put => {
+                if !self.token_ok(args.token.as_deref(), &addr).await {
+                    return Some(Self::error(t, KRPC_ERROR_PROTOCOL, "Bad token"));
+                }
+                let Some(value) = args.v.clone() else {
+                    return Some(Self::error(t, KRPC_ERROR_PROTOCOL, "Missing value"));
+                };
+                let stored = match &args.k {
+                    None => self.items.lock().await.put_immutable(value),
+                    Some(k) => {
+                        let public_key = <[u8; 32]>::try_from(k.as_slice()).ok();
+                        let signature = args.sig.as_deref().and_then(|sig| <[u8; 64]>::try_from(sig).ok());
+                        let (Some(public_key), Some(signature), Some(seq)) = (public_key, signature, args.seq) else {
+                            return Some(Self::error(t, KRPC_ERROR_PROTOCOL, "Missing or invalid k, sig or seq"));
+                        };
+                        let item = MutableItem {
+                            value,
+                            public_key,
+                            salt: args.salt.clone().unwrap_or_default(),
+                            seq,
+                            signature,
+                        };
+                        self.items.lock().await.put_mutable(item, args.cas)
+                    }
+                };
+                if let Err((code, message)) = stored {
+                    return Some(Self::error(t, code, message));
+                }
+                protocol::KrpcResponse::default()
+            }
+            // Parsed so they are told apart from malformed queries, but not served
+            protocol::KrpcQueryKind::FindPeer | protocol::KrpcQueryKind::Lookup | protocol::KrpcQueryKind::Announce => {
+                return Some(Self::error(t, KRPC_ERROR_METHOD_UNKNOWN, "Method Unknown"));
+            }
+        };
+        
+        let mut reply = protocol::KrpcMessage {
+            t,
+            y: protocol::KrpcMessageType::Response,
+            q: None,
+            a: None,
+            r: Some(protocol::KrpcResponse {
+                id: Some(self.node_id.read().expect("node id lock poisoned").to_vec()),
+                ..response
+            }),
+            e: None,
+            ip: Some(encode_compact_peer(addr)),
+        };
+        Self::fit_datagram(&mut reply);
+        Some(reply)
+    }
+
+    /// Drop announced values, then peers, from the end of a reply until it
+    /// encodes to less than [`MAX_KRPC_MESSAGE_SIZE`] bytes, the most a
+    /// receiver accepts.
+    fn fit_datagram(reply: &mut protocol::KrpcMessage) {
+        let too_large = |reply: &protocol::KrpcMessage| {
+            protocol::encode_krpc(reply).is_ok_and(|data| data.len() >= MAX_KRPC_MESSAGE_SIZE)
+        };
+        while too_large(reply) {
+            let Some(response) = reply.r.as_mut() else {
+                return;
+            };
+            if let Some(peer_values) = response.peer_values.as_mut() {
+                peer_values.pop();
+                if peer_values.is_empty() {
+                    response.peer_values = None;
+                }
+            } else if let Some(values) = response.values.as_mut().filter(|values| !values.is_empty()) {
+                values.pop();
+            } else {
+                return;
+            }
+        }
+    }
+
+    /// Whether `token` was issued to the querier at `addr`.
+    async fn token_ok(&self, token: Option<&[u8]>, addr: &SocketAddr) -> bool {
+        match token {
+            Some(token) => self.token_secret.lock().await.is_valid(token, addr.ip()),
+            None => false,
+        }
+    }
+
+    /// Which node families (IPv4, IPv6) the querier asked for.
+    ///
+    /// Without a `want` argument, answer in the family the query arrived on (BEP 32).
+    fn wanted_families(want: Option<&[String]>, from: &SocketAddr) -> (bool, bool) {
+        match want {
+            Some(want) => (
+                want.iter().any(|w| w == WANT_IPV4),
+                want.iter().any(|w| w == WANT_IPV6),
+            ),
+            None => (from.is_ipv4(), from.is_ipv6()),
+        }
+    }
+
+    /// Compact `nodes` / `nodes6` for the nodes we know closest to `target`.
+    async fn closest_nodes(&self, target: &[u8; 20], want_v4: bool, want_v6: bool) -> protocol::KrpcResponse {
+        let rt = self.routing_table.lock().await;
+        let mut response = protocol::KrpcResponse::default();
+        if want_v4 {
+            let nodes = rt.closest_where(target, rt.k, |n| n.addr.is_ipv4());
+            response.nodes = Some(encode_compact_nodes(&nodes));
+        }
+        if want_v6 {
+            let nodes = rt.closest_where(target, rt.k, |n| n.addr.is_ipv6());
+            response.nodes6 = Some(encode_compact_nodes6(&nodes));
+        }
+        response
+    }
+
+    /// Unexpired peers announced under `info_hash`.
+    #[cfg(test)]
+    async fn stored_peers(&self, info_hash: &[u8; 32]) -> Vec<SocketAddr> {
+        self.stored_peer_values(info_hash).await.into_iter().map(|(addr, _)| addr).collect()
+    }
+
+    /// Unexpired peers announced under `info_hash`, with the data each
+    /// announced.
+    async fn stored_peer_values(&self, info_hash: &[u8; 32]) -> Vec<(SocketAddr, Option<Vec<u8>>)> {
+        let mut store = self.peer_store.lock().await;
+        let Some(peers) = store.get_mut(info_hash) else {
+            return Vec::new();
+        };
+        peers.retain(|peer| peer.announced.elapsed() < PEER_TTL);
+        let result = peers.iter().map(|peer| (peer.addr, peer.value.clone())).collect::<Vec<_>>();
+        if peers.is_empty() {
+            store.remove(info_hash);
+        }
+        result
+    }
+
+    /// Record (or refresh) an announced peer.
+    async fn store_peer(&self, info_hash: [u8; 32], peer: SocketAddr, value: Option<Vec<u8>>) {
+        let mut store = self.peer_store.lock().await;
+        let peers = store.entry(info_hash).or_default();
+        peers.retain(|stored| stored.addr != peer && stored.announced.elapsed() < PEER_TTL);
+        if peers.len() >= MAX_PEERS_PER_INFO_HASH {
+            // Evict the least recently announced peer
+            peers.remove(0);
+        }
+        peers.push(StoredPeer {
+            addr: peer,
+            value,
+            announced: Instant::now(),
+        });
+    }
+
+    /// Forget an announced peer.
+    async fn remove_peer(&self, info_hash: &[u8; 32], peer: SocketAddr) {
+        let mut store = self.peer_store.lock().await;
+        if let Some(peers) = store.get_mut(info_hash) {
+            peers.retain(|stored| stored.addr != peer);
+            if peers.is_empty() {
+                store.remove(info_hash);
+            }
+        }
+    }
+
+    fn error(t: Vec<u8>, code: i64, message: &str) -> protocol::KrpcMessage {
+        protocol::KrpcMessage {
+            t,
+            y: protocol::KrpcMessageType::Error,
+            q: None,
+            a: None,
+            r: None,
+            e: Some((code, message.to_string())),
+            ip: None,
+        }
+    }
+}
+
+impl DhtClient {
+    pub async fn new(config: DhtConfig) -> Result<Self, DhtError> {
+        config.validate()?;
+        
+        // Bind UDP socket(s)
+        let sockets = DhtSockets::bind(config.bind_addr, config.bind_port, config.ipv6).await?;
+        Ok(Self::start(config, sockets))
+    }
+
+    /// Run a node over `transport` instead of binding UDP sockets.
+    ///
+    /// The node is IPv4-only, so `transport` needs an IPv4 local address;
+    /// `bind_addr`, `bind_port` and `ipv6` are ignored. The node must be the only reader
+    /// of `transport`.
+    pub fn with_transport(config: DhtConfig, transport: Arc<dyn PacketTransport>) -> Result<Self, DhtError> {
+        config.validate()?;
+        Ok(Self::start(config, DhtSockets::single(transport)?))
+    }
+
+    /// Run a node on an already bound UDP socket, so the port it is seen
+    /// from can be shared with holepunching and connections.
+    ///
+    /// The node is IPv4-only or IPv6-only, after the socket's family;
+    /// `bind_addr`, `bind_port` and `ipv6` are ignored. Its background task
+    /// reads the socket until [`shutdown`](Self::shutdown), so until then
+    /// nothing else may read it. To keep punching and connecting on the
+    /// socket meanwhile, run the node on a [`Demux`](crate::packet::Demux)
+    /// over it instead.
+    pub async fn with_socket(config: DhtConfig, socket: Arc<UdpSocket>) -> Result<Self, DhtError> {
+        config.validate()?;
+        let local_addr = socket.local_addr()?;
+        let sockets = match local_addr {
+            SocketAddr::V4(_) => DhtSockets { v4: Some(socket), v6: None },
+            SocketAddr::V6(_) => DhtSockets { v4: None, v6: Some(socket) },
+        };
+        Ok(Self::start(config, sockets))
+    }
+
+    /// Spawn the background tasks of a node reading `sockets`.
+    fn start(config: DhtConfig, sockets: DhtSockets) -> Self {
+        // Generate node ID (20 bytes for mainline DHT compatibility), bound to
+        // our public IP when we know it (BEP 42)
+        let node_id = match (config.node_id, config.public_ip) {
+            (Some(id), _) => id,
+            (None, Some(ip)) => node_id::secure_node_id(ip),
+            (None, None) => rand::thread_rng().gen(),
+        };
+        let node_id: SharedNodeId = Arc::new(std::sync::RwLock::new(node_id));
+        
+        let routing_table = Arc::new(Mutex::new(RoutingTable::new(config.bucket_k)));
+        let pending: PendingQueries = Arc::new(Mutex::new(HashMap::new()));
+        let dropped_queries = Arc::new(AtomicU64::new(0));
+        let dropped_packets = Arc::new(AtomicU64::new(0));
+        let (query_tx, query_rx) = mpsc::channel(INCOMING_QUERY_QUEUE_SIZE);
+        
+        let handler = QueryHandler {
+            sockets: sockets.clone(),
+            node_id: node_id.clone(),
+            routing_table: routing_table.clone(),
+            peer_store: Arc::new(Mutex::new(HashMap::new())),
+            token_secret: Arc::new(Mutex::new(TokenSecret::new())),
+            items: Mutex::new(ItemStore::default()),
+            rate_limiter: Mutex::new(QueryRateLimiter::new(config.max_queries_per_ip, config.max_queries_total)),
+            dropped_queries: dropped_queries.clone(),
+        };
+        
+        let querier = Querier {
+            sockets: sockets.clone(),
+            node_id: node_id.clone(),
+            routing_table: routing_table.clone(),
+            pending: pending.clone(),
+            max_in_flight: config.max_in_flight_queries,
+            query_timeout: config.query_timeout,
+            min_query_timeout: config.min_query_timeout,
+            shutdown: CancellationToken::new(),
+            observed: Arc::new(std::sync::Mutex::new(ObservedAddresses::default())),
+            contacts: config
+                .detect_reachability
+                .then(|| Arc::new(std::sync::Mutex::new(Contacts::default()))),
+            metrics: MetricsHandle::default(),
+        };
+        
+        let mut tasks: Vec<JoinHandle<()>> = sockets
+            .all()
+            .map(|socket| {
+                tokio::spawn(Self::recv_loop(
+                    socket.clone(),
+                    pending.clone(),
+                    query_tx.clone(),
+                    dropped_packets.clone(),
+                    config.recv_batch_size,
+                    querier.contacts.clone(),
+                ))
+            })
+            .collect();
+        tasks.push(tokio::spawn(handler.run(query_rx)));
+        tasks.push(tokio::spawn(Self::maintenance_loop(
+            querier.clone(),
+            config.maintenance_interval,
+            config.max_node_failures,
+        )));
+
+        let mut bootstrap_sources = config.bootstrap_sources;
+        if !config.bootstrap.is_empty() {
+            bootstrap_sources.insert(0, BootstrapSource::Static(config.bootstrap));
+        }
+        let mdns_responders = Self::advertise_mdns(&sockets, &bootstrap_sources);
+        
+        Self {
+            sockets,
+            node_id,
+            routing_table,
+            bootstrap_sources,
+            mdns_responders: std::sync::Mutex::new(mdns_responders),
+            resolver: Arc::new(SystemResolver),
+            bootstrap_dns_timeout: config.bootstrap_dns_timeout,
+            bootstrap_ping_timeout: config.bootstrap_ping_timeout,
+            max_node_failures: config.max_node_failures,
+            lookup_alpha: config.lookup_alpha,
+            peer_cache: std::sync::Mutex::new(HashMap::new()),
+            peer_cache_ttl: config.peer_cache_ttl,
+            querier,
+            dropped_queries,
+            dropped_packets,
+            tasks: Mutex::new(tasks),
+        }
+    }
+
+    /// Resolve bootstrap entries with `resolver` instead of the system
+    /// resolver.
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// Join the DHT and populate the routing table from bootstrap nodes.
+    ///
+    /// Every address a bootstrap source yields is pinged, a few at a time,
+    /// and each one that answers joins the routing table, so a host with
+    /// some dead records still gets us in. Sources that fail are skipped.
+    pub async fn bootstrap(&self) -> Result<(), DhtError> {
+        let defaults;
+        let sources = if self.bootstrap_sources.is_empty() {
+            // If no bootstrap nodes configured, use mainline DHT defaults
+            defaults = [BootstrapSource::Static(vec![
+                "router.bittorrent.com:6881".to_string(),
+                "dht.transmissionbt.com:6881".to_string(),
+                "router.utorrent.com:6881".to_string(),
+            ])];
+            &defaults[..]
+        } else {
+            &self.bootstrap_sources[..]
+        };
+        
+        let mut addrs: Vec<SocketAddr> = Vec::new();
+        for source in sources {
+            let found = match source {
+                BootstrapSource::Static(entries) => {
+                    let mut found = Vec::new();
+                    for entry in entries {
+                        found.extend(self.resolve_bootstrap_entry(entry).await);
+                    }
+                    found
+                }
+                BootstrapSource::Mdns { service_name } => self.discover_mdns(service_name).await,
+            };
+            // Every address in a family we have a socket for
+            for addr in found {
+                if self.sockets.for_addr(&addr).is_some() && !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+        }
+        
+        futures::stream::iter(addrs)
+            .for_each_concurrent(BOOTSTRAP_PING_CONCURRENCY, |addr| async move {
+                // Silently ignore errors and timeouts
+                let _ = tokio::time::timeout(self.bootstrap_ping_timeout, self.ping(addr)).await;
+            })
+            .await;
+        
+        Ok(())
+    }
+
+    /// Every address a `host:port` bootstrap entry resolves to.
+    async fn resolve_bootstrap_entry(&self, entry: &str) -> Vec<SocketAddr> {
+        // Use a shorter timeout for DNS resolution
+        match tokio::time::timeout(self.bootstrap_dns_timeout, self.resolver.resolve(entry)).await {
+            Ok(Ok(resolved)) => resolved,
+            // Names that do not resolve (yet) are skipped; the config
+            // was already checked for malformed entries
+            Ok(Err(e)) => {
+                tracing::debug!("Bootstrap node {} did not resolve: {}", entry, e);
+                Vec::new()
+            }
+            Err(_) => {
+                tracing::debug!("Resolving bootstrap node {} timed out", entry);
+                Vec::new()
+            }
+        }
+    }
+
+    /// The nodes answering an mDNS query for `service_name` within the DNS
+    /// timeout, other than this one.
+    async fn discover_mdns(&self, service_name: &str) -> Vec<SocketAddr> {
+        let own: Vec<String> = self
+            .mdns_responders
+            .lock()
+            .expect("mDNS responders lock poisoned")
+            .iter()
+            .map(|responder| responder.instance().to_string())
+            .collect();
+        match mdns::query(service_name, self.bootstrap_dns_timeout).await {
+            Ok(services) => services
+                .into_iter()
+                .filter(|service| !own.contains(&service.instance))
+                .map(|service| service.addr)
+                .collect(),
+            Err(e) => {
+                tracing::debug!("mDNS query for {} failed: {}", service_name, e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Answer mDNS queries for each `Mdns` source's service with our IPv4
+    /// port, under a random instance name.
+    fn advertise_mdns(sockets: &DhtSockets, sources: &[BootstrapSource]) -> Vec<mdns::MdnsResponder> {
+        let Some(port) = sockets.v4.as_ref().and_then(|v4| v4.local_addr().ok()).map(|addr| addr.port()) else {
+            return Vec::new();
+        };
+        let instance = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        let mut responders = Vec::new();
+        for source in sources {
+            if let BootstrapSource::Mdns { service_name } = source {
+                match mdns::MdnsResponder::bind(service_name, &instance, port) {
+                    Ok(responder) => responders.push(responder),
+                    Err(e) => tracing::debug!("Not advertising on mDNS as {}: {}", service_name, e),
+                }
+            }
+        }
+        responders
+    }
+
+    /// Ping the node at `addr` and return its id.
+    ///
+    /// Like [`find_node_at`](Self::find_node_at) and
+    /// [`get_peers_at`](Self::get_peers_at), this queries that one node and
+    /// nothing else, for probing a single node from tools and tests. A node
+    /// that answers joins the routing table as with any query.
+    pub async fn ping_node(&self, addr: SocketAddr) -> Result<[u8; 20], DhtError> {
+        self.ping(addr).await?.try_into().map_err(|_| {
+            DhtError::Protocol(protocol::ProtocolError::Malformed("missing `id` in ping response".to_string()))
+        })
+    }
+
+    /// Ask the node at `addr` for the nodes it knows closest to `target`,
+    /// as `(node id, address)` pairs.
+    pub async fn find_node_at(&self, addr: SocketAddr, target: [u8; 20]) -> Result<Vec<([u8; 20], SocketAddr)>, DhtError> {
+        let nodes = self.find_node(addr, &target).await?;
+        Ok(nodes.into_iter().map(|node| (node.node_id, node.addr)).collect())
+    }
+
+    /// Ask the node at `addr` for the peers it stores under `topic`, the
+    /// nodes it knows closer to it and a token to announce with.
+    pub async fn get_peers_at(&self, addr: SocketAddr, topic: Topic) -> Result<GetPeersReply, DhtError> {
+        let response = self.get_peers(addr, &topic.0).await?;
+        Ok(GetPeersReply {
+            peers: response.peers,
+            nodes: response.nodes.into_iter().map(|node| (node.node_id, node.addr)).collect(),
+            token: response.token,
+        })
+    }
+
+    /// Send a ping query to a node
+    async fn ping(&self, addr: SocketAddr) -> Result<Vec<u8>, DhtError> {
+        self.querier.ping(addr).await
+    }
+
+    /// Send a find_node query to locate nodes near a target
+    async fn find_node(&self, addr: SocketAddr, target: &[u8; 20]) -> Result<Vec<NodeInfo>, DhtError> {
+        let r = self
+            .query(
+                addr,
+                protocol::KrpcQueryKind::FindNode,
+                protocol::KrpcArgs {
+                    id: Some(self.node_id().to_vec()),
+                    target: Some(target.to_vec()),
+                    want: Some(self.sockets.want()),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        
+        // Parse compact node info from response
+        let mut nodes = Vec::new();
+        if let Some(data) = r.nodes {
+            nodes.extend(parse_compact_nodes(&data));
+        }
+        if let Some(data) = r.nodes6 {
+            nodes.extend(parse_compact_nodes6(&data));
+        }
+        
+        Ok(nodes)
+    }
+
+    /// Get peers for a given info hash (topic) from a node.
+    ///
+    /// Returns any peers stored by the node (`values`), the closer nodes it
+    /// knows about (`nodes` and `nodes6`), and the token required for `announce_peer`.
+    async fn get_peers(&self, addr: SocketAddr, info_hash: &[u8; 32]) -> Result<GetPeersResponse, DhtError> {
+        let r = self
+            .query(
+                addr,
+                protocol::KrpcQueryKind::GetPeers,
+                protocol::KrpcArgs {
+                    id: Some(self.node_id().to_vec()),
+                    info_hash: Some(info_hash.to_vec()),
+                    want: Some(self.sockets.want()),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        
+        let mut result = GetPeersResponse {
+            peers: Vec::new(),
+            nodes: Vec::new(),
+            token: None,
+        };
+        
+        // The node answered, so it is alive: remember it for future queries
+        if let Some(id) = r.id.as_deref().and_then(|id| <[u8; 20]>::try_from(id).ok()) {
+            self.routing_table.lock().await.add_node(id, addr);
+        }
+
+        // Extract token for announce_peer
+        result.token = r.token;
+        
+        // Data announced with the peers, by address
+        let peer_values: HashMap<SocketAddr, Vec<u8>> = r
+            .peer_values
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|entry| entry.value.len() <= MAX_ANNOUNCE_VALUE_SIZE)
+            .filter_map(|entry| Some((parse_compact_peer(&entry.peer)?, entry.value)))
+            .collect();
+
+        // Keyed peers first, so their public key is kept when the same
+        // address is also listed without one
+        for value in r.peers.unwrap_or_default() {
+            match parse_keyed_peer(&value) {
+                Some(mut peer) => {
+                    peer.value = peer_values.get(&peer.addr).cloned();
+                    result.peers.push(peer)
+                }
+                None => tracing::debug!("Skipping keyed peer of unknown length: {}", value.len()),
+            }
+        }
+        
+        // Parse compact peer info from values field
+        // BEP 5 defines both IPv4 (6 bytes) and IPv6 (18 bytes) formats
+        for value in r.values.unwrap_or_default() {
+            match parse_compact_peer(&value) {
+                Some(addr) if result.peers.iter().any(|p| p.addr == addr) => {}
+                Some(addr) => result.peers.push(PeerAddress {
+                    addr,
+                    node_id: None,
+                    value: peer_values.get(&addr).cloned(),
+                }),
+                None => {
+                    // Unknown format, skip
+                    tracing::debug!("Skipping peer with unknown compact format length: {}", value.len());
+                }
+            }
+        }
+
+        // Closer nodes to continue an iterative lookup with
+        if let Some(nodes) = r.nodes {
+            result.nodes = parse_compact_nodes(&nodes);
+        }
+        if let Some(nodes6) = r.nodes6 {
+            result.nodes.extend(parse_compact_nodes6(&nodes6));
+        }
+        
+        Ok(result)
+    }
+
+    /// Announce our presence for a topic to a specific node
+    async fn announce_peer(
+        &self,
+        addr: SocketAddr,
+        info_hash: &[u8; 32],
+        port: u16,
+        token: Vec<u8>,
+        value: Option<&[u8]>,
+    ) -> Result<(), DhtError> {
+        self.announce_query(protocol::KrpcQueryKind::AnnouncePeer, addr, info_hash, port, token, value).await
+    }
+
+    /// Withdraw an earlier announcement from a specific node
+    async fn unannounce_peer(&self, addr: SocketAddr, info_hash: &[u8; 32], port: u16, token: Vec<u8>) -> Result<(), DhtError> {
+        self.announce_query(protocol::KrpcQueryKind::Unannounce, addr, info_hash, port, token, None).await
+    }
+
+    async fn announce_query(
+        &self,
+        kind: protocol::KrpcQueryKind,
+        addr: SocketAddr,
+        info_hash: &[u8; 32],
+        port: u16,
+        token: Vec<u8>,
+        value: Option<&[u8]>,
+    ) -> Result<(), DhtError> {
+        let _response = self
+            .query(addr, kind, announce_args(self.node_id(), info_hash, port, token, value))
+            .await?;
+        
+        Ok(())
+    }
+
+    /// Announce our presence for `topic`.
+    ///
+    /// In KRPC terms this often maps to `announce_peer` / topic announce.
+    /// This is a simplified implementation that announces to bootstrap nodes.
+    ///
+    /// A `port` of 0 sets `implied_port`, so nodes record the port of our
+    /// DHT socket as seen from outside.
+    ///
+    /// Up to `alpha` nodes are announced to at a time, so a slow node does not
+    /// hold up the rest.
+    ///
+    /// Returns the addresses of the nodes that accepted the announcement, so
+    /// it can later be withdrawn with [`DhtClient::unannounce`]. Fails with the
+    /// last node's error only if there were nodes to announce to and none of
+    /// them accepted.
+    pub async fn announce(&self, topic: Topic, port: u16) -> Result<Vec<SocketAddr>, DhtError> {
+        self.announce_value(topic, port, None).await
+    }
+
+    /// Announce our presence for `topic` like [`announce`](Self::announce),
+    /// with `value` attached for peers that look us up, e.g. the version
+    /// of a protocol we speak. Peers receive it as [`PeerAddress::value`].
+    ///
+    /// Fails with [`DhtError::AnnounceValueTooLarge`] for values over
+    /// [`MAX_ANNOUNCE_VALUE_SIZE`] bytes.
+    pub async fn announce_with_value(&self, topic: Topic, port: u16, value: &[u8]) -> Result<Vec<SocketAddr>, DhtError> {
+        if value.len() > MAX_ANNOUNCE_VALUE_SIZE {
+            return Err(DhtError::AnnounceValueTooLarge(value.len()));
+        }
+        self.announce_value(topic, port, Some(value)).await
+    }
+
+    /// Announce `port` on every topic in `topics` like
+    /// [`announce`](Self::announce), returning each topic's outcome in order.
+    ///
+    /// The nodes are picked once for the whole batch, and each is asked for
+    /// an announce token once: nodes issue tokens per querying address, not
+    /// per topic, so that one token serves every topic. Fails as a whole only
+    /// if there are no nodes and bootstrapping fails.
+    pub async fn announce_all(&self, topics: &[Topic], port: u16) -> Result<Vec<Result<Vec<SocketAddr>, DhtError>>, DhtError> {
+        let nodes = self.announce_nodes().await?;
+        Ok(self.announce_topics(nodes, topics, port, None).await)
+    }
+
+    /// Announce `port` on every topic in `topics` like
+    /// [`announce_all`](Self::announce_all), with `value` attached to each
+    /// like [`announce_with_value`](Self::announce_with_value).
+    pub async fn announce_all_with_value(
+        &self,
+        topics: &[Topic],
+        port: u16,
+        value: &[u8],
+    ) -> Result<Vec<Result<Vec<SocketAddr>, DhtError>>, DhtError> {
+        if value.len() > MAX_ANNOUNCE_VALUE_SIZE {
+            return Err(DhtError::AnnounceValueTooLarge(value.len()));
+        }
+        let nodes = self.announce_nodes().await?;
+        Ok(self.announce_topics(nodes, topics, port, Some(value)).await)
+    }
+
+    async fn announce_value(&self, topic: Topic, port: u16, value: Option<&[u8]>) -> Result<Vec<SocketAddr>, DhtError> {
+        let nodes = self.announce_nodes().await?;
+        self.announce_topics(nodes, &[topic], port, value)
+            .await
+            .pop()
+            .expect("one outcome per topic")
+    }
+
+    /// The nodes to announce to, bootstrapping first if we know none.
+    async fn announce_nodes(&self) -> Result<Vec<NodeInfo>, DhtError> {
+        // Get nodes from routing table
+        let nodes = {
+            let rt = self.routing_table.lock().await;
+            rt.get_nodes(10)
+        };
+        
+        // If routing table is empty, bootstrap first
+        if nodes.is_empty() {
+            self.bootstrap().await?;
+        }
+        
+        // Get updated node list
+        let nodes = {
+            let rt = self.routing_table.lock().await;
+            rt.get_nodes(10)
+        };
+        Ok(nodes)
+    }
+
+    /// Announce every topic in `topics` to `nodes`, alpha nodes at a time,
+    /// returning each topic's outcome in order.
+    ///
+    /// A node that gives no token counts as a failure of the first topic,
+    /// with its error, and of the others as [`DhtError::MissingToken`].
+    async fn announce_topics(
+        &self,
+        nodes: Vec<NodeInfo>,
+        topics: &[Topic],
+        port: u16,
+        value: Option<&[u8]>,
+    ) -> Vec<Result<Vec<SocketAddr>, DhtError>> {
+        let attempted = nodes.len();
+        let mut announced = vec![Vec::new(); topics.len()];
+        let mut last_errors: Vec<Option<DhtError>> = topics.iter().map(|_| None).collect();
+        let mut results = futures::stream::iter(nodes)
+            .map(|node| async move { (node.addr, self.announce_to(node.addr, topics, port, value).await) })
+            .buffer_unordered(self.lookup_alpha);
+        while let Some((addr, result)) = results.next().await {
+            let outcomes = match result {
+                Ok(outcomes) => outcomes,
+                Err(e) => {
+                    tracing::debug!("Failed to get a token from node {}: {}", addr, e);
+                    last_errors[0] = Some(e);
+                    continue;
+                }
+            };
+            for (i, outcome) in outcomes.into_iter().enumerate() {
+                match outcome {
+                    Ok(()) => announced[i].push(addr),
+                    Err(e) => {
+                        tracing::debug!("Failed to announce to node {}: {}", addr, e);
+                        last_errors[i] = Some(e);
+                    }
+                }
+            }
+        }
+
+        announced
+            .into_iter()
+            .zip(last_errors)
+            .map(|(announced, last_error)| {
+                tracing::debug!("Announce accepted by {} of {} nodes", announced.len(), attempted);
+                if announced.is_empty() && attempted > 0 {
+                    Err(last_error.unwrap_or(DhtError::MissingToken))
+                } else {
+                    Ok(announced)
+                }
+            })
+            .collect()
+    }
+
+    /// Fetch a token from a node with a `get_peers` for the first of
+    /// `topics`, then announce each of them to it, alpha at a time. Returns
+    /// each topic's outcome in order.
+    async fn announce_to(
+        &self,
+        addr: SocketAddr,
+        topics: &[Topic],
+        port: u16,
+        value: Option<&[u8]>,
+    ) -> Result<Vec<Result<(), DhtError>>, DhtError> {
+        let Some(first) = topics.first() else {
+            return Ok(Vec::new());
+        };
+        let token = match self.get_peers(addr, &first.0).await? {
+            GetPeersResponse { token: Some(token), .. } => token,
+            _ => return Err(DhtError::MissingToken),
+        };
+        let announces: Vec<_> = topics
+            .iter()
+            .map(|topic| self.announce_peer(addr, &topic.0, port, token.clone(), value))
+            .collect();
+        Ok(futures::stream::iter(announces).buffered(self.lookup_alpha).collect().await)
+    }
+
+    /// Withdraw an announcement of `port` for `topic` from `nodes`.
+    ///
+    /// Uses hyperdht's `unannounce` query, which takes the same token as
+    /// `announce_peer`. Nodes that do not answer are skipped; their record
+    /// expires on its own.
+    pub async fn unannounce(&self, topic: Topic, port: u16, nodes: &[SocketAddr]) -> Result<(), DhtError> {
+        let info_hash = topic.0;
+        
+        for &addr in nodes {
+            match self.get_peers(addr, &info_hash).await {
+                Ok(GetPeersResponse { token: Some(token), .. }) => {
+                    if let Err(e) = self.unannounce_peer(addr, &info_hash, port, token).await {
+                        tracing::debug!("Failed to unannounce from node {}: {}", addr, e);
+                    }
+                }
+                Err(e) => tracing::debug!("Failed to get peers from node {}: {}", addr, e),
+                _ => tracing::debug!("Node {} did not provide a token", addr),
+            }
+        }
+        
+        Ok(())
+    }
+
+    /// Lookup peers for `topic` using the default [`LookupOptions`] with
+    /// the configured [`DhtConfig::lookup_alpha`].
+    pub async fn lookup(&self, topic: Topic) -> Result<Vec<PeerAddress>, DhtError> {
+        self.lookup_with(topic, self.lookup_options()).await
+    }
+
+    /// The default [`LookupOptions`], with the configured alpha.
+    fn lookup_options(&self) -> LookupOptions {
+        LookupOptions {
+            alpha: self.lookup_alpha,
+            ..LookupOptions::default()
+        }
+    }
+
+    /// Iterative Kademlia lookup for peers announced on `topic`.
+    ///
+    /// Starts from the closest known nodes to the topic and repeatedly sends
+    /// `get_peers` to the `alpha` closest nodes that have not been queried yet,
+    /// folding any `nodes` they return into the candidate set. The lookup ends
+    /// when the [`DhtConfig::bucket_k`] closest candidates have all been queried, after
+    /// `max_hops` rounds, or once `max_peers` peers have been collected.
+    ///
+    /// Peers returned by several nodes are reported once, in the order they
+    /// were first seen, with a public key and announced value if any node
+    /// supplied them.
+    ///
+    /// A lookup that ran to the end is cached for
+    /// [`DhtConfig::peer_cache_ttl`]; until then lookups of the same topic
+    /// return its peers without any network traffic, unless `opts.force` is
+    /// set.
+    pub async fn lookup_with(&self, topic: Topic, opts: LookupOptions) -> Result<Vec<PeerAddress>, DhtError> {
+        if !opts.force {
+            if let Some(mut peers) = self.cached_peers(&topic) {
+                peers.truncate(opts.max_peers.unwrap_or(usize::MAX));
+                return Ok(peers);
+            }
+        }
+        let max_peers = opts.max_peers;
+        let peers = self.run_lookup(topic, opts, |_| {}).await?;
+        // A lookup stopped at max_peers may have missed some
+        if max_peers.is_none_or(|max| peers.len() < max) {
+            self.cache_peers(topic, &peers);
+        }
+        Ok(peers)
+    }
+
+    /// The peers of a lookup of `topic` that finished within the TTL.
+    fn cached_peers(&self, topic: &Topic) -> Option<Vec<PeerAddress>> {
+        let cache = self.peer_cache.lock().expect("peer cache poisoned");
+        cache
+            .get(topic)
+            .filter(|(_, found_at)| found_at.elapsed() < self.peer_cache_ttl)
+            .map(|(peers, _)| peers.clone())
+    }
+
+    fn cache_peers(&self, topic: Topic, peers: &[PeerAddress]) {
+        if self.peer_cache_ttl.is_zero() {
+            return;
+        }
+        let mut cache = self.peer_cache.lock().expect("peer cache poisoned");
+        cache.retain(|_, (_, found_at)| found_at.elapsed() < self.peer_cache_ttl);
+        cache.insert(topic, (peers.to_vec(), Instant::now()));
+    }
+
+    /// Like [`lookup`](Self::lookup), but yields each peer as soon as it is
+    /// found instead of once the lookup has converged. Streams always query
+    /// the network and leave the peer cache alone.
+    pub fn lookup_stream(&self, topic: Topic) -> impl Stream<Item = PeerAddress> + '_ {
+        self.lookup_stream_with(topic, self.lookup_options())
+    }
+
+    /// Like [`lookup_with`](Self::lookup_with), but yields each peer as soon
+    /// as it is found. The stream ends when the lookup does.
+    ///
+    /// A peer is yielded once, when first seen, so a public key supplied only
+    /// by a later node is not reported. A failed lookup just ends the stream.
+    pub fn lookup_stream_with(&self, topic: Topic, opts: LookupOptions) -> impl Stream<Item = PeerAddress> + '_ {
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let lookup = async move {
+            // Dropping `tx` when the lookup ends closes the stream
+            let send = |peer: &PeerAddress| {
+                let _ = tx.unbounded_send(peer.clone());
+            };
+            if let Err(e) = self.run_lookup(topic, opts, send).await {
+                tracing::debug!("Streaming lookup failed: {}", e);
+            }
+            None
+        };
+        futures::stream::select(rx, futures::stream::once(lookup).filter_map(futures::future::ready))
+    }
+
+    /// The iterative lookup behind [`lookup_with`](Self::lookup_with) and
+    /// [`lookup_stream_with`](Self::lookup_stream_with), calling `found` for
+    /// each new peer as it is seen.
+    async fn run_lookup(
+        &self,
+        topic: Topic,
+        opts: LookupOptions,
+        mut found: impl FnMut(&PeerAddress),
+    ) -> Result<Vec<PeerAddress>, DhtError> {
+        let info_hash = topic.0;
+        let target = topic_target(&topic);
+        let metrics = self.querier.metrics.get();
+        self.bootstrap_if_empty().await?;
+        
+        // Candidates ordered by distance to the target
+        let (k, mut candidates): (usize, BTreeMap<[u8; 20], NodeInfo>) = {
+            let rt = self.routing_table.lock().await;
+            let closest = rt.closest(&target, rt.k)
+                .into_iter()
+                .map(|n| (xor_distance(&n.node_id, &target), n))
+                .collect();
+            (rt.k, closest)
+        };
+        let mut queried: HashSet<[u8; 20]> = HashSet::new();
+        let mut all_peers = Vec::new();
+        let mut seen_peers: HashSet<SocketAddr> = HashSet::new();
+        
+        for hop in 0..opts.max_hops {
+            // The alpha closest nodes among the k closest we have not asked yet
+            let round: Vec<NodeInfo> = candidates
+                .values()
+                .take(k)
+                .filter(|n| !queried.contains(&n.node_id))
+                .take(opts.alpha.max(1))
+                .cloned()
+                .collect();
+            
+            if round.is_empty() {
+                // Every node in the closest set has answered: converged
+                tracing::debug!("Lookup converged after {} hop(s)", hop);
+                break;
+            }
+            
+            // Query the whole round concurrently
+            for node in &round {
+                queried.insert(node.node_id);
+            }
+            let responses = futures::future::join_all(
+                round.iter().map(|node| self.get_peers(node.addr, &info_hash)),
+            )
+            .await;
+            
+            for (node, result) in round.into_iter().zip(responses) {
+                match result {
+                    Ok(response) => {
+                        for peer in response.peers {
+                            if seen_peers.insert(peer.addr) {
+                                if opts.max_peers.is_none_or(|max| all_peers.len() < max) {
+                                    metrics.on_peer_discovered(&topic, &peer);
+                                    found(&peer);
+                                }
+                                all_peers.push(peer);
+                            } else if let Some(seen) = all_peers.iter_mut().find(|p| p.addr == peer.addr) {
+                                seen.node_id = seen.node_id.or(peer.node_id);
+                                seen.value = seen.value.take().or(peer.value);
+                            }
+                        }
+                        for next in response.nodes {
+                            // Skip nodes in an address family we cannot reach
+                            if next.node_id != self.node_id() && self.sockets.for_addr(&next.addr).is_some() {
+                                candidates
+                                    .entry(xor_distance(&next.node_id, &target))
+                                    .or_insert(next);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        // Unresponsive nodes drop out of the closest set
+                        tracing::debug!("Lookup query to {} failed: {}", node.addr, e);
+                        candidates.remove(&xor_distance(&node.node_id, &target));
+                    }
+                }
+            }
+            
+            if let Some(max_peers) = opts.max_peers {
+                if all_peers.len() >= max_peers {
+                    all_peers.truncate(max_peers);
+                    return Ok(all_peers);
+                }
+            }
+        }
+        
+        Ok(all_peers)
+    }
+
+    /// Store `value` as an immutable item (BEP 44) on the closest nodes to
+    /// its target.
+    ///
+    /// Returns the target, the SHA-1 of the bencoded value, to fetch it with
+    /// [`DhtClient::get_immutable`]. Fails with the last node's error if
+    /// every node rejected the item.
+    pub async fn put_immutable(&self, value: &[u8]) -> Result<[u8; 20], DhtError> {
+        check_value_size(value)?;
+        let target = storage::immutable_target(value);
+        let args = protocol::KrpcArgs {
+            v: Some(value.to_vec()),
+            ..Default::default()
+        };
+        self.put_item(&target, args).await?;
+        Ok(target)
+    }
+
+    /// Fetch the immutable item stored under `target`.
+    ///
+    /// Values that do not hash to `target` are discarded.
+    pub async fn get_immutable(&self, target: [u8; 20]) -> Result<Option<Vec<u8>>, DhtError> {
+        for node in self.storage_nodes(&target).await? {
+            match self.get_item(node.addr, &target).await {
+                Ok(protocol::KrpcResponse { v: Some(value), .. }) => {
+                    if storage::immutable_target(&value) == target {
+                        return Ok(Some(value));
+                    }
+                    tracing::debug!("Node {} returned a value that does not match its target", node.addr);
+                }
+                Ok(_) => {}
+                Err(e) => tracing::debug!("Failed to get item from node {}: {}", node.addr, e),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Sign `value` with `key` and store it as version `seq` of a mutable
+    /// item (BEP 44), optionally under `salt`.
+    ///
+    /// Nodes only accept a higher `seq` than the one they hold; with `cas`,
+    /// they only replace the item if it is currently at version `cas`.
+    /// Returns the item's target. Fails with the last node's error if every
+    /// node rejected the item.
+    pub async fn put_mutable(
+        &self,
+        key: &SigningKey,
+        salt: Option<&[u8]>,
+        seq: i64,
+        value: &[u8],
+        cas: Option<i64>,
+    ) -> Result<[u8; 20], DhtError> {
+        check_value_size(value)?;
+        let salt = salt.unwrap_or_default();
+        if salt.len() > storage::MAX_SALT_SIZE {
+            return Err(DhtError::SaltTooLarge(salt.len()));
+        }
+        let item = MutableItem::sign(key, salt, seq, value);
+        let target = item.target();
+        let args = protocol::KrpcArgs {
+            v: Some(item.value),
+            k: Some(item.public_key.to_vec()),
+            sig: Some(item.signature.to_vec()),
+            seq: Some(seq),
+            salt: (!salt.is_empty()).then(|| salt.to_vec()),
+            cas,
+            ..Default::default()
+        };
+        self.put_item(&target, args).await?;
+        Ok(target)
+    }
+
+    /// Fetch the latest version of the mutable item under `public_key` and
+    /// `salt`.
+    ///
+    /// Items whose signature does not verify against `public_key` are
+    /// discarded; of the rest, the one with the highest sequence number wins.
+    pub async fn get_mutable(&self, public_key: &[u8; 32], salt: Option<&[u8]>) -> Result<Option<MutableItem>, DhtError> {
+        let salt = salt.unwrap_or_default();
+        let target = storage::mutable_target(public_key, salt);
+        let mut latest: Option<MutableItem> = None;
+        for node in self.storage_nodes(&target).await? {
+            let response = match self.get_item(node.addr, &target).await {
+                Ok(response) => response,
+                Err(e) => {
+                    tracing::debug!("Failed to get item from node {}: {}", node.addr, e);
+                    continue;
+                }
+            };
+            let signature = response.sig.as_deref().and_then(|sig| <[u8; 64]>::try_from(sig).ok());
+            let (Some(value), Some(signature), Some(seq)) = (response.v, signature, response.seq) else {
+                continue;
+            };
+            let item = MutableItem {
+                value,
+                public_key: *public_key,
+                salt: salt.to_vec(),
+                seq,
+                signature,
+            };
+            if !item.verify() {
+                tracing::debug!("Discarding badly signed item from node {}", node.addr);
+                continue;
+            }
+            if latest.as_ref().is_none_or(|latest| item.seq > latest.seq) {
+                latest = Some(item);
+            }
+        }
+        Ok(latest)
+    }
+
+    /// Bootstrap if the routing table is empty.
+    pub(crate) async fn bootstrap_if_empty(&self) -> Result<(), DhtError> {
+        if self.routing_table.lock().await.nodes.is_empty() {
+            self.bootstrap().await?;
+        }
+        Ok(())
+    }
+
+    /// The known nodes closest to `target`, bootstrapping first if we know none.
+    async fn storage_nodes(&self, target: &[u8; 20]) -> Result<Vec<NodeInfo>, DhtError> {
+        self.bootstrap_if_empty().await?;
+        let rt = self.routing_table.lock().await;
+        Ok(rt.closest(target, rt.k))
+    }
+
+    /// Send a BEP 44 `get` for `target` to a node.
+    async fn get_item(&self, addr: SocketAddr, target: &[u8; 20]) -> Result<protocol::KrpcResponse, DhtError> {
+        self.query(
+            addr,
+            protocol::KrpcQueryKind::Get,
+            protocol::KrpcArgs {
+                id: Some(self.node_id().to_vec()),
+                target: Some(target.to_vec()),
+                want: Some(self.sockets.want()),
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    /// Put an item on the nodes closest to `target`, fetching a token from
+    /// each with `get` first.
+    async fn put_item(&self, target: &[u8; 20], args: protocol::KrpcArgs) -> Result<(), DhtError> {
+        let mut stored = 0usize;
+        let mut last_error = None;
+        for node in self.storage_nodes(target).await? {
+            let token = match self.get_item(node.addr, target).await {
+                Ok(protocol::KrpcResponse { token: Some(token), .. }) => token,
+                Ok(_) => {
+                    tracing::debug!("Node {} did not provide a token", node.addr);
+                    continue;
+                }
+                Err(e) => {
+                    tracing::debug!("Failed to get token from node {}: {}", node.addr, e);
+                    last_error = Some(e);
+                    continue;
+                }
+            };
+            let put_args = protocol::KrpcArgs {
+                id: Some(self.node_id().to_vec()),
+                token: Some(token),
+                ..args.clone()
+            };
+            match self.query(node.addr, protocol::KrpcQueryKind::Put, put_args).await {
+                Ok(_) => stored += 1,
+                Err(e) => {
+                    tracing::debug!("Failed to put item on node {}: {}", node.addr, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        match (stored, last_error) {
+            (0, Some(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Wait until every in-flight query has been answered or has timed out,
+    /// e.g. so announces are acknowledged before exiting.
+    ///
+    /// Queries sent while flushing are waited for too. Gives up with
+    /// [`DhtError::Timeout`] after twice the query timeout.
+    pub async fn flush(&self) -> Result<(), DhtError> {
+        let drained = async {
+            while !self.querier.pending.lock().await.is_empty() {
+                tokio::time::sleep(FLUSH_POLL_INTERVAL).await;
+            }
+        };
+        tokio::time::timeout(self.querier.query_timeout * 2, drained)
+            .await
+            .map_err(|_| DhtError::Timeout)
+    }
+
+    /// Stop the client: fail in-flight queries with [`DhtError::Shutdown`],
+    /// then stop the background tasks and wait for them to finish.
+    ///
+    /// Afterwards the node no longer reads its sockets, so it neither
+    /// answers queries nor sends new ones.
+    pub async fn shutdown(&self) -> Result<(), DhtError> {
+        self.querier.shutdown.cancel();
+        self.mdns_responders.lock().expect("mDNS responders lock poisoned").clear();
+        let tasks: Vec<JoinHandle<()>> = self.tasks.lock().await.drain(..).collect();
+        for task in &tasks {
+            task.abort();
+        }
+        for task in tasks {
+            if let Err(e) = task.await {
+                if e.is_panic() {
+                    tracing::warn!("DHT task panicked: {}", e);
+                }
+            }
+        }
+        self.querier.pending.lock().await.clear();
+        Ok(())
+    }
+
+    /// Get the local socket address: the IPv4 socket's, or the IPv6 socket's
+    /// on an IPv6-only node.
+    pub fn local_addr(&self) -> Result<SocketAddr, DhtError> {
+        Ok(self.sockets.primary().local_addr()?)
+    }
+
+    /// Get the local IPv6 socket address, if the node has an IPv6 socket.
+    pub fn local_addr_v6(&self) -> Option<SocketAddr> {
+        self.sockets.v6.as_ref().and_then(|s| s.local_addr().ok())
+    }
+
+    /// Get this node's ID (for testing)
+    pub fn node_id(&self) -> [u8; 20] {
+        *self.node_id.read().expect("node id lock poisoned")
+    }
+
+    /// This node's id as a z-base32 string to share out of band;
+    /// [`node_id::parse_identity`] turns it back into the id.
+    ///
+    /// Changes with the id, e.g. after
+    /// [`regenerate_node_id`](Self::regenerate_node_id).
+    pub fn identity(&self) -> String {
+        node_id::encode_identity(&self.node_id())
+    }
+
+    /// Replace the node id with a random one that satisfies BEP 42 for
+    /// `public_ip`, e.g. once STUN has told us our WAN address.
+    ///
+    /// Returns the new id. Nodes that already know us keep the old id in
+    /// their routing tables until they hear from us again.
+    pub fn regenerate_node_id(&self, public_ip: IpAddr) -> [u8; 20] {
+        let id = node_id::secure_node_id(public_ip);
+        *self.node_id.write().expect("node id lock poisoned") = id;
+        id
+    }
+
+    /// Our external address as the nodes we query see it, once any have
+    /// said. With conflicting reports, the one most responders agree on.
+    ///
+    /// Behind a NAT this is the WAN mapping of the DHT socket, a candidate
+    /// peers can holepunch to.
+    pub fn observed_address(&self) -> Option<SocketAddr> {
+        self.querier.observed.lock().expect("observed lock poisoned").best()
+    }
+
+    /// Find out whether nodes can reach our DHT port unsolicited.
+    ///
+    /// Needs [`DhtConfig::detect_reachability`]; without it the answer is
+    /// always [`Reachability::Unknown`]. Pings a few known nodes
+    /// (bootstrapping first if we know none), which also tells us the address
+    /// they see us at. Once any node we never sent to has queried us, we are
+    /// [`Reachability::DirectlyReachable`]. If none has and the nodes see us
+    /// at another address than our own, a NAT only lets in the nodes we
+    /// contacted: [`Reachability::NatPortRestricted`]. Otherwise, e.g. on a
+    /// public address nobody has queried yet, it is
+    /// [`Reachability::Unknown`]; asking again once other nodes have learned
+    /// about us may tell.
+    pub async fn check_reachability(&self) -> Reachability {
+        let Some(contacts) = self.querier.contacts.clone() else {
+            return Reachability::Unknown;
+        };
+        if let Err(e) = self.bootstrap_if_empty().await {
+            tracing::debug!("Reachability unknown, bootstrap failed: {}", e);
+            return Reachability::Unknown;
+        }
+        let nodes: Vec<SocketAddr> = {
+            let rt = self.routing_table.lock().await;
+            rt.closest(&self.node_id(), REACHABILITY_PROBES).into_iter().map(|n| n.addr).collect()
+        };
+        futures::future::join_all(nodes.into_iter().map(|addr| self.ping(addr))).await;
+
+        if let Some(from) = contacts.lock().expect("contacts lock poisoned").unsolicited() {
+            tracing::debug!("Queried unasked by {}, directly reachable", from);
+            return Reachability::DirectlyReachable;
+        }
+        match self.observed_address() {
+            Some(observed) if !self.is_own_address(observed) => Reachability::NatPortRestricted,
+            _ => Reachability::Unknown,
+        }
+    }
+
+    /// Whether `addr` is the address of one of our sockets, so that nodes
+    /// see us untranslated.
+    fn is_own_address(&self, addr: SocketAddr) -> bool {
+        let interfaces = || if_addrs::get_if_addrs().unwrap_or_default();
+        [self.local_addr().ok(), self.local_addr_v6()]
+            .into_iter()
+            .flatten()
+            .filter(|local| local.port() == addr.port())
+            .any(|local| {
+                local.ip() == addr.ip()
+                    || (local.ip().is_unspecified() && interfaces().iter().any(|i| i.ip() == addr.ip()))
+            })
+    }
+
+    /// Number of incoming queries dropped by the rate limit so far.
+    pub fn dropped_queries(&self) -> u64 {
+        self.dropped_queries.load(Ordering::Relaxed)
+    }
+
+    /// Number of incoming datagrams dropped so far because they were larger
+    /// than any KRPC message we accept, or failed to decode.
+    pub fn dropped_packets(&self) -> u64 {
+        self.dropped_packets.load(Ordering::Relaxed)
+    }
+
+    /// Report queries sent, query timeouts and discovered peers to `metrics`
+    /// from now on, including queries made by background maintenance.
+    pub fn set_metrics(&self, metrics: Arc<dyn SwarmMetrics>) {
+        self.querier.metrics.set(metrics);
+    }
 
     /// Manually add a node to the routing table (for testing)
     pub async fn add_node_to_routing_table(&self, node_id: [u8; 20], addr: SocketAddr) {
@@ -482,62 +2402,826 @@ impl DhtClient {
         rt.add_node(node_id, addr);
     }
 
-    // ---- low-level helpers ----
+    /// Number of nodes in the routing table, e.g. to tell whether bootstrap
+    /// reached anyone.
+    pub async fn routing_table_len(&self) -> usize {
+        self.routing_table.lock().await.nodes.len()
+    }
+
+    /// A snapshot of the routing table as `(node id, address)` pairs, least
+    /// recently seen first.
+    pub async fn known_nodes(&self) -> Vec<([u8; 20], SocketAddr)> {
+        let rt = self.routing_table.lock().await;
+        rt.nodes.iter().map(|node| (node.node_id, node.addr)).collect()
+    }
+
+    // ---- low-level helpers ----
+
+    async fn query(
+        &self,
+        addr: SocketAddr,
+        kind: protocol::KrpcQueryKind,
+        args: protocol::KrpcArgs,
+    ) -> Result<protocol::KrpcResponse, DhtError> {
+        self.querier.query(addr, kind, args).await
+    }
+
+    /// Ping the least recently seen node of every bucket, evicting nodes
+    /// that have failed `max_node_failures` pings in a row. Nodes that answer
+    /// are refreshed by the ping itself.
+    pub async fn maintain_routing_table(&self) {
+        Self::maintenance_pass(&self.querier, self.max_node_failures).await;
+    }
+
+    async fn maintenance_pass(querier: &Querier, max_failures: u32) {
+        let stalest = querier.routing_table.lock().await.stalest_per_bucket(&querier.node_id());
+        let results = futures::future::join_all(stalest.iter().map(|node| querier.ping(node.addr))).await;
+        let mut rt = querier.routing_table.lock().await;
+        for (node, result) in stalest.iter().zip(results) {
+            if let Err(e) = result {
+                if rt.record_failure(&node.node_id, max_failures) {
+                    tracing::debug!("Evicted unresponsive node {}: {}", node.addr, e);
+                }
+            }
+        }
+    }
+
+    /// Run a maintenance pass every `interval` until the client shuts down.
+    async fn maintenance_loop(querier: Querier, interval: Duration, max_failures: u32) {
+        let mut ticks = tokio::time::interval(interval);
+        // The first tick completes immediately; the table is still empty then
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            Self::maintenance_pass(&querier, max_failures).await;
+        }
+    }
+
+    /// Read the socket forever, `batch_size` datagrams at a time where the
+    /// socket allows, dispatching responses to their waiters and incoming
+    /// queries to the query handler.
+    ///
+    /// A datagram that fills the whole buffer was probably truncated, and is
+    /// dropped as malformed rather than decoded.
+    async fn recv_loop(
+        socket: Arc<dyn PacketTransport>,
+        pending: PendingQueries,
+        queries: mpsc::Sender<IncomingQuery>,
+        dropped_packets: Arc<AtomicU64>,
+        batch_size: usize,
+        contacts: Option<SharedContacts>,
+    ) {
+        let mut batch = RecvBatch::new(batch_size, MAX_KRPC_MESSAGE_SIZE);
+        loop {
+            if let Err(e) = socket.recv_batch(&mut batch).await {
+                tracing::debug!("DHT socket receive failed: {}", e);
+                continue;
+            }
+            for (datagram, addr) in batch.iter() {
+                if datagram.len() == MAX_KRPC_MESSAGE_SIZE {
+                    tracing::debug!("Dropping oversized packet from {}", addr);
+                    dropped_packets.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+
+                let msg = match protocol::decode_krpc(datagram) {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        tracing::debug!("Dropping undecodable packet from {}: {}", addr, e);
+                        dropped_packets.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                };
+
+                match msg.y {
+                    protocol::KrpcMessageType::Query => {
+                        if let Some(contacts) = &contacts {
+                            contacts.lock().expect("contacts lock poisoned").queried_by(addr);
+                        }
+                        if queries.try_send((addr, msg)).is_err() {
+                            tracing::debug!("Query handler busy, dropping query from {}", addr);
+                        }
+                    }
+                    protocol::KrpcMessageType::Response | protocol::KrpcMessageType::Error => {
+                        // Responses count only from the node that was asked
+                        match pending.lock().await.remove(&(pending_addr(addr), msg.t.clone())) {
+                            Some(waiter) => {
+                                let _ = waiter.send(msg);
+                            }
+                            None => {
+                                tracing::debug!("Dropping unsolicited response from {}", addr);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Drop for DhtClient {
+    fn drop(&mut self) {
+        // Fail queries still waiting in tasks that outlive us
+        self.querier.shutdown.cancel();
+        for task in self.tasks.get_mut().iter() {
+            task.abort();
+        }
+    }
+}
+
+impl Querier {
+    fn node_id(&self) -> [u8; 20] {
+        *self.node_id.read().expect("node id lock poisoned")
+    }
+
+    /// Send a ping query to a node
+    async fn ping(&self, addr: SocketAddr) -> Result<Vec<u8>, DhtError> {
+        let r = self
+            .query(
+                addr,
+                protocol::KrpcQueryKind::Ping,
+                protocol::KrpcArgs {
+                    id: Some(self.node_id().to_vec()),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        
+        // Add responding node to routing table
+        if let Some(id) = &r.id {
+            if id.len() == 20 {
+                let mut node_id = [0u8; 20];
+                node_id.copy_from_slice(&id[..20]);
+                let mut rt = self.routing_table.lock().await;
+                rt.add_node(node_id, addr);
+            }
+        }
+        
+        Ok(r.id.unwrap_or_default())
+    }
+
+    /// A random transaction id, so responses cannot be spoofed by guessing
+    /// the next one.
+    fn get_transaction_id(&self) -> Vec<u8> {
+        rand::random::<u16>().to_be_bytes().to_vec()
+    }
+
+    /// Register `waiter` for a query to `addr` under a transaction id no
+    /// outstanding query to it uses.
+    ///
+    /// When `max_in_flight` queries are outstanding, waiters whose query was
+    /// dropped before finishing are reaped first; if the map is still full
+    /// the query fails with [`DhtError::TooManyInFlight`].
+    async fn register(
+        &self,
+        addr: SocketAddr,
+        waiter: oneshot::Sender<protocol::KrpcMessage>,
+    ) -> Result<(SocketAddr, Vec<u8>), DhtError> {
+        let mut pending = self.pending.lock().await;
+        if pending.len() >= self.max_in_flight {
+            pending.retain(|_, waiter| !waiter.is_closed());
+            if pending.len() >= self.max_in_flight {
+                return Err(DhtError::TooManyInFlight);
+            }
+        }
+        // Fewer ids are in use than exist, so a free one turns up
+        let key = loop {
+            let key = (pending_addr(addr), self.get_transaction_id());
+            if !pending.contains_key(&key) {
+                break key;
+            }
+        };
+        pending.insert(key.clone(), waiter);
+        Ok(key)
+    }
+
+    async fn send_krpc(&self, to: SocketAddr, msg: protocol::KrpcMessage) -> Result<(), DhtError> {
+        let data = protocol::encode_krpc(&msg)?;
+        if let Some(contacts) = &self.contacts {
+            contacts.lock().expect("contacts lock poisoned").sent_to(to);
+        }
+        self.sockets.send_to(&data, to).await?;
+        Ok(())
+    }
+
+    /// How long to wait for node `node_id` to answer: [`RTT_TIMEOUT_FACTOR`]
+    /// times its average response time, at least `min_query_timeout`,
+    /// doubled for each query it let time out since its last answer, and at
+    /// most twice `query_timeout`. A node that has not answered before, or
+    /// is not in the routing table, gets `query_timeout`.
+    async fn timeout_for(&self, node_id: Option<&[u8; 20]>) -> Duration {
+        let estimate = match node_id {
+            Some(id) => self.routing_table.lock().await.rtt(id),
+            None => None,
+        };
+        match estimate {
+            Some((rtt, timeouts)) => (rtt * RTT_TIMEOUT_FACTOR)
+                .max(self.min_query_timeout.min(self.query_timeout))
+                .saturating_mul(1 << timeouts)
+                .min(self.query_timeout * 2),
+            None => self.query_timeout,
+        }
+    }
+
+    /// Send a query to `addr` and wait for the matching response.
+    ///
+    /// A oneshot waiter is registered under a free transaction id before the
+    /// query is sent (see [`register`](Self::register)); the receive loop
+    /// completes it when the response arrives.
+    /// An error reply is returned as [`DhtError::KrpcError`]. How long the
+    /// response takes feeds the node's average response time.
+    async fn query(
+        &self,
+        addr: SocketAddr,
+        kind: protocol::KrpcQueryKind,
+        args: protocol::KrpcArgs,
+    ) -> Result<protocol::KrpcResponse, DhtError> {
+        if self.shutdown.is_cancelled() {
+            return Err(DhtError::Shutdown);
+        }
+        let (tx, rx) = oneshot::channel();
+        let key = self.register(addr, tx).await?;
+        
+        let metrics = self.metrics.get();
+        let msg = protocol::KrpcMessage {
+            t: key.1.clone(),
+            y: protocol::KrpcMessageType::Query,
+            q: Some(kind.clone()),
+            a: Some(args),
+            r: None,
+            e: None,
+            ip: None,
+        };
+        
+        let node_id = self.routing_table.lock().await.node_at(&addr);
+        let timeout = self.timeout_for(node_id.as_ref()).await;
+        let sent_at = Instant::now();
+        if let Err(e) = self.send_krpc(addr, msg).await {
+            self.pending.lock().await.remove(&key);
+            return Err(e);
+        }
+        metrics.on_query_sent(&kind, addr);
+        
+        let answer = tokio::select! {
+            answer = tokio::time::timeout(timeout, rx) => answer,
+            _ = self.shutdown.cancelled() => {
+                self.pending.lock().await.remove(&key);
+                return Err(DhtError::Shutdown);
+            }
+        };
+        if let Some(id) = &node_id {
+            match &answer {
+                Ok(Ok(_)) => self.routing_table.lock().await.record_rtt(id, sent_at.elapsed()),
+                Err(_) => self.routing_table.lock().await.record_timeout(id, timeout),
+                Ok(Err(_)) => {}
+            }
+        }
+        match answer {
+            Ok(Ok(mut response)) => match response.body()? {
+                protocol::KrpcBody::Response(_) => {
+                    if let Some(observed) = response.ip.as_deref().and_then(parse_compact_peer) {
+                        self.observed.lock().expect("observed lock poisoned").record(addr, observed);
+                    }
+                    Ok(response.r.take().unwrap_or_default())
+                }
+                protocol::KrpcBody::Error { code, message } => Err(DhtError::KrpcError {
+                    code,
+                    message: message.to_owned(),
+                }),
+                // Only responses and errors are handed to a waiting query
+                protocol::KrpcBody::Query { .. } => Err(DhtError::Protocol(protocol::ProtocolError::Malformed(
+                    "query in place of a response".into(),
+                ))),
+            },
+            // The waiter was dropped without an answer (client shutting down)
+            Ok(Err(_)) => Err(DhtError::Shutdown),
+            Err(_) => {
+                self.pending.lock().await.remove(&key);
+                metrics.on_query_timeout(&kind, addr);
+                Err(DhtError::Timeout)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dht_client_creation() {
+        let config = DhtConfig {
+            bootstrap: vec![],
+            bind_port: 0, // Let OS choose port
+            ..Default::default()
+        };
+
+        let client = DhtClient::new(config).await.expect("Failed to create DHT client");
+        
+        // Verify node ID is generated
+        assert_ne!(client.node_id(), [0u8; 20]);
+    }
+
+    #[tokio::test]
+    async fn test_bind_addr_ipv4_interface() {
+        let client = DhtClient::new(DhtConfig {
+            bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        assert_eq!(client.local_addr().unwrap().ip(), Ipv4Addr::LOCALHOST);
+        // A pinned interface gets no extra IPv6 socket
+        assert_eq!(client.local_addr_v6(), None);
+
+        let other = DhtClient::new(DhtConfig::default()).await.unwrap();
+        other.ping(client.local_addr().unwrap()).await.expect("node should answer on loopback");
+    }
+
+    #[tokio::test]
+    async fn test_bind_addr_ipv6_interface() {
+        let config = DhtConfig {
+            bind_addr: IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ..Default::default()
+        };
+        let client = DhtClient::new(config.clone()).await.unwrap();
+        assert_eq!(client.local_addr().unwrap().ip(), Ipv6Addr::LOCALHOST);
+        assert_eq!(client.local_addr_v6(), client.local_addr().ok());
+        assert_eq!(client.sockets.want(), vec![WANT_IPV6.to_string()]);
+
+        // IPv6-only nodes talk to each other, and not to IPv4 addresses
+        let other = DhtClient::new(config).await.unwrap();
+        other.ping(client.local_addr().unwrap()).await.expect("node should answer on ::1");
+        assert!(other.ping("127.0.0.1:6881".parse().unwrap()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_query_timeout_is_configurable() {
+        let config = DhtConfig {
+            query_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let client = DhtClient::new(config).await.unwrap();
+        // TEST-NET address that never answers
+        let black_hole: SocketAddr = "192.0.2.1:6881".parse().unwrap();
+        let started = Instant::now();
+        assert!(matches!(client.ping(black_hole).await, Err(DhtError::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let zero = DhtConfig {
+            bootstrap_ping_timeout: Duration::ZERO,
+            ..Default::default()
+        };
+        assert!(matches!(DhtClient::new(zero).await, Err(DhtError::InvalidConfig(_))));
+    }
+
+    #[tokio::test]
+    async fn test_identity_names_the_node_id() {
+        let node_id: [u8; 20] = std::array::from_fn(|i| 0xa0 ^ i as u8);
+        let client = DhtClient::new(DhtConfig { node_id: Some(node_id), ..Default::default() })
+            .await
+            .unwrap();
+        let identity = client.identity();
+        assert_eq!(node_id::parse_identity(&identity).unwrap(), node_id);
+        assert_eq!(identity, node_id::encode_identity(&node_id));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_alpha_and_bucket_k_must_be_non_zero() {
+        for config in [
+            DhtConfig { lookup_alpha: 0, ..Default::default() },
+            DhtConfig { bucket_k: 0, ..Default::default() },
+        ] {
+            assert!(matches!(DhtClient::new(config).await, Err(DhtError::InvalidConfig(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_any_recv_batch_size_answers_a_burst() {
+        for recv_batch_size in [0, MAX_RECV_BATCH_SIZE + 1] {
+            let config = DhtConfig { recv_batch_size, ..Default::default() };
+            assert!(matches!(DhtClient::new(config).await, Err(DhtError::InvalidConfig(_))));
+        }
+
+        let local = DhtConfig {
+            bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            ipv6: false,
+            max_queries_per_ip: 100,
+            ..Default::default()
+        };
+        let client = Arc::new(DhtClient::new(local.clone()).await.unwrap());
+        for recv_batch_size in [1, 8] {
+            let node = DhtClient::new(DhtConfig { recv_batch_size, ..local.clone() }).await.unwrap();
+            let addr = node.local_addr().unwrap();
+            let pings: Vec<_> = (0..20)
+                .map(|_| {
+                    let client = client.clone();
+                    tokio::spawn(async move { client.ping_node(addr).await })
+                })
+                .collect();
+            for ping in pings {
+                let answered = ping.await.unwrap().expect("every ping of the burst should be answered");
+                assert_eq!(answered, node.node_id());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_queries_are_capped_and_reclaimed() {
+        const CAP: usize = 4;
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        let client = Arc::new(
+            DhtClient::new(DhtConfig {
+                bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                ipv6: false,
+                query_timeout: Duration::from_millis(300),
+                max_in_flight_queries: CAP,
+                ..Default::default()
+            })
+            .await
+            .unwrap(),
+        );
+
+        let unanswered: Vec<_> = (0..CAP)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move { client.ping(silent_addr).await })
+            })
+            .collect();
+        while client.querier.pending.lock().await.len() < CAP {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        // One more fails straight away instead of growing the map
+        let started = Instant::now();
+        assert!(matches!(client.ping(silent_addr).await, Err(DhtError::TooManyInFlight)));
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        // Timed-out queries give their entries back
+        for query in unanswered {
+            assert!(matches!(query.await.unwrap(), Err(DhtError::Timeout)));
+        }
+        assert!(client.querier.pending.lock().await.is_empty());
+        assert!(matches!(client.ping(silent_addr).await, Err(DhtError::Timeout)));
+
+        assert!(matches!(
+            DhtClient::new(DhtConfig { max_in_flight_queries: 0, ..Default::default() }).await,
+            Err(DhtError::InvalidConfig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_queries_are_reaped_when_full() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        let client = Arc::new(
+            DhtClient::new(DhtConfig {
+                bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                ipv6: false,
+                max_in_flight_queries: 1,
+                ..Default::default()
+            })
+            .await
+            .unwrap(),
+        );
+
+        // A query dropped mid-wait leaves its entry behind
+        let abandoned = {
+            let client = client.clone();
+            tokio::spawn(async move { client.ping(silent_addr).await })
+        };
+        while client.querier.pending.lock().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        abandoned.abort();
+        let _ = abandoned.await;
+        assert_eq!(client.querier.pending.lock().await.len(), 1);
+
+        // The next query reclaims it and goes out, rather than overflowing
+        let next = tokio::time::timeout(Duration::from_millis(200), client.ping(silent_addr)).await;
+        assert!(next.is_err(), "query should be waiting for its answer, got {:?}", next);
+    }
+
+    #[tokio::test]
+    async fn test_responses_count_only_from_the_queried_node() {
+        let client = Arc::new(
+            DhtClient::new(DhtConfig {
+                bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                ipv6: false,
+                ..Default::default()
+            })
+            .await
+            .unwrap(),
+        );
+        let client_addr = client.local_addr().unwrap();
+        let node = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ping = {
+            let (client, node_addr) = (client.clone(), node.local_addr().unwrap());
+            tokio::spawn(async move { client.ping(node_addr).await })
+        };
+
+        let mut buf = [0u8; MAX_KRPC_MESSAGE_SIZE];
+        let (len, _) = node.recv_from(&mut buf).await.unwrap();
+        let query = protocol::decode_krpc(&buf[..len]).unwrap();
+        let response = |id: [u8; 20]| {
+            protocol::encode_krpc(&protocol::KrpcMessage {
+                t: query.t.clone(),
+                y: protocol::KrpcMessageType::Response,
+                q: None,
+                a: None,
+                r: Some(protocol::KrpcResponse {
+                    id: Some(id.to_vec()),
+                    ..Default::default()
+                }),
+                e: None,
+                ip: None,
+            })
+            .unwrap()
+        };
+
+        // The right transaction id from another address is ignored
+        spoofer.send_to(&response([6; 20]), client_addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!ping.is_finished());
+        node.send_to(&response([7; 20]), client_addr).await.unwrap();
+        assert_eq!(ping.await.unwrap().unwrap(), vec![7; 20]);
+    }
+
+    #[tokio::test]
+    async fn test_query_timeout_adapts_to_response_time() {
+        let responder = DhtClient::new(DhtConfig {
+            bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let client = DhtClient::new(DhtConfig::default()).await.unwrap();
+        let addr = responder.local_addr().unwrap();
+
+        // Warm up the estimate: loopback answers in well under a millisecond
+        for _ in 0..5 {
+            client.ping(addr).await.expect("responder should answer");
+        }
+        let node_id = client.routing_table.lock().await.node_at(&addr).expect("responder should be in the table");
+        let (rtt, _) = client.routing_table.lock().await.rtt(&node_id).expect("responses should be timed");
+        assert!(rtt < Duration::from_millis(50), "loopback rtt was {:?}", rtt);
+
+        // Once it goes silent, a query gives up after the minimum timeout,
+        // not the 5 second default
+        responder.shutdown().await.unwrap();
+        let started = Instant::now();
+        assert!(matches!(client.ping(addr).await, Err(DhtError::Timeout)));
+        let waited = started.elapsed();
+        assert!(waited >= DEFAULT_MIN_QUERY_TIMEOUT, "gave up after {:?}", waited);
+        assert!(waited < Duration::from_secs(1), "waited {:?}", waited);
+
+        // The next query backs off, in case the node only slowed down
+        let started = Instant::now();
+        assert!(matches!(client.ping(addr).await, Err(DhtError::Timeout)));
+        let waited = started.elapsed();
+        assert!(waited >= DEFAULT_MIN_QUERY_TIMEOUT * 2, "did not back off, gave up after {:?}", waited);
+        assert!(waited < Duration::from_secs(2), "waited {:?}", waited);
+    }
+
+    #[test]
+    fn test_timeouts_raise_the_response_time_estimate() {
+        let mut table = RoutingTable::new(8);
+        let (node, other) = ([1u8; 20], [2u8; 20]);
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        table.add_node(node, addr);
+        table.add_node(other, addr);
+        table.record_rtt(&node, Duration::from_millis(80));
+        assert_eq!(table.rtt(&other), None, "estimates are per node, not per address");
+
+        table.record_timeout(&node, Duration::from_millis(400));
+        table.record_timeout(&node, Duration::from_millis(800));
+        let (rtt, timeouts) = table.rtt(&node).unwrap();
+        assert_eq!(timeouts, 2);
+        assert!(rtt > Duration::from_millis(80), "timeouts should raise the estimate, got {:?}", rtt);
+
+        // An answer ends the backoff, and the node keeps it when seen again
+        table.record_rtt(&node, Duration::from_millis(80));
+        table.add_node(node, addr);
+        assert_eq!(table.rtt(&node).map(|(_, timeouts)| timeouts), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_the_node() {
+        let config = DhtConfig {
+            query_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let client = DhtClient::new(config.clone()).await.unwrap();
+        let other = DhtClient::new(config).await.unwrap();
+        let addr: SocketAddr = format!("127.0.0.1:{}", client.local_addr().unwrap().port()).parse().unwrap();
+        let other_addr: SocketAddr = format!("127.0.0.1:{}", other.local_addr().unwrap().port()).parse().unwrap();
+        other.ping(addr).await.expect("node should answer before shutdown");
+
+        client.shutdown().await.unwrap();
+        assert!(client.tasks.lock().await.is_empty());
+        assert!(matches!(client.ping(other_addr).await, Err(DhtError::Shutdown)));
+        // Nothing reads the socket any more
+        assert!(matches!(other.ping(addr).await, Err(DhtError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn test_dropping_the_client_stops_its_tasks() {
+        let config = DhtConfig {
+            bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            ipv6: false,
+            ..Default::default()
+        };
+        let client = Arc::new(DhtClient::new(config).await.unwrap());
+        let addr = client.local_addr().unwrap();
+        let shared = client.clone();
+        assert!(std::net::UdpSocket::bind(addr).is_err(), "the client holds its port");
+
+        // A clone keeps the client running
+        drop(client);
+        assert!(std::net::UdpSocket::bind(addr).is_err());
+
+        // Once the last one goes, the aborted tasks let go of the socket
+        drop(shared);
+        let released = async {
+            while std::net::UdpSocket::bind(addr).is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), released)
+            .await
+            .expect("the port should be released once the client is dropped");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_packet_is_dropped_and_node_keeps_answering() {
+        let client = DhtClient::new(DhtConfig::default()).await.unwrap();
+        let other = DhtClient::new(DhtConfig::default()).await.unwrap();
+        let addr: SocketAddr = format!("127.0.0.1:{}", client.local_addr().unwrap().port()).parse().unwrap();
+
+        // A ping padded past the largest message we read
+        let ping = protocol::KrpcMessage {
+            t: vec![1, 2],
+            y: protocol::KrpcMessageType::Query,
+            q: Some(protocol::KrpcQueryKind::Ping),
+            a: Some(protocol::KrpcArgs {
+                id: Some(vec![7; 20]),
+                ..Default::default()
+            }),
+            r: None,
+            e: None,
+            ip: None,
+        };
+        let mut oversized = protocol::encode_krpc(&ping).unwrap();
+        oversized.resize(MAX_KRPC_MESSAGE_SIZE * 2, b'x');
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sender.send_to(&oversized, addr).await.unwrap();
+
+        // Not answered, and the receive loop carries on
+        let mut buf = [0u8; 64];
+        assert!(tokio::time::timeout(Duration::from_millis(200), sender.recv_from(&mut buf)).await.is_err());
+        other.ping(addr).await.expect("node should still answer");
+        assert_eq!(client.dropped_packets(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_fails_in_flight_queries() {
+        let client = Arc::new(DhtClient::new(DhtConfig::default()).await.unwrap());
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+
+        let querying = client.clone();
+        let ping = tokio::spawn(async move { querying.ping(silent_addr).await });
+        silent.recv_from(&mut [0u8; 1500]).await.unwrap();
 
-    async fn get_transaction_id(&self) -> Vec<u8> {
-        let mut tx = self.next_transaction_id.lock().await;
-        let id = *tx;
-        *tx = tx.wrapping_add(1);
-        id.to_be_bytes().to_vec()
+        let started = Instant::now();
+        client.shutdown().await.unwrap();
+        assert!(matches!(ping.await.unwrap(), Err(DhtError::Shutdown)));
+        assert!(started.elapsed() < Duration::from_secs(1), "query should not sit out its timeout");
     }
 
-    async fn send_krpc(&self, to: SocketAddr, msg: protocol::KrpcMessage) -> Result<(), DhtError> {
-        let data = protocol::encode_krpc(&msg)?;
-        self.socket.send_to(&data, to).await?;
-        Ok(())
+    #[tokio::test]
+    async fn test_flush_waits_for_pending_queries() {
+        let client = Arc::new(DhtClient::new(DhtConfig::default()).await.unwrap());
+        let slow = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let slow_addr = slow.local_addr().unwrap();
+
+        let querying = client.clone();
+        let ping = tokio::spawn(async move { querying.ping(slow_addr).await });
+        let mut buf = [0u8; 1500];
+        let (len, from) = slow.recv_from(&mut buf).await.unwrap();
+        let query = protocol::decode_krpc(&buf[..len]).unwrap();
+
+        // Answer only after flush has started waiting
+        let answer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let reply = protocol::KrpcMessage {
+                t: query.t,
+                y: protocol::KrpcMessageType::Response,
+                q: None,
+                a: None,
+                r: Some(protocol::KrpcResponse {
+                    id: Some(vec![5; 20]),
+                    ..Default::default()
+                }),
+                e: None,
+                ip: None,
+            };
+            slow.send_to(&protocol::encode_krpc(&reply).unwrap(), from).await.unwrap();
+        });
+
+        let started = Instant::now();
+        client.flush().await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(150), "flush returned before the answer");
+        assert!(client.querier.pending.lock().await.is_empty());
+        assert_eq!(ping.await.unwrap().unwrap(), vec![5; 20]);
+        answer.await.unwrap();
     }
 
-    async fn recv_krpc(&self) -> Result<(SocketAddr, protocol::KrpcMessage), DhtError> {
-        let mut buf = vec![0u8; MAX_KRPC_MESSAGE_SIZE];
-        let (len, addr) = self.socket.recv_from(&mut buf).await?;
-        buf.truncate(len);
-        let msg = protocol::decode_krpc(&buf)?;
-        Ok((addr, msg))
+    #[tokio::test]
+    async fn test_maintenance_evicts_dead_nodes() {
+        let client = DhtClient::new(DhtConfig {
+            query_timeout: Duration::from_millis(100),
+            max_node_failures: 2,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let live = DhtClient::new(DhtConfig::default()).await.unwrap();
+        let live_addr: SocketAddr = format!("127.0.0.1:{}", live.local_addr().unwrap().port()).parse().unwrap();
+        let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // Different buckets, so both are the stalest node of theirs
+        let own_id = client.node_id();
+        let dead_bucket = if bucket_index(&own_id, &live.node_id()) == 1 { 2 } else { 1 };
+        let mut dead_id = own_id;
+        dead_id[0] ^= 0x80 >> dead_bucket;
+        client.add_node_to_routing_table(dead_id, dead.local_addr().unwrap()).await;
+        client.add_node_to_routing_table(live.node_id(), live_addr).await;
+
+        // One failure is tolerated, the second evicts
+        client.maintain_routing_table().await;
+        assert_eq!(client.routing_table.lock().await.nodes.len(), 2);
+        client.maintain_routing_table().await;
+        let ids: Vec<[u8; 20]> = client.routing_table.lock().await.nodes.iter().map(|n| n.node_id).collect();
+        assert_eq!(ids, vec![live.node_id()]);
     }
 
-    async fn recv_response(&self, tx_id: &[u8]) -> Result<protocol::KrpcMessage, DhtError> {
-        // Simple implementation: receive messages until we find matching transaction ID
-        // In a full implementation, this would use a proper request/response matcher
-        for _ in 0..MAX_RESPONSE_ATTEMPTS {
-            let (_, msg) = self.recv_krpc().await?;
-            if msg.t == tx_id {
-                return Ok(msg);
-            }
-        }
-        Err(DhtError::Timeout)
+    #[test]
+    fn test_bucket_index_is_common_prefix_length() {
+        let own = [0u8; 20];
+        let mut other = [0u8; 20];
+        other[0] = 0x80;
+        assert_eq!(bucket_index(&own, &other), 0);
+        other[0] = 0x01;
+        assert_eq!(bucket_index(&own, &other), 7);
+        other = [0u8; 20];
+        other[19] = 0x01;
+        assert_eq!(bucket_index(&own, &other), 159);
+        assert_eq!(bucket_index(&own, &own), 160);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[tokio::test]
+    async fn test_node_id_follows_public_ip() {
+        let first: IpAddr = "124.31.75.21".parse().unwrap();
+        let client = DhtClient::new(DhtConfig {
+            public_ip: Some(first),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        assert!(node_id::is_secure_node_id(&client.node_id(), first));
+
+        // Learning a new WAN address yields a new id valid for it
+        let second: IpAddr = "2001:db8::7".parse().unwrap();
+        let id = client.regenerate_node_id(second);
+        assert_eq!(client.node_id(), id);
+        assert!(node_id::is_secure_node_id(&id, second));
+    }
 
     #[tokio::test]
-    async fn test_dht_client_creation() {
-        let config = DhtConfig {
-            bootstrap: vec![],
-            bind_port: 0, // Let OS choose port
-        };
+    async fn test_known_nodes_reports_routing_table() {
+        let client = DhtClient::new(DhtConfig::default()).await.unwrap();
+        assert_eq!(client.routing_table_len().await, 0);
+        assert!(client.known_nodes().await.is_empty());
 
-        let client = DhtClient::new(config).await.expect("Failed to create DHT client");
-        
-        // Verify node ID is generated
-        assert_ne!(client.node_id, [0u8; 20]);
+        let a: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        let b: SocketAddr = "[::1]:7002".parse().unwrap();
+        client.add_node_to_routing_table([1; 20], a).await;
+        client.add_node_to_routing_table([2; 20], b).await;
+        // Seen again, at a new address
+        client.add_node_to_routing_table([1; 20], "127.0.0.1:7003".parse().unwrap()).await;
+
+        assert_eq!(client.routing_table_len().await, 2);
+        assert_eq!(
+            client.known_nodes().await,
+            [([2; 20], b), ([1; 20], "127.0.0.1:7003".parse().unwrap())]
+        );
     }
 
     #[tokio::test]
     async fn test_routing_table() {
-        let mut rt = RoutingTable::new();
+        let mut rt = RoutingTable::new(DEFAULT_BUCKET_K);
         
         let node_id = [1u8; 20];
         let addr = "127.0.0.1:8080".parse().unwrap();
@@ -552,6 +3236,7 @@ mod tests {
         let config = DhtConfig {
             bootstrap: vec![],
             bind_port: 0,
+            ..Default::default()
         };
 
         let client = DhtClient::new(config).await.expect("Failed to create DHT client");
@@ -564,11 +3249,321 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    /// A transport counting the datagrams it sends.
+    struct CountingTransport {
+        inner: Arc<dyn PacketTransport>,
+        sent: AtomicU64,
+    }
+
+    impl PacketTransport for CountingTransport {
+        fn poll_send_to(
+            &self,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+            target: SocketAddr,
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.sent.fetch_add(1, Ordering::SeqCst);
+            self.inner.poll_send_to(cx, buf, target)
+        }
+
+        fn poll_recv_from(
+            &self,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<SocketAddr>> {
+            self.inner.poll_recv_from(cx, buf)
+        }
+
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            self.inner.local_addr()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_direct_queries_reach_one_node() {
+        let network = crate::packet::MemoryNetwork::new();
+        let bind = |addr: &str| network.bind(addr.parse().unwrap()).unwrap();
+        let with_id = |id| DhtConfig {
+            node_id: Some(id),
+            ..Default::default()
+        };
+        let target_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let target = DhtClient::with_transport(with_id([1; 20]), bind("10.0.0.1:6881")).unwrap();
+        let known_addr: SocketAddr = "10.1.0.1:6881".parse().unwrap();
+        target.add_node_to_routing_table([3; 20], known_addr).await;
+        let prober = DhtClient::with_transport(with_id([2; 20]), bind("10.2.0.1:6881")).unwrap();
+
+        assert_eq!(prober.ping_node(target_addr).await.unwrap(), [1; 20]);
+        assert!(prober.find_node_at(target_addr, [3; 20]).await.unwrap().contains(&([3; 20], known_addr)));
+
+        let topic = Topic::from_key(b"direct-queries");
+        let empty = prober.get_peers_at(target_addr, topic).await.unwrap();
+        assert!(empty.peers.is_empty());
+        assert!(empty.token.is_some());
+        prober.announce(topic, 7000).await.unwrap();
+        let reply = prober.get_peers_at(target_addr, topic).await.unwrap();
+        let peers: Vec<SocketAddr> = reply.peers.iter().map(|peer| peer.addr).collect();
+        assert_eq!(peers, ["10.2.0.1:7000".parse::<SocketAddr>().unwrap()]);
+
+        // Nobody listens here
+        let silent: SocketAddr = "10.3.0.1:6881".parse().unwrap();
+        assert!(matches!(prober.ping_node(silent).await, Err(DhtError::Timeout)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announced_value_reaches_lookups() {
+        let network = crate::packet::MemoryNetwork::new();
+        let bind = |addr: &str| network.bind(addr.parse().unwrap()).unwrap();
+        let node = DhtClient::with_transport(DhtConfig::default(), bind("10.0.0.1:6881")).unwrap();
+        let node_addr = node.local_addr().unwrap();
+        let with_value = DhtClient::with_transport(DhtConfig::default(), bind("10.1.0.1:6881")).unwrap();
+        let without = DhtClient::with_transport(DhtConfig::default(), bind("10.2.0.1:6881")).unwrap();
+        let looker = DhtClient::with_transport(DhtConfig::default(), bind("10.3.0.1:6881")).unwrap();
+        for client in [&with_value, &without, &looker] {
+            client.add_node_to_routing_table(node.node_id(), node_addr).await;
+        }
+        let topic = Topic::from_key(b"announce-value");
+
+        let too_large = [0u8; MAX_ANNOUNCE_VALUE_SIZE + 1];
+        assert!(matches!(
+            with_value.announce_with_value(topic, 4000, &too_large).await,
+            Err(DhtError::AnnounceValueTooLarge(len)) if len == MAX_ANNOUNCE_VALUE_SIZE + 1
+        ));
+        with_value.announce_with_value(topic, 4000, b"pluresdb-sync/3").await.unwrap();
+        without.announce(topic, 5000).await.unwrap();
+
+        let mut peers = looker.lookup(topic).await.unwrap();
+        peers.sort_by_key(|peer| peer.addr);
+        let found: Vec<_> = peers.iter().map(|peer| (peer.addr.to_string(), peer.value.as_deref())).collect();
+        assert_eq!(
+            found,
+            [
+                ("10.1.0.1:4000".to_string(), Some(b"pluresdb-sync/3".as_slice())),
+                ("10.2.0.1:5000".to_string(), None),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce_all_fetches_one_token_per_node() {
+        let network = crate::packet::MemoryNetwork::new();
+        let node =
+            DhtClient::with_transport(DhtConfig::default(), network.bind("10.0.0.1:6881".parse().unwrap()).unwrap()).unwrap();
+        let node_addr = node.local_addr().unwrap();
+        let counting = Arc::new(CountingTransport {
+            inner: network.bind("10.0.0.2:6881".parse().unwrap()).unwrap(),
+            sent: AtomicU64::new(0),
+        });
+        let announcer = DhtClient::with_transport(DhtConfig::default(), counting.clone()).unwrap();
+        announcer.add_node_to_routing_table(node.node_id(), node_addr).await;
+        let topics = [b"batch-1", b"batch-2", b"batch-3"].map(|key| Topic::from_key(key));
+
+        let before = counting.sent.load(Ordering::SeqCst);
+        let results = announcer.announce_all(&topics, 7000).await.unwrap();
+        // One get_peers for the token, then an announce_peer per topic
+        assert_eq!(counting.sent.load(Ordering::SeqCst) - before, 1 + topics.len() as u64);
+        for result in results {
+            assert_eq!(result.unwrap(), vec![node_addr]);
+        }
+        for topic in topics {
+            let reply = announcer.get_peers_at(node_addr, topic).await.unwrap();
+            let peers: Vec<SocketAddr> = reply.peers.iter().map(|peer| peer.addr).collect();
+            assert_eq!(peers, ["10.0.0.2:7000".parse::<SocketAddr>().unwrap()]);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce_all_attaches_the_value_to_every_topic() {
+        let network = crate::packet::MemoryNetwork::new();
+        let bind = |addr: &str| network.bind(addr.parse().unwrap()).unwrap();
+        let node = DhtClient::with_transport(DhtConfig::default(), bind("10.0.0.1:6881")).unwrap();
+        let node_addr = node.local_addr().unwrap();
+        let announcer = DhtClient::with_transport(DhtConfig::default(), bind("10.1.0.1:6881")).unwrap();
+        announcer.add_node_to_routing_table(node.node_id(), node_addr).await;
+        let topics = [b"value-1", b"value-2"].map(|key| Topic::from_key(key));
+
+        let too_large = [0u8; MAX_ANNOUNCE_VALUE_SIZE + 1];
+        assert!(matches!(
+            announcer.announce_all_with_value(&topics, 7000, &too_large).await,
+            Err(DhtError::AnnounceValueTooLarge(len)) if len == MAX_ANNOUNCE_VALUE_SIZE + 1
+        ));
+        let results = announcer.announce_all_with_value(&topics, 7000, b"v1").await.unwrap();
+        assert!(results.into_iter().all(|result| result.is_ok()));
+        for topic in topics {
+            let reply = announcer.get_peers_at(node_addr, topic).await.unwrap();
+            let values: Vec<_> = reply.peers.iter().map(|peer| peer.value.as_deref()).collect();
+            assert_eq!(values, [Some(b"v1".as_slice())]);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lookup_is_cached_until_the_ttl_expires() {
+        let network = crate::packet::MemoryNetwork::new();
+        let announcer =
+            DhtClient::with_transport(DhtConfig::default(), network.bind("10.0.0.1:6881".parse().unwrap()).unwrap()).unwrap();
+        let counting = Arc::new(CountingTransport {
+            inner: network.bind("10.0.0.2:6881".parse().unwrap()).unwrap(),
+            sent: AtomicU64::new(0),
+        });
+        let config = DhtConfig {
+            peer_cache_ttl: Duration::from_secs(30),
+            ..Default::default()
+        };
+        let client = DhtClient::with_transport(config, counting.clone()).unwrap();
+        client.add_node_to_routing_table(announcer.node_id(), announcer.local_addr().unwrap()).await;
+        let topic = Topic::from_key(b"cached-lookup");
+        let sent = || counting.sent.load(Ordering::SeqCst);
+
+        // The first lookup asks the network and finds nothing yet
+        assert!(client.lookup(topic).await.unwrap().is_empty());
+        let after_first = sent();
+        assert!(after_first > 0);
+
+        // Within the TTL the result is reused, even though a peer has since
+        // announced
+        let peer =
+            DhtClient::with_transport(DhtConfig::default(), network.bind("10.0.0.3:6881".parse().unwrap()).unwrap()).unwrap();
+        peer.add_node_to_routing_table(announcer.node_id(), announcer.local_addr().unwrap()).await;
+        peer.announce(topic, 4000).await.unwrap();
+        tokio::time::sleep(Duration::from_secs(29)).await;
+        assert!(client.lookup(topic).await.unwrap().is_empty());
+        assert_eq!(sent(), after_first, "a cached lookup should send nothing");
+
+        // `force` bypasses the cache
+        let forced = LookupOptions {
+            force: true,
+            ..Default::default()
+        };
+        assert_eq!(client.lookup_with(topic, forced).await.unwrap().len(), 1);
+        let after_forced = sent();
+        assert!(after_forced > after_first);
+
+        // Once the forced result has expired, the next lookup refreshes
+        tokio::time::sleep(Duration::from_secs(31)).await;
+        assert_eq!(client.lookup(topic).await.unwrap().len(), 1);
+        assert!(sent() > after_forced, "an expired entry should be refreshed");
+    }
+
+    #[tokio::test]
+    async fn test_check_reachability_over_loopback() {
+        let local = |detect_reachability| DhtConfig {
+            bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            ipv6: false,
+            detect_reachability,
+            ..Default::default()
+        };
+        let responder = DhtClient::new(local(false)).await.unwrap();
+        let client = DhtClient::new(local(true)).await.unwrap();
+        client.add_node_to_routing_table(responder.node_id(), responder.local_addr().unwrap()).await;
+
+        // Seen at our own address, but nobody has come to us unasked yet
+        assert_eq!(client.check_reachability().await, Reachability::Unknown);
+
+        // A node we never sent to queries us, as one that found us through
+        // another node's table would
+        let stranger = DhtClient::new(local(false)).await.unwrap();
+        stranger.ping(client.local_addr().unwrap()).await.unwrap();
+        assert_eq!(client.check_reachability().await, Reachability::DirectlyReachable);
+
+        // Without opting in, there is nothing to go by
+        let undetected = DhtClient::new(local(false)).await.unwrap();
+        undetected.add_node_to_routing_table(responder.node_id(), responder.local_addr().unwrap()).await;
+        stranger.ping(undetected.local_addr().unwrap()).await.unwrap();
+        assert_eq!(undetected.check_reachability().await, Reachability::Unknown);
+    }
+
+    /// A transport only letting in packets from addresses it sent to, like a
+    /// port-restricted NAT.
+    struct PortRestrictedNat {
+        inner: Arc<dyn PacketTransport>,
+        contacted: std::sync::Mutex<HashSet<SocketAddr>>,
+    }
+
+    impl PacketTransport for PortRestrictedNat {
+        fn poll_send_to(
+            &self,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+            target: SocketAddr,
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.contacted.lock().unwrap().insert(target);
+            self.inner.poll_send_to(cx, buf, target)
+        }
+
+        fn poll_recv_from(
+            &self,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<SocketAddr>> {
+            loop {
+                let from = std::task::ready!(self.inner.poll_recv_from(cx, buf))?;
+                if self.contacted.lock().unwrap().contains(&from) {
+                    return std::task::Poll::Ready(Ok(from));
+                }
+                buf.clear();
+            }
+        }
+
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            self.inner.local_addr()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check_reachability_classifies_nat_behavior() {
+        let network = crate::packet::MemoryNetwork::new();
+        // A node reporting every querier at the WAN address of a NAT
+        let node_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let node = network.bind(node_addr).unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; MAX_KRPC_MESSAGE_SIZE];
+            loop {
+                let (len, from) = node.recv_from(&mut buf).await.unwrap();
+                let query = protocol::decode_krpc(&buf[..len]).unwrap();
+                let reply = protocol::KrpcMessage {
+                    t: query.t,
+                    y: protocol::KrpcMessageType::Response,
+                    q: None,
+                    a: None,
+                    r: Some(protocol::KrpcResponse {
+                        id: Some(vec![9; 20]),
+                        ..Default::default()
+                    }),
+                    e: None,
+                    ip: Some(encode_compact_peer("203.0.113.5:40000".parse().unwrap())),
+                };
+                node.send_to(&protocol::encode_krpc(&reply).unwrap(), from).await.unwrap();
+            }
+        });
+        let config = DhtConfig {
+            detect_reachability: true,
+            ..Default::default()
+        };
+
+        // Behind the NAT, a stranger's query never arrives
+        let nat = Arc::new(PortRestrictedNat {
+            inner: network.bind("10.0.0.3:6881".parse().unwrap()).unwrap(),
+            contacted: Default::default(),
+        });
+        let natted = DhtClient::with_transport(config.clone(), nat).unwrap();
+        natted.add_node_to_routing_table([9; 20], node_addr).await;
+        let stranger =
+            DhtClient::with_transport(DhtConfig::default(), network.bind("10.0.0.4:6881".parse().unwrap()).unwrap()).unwrap();
+        assert!(stranger.ping("10.0.0.3:6881".parse().unwrap()).await.is_err());
+        assert_eq!(natted.check_reachability().await, Reachability::NatPortRestricted);
+
+        // A node nobody answers cannot tell
+        let alone = DhtClient::with_transport(config, network.bind("10.0.0.5:6881".parse().unwrap()).unwrap()).unwrap();
+        alone.add_node_to_routing_table([8; 20], "10.0.0.6:6881".parse().unwrap()).await;
+        assert_eq!(alone.check_reachability().await, Reachability::Unknown);
+    }
+
     #[tokio::test]
     async fn test_lookup_with_empty_routing_table() {
         let config = DhtConfig {
             bootstrap: vec![],
             bind_port: 0,
+            ..Default::default()
         };
 
         let client = DhtClient::new(config).await.expect("Failed to create DHT client");
@@ -586,49 +3581,618 @@ mod tests {
         let config = DhtConfig {
             bootstrap: vec![],
             bind_port: 0,
+            ..Default::default()
         };
 
         let client = DhtClient::new(config).await.expect("Failed to create DHT client");
         
-        let tx1 = client.get_transaction_id().await;
-        let tx2 = client.get_transaction_id().await;
+        let ids: HashSet<Vec<u8>> = (0..16).map(|_| client.querier.get_transaction_id()).collect();
         
-        // Transaction IDs should be different
-        assert_ne!(tx1, tx2);
+        // Transaction IDs are random two-byte strings, not a counter
+        assert!(ids.iter().all(|id| id.len() == 2));
+        assert!(ids.len() > 1);
     }
 
     #[tokio::test]
     async fn test_compact_peer_parsing_ipv4() {
         // Test IPv4 compact peer info parsing
-        let ipv4_peer = vec![127, 0, 0, 1, 0x1F, 0x90]; // 127.0.0.1:8080
+        let ipv4_peer = [127, 0, 0, 1, 0x1F, 0x90]; // 127.0.0.1:8080
         assert_eq!(ipv4_peer.len(), COMPACT_PEER_INFO_SIZE_IPV4);
         
-        // Verify parsing logic
-        let ip = std::net::Ipv4Addr::new(ipv4_peer[0], ipv4_peer[1], ipv4_peer[2], ipv4_peer[3]);
-        let port = u16::from_be_bytes([ipv4_peer[4], ipv4_peer[5]]);
+        let addr = parse_compact_peer(&ipv4_peer).expect("valid IPv4 peer");
         
-        assert_eq!(ip.to_string(), "127.0.0.1");
-        assert_eq!(port, 8080);
+        assert_eq!(addr.ip().to_string(), "127.0.0.1");
+        assert_eq!(addr.port(), 8080);
     }
 
     #[tokio::test]
     async fn test_compact_peer_parsing_ipv6() {
         // Test IPv6 compact peer info parsing
-        let ipv6_peer = vec![
+        let ipv6_peer = [
             0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00,
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
             0x1F, 0x90  // port 8080
         ];
         assert_eq!(ipv6_peer.len(), COMPACT_PEER_INFO_SIZE_IPV6);
         
-        // Verify parsing logic
-        let mut ipv6_bytes = [0u8; 16];
-        ipv6_bytes.copy_from_slice(&ipv6_peer[0..16]);
-        let ip = std::net::Ipv6Addr::from(ipv6_bytes);
-        let port = u16::from_be_bytes([ipv6_peer[16], ipv6_peer[17]]);
+        let addr = parse_compact_peer(&ipv6_peer).expect("valid IPv6 peer");
+        
+        assert_eq!(addr.ip().to_string(), "2001:db8::1");
+        assert_eq!(addr.port(), 8080);
+    }
+
+    #[test]
+    fn test_compact_node_parsing() {
+        let mut data = vec![7u8; 20];
+        data.extend_from_slice(&[10, 0, 0, 1, 0x1A, 0xE1]); // 10.0.0.1:6881
+        data.extend_from_slice(&[1, 2, 3]); // trailing garbage is ignored
+        
+        let nodes = parse_compact_nodes(&data);
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].node_id, [7u8; 20]);
+        assert_eq!(nodes[0].addr, "10.0.0.1:6881".parse().unwrap());
+    }
+
+    #[test]
+    fn test_routing_table_limits_nodes_per_prefix() {
+        let mut rt = RoutingTable::new(DEFAULT_BUCKET_K);
+        for i in 0..50u8 {
+            rt.add_node([i; 20], SocketAddr::from(([203, 0, 113, i], 6881)));
+        }
+        assert_eq!(rt.nodes.len(), MAX_NODES_PER_PREFIX);
+
+        // Nodes already in the table are still refreshed
+        rt.add_node([0; 20], "203.0.113.0:6881".parse().unwrap());
+        assert_eq!(rt.nodes.last().unwrap().node_id, [0; 20]);
+        assert_eq!(rt.nodes.len(), MAX_NODES_PER_PREFIX);
+
+        // The next /24 over, and IPv6 by /48
+        rt.add_node([0xAA; 20], "203.0.114.1:6881".parse().unwrap());
+        for i in 1..10u8 {
+            rt.add_node([0xB0 + i; 20], format!("[2001:db8:1:{}::1]:6881", i).parse().unwrap());
+        }
+        rt.add_node([0xC0; 20], "[2001:db8:2::1]:6881".parse().unwrap());
+        assert_eq!(rt.nodes.len(), 2 * MAX_NODES_PER_PREFIX + 2);
+
+        // Local nodes are not limited
+        for i in 1..10u8 {
+            rt.add_node([0xD0 + i; 20], SocketAddr::from(([127, 0, 0, 1], 7000 + i as u16)));
+        }
+        assert_eq!(rt.nodes.len(), 2 * MAX_NODES_PER_PREFIX + 11);
+    }
+
+    #[test]
+    fn test_routing_table_closest_orders_by_distance() {
+        let mut rt = RoutingTable::new(DEFAULT_BUCKET_K);
+        let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        rt.add_node([0xF0; 20], addr);
+        rt.add_node([0x01; 20], addr);
+        rt.add_node([0x10; 20], addr);
+        
+        let closest = rt.closest(&[0u8; 20], 2);
+        let ids: Vec<[u8; 20]> = closest.iter().map(|n| n.node_id).collect();
+        assert_eq!(ids, vec![[0x01; 20], [0x10; 20]]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrent_pings_resolve_to_their_own_response() {
+        const PINGS: usize = 20;
+        
+        // Responder that collects every ping first, then answers in reverse
+        // order with an id derived from each query's transaction id.
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let responder_addr = responder.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_KRPC_MESSAGE_SIZE];
+            let mut queries = Vec::new();
+            while queries.len() < PINGS {
+                let (len, from) = responder.recv_from(&mut buf).await.unwrap();
+                queries.push((protocol::decode_krpc(&buf[..len]).unwrap(), from));
+            }
+            for (query, from) in queries.into_iter().rev() {
+                let mut id = vec![0u8; 20];
+                id[..query.t.len()].copy_from_slice(&query.t);
+                let reply = protocol::KrpcMessage {
+                    t: query.t,
+                    y: protocol::KrpcMessageType::Response,
+                    q: None,
+                    a: None,
+                    r: Some(protocol::KrpcResponse {
+                        id: Some(id),
+                        ..Default::default()
+                    }),
+                    e: None,
+                    ip: None,
+                };
+                let data = protocol::encode_krpc(&reply).unwrap();
+                responder.send_to(&data, from).await.unwrap();
+            }
+        });
+        
+        let client = Arc::new(
+            DhtClient::new(DhtConfig {
+                bootstrap: vec![],
+                bind_port: 0,
+                ..Default::default()
+            })
+            .await
+            .unwrap(),
+        );
+        
+        let handles: Vec<_> = (0..PINGS)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move { client.ping(responder_addr).await })
+            })
+            .collect();
         
-        assert_eq!(ip.to_string(), "2001:db8::1");
-        assert_eq!(port, 8080);
+        let mut ids = HashSet::new();
+        for handle in handles {
+            let id = handle.await.unwrap().expect("every ping should get a response");
+            ids.insert(id);
+        }
+        
+        // Each ping saw a different response: none was stolen or delivered twice
+        assert_eq!(ids.len(), PINGS);
+        assert!(client.querier.pending.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_error_reply_is_returned_as_krpc_error() {
+        // Responder that rejects every query with a protocol error
+        let responder = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let responder_addr = responder.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_KRPC_MESSAGE_SIZE];
+            let (len, from) = responder.recv_from(&mut buf).await.unwrap();
+            let query = protocol::decode_krpc(&buf[..len]).unwrap();
+            let reply = QueryHandler::error(query.t, KRPC_ERROR_PROTOCOL, "bad query");
+            responder.send_to(&protocol::encode_krpc(&reply).unwrap(), from).await.unwrap();
+        });
+
+        let client = DhtClient::new(DhtConfig::default()).await.unwrap();
+        let started = Instant::now();
+        match client.ping(responder_addr).await {
+            Err(DhtError::KrpcError { code, message }) => {
+                assert_eq!(code, KRPC_ERROR_PROTOCOL);
+                assert_eq!(message, "bad query");
+            }
+            other => panic!("expected a KRPC error, got {:?}", other),
+        }
+        assert!(started.elapsed() < DEFAULT_QUERY_TIMEOUT / 5, "error should not wait for the query timeout");
+        assert!(client.querier.pending.lock().await.is_empty());
+    }
+
+    async fn test_query_handler() -> QueryHandler {
+        QueryHandler {
+            sockets: DhtSockets {
+                v4: Some(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap())),
+                v6: None,
+            },
+            node_id: Arc::new(std::sync::RwLock::new([0xAA; 20])),
+            routing_table: Arc::new(Mutex::new(RoutingTable::new(DEFAULT_BUCKET_K))),
+            peer_store: Arc::new(Mutex::new(HashMap::new())),
+            token_secret: Arc::new(Mutex::new(TokenSecret::new())),
+            items: Mutex::new(ItemStore::default()),
+            rate_limiter: Mutex::new(QueryRateLimiter::new(DEFAULT_MAX_QUERIES_PER_IP, DEFAULT_MAX_QUERIES_TOTAL)),
+            dropped_queries: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn test_query(q: protocol::KrpcQueryKind, args: protocol::KrpcArgs) -> protocol::KrpcMessage {
+        protocol::KrpcMessage {
+            t: vec![0, 1],
+            y: protocol::KrpcMessageType::Query,
+            q: Some(q),
+            a: Some(protocol::KrpcArgs {
+                id: Some(vec![0xBB; 20]),
+                ..args
+            }),
+            r: None,
+            e: None,
+            ip: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_handler_answers_ping_and_learns_querier() {
+        let handler = test_query_handler().await;
+        let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        
+        let reply = handler
+            .handle(from, test_query(protocol::KrpcQueryKind::Ping, Default::default()))
+            .await
+            .expect("ping should be answered");
+        
+        assert_eq!(reply.t, vec![0, 1]);
+        assert_eq!(reply.r.unwrap().id, Some(vec![0xAA; 20]));
+        assert_eq!(handler.routing_table.lock().await.nodes[0].addr, from);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_query_handler_throttles_flooding_source() {
+        let handler = test_query_handler().await;
+        let flooder: SocketAddr = "10.0.0.1:7000".parse().unwrap();
+        
+        let mut answered = 0;
+        for _ in 0..50 {
+            let query = test_query(protocol::KrpcQueryKind::Ping, Default::default());
+            if handler.handle(flooder, query).await.is_some() {
+                answered += 1;
+            }
+        }
+        assert_eq!(answered, DEFAULT_MAX_QUERIES_PER_IP);
+        assert_eq!(handler.dropped_queries.load(Ordering::Relaxed), 40);
+        
+        // Another source is still answered
+        let quiet: SocketAddr = "10.0.0.2:7000".parse().unwrap();
+        let query = test_query(protocol::KrpcQueryKind::Ping, Default::default());
+        assert!(handler.handle(quiet, query).await.is_some());
+        
+        // And the flooder is answered again once its bucket refills
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let query = test_query(protocol::KrpcQueryKind::Ping, Default::default());
+        assert!(handler.handle(flooder, query).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_query_handler_find_node_returns_closest() {
+        let handler = test_query_handler().await;
+        handler.routing_table.lock().await.add_node([0x01; 20], "10.0.0.1:1".parse().unwrap());
+        
+        let reply = handler
+            .handle(
+                "127.0.0.1:7000".parse().unwrap(),
+                test_query(
+                    protocol::KrpcQueryKind::FindNode,
+                    protocol::KrpcArgs {
+                        target: Some(vec![0x00; 20]),
+                        ..Default::default()
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+        
+        let nodes = parse_compact_nodes(&reply.r.unwrap().nodes.unwrap());
+        // The querier itself was learned too, but 0x01.. is closer to the target
+        assert_eq!(nodes[0].node_id, [0x01; 20]);
+    }
+
+    #[test]
+    fn test_parse_compact_nodes6() {
+        // Two nodes: ::1 port 6881 and 2001:db8::2 port 443, plus trailing garbage
+        let mut data = Vec::new();
+        data.extend_from_slice(&[0x11; 20]);
+        data.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        data.extend_from_slice(&6881u16.to_be_bytes());
+        data.extend_from_slice(&[0x22; 20]);
+        data.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        data.extend_from_slice(&443u16.to_be_bytes());
+        data.extend_from_slice(&[0xFF; 5]);
+        
+        let nodes = parse_compact_nodes6(&data);
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].node_id, [0x11; 20]);
+        assert_eq!(nodes[0].addr, "[::1]:6881".parse().unwrap());
+        assert_eq!(nodes[1].node_id, [0x22; 20]);
+        assert_eq!(nodes[1].addr, "[2001:db8::2]:443".parse().unwrap());
+        
+        // Encoding skips IPv4 nodes and round-trips the rest
+        let mut mixed = nodes.clone();
+        mixed.push(NodeInfo::new([0x33; 20], "10.0.0.1:1".parse().unwrap()));
+        assert_eq!(encode_compact_nodes6(&mixed), data[..2 * COMPACT_NODE_INFO_SIZE_IPV6]);
+    }
+
+    #[tokio::test]
+    async fn test_query_handler_honors_want() {
+        let handler = test_query_handler().await;
+        {
+            let mut rt = handler.routing_table.lock().await;
+            rt.add_node([0x01; 20], "10.0.0.1:1".parse().unwrap());
+            rt.add_node([0x02; 20], "[2001:db8::1]:1".parse().unwrap());
+        }
+        let find_node = |want: Option<Vec<String>>| {
+            test_query(
+                protocol::KrpcQueryKind::FindNode,
+                protocol::KrpcArgs {
+                    target: Some(vec![0x00; 20]),
+                    want,
+                    ..Default::default()
+                },
+            )
+        };
+        
+        // Explicit want for both families
+        let want = Some(vec![WANT_IPV4.to_string(), WANT_IPV6.to_string()]);
+        let r = handler.handle("10.9.9.9:7000".parse().unwrap(), find_node(want)).await.unwrap().r.unwrap();
+        assert!(parse_compact_nodes(&r.nodes.unwrap()).iter().all(|n| n.addr.is_ipv4()));
+        let nodes6 = parse_compact_nodes6(&r.nodes6.unwrap());
+        assert_eq!(nodes6[0].node_id, [0x02; 20]);
+        
+        // Without want, an IPv6 querier only gets nodes6
+        let r = handler.handle("[2001:db8::9]:7000".parse().unwrap(), find_node(None)).await.unwrap().r.unwrap();
+        assert!(r.nodes.is_none());
+        assert!(r.nodes6.is_some());
+    }
+
+    fn test_announce(token: Vec<u8>) -> protocol::KrpcMessage {
+        test_query(
+            protocol::KrpcQueryKind::AnnouncePeer,
+            protocol::KrpcArgs {
+                info_hash: Some(vec![3; 32]),
+                port: Some(9000),
+                token: Some(token),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Ask the handler for a token as a `get_peers` from `from` would.
+    async fn issued_token(handler: &QueryHandler, from: SocketAddr) -> Vec<u8> {
+        let reply = handler
+            .handle(
+                from,
+                test_query(
+                    protocol::KrpcQueryKind::GetPeers,
+                    protocol::KrpcArgs {
+                        info_hash: Some(vec![3; 32]),
+                        ..Default::default()
+                    },
+                ),
+            )
+            .await
+            .unwrap();
+        reply.r.unwrap().token.expect("get_peers should issue a token")
+    }
+
+    #[tokio::test]
+    async fn test_query_handler_announce_requires_token() {
+        let handler = test_query_handler().await;
+        let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        
+        let rejected = handler.handle(from, test_announce(b"bogus".to_vec())).await.unwrap();
+        assert!(matches!(rejected.y, protocol::KrpcMessageType::Error));
+        assert!(handler.stored_peers(&[3; 32]).await.is_empty());
+        
+        let token = issued_token(&handler, from).await;
+        let accepted = handler.handle(from, test_announce(token)).await.unwrap();
+        assert!(matches!(accepted.y, protocol::KrpcMessageType::Response));
+        assert_eq!(handler.stored_peers(&[3; 32]).await, vec!["127.0.0.1:9000".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_query_handler_returns_announced_values() {
+        let handler = test_query_handler().await;
+        let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let token = issued_token(&handler, from).await;
+
+        let mut oversized = test_announce(token.clone());
+        oversized.a.as_mut().unwrap().value = Some(vec![0; MAX_ANNOUNCE_VALUE_SIZE + 1]);
+        let rejected = handler.handle(from, oversized).await.unwrap();
+        assert!(matches!(rejected.e, Some((KRPC_ERROR_PROTOCOL, _))));
+        assert!(handler.stored_peers(&[3; 32]).await.is_empty());
+
+        let mut announce = test_announce(token);
+        announce.a.as_mut().unwrap().value = Some(b"sync/2".to_vec());
+        handler.handle(from, announce).await.unwrap();
+        let mut get_peers = test_announce(Vec::new());
+        get_peers.q = Some(protocol::KrpcQueryKind::GetPeers);
+        let reply = handler.handle(from, get_peers).await.unwrap().r.unwrap();
+        assert_eq!(
+            reply.peer_values,
+            Some(vec![protocol::PeerValue {
+                peer: encode_compact_peer("127.0.0.1:9000".parse().unwrap()),
+                value: b"sync/2".to_vec(),
+            }])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_peers_reply_fits_a_datagram() {
+        let handler = test_query_handler().await;
+        let value = vec![7; MAX_ANNOUNCE_VALUE_SIZE];
+        for port in 9000..9100 {
+            let peer = SocketAddr::new("127.0.0.1".parse().unwrap(), port);
+            handler.store_peer([3; 32], peer, Some(value.clone())).await;
+        }
+
+        let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let mut get_peers = test_announce(Vec::new());
+        get_peers.q = Some(protocol::KrpcQueryKind::GetPeers);
+        let reply = handler.handle(from, get_peers).await.unwrap();
+
+        assert!(protocol::encode_krpc(&reply).unwrap().len() < MAX_KRPC_MESSAGE_SIZE);
+        let reply = reply.r.unwrap();
+        assert_eq!(reply.values.unwrap().len(), 100);
+        let peer_values = reply.peer_values.unwrap();
+        assert!(!peer_values.is_empty() && peer_values.len() < 100);
+        assert!(peer_values.iter().all(|entry| entry.value == value));
+    }
+
+    #[tokio::test]
+    async fn test_query_handler_unannounce_removes_peer() {
+        let handler = test_query_handler().await;
+        let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let token = issued_token(&handler, from).await;
+        handler.handle(from, test_announce(token.clone())).await.unwrap();
+        
+        let mut unannounce = test_announce(token);
+        unannounce.q = Some(protocol::KrpcQueryKind::Unannounce);
+        let reply = handler.handle(from, unannounce).await.unwrap();
+        
+        assert!(matches!(reply.y, protocol::KrpcMessageType::Response));
+        assert!(handler.stored_peers(&[3; 32]).await.is_empty());
+    }
+
+    #[test]
+    fn test_announce_args_imply_port_only_for_port_zero() {
+        let encoded = |port| {
+            let msg = protocol::KrpcMessage {
+                t: vec![0, 1],
+                y: protocol::KrpcMessageType::Query,
+                q: Some(protocol::KrpcQueryKind::AnnouncePeer),
+                a: Some(announce_args([1; 20], &[3; 32], port, b"tok".to_vec(), None)),
+                r: None,
+                e: None,
+                ip: None,
+            };
+            protocol::encode_krpc(&msg).unwrap()
+        };
+        let contains = |haystack: &[u8], needle: &[u8]| haystack.windows(needle.len()).any(|w| w == needle);
+        
+        assert!(contains(&encoded(0), b"12:implied_porti1e"));
+        let explicit = encoded(9000);
+        assert!(!contains(&explicit, b"implied_port"));
+        assert!(contains(&explicit, b"4:porti9000e"));
+    }
+
+    #[tokio::test]
+    async fn test_query_handler_announce_with_implied_port() {
+        let handler = test_query_handler().await;
+        let from: SocketAddr = "127.0.0.1:7000".parse().unwrap();
+        let token = issued_token(&handler, from).await;
+        
+        let mut announce = test_announce(Vec::new());
+        announce.a = Some(announce_args([1; 20], &[3; 32], 0, token, None));
+        let reply = handler.handle(from, announce).await.unwrap();
+        
+        assert!(matches!(reply.y, protocol::KrpcMessageType::Response));
+        assert_eq!(handler.stored_peers(&[3; 32]).await, vec![from]);
+    }
+
+    #[tokio::test]
+    async fn test_announce_token_is_bound_to_requesting_ip() {
+        let handler = test_query_handler().await;
+        let token = issued_token(&handler, "10.0.0.1:7000".parse().unwrap()).await;
+        
+        let reply = handler
+            .handle("10.0.0.2:7000".parse().unwrap(), test_announce(token))
+            .await
+            .unwrap();
+        
+        assert!(matches!(reply.e, Some((KRPC_ERROR_PROTOCOL, _))));
+        assert!(handler.stored_peers(&[3; 32]).await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_announce_token_survives_one_rotation() {
+        let handler = test_query_handler().await;
+        let from: SocketAddr = "10.0.0.1:7000".parse().unwrap();
+        let token = issued_token(&handler, from).await;
+        
+        // Still valid just before and just after the secret rotates
+        tokio::time::advance(TOKEN_ROTATION_INTERVAL - std::time::Duration::from_secs(1)).await;
+        assert!(handler.token_secret.lock().await.is_valid(&token, from.ip()));
+        tokio::time::advance(std::time::Duration::from_secs(2)).await;
+        let reply = handler.handle(from, test_announce(token.clone())).await.unwrap();
+        assert!(matches!(reply.y, protocol::KrpcMessageType::Response));
+        
+        // Stale once a second rotation retires the secret it came from
+        tokio::time::advance(TOKEN_ROTATION_INTERVAL).await;
+        assert!(!handler.token_secret.lock().await.is_valid(&token, from.ip()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stored_peers_expire() {
+        let handler = test_query_handler().await;
+        handler.store_peer([5; 32], "10.0.0.5:5000".parse().unwrap(), None).await;
+        assert_eq!(handler.stored_peers(&[5; 32]).await.len(), 1);
+        
+        tokio::time::advance(PEER_TTL).await;
+        assert!(handler.stored_peers(&[5; 32]).await.is_empty());
+        assert!(handler.peer_store.lock().await.is_empty());
+    }
+
+    /// Resolves every name to the same addresses.
+    struct StaticResolver(Vec<SocketAddr>);
+
+    impl Resolver for StaticResolver {
+        fn resolve<'a>(&'a self, _host_port: &'a str) -> futures::future::BoxFuture<'a, std::io::Result<Vec<SocketAddr>>> {
+            Box::pin(async move { Ok(self.0.clone()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_pings_every_resolved_address() {
+        let live = DhtClient::new(DhtConfig::default()).await.unwrap();
+        let live_addr: SocketAddr = format!("127.0.0.1:{}", live.local_addr().unwrap().port()).parse().unwrap();
+        // Answers nothing, like a stale DNS record
+        let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let config = DhtConfig {
+            bootstrap: vec!["bootstrap.example:6881".to_string()],
+            bootstrap_ping_timeout: Duration::from_millis(300),
+            ..Default::default()
+        };
+        let client = DhtClient::new(config)
+            .await
+            .unwrap()
+            .with_resolver(Arc::new(StaticResolver(vec![dead.local_addr().unwrap(), live_addr])));
+        client.bootstrap().await.unwrap();
+
+        let nodes = client.routing_table.lock().await.get_nodes(8);
+        let addrs: Vec<_> = nodes.iter().map(|node| node.addr).collect();
+        assert_eq!(addrs, [live_addr]);
+    }
+
+    /// Resolves each name to its own addresses.
+    struct NamedResolver(HashMap<&'static str, SocketAddr>);
+
+    impl Resolver for NamedResolver {
+        fn resolve<'a>(&'a self, host_port: &'a str) -> futures::future::BoxFuture<'a, std::io::Result<Vec<SocketAddr>>> {
+            Box::pin(async move { Ok(self.0.get(host_port).copied().into_iter().collect()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_sources_are_combined() {
+        let mut names = HashMap::new();
+        let mut live = Vec::new();
+        for name in ["static.example:6881", "listed.example:6881", "seed.example:6881"] {
+            let node = DhtClient::new(DhtConfig::default()).await.unwrap();
+            names.insert(name, SocketAddr::from(([127, 0, 0, 1], node.local_addr().unwrap().port())));
+            live.push(node);
+        }
+
+        let config = DhtConfig {
+            bootstrap: vec!["static.example:6881".to_string()],
+            bootstrap_sources: vec![
+                BootstrapSource::Static(vec!["listed.example:6881".to_string(), "seed.example:6881".to_string()]),
+            ],
+            ..Default::default()
+        };
+        let client = DhtClient::new(config)
+            .await
+            .unwrap()
+            .with_resolver(Arc::new(NamedResolver(names.clone())));
+        client.bootstrap().await.unwrap();
+
+        let mut addrs: Vec<_> = client.known_nodes().await.into_iter().map(|(_, addr)| addr).collect();
+        let mut expected: Vec<_> = names.into_values().collect();
+        addrs.sort();
+        expected.sort();
+        assert_eq!(addrs, expected);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_bootstrap_sources_are_rejected() {
+        let config = DhtConfig {
+            bootstrap_sources: vec![
+                BootstrapSource::Static(vec!["good.example:6881".to_string(), "no-port".to_string()]),
+                BootstrapSource::Static(vec!["seed.example".to_string()]),
+                BootstrapSource::Mdns { service_name: "_bad.._udp.local".to_string() },
+                BootstrapSource::Mdns { service_name: mdns::DEFAULT_SERVICE_NAME.to_string() },
+            ],
+            ..Default::default()
+        };
+        match DhtClient::new(config).await {
+            Err(DhtError::InvalidBootstrap(entries)) => {
+                assert_eq!(entries, "no-port, seed.example, _bad.._udp.local")
+            }
+            Err(e) => panic!("Expected InvalidBootstrap, got {}", e),
+            Ok(_) => panic!("Expected InvalidBootstrap, client was created"),
+        }
     }
 
     #[tokio::test]
@@ -636,6 +4200,7 @@ mod tests {
         let config = DhtConfig {
             bootstrap: vec![],
             bind_port: 0,
+            ..Default::default()
         };
 
         let client = std::sync::Arc::new(
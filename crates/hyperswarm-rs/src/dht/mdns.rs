@@ -0,0 +1,603 @@
+//! Finding DHT nodes on the local network with multicast DNS.
+//!
+//! A node that wants to be found runs an [`MdnsResponder`], which answers
+//! PTR queries for a service name such as `_hyperswarm._udp.local` with a
+//! service instance (SRV record) pointing at its DHT port. A node that wants
+//! to bootstrap sends such a query with [`query`] and pings whoever answers,
+//! so machines on one LAN join each other without any configuration.
+//!
+//! Only as much DNS as that takes is spoken: PTR questions, and
+//! PTR, SRV, A and AAAA answers. Queries ask for unicast replies from a
+//! port other than 5353, which responders answer directly (RFC 6762's
+//! "legacy unicast"); a responder names no addresses of its own, so an
+//! instance is found at the address its answer came from unless the
+//! answer carries A or AAAA records for it.
+//!
+//! Both ends stay on the link, as RFC 6762 §11 asks: a responder ignores
+//! queries from outside the networks of its interfaces, so it cannot be
+//! made to send its port off the link, and a querier only takes answers
+//! from the mDNS port of a host on those networks, carrying its query's id.
+//! Address records pointing off the link are ignored too.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+/// The IPv4 mDNS group.
+pub const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+/// The port mDNS responders listen on.
+pub const MDNS_PORT: u16 = 5353;
+/// Service name Hyperswarm nodes advertise themselves under.
+pub const DEFAULT_SERVICE_NAME: &str = "_hyperswarm._udp.local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on a question's class to ask for a unicast reply, and on a record's
+/// class to mark it as the only one of its name and type.
+const CLASS_TOP_BIT: u16 = 0x8000;
+/// Flags of an authoritative response.
+const FLAGS_RESPONSE: u16 = 0x8400;
+/// How long answers may be cached.
+const RECORD_TTL: u32 = 120;
+/// Compression pointers followed in one name before it is taken for a loop.
+const MAX_NAME_POINTERS: usize = 16;
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
+/// Receive buffer, larger than any mDNS message.
+const MAX_MESSAGE_SIZE: usize = 9000;
+/// Pause before reading again after a failed read.
+const RECV_ERROR_BACKOFF: Duration = Duration::from_millis(10);
+/// Longest pause between reads while they keep failing.
+const MAX_RECV_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Whether `name` can be sent as a DNS name: dot-separated labels of 1 to
+/// 63 bytes, 255 at most in all.
+pub fn is_valid_service_name(name: &str) -> bool {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    !name.is_empty()
+        && name.len() < MAX_NAME_LEN
+        && name.split('.').all(|label| !label.is_empty() && label.len() <= MAX_LABEL_LEN)
+}
+
+/// A service instance found by [`query`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MdnsService {
+    /// The instance's name, without the service name.
+    pub instance: String,
+    /// Where the instance's DHT node listens.
+    pub addr: SocketAddr,
+}
+
+/// Ask the local networks for instances of `service_name`, collecting
+/// answers for `wait`.
+///
+/// The query goes out on every IPv4 interface, loopback included, so nodes
+/// on this host answer too. Only answers to this query from the mDNS port
+/// of an on-link host count. An instance reachable at several addresses is
+/// listed once per address.
+pub async fn query(service_name: &str, wait: Duration) -> io::Result<Vec<MdnsService>> {
+    let deadline = tokio::time::Instant::now() + wait;
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_multicast_ttl_v4(255)?;
+    let id = rand::thread_rng().gen();
+    let message = encode_query(id, service_name);
+    let mut sent = false;
+    for interface in multicast_interfaces() {
+        let chosen = socket2::SockRef::from(&socket).set_multicast_if_v4(&interface);
+        let sent_here = match chosen {
+            Ok(()) => socket.send_to(&message, (MDNS_GROUP, MDNS_PORT)).await,
+            Err(e) => Err(e),
+        };
+        match sent_here {
+            Ok(_) => sent = true,
+            Err(e) => tracing::debug!("mDNS query on {} failed: {}", interface, e),
+        }
+    }
+    if !sent {
+        return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "no interface to send the mDNS query on"));
+    }
+
+    let networks = local_networks();
+    let on_link = |ip| is_on_link(ip, &networks);
+    let mut found = Vec::new();
+    let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = received?;
+        if from.port() != MDNS_PORT || !on_link(from.ip()) {
+            continue;
+        }
+        for service in parse_answers(&buf[..len], service_name, id, from.ip(), on_link) {
+            if !found.contains(&service) {
+                found.push(service);
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Answers mDNS queries for a service with one instance, until dropped.
+pub struct MdnsResponder {
+    instance: String,
+    task: JoinHandle<()>,
+}
+
+impl MdnsResponder {
+    /// Join the mDNS group on every IPv4 interface and answer queries for
+    /// `service_name` with instance `instance` on `port`.
+    ///
+    /// The port is shared with other responders on the host. Must be called
+    /// within a Tokio runtime.
+    pub fn bind(service_name: &str, instance: &str, port: u16) -> io::Result<Self> {
+        if !is_valid_service_name(service_name) || !is_valid_service_name(&format!("{}.{}", instance, service_name)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid mDNS service or instance name"));
+        }
+        let socket = bind_group_socket()?;
+        let responder = Responder {
+            service_name: normalize(service_name),
+            instance_name: normalize(&format!("{}.{}", instance, service_name)),
+            port,
+        };
+        Ok(Self {
+            instance: instance.to_string(),
+            task: tokio::spawn(responder.run(socket)),
+        })
+    }
+
+    /// The instance this responder advertises.
+    pub fn instance(&self) -> &str {
+        &self.instance
+    }
+}
+
+impl Drop for MdnsResponder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// The receive loop of an [`MdnsResponder`].
+struct Responder {
+    service_name: String,
+    instance_name: String,
+    port: u16,
+}
+
+impl Responder {
+    /// Answer queries read from `socket`, backing off while reads fail.
+    async fn run(self, socket: UdpSocket) {
+        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+        let mut backoff = RECV_ERROR_BACKOFF;
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    tracing::debug!("mDNS receive failed, retrying in {:?}: {}", backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_RECV_ERROR_BACKOFF);
+                    continue;
+                }
+            };
+            backoff = RECV_ERROR_BACKOFF;
+            let Some(message) = Message::parse(&buf[..len]) else {
+                continue;
+            };
+            let asked = message.questions.iter().any(|(name, qtype)| {
+                *name == self.service_name && (*qtype == TYPE_PTR || *qtype == TYPE_ANY)
+            });
+            if message.response || !asked {
+                continue;
+            }
+            // Only hosts on our networks may ask
+            if !is_on_link(from.ip(), &local_networks()) {
+                tracing::debug!("Ignoring mDNS query from off-link {}", from);
+                continue;
+            }
+            // Queriers on the mDNS port read the group; any other port wants
+            // a direct reply, which repeats the question
+            let (reply, to) = if from.port() == MDNS_PORT {
+                (self.encode_response(0, false), SocketAddr::from((MDNS_GROUP, MDNS_PORT)))
+            } else {
+                (self.encode_response(message.id, true), from)
+            };
+            if let Err(e) = socket.send_to(&reply, to).await {
+                tracing::debug!("mDNS reply to {} failed: {}", to, e);
+            }
+        }
+    }
+
+    /// A PTR record for the instance and the SRV record locating it.
+    fn encode_response(&self, id: u16, with_question: bool) -> Vec<u8> {
+        let host = format!("{}.local", self.instance_name.split('.').next().unwrap_or_default());
+        let mut out = Vec::new();
+        write_header(&mut out, id, FLAGS_RESPONSE, u16::from(with_question), 2);
+        if with_question {
+            write_name(&mut out, &self.service_name);
+            out.extend_from_slice(&TYPE_PTR.to_be_bytes());
+            out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        }
+
+        let mut ptr = Vec::new();
+        write_name(&mut ptr, &self.instance_name);
+        write_record(&mut out, &self.service_name, TYPE_PTR, CLASS_IN, &ptr);
+
+        let mut srv = Vec::new();
+        srv.extend_from_slice(&0u16.to_be_bytes()); // priority
+        srv.extend_from_slice(&0u16.to_be_bytes()); // weight
+        srv.extend_from_slice(&self.port.to_be_bytes());
+        write_name(&mut srv, &host);
+        write_record(&mut out, &self.instance_name, TYPE_SRV, CLASS_IN | CLASS_TOP_BIT, &srv);
+        out
+    }
+}
+
+/// A UDP socket on the mDNS port, in the group on every IPv4 interface that
+/// lets it join.
+fn bind_group_socket() -> io::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    let mut joined = false;
+    for interface in multicast_interfaces() {
+        match socket.join_multicast_v4(&MDNS_GROUP, &interface) {
+            Ok(()) => joined = true,
+            Err(e) => tracing::debug!("Not answering mDNS on {}: {}", interface, e),
+        }
+    }
+    if !joined {
+        return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, "could not join the mDNS group"));
+    }
+    UdpSocket::from_std(socket.into())
+}
+
+/// Addresses of the IPv4 interfaces, loopback included.
+fn multicast_interfaces() -> Vec<Ipv4Addr> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            tracing::debug!("Failed to enumerate network interfaces: {}", e);
+            return vec![Ipv4Addr::LOCALHOST];
+        }
+    };
+    let mut addrs = Vec::new();
+    for interface in interfaces {
+        if let IpAddr::V4(ip) = interface.ip() {
+            if !addrs.contains(&ip) {
+                addrs.push(ip);
+            }
+        }
+    }
+    addrs
+}
+
+/// The networks of this host's interfaces, as address and netmask; just
+/// loopback if they cannot be listed.
+fn local_networks() -> Vec<(IpAddr, IpAddr)> {
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces
+            .into_iter()
+            .map(|interface| match interface.addr {
+                if_addrs::IfAddr::V4(v4) => (IpAddr::V4(v4.ip), IpAddr::V4(v4.netmask)),
+                if_addrs::IfAddr::V6(v6) => (IpAddr::V6(v6.ip), IpAddr::V6(v6.netmask)),
+            })
+            .collect(),
+        Err(e) => {
+            tracing::debug!("Failed to enumerate network interfaces: {}", e);
+            vec![(IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V4(Ipv4Addr::new(255, 0, 0, 0)))]
+        }
+    }
+}
+
+/// Whether `ip` is on one of `networks`.
+fn is_on_link(ip: IpAddr, networks: &[(IpAddr, IpAddr)]) -> bool {
+    networks.iter().any(|&(addr, netmask)| match (ip, addr, netmask) {
+        (IpAddr::V4(ip), IpAddr::V4(addr), IpAddr::V4(netmask)) => {
+            u32::from(ip) & u32::from(netmask) == u32::from(addr) & u32::from(netmask)
+        }
+        (IpAddr::V6(ip), IpAddr::V6(addr), IpAddr::V6(netmask)) => {
+            u128::from(ip) & u128::from(netmask) == u128::from(addr) & u128::from(netmask)
+        }
+        _ => false,
+    })
+}
+
+/// A name lowercased and without its trailing dot, for comparing.
+fn normalize(name: &str) -> String {
+    name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase()
+}
+
+fn write_header(out: &mut Vec<u8>, id: u16, flags: u16, questions: u16, answers: u16) {
+    for field in [id, flags, questions, answers, 0, 0] {
+        out.extend_from_slice(&field.to_be_bytes());
+    }
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.strip_suffix('.').unwrap_or(name).split('.') {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+fn write_record(out: &mut Vec<u8>, name: &str, rtype: u16, class: u16, data: &[u8]) {
+    write_name(out, name);
+    out.extend_from_slice(&rtype.to_be_bytes());
+    out.extend_from_slice(&class.to_be_bytes());
+    out.extend_from_slice(&RECORD_TTL.to_be_bytes());
+    out.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+/// A PTR query for `service_name`, asking for a unicast reply.
+fn encode_query(id: u16, service_name: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_header(&mut out, id, 0, 1, 0);
+    write_name(&mut out, service_name);
+    out.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    out.extend_from_slice(&(CLASS_IN | CLASS_TOP_BIT).to_be_bytes());
+    out
+}
+
+/// The instances of `service_name` a response to query `id` names.
+/// Instances without address records passing `on_link` are placed at
+/// `from`, the responder's address.
+fn parse_answers(
+    data: &[u8],
+    service_name: &str,
+    id: u16,
+    from: IpAddr,
+    on_link: impl Fn(IpAddr) -> bool,
+) -> Vec<MdnsService> {
+    let Some(message) = Message::parse(data) else {
+        return Vec::new();
+    };
+    if !message.response || message.id != id {
+        return Vec::new();
+    }
+    let service_name = normalize(service_name);
+    let record_name = |record: &Record| read_name(data, record.data.start).map(|(name, _)| name);
+
+    let mut locations = HashMap::new();
+    let mut addresses: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for record in &message.records {
+        let rdata = &data[record.data.clone()];
+        match record.rtype {
+            TYPE_SRV if rdata.len() > 6 => {
+                let port = u16::from_be_bytes([rdata[4], rdata[5]]);
+                if let Some((target, _)) = read_name(data, record.data.start + 6) {
+                    locations.insert(record.name.clone(), (port, target));
+                }
+            }
+            TYPE_A if rdata.len() == 4 => {
+                let ip = IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]));
+                if on_link(ip) {
+                    addresses.entry(record.name.clone()).or_default().push(ip);
+                }
+            }
+            TYPE_AAAA if rdata.len() == 16 => {
+                let octets: [u8; 16] = rdata.try_into().expect("length checked");
+                if on_link(IpAddr::from(octets)) {
+                    addresses.entry(record.name.clone()).or_default().push(IpAddr::from(octets));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let suffix = format!(".{}", service_name);
+    let mut services = Vec::new();
+    for record in message.records.iter().filter(|r| r.rtype == TYPE_PTR && r.name == service_name) {
+        let Some(instance_name) = record_name(record) else {
+            continue;
+        };
+        let Some((port, target)) = locations.get(&instance_name) else {
+            continue;
+        };
+        let instance = instance_name.strip_suffix(&suffix).unwrap_or(&instance_name).to_string();
+        let ips = addresses.get(target).cloned().unwrap_or_else(|| vec![from]);
+        for ip in ips {
+            services.push(MdnsService {
+                instance: instance.clone(),
+                addr: SocketAddr::new(ip, *port),
+            });
+        }
+    }
+    services
+}
+
+/// A resource record; `data` indexes the message it was read from.
+struct Record {
+    name: String,
+    rtype: u16,
+    data: std::ops::Range<usize>,
+}
+
+/// The parts of a DNS message the responder and querier look at.
+struct Message {
+    id: u16,
+    response: bool,
+    /// Name and type of each question.
+    questions: Vec<(String, u16)>,
+    /// Answer, authority and additional records alike.
+    records: Vec<Record>,
+}
+
+impl Message {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let field = |at: usize| data.get(at..at + 2).map(|b| u16::from_be_bytes([b[0], b[1]]));
+        let id = field(0)?;
+        let response = field(2)? & 0x8000 != 0;
+        let question_count = field(4)?;
+        let record_count = field(6)? as usize + field(8)? as usize + field(10)? as usize;
+
+        let mut pos = 12;
+        let mut questions = Vec::new();
+        for _ in 0..question_count {
+            let (name, next) = read_name(data, pos)?;
+            questions.push((name, field(next)?));
+            pos = next + 4;
+        }
+        let mut records = Vec::new();
+        for _ in 0..record_count {
+            let (name, next) = read_name(data, pos)?;
+            let rtype = field(next)?;
+            let len = field(next + 8)? as usize;
+            let start = next + 10;
+            data.get(start..start + len)?;
+            records.push(Record { name, rtype, data: start..start + len });
+            pos = start + len;
+        }
+        Some(Self { id, response, questions, records })
+    }
+}
+
+/// Read the possibly compressed name at `pos`, normalized, and the position
+/// after it.
+fn read_name(data: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *data.get(pos)? as usize;
+        if len == 0 {
+            return Some((labels.join(".").to_ascii_lowercase(), end.unwrap_or(pos + 1)));
+        }
+        if len & 0xC0 == 0xC0 {
+            pointers += 1;
+            if pointers > MAX_NAME_POINTERS {
+                return None;
+            }
+            end.get_or_insert(pos + 2);
+            pos = ((len & 0x3F) << 8) | *data.get(pos + 1)? as usize;
+            continue;
+        }
+        if len > MAX_LABEL_LEN {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(data.get(pos + 1..pos + 1 + len)?).into_owned());
+        pos += 1 + len;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERVICE: &str = "_hyperswarm._udp.local";
+
+    fn responder(port: u16) -> Responder {
+        Responder {
+            service_name: SERVICE.to_string(),
+            instance_name: format!("node1.{}", SERVICE),
+            port,
+        }
+    }
+
+    #[test]
+    fn test_service_names() {
+        assert!(is_valid_service_name(SERVICE));
+        assert!(is_valid_service_name("_hyperswarm._udp.local."));
+        assert!(!is_valid_service_name(""));
+        assert!(!is_valid_service_name("_hyperswarm.._udp"));
+        assert!(!is_valid_service_name(&"a".repeat(64)));
+    }
+
+    #[test]
+    fn test_query_asks_for_the_service() {
+        let message = Message::parse(&encode_query(7, "_Hyperswarm._udp.local.")).unwrap();
+        assert_eq!(message.id, 7);
+        assert!(!message.response);
+        assert_eq!(message.questions, vec![(SERVICE.to_string(), TYPE_PTR)]);
+    }
+
+    #[test]
+    fn test_response_locates_the_instance() {
+        let from = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        let response = responder(6881).encode_response(7, true);
+        assert_eq!(Message::parse(&response).unwrap().id, 7);
+        assert_eq!(
+            parse_answers(&response, SERVICE, 7, from, |_| true),
+            vec![MdnsService { instance: "node1".to_string(), addr: SocketAddr::new(from, 6881) }]
+        );
+        // Another service's answers are not ours
+        assert!(parse_answers(&response, "_other._udp.local", 7, from, |_| true).is_empty());
+        // Nor are answers to another query, or queries
+        assert!(parse_answers(&response, SERVICE, 8, from, |_| true).is_empty());
+        assert!(parse_answers(&encode_query(7, SERVICE), SERVICE, 7, from, |_| true).is_empty());
+    }
+
+    #[test]
+    fn test_address_records_and_compression() {
+        // A response as other responders write it: names compressed against
+        // the PTR record's owner, and an A record for the target host
+        let mut out = Vec::new();
+        write_header(&mut out, 0, FLAGS_RESPONSE, 0, 3);
+        // "node2" followed by a pointer to the service name at offset 12
+        write_record(&mut out, SERVICE, TYPE_PTR, CLASS_IN, &[5, b'n', b'o', b'd', b'e', b'2', 0xC0, 12]);
+        let instance_at = out.len() - 8;
+        let mut srv = vec![0, 0, 0, 0, 0x1A, 0xE1];
+        write_name(&mut srv, "host.local");
+        out.extend_from_slice(&[0xC0, instance_at as u8]);
+        out.extend_from_slice(&TYPE_SRV.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        out.extend_from_slice(&RECORD_TTL.to_be_bytes());
+        out.extend_from_slice(&(srv.len() as u16).to_be_bytes());
+        out.extend_from_slice(&srv);
+        write_record(&mut out, "HOST.local", TYPE_A, CLASS_IN, &[10, 0, 0, 5]);
+
+        let from = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20));
+        assert_eq!(
+            parse_answers(&out, SERVICE, 0, from, |_| true),
+            vec![MdnsService { instance: "node2".to_string(), addr: "10.0.0.5:6881".parse().unwrap() }]
+        );
+        // An address off the link is not taken; the answer's source is
+        assert_eq!(
+            parse_answers(&out, SERVICE, 0, from, |ip| ip == from),
+            vec![MdnsService { instance: "node2".to_string(), addr: SocketAddr::new(from, 6881) }]
+        );
+    }
+
+    #[test]
+    fn test_on_link_addresses() {
+        let networks = [
+            (IpAddr::V4(Ipv4Addr::new(192, 168, 1, 7)), IpAddr::V4(Ipv4Addr::new(255, 255, 255, 0))),
+            ("fe80::1".parse().unwrap(), "ffff:ffff:ffff:ffff::".parse().unwrap()),
+        ];
+        assert!(is_on_link("192.168.1.20".parse().unwrap(), &networks));
+        assert!(!is_on_link("192.168.2.20".parse().unwrap(), &networks));
+        assert!(!is_on_link("203.0.113.9".parse().unwrap(), &networks));
+        assert!(is_on_link("fe80::abcd".parse().unwrap(), &networks));
+        assert!(!is_on_link("2001:db8::1".parse().unwrap(), &networks));
+        // This host's loopback is always its own
+        assert!(is_on_link(IpAddr::V4(Ipv4Addr::LOCALHOST), &local_networks()));
+    }
+
+    #[test]
+    fn test_malformed_messages_are_ignored() {
+        let response = responder(6881).encode_response(0, false);
+        for len in 0..response.len() {
+            let _ = parse_answers(&response[..len], SERVICE, 0, IpAddr::V4(Ipv4Addr::LOCALHOST), |_| true);
+        }
+        // A name pointing at itself
+        let mut looping = Vec::new();
+        write_header(&mut looping, 0, FLAGS_RESPONSE, 1, 0);
+        looping.extend_from_slice(&[0xC0, 12, 0, 12, 0, 1]);
+        assert!(Message::parse(&looping).is_none());
+    }
+}
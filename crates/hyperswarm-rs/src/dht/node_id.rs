@@ -0,0 +1,229 @@
+//! BEP 42: node ids bound to the node's external IP address.
+//!
+//! The first 21 bits of a secure node id are a CRC32C of the masked IP and a
+//! 3-bit random value, which is stored in the last byte of the id. A node can
+//! therefore only choose a small number of ids per IP address, which makes
+//! placing many nodes next to a target (a Sybil attack) expensive.
+//!
+//! [`encode_identity`] and [`parse_identity`] write a node id as the
+//! z-base32 string users share to name a node out of band.
+
+use std::net::IpAddr;
+
+use rand::Rng;
+
+use super::DhtError;
+
+const IPV4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const IPV6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+/// The z-base32 alphabet, ordered so the most common characters are the
+/// easiest to read and type.
+const ZBASE32_ALPHABET: &[u8; 32] = b"ybndrfg8ejkmcpqxot1uwisza345h769";
+/// A 160-bit id is exactly 32 five-bit characters.
+const IDENTITY_LEN: usize = 32;
+
+/// Generate a random node id that satisfies BEP 42 for `ip`.
+pub fn secure_node_id(ip: IpAddr) -> [u8; 20] {
+    let mut id: [u8; 20] = rand::thread_rng().gen();
+    let crc = ip_crc(ip, id[19]);
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 0x07);
+    id
+}
+
+/// Whether `id` satisfies BEP 42 for a node at `ip`.
+///
+/// Nodes on local and private networks are exempt, so any id is valid for
+/// them.
+pub fn is_secure_node_id(id: &[u8; 20], ip: IpAddr) -> bool {
+    if is_exempt(ip) {
+        return true;
+    }
+    let crc = ip_crc(ip, id[19]);
+    id[0] == (crc >> 24) as u8 && id[1] == (crc >> 16) as u8 && (id[2] & 0xf8) == ((crc >> 8) as u8 & 0xf8)
+}
+
+/// `id` as a 32-character z-base32 string, for sharing a node's identity.
+pub fn encode_identity(id: &[u8; 20]) -> String {
+    let mut out = String::with_capacity(IDENTITY_LEN);
+    let (mut bits, mut pending) = (0u32, 0u32);
+    for &byte in id {
+        bits = (bits << 8) | byte as u32;
+        pending += 8;
+        while pending >= 5 {
+            pending -= 5;
+            out.push(ZBASE32_ALPHABET[(bits >> pending) as usize & 0x1f] as char);
+        }
+    }
+    out
+}
+
+/// Parse an identity written by [`encode_identity`], in either case.
+///
+/// Anything but 32 z-base32 characters is [`DhtError::InvalidIdentity`].
+pub fn parse_identity(identity: &str) -> Result<[u8; 20], DhtError> {
+    if identity.chars().count() != IDENTITY_LEN {
+        return Err(DhtError::InvalidIdentity(format!(
+            "expected {} characters, got {}",
+            IDENTITY_LEN,
+            identity.chars().count()
+        )));
+    }
+    let mut id = [0u8; 20];
+    let (mut bits, mut pending, mut filled) = (0u32, 0u32, 0usize);
+    for c in identity.chars() {
+        let value = ZBASE32_ALPHABET
+            .iter()
+            .position(|&digit| digit as char == c.to_ascii_lowercase())
+            .ok_or_else(|| DhtError::InvalidIdentity(format!("{:?} is not a z-base32 character", c)))?;
+        bits = (bits << 5) | value as u32;
+        pending += 5;
+        if pending >= 8 {
+            pending -= 8;
+            id[filled] = (bits >> pending) as u8;
+            filled += 1;
+        }
+    }
+    Ok(id)
+}
+
+/// CRC32C of `ip` masked as BEP 42 describes, with the low 3 bits of `rand`
+/// in its top bits.
+fn ip_crc(ip: IpAddr, rand: u8) -> u32 {
+    let r = rand & 0x07;
+    match ip {
+        IpAddr::V4(v4) => {
+            let mut masked = v4.octets();
+            for (byte, mask) in masked.iter_mut().zip(IPV4_MASK) {
+                *byte &= mask;
+            }
+            masked[0] |= r << 5;
+            crc32c(&masked)
+        }
+        IpAddr::V6(v6) => {
+            let mut masked = [0u8; 8];
+            masked.copy_from_slice(&v6.octets()[..8]);
+            for (byte, mask) in masked.iter_mut().zip(IPV6_MASK) {
+                *byte &= mask;
+            }
+            masked[0] |= r << 5;
+            crc32c(&masked)
+        }
+    }
+}
+
+/// Addresses BEP 42 does not apply to.
+fn is_exempt(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_private() || v4.is_loopback() || v4.is_link_local(),
+        IpAddr::V6(v6) => v6.is_loopback() || v6.is_unique_local() || v6.is_unicast_link_local(),
+    }
+}
+
+/// CRC-32C (Castagnoli), bit by bit; only ever run over a few bytes.
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn test_bep42_vectors() {
+        // IP, random byte, and expected first three bytes from BEP 42
+        let vectors: [(&str, u8, [u8; 3]); 5] = [
+            ("124.31.75.21", 1, [0x5f, 0xbf, 0xbf]),
+            ("21.75.31.124", 86, [0x5a, 0x3c, 0xe9]),
+            ("65.23.51.170", 22, [0xa5, 0xd4, 0x32]),
+            ("84.124.73.14", 65, [0x1b, 0x03, 0x21]),
+            ("43.213.53.83", 90, [0xe5, 0x6f, 0x6c]),
+        ];
+        for (ip, rand, prefix) in vectors {
+            let ip: IpAddr = ip.parse().unwrap();
+            let mut id = [0u8; 20];
+            id[..3].copy_from_slice(&prefix);
+            id[19] = rand;
+            assert!(is_secure_node_id(&id, ip), "BEP 42 vector for {} should validate", ip);
+            id[0] ^= 0x80;
+            assert!(!is_secure_node_id(&id, ip));
+        }
+    }
+
+    #[test]
+    fn test_generated_ids_validate() {
+        let ips = ["124.31.75.21", "8.8.8.8", "203.0.113.7", "2001:db8::1", "2606:4700::1111"];
+        for ip in ips {
+            let ip: IpAddr = ip.parse().unwrap();
+            for _ in 0..16 {
+                let id = secure_node_id(ip);
+                assert!(is_secure_node_id(&id, ip), "generated id should be valid for {}", ip);
+            }
+            let other: IpAddr = "1.2.3.4".parse().unwrap();
+            assert!(!(0..16).all(|_| is_secure_node_id(&secure_node_id(ip), other)));
+        }
+    }
+
+    #[test]
+    fn test_identity_round_trip() {
+        assert_eq!(encode_identity(&[0u8; 20]), "y".repeat(32));
+        assert_eq!(encode_identity(&[0xff; 20]), "9".repeat(32));
+        // The first 5 bits select the first character, and so on
+        let mut id = [0u8; 20];
+        id[0] = 0b0000_1000;
+        assert_eq!(encode_identity(&id), format!("b{}", "y".repeat(31)));
+
+        let mut ids = vec![[0u8; 20], [0xff; 20], std::array::from_fn(|i| i as u8)];
+        ids.extend((0..16).map(|_| rand::thread_rng().gen::<[u8; 20]>()));
+        for id in ids {
+            let identity = encode_identity(&id);
+            assert_eq!(identity.len(), 32);
+            assert_eq!(parse_identity(&identity).unwrap(), id);
+            assert_eq!(parse_identity(&identity.to_uppercase()).unwrap(), id);
+        }
+    }
+
+    #[test]
+    fn test_malformed_identities_are_rejected() {
+        let valid = encode_identity(&std::array::from_fn(|i| i as u8 * 13));
+        let malformed = [
+            String::new(),
+            valid[..31].to_string(),
+            format!("{}y", valid),
+            // '0', 'l', 'v' and '2' are left out of the alphabet
+            format!("0{}", &valid[1..]),
+            format!("{}l", &valid[..31]),
+            format!("{}v{}", &valid[..10], &valid[11..]),
+            format!("{}2", &valid[..31]),
+            format!("{}é", &valid[..31]),
+        ];
+        for identity in malformed {
+            assert!(
+                matches!(parse_identity(&identity), Err(DhtError::InvalidIdentity(_))),
+                "{:?} should be rejected",
+                identity
+            );
+        }
+    }
+
+    #[test]
+    fn test_private_addresses_are_exempt() {
+        assert!(is_secure_node_id(&[0u8; 20], "192.168.1.10".parse().unwrap()));
+        assert!(is_secure_node_id(&[0u8; 20], "127.0.0.1".parse().unwrap()));
+        assert!(!is_secure_node_id(&[0u8; 20], "8.8.8.8".parse().unwrap()));
+    }
+}
@@ -0,0 +1,78 @@
+//! Our external address as other nodes see it.
+//!
+//! Responders echo the address a query came from in the `ip` field of their
+//! reply. Behind a NAT that is our WAN mapping, learned without a STUN
+//! server. Each responder gets one vote, its latest report, so a single node
+//! cannot outvote the rest by answering often.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+
+/// Responders whose reports are kept; older ones are forgotten.
+const MAX_REPORTS: usize = 32;
+
+/// Recent reports of our address, one per responder.
+#[derive(Default)]
+pub(crate) struct ObservedAddresses {
+    /// `(responder, reported address)`, oldest first.
+    reports: VecDeque<(SocketAddr, SocketAddr)>,
+}
+
+impl ObservedAddresses {
+    /// Record that `responder` saw our query come from `observed`.
+    pub(crate) fn record(&mut self, responder: SocketAddr, observed: SocketAddr) {
+        self.reports.retain(|(from, _)| *from != responder);
+        if self.reports.len() == MAX_REPORTS {
+            self.reports.pop_front();
+        }
+        self.reports.push_back((responder, observed));
+    }
+
+    /// The address reported by most responders; ties go to the most recently
+    /// reported one.
+    pub(crate) fn best(&self) -> Option<SocketAddr> {
+        let mut best: Option<(SocketAddr, usize)> = None;
+        for (_, observed) in self.reports.iter().rev() {
+            let votes = self.reports.iter().filter(|(_, o)| o == observed).count();
+            if best.is_none_or(|(_, most)| votes > most) {
+                best = Some((*observed, votes));
+            }
+        }
+        best.map(|(addr, _)| addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_majority_wins() {
+        let mut observed = ObservedAddresses::default();
+        assert_eq!(observed.best(), None);
+
+        observed.record(addr("10.0.0.1:1"), addr("203.0.113.5:4000"));
+        observed.record(addr("10.0.0.2:1"), addr("203.0.113.5:4000"));
+        observed.record(addr("10.0.0.3:1"), addr("198.51.100.9:4000"));
+        assert_eq!(observed.best(), Some(addr("203.0.113.5:4000")));
+    }
+
+    #[test]
+    fn test_one_vote_per_responder() {
+        let mut observed = ObservedAddresses::default();
+        observed.record(addr("10.0.0.1:1"), addr("203.0.113.5:4000"));
+        observed.record(addr("10.0.0.2:1"), addr("203.0.113.5:4000"));
+        for _ in 0..10 {
+            observed.record(addr("10.0.0.3:1"), addr("198.51.100.9:4000"));
+        }
+        assert_eq!(observed.best(), Some(addr("203.0.113.5:4000")));
+
+        // A changed mapping replaces the responder's earlier report
+        observed.record(addr("10.0.0.1:1"), addr("198.51.100.9:4000"));
+        assert_eq!(observed.best(), Some(addr("198.51.100.9:4000")));
+    }
+}
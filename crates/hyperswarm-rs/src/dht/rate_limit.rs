@@ -0,0 +1,131 @@
+//! Rate limiting of incoming queries.
+//!
+//! A node that answers queries can be abused to amplify traffic towards a
+//! spoofed source, or simply flooded. Each source IP gets a token bucket
+//! refilled at a fixed rate, and all sources share one more bucket that caps
+//! the total; a query is answered only if both have a token left.
+
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+
+use tokio::time::Instant;
+
+/// Source IPs tracked; past this, the least recently seen is forgotten.
+const MAX_TRACKED_SOURCES: usize = 4096;
+
+/// Tokens refilled continuously at `rate` per second, up to one second's worth.
+struct TokenBucket {
+    tokens: f64,
+    rate: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32, now: Instant) -> Self {
+        Self {
+            tokens: rate as f64,
+            rate: rate as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+}
+
+/// Per-source and global token buckets for incoming queries.
+pub(crate) struct QueryRateLimiter {
+    per_source_rate: u32,
+    sources: HashMap<IpAddr, TokenBucket>,
+    /// The IPs in `sources` by when they last sent a query, oldest first.
+    by_use: BTreeSet<(Instant, IpAddr)>,
+    global: TokenBucket,
+}
+
+impl QueryRateLimiter {
+    /// Allow `per_source` queries per second from each IP and `total` per
+    /// second overall.
+    pub(crate) fn new(per_source: u32, total: u32) -> Self {
+        Self {
+            per_source_rate: per_source,
+            sources: HashMap::new(),
+            by_use: BTreeSet::new(),
+            global: TokenBucket::new(total, Instant::now()),
+        }
+    }
+
+    /// Whether a query from `ip` may be answered, using up a token if so.
+    pub(crate) fn allow(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let source = match self.sources.get_mut(&ip) {
+            Some(source) => {
+                self.by_use.remove(&(source.updated, ip));
+                source
+            }
+            None => {
+                if self.sources.len() >= MAX_TRACKED_SOURCES {
+                    if let Some((_, idle)) = self.by_use.pop_first() {
+                        self.sources.remove(&idle);
+                    }
+                }
+                self.sources.entry(ip).or_insert(TokenBucket::new(self.per_source_rate, now))
+            }
+        };
+        source.refill(now);
+        self.global.refill(now);
+        self.by_use.insert((now, ip));
+        if source.tokens < 1.0 || self.global.tokens < 1.0 {
+            return false;
+        }
+        source.tokens -= 1.0;
+        self.global.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_per_source_limit_refills() {
+        let mut limiter = QueryRateLimiter::new(5, 100);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!((0..10).filter(|_| limiter.allow(ip)).count(), 5);
+
+        tokio::time::advance(Duration::from_millis(400)).await;
+        assert_eq!((0..10).filter(|_| limiter.allow(ip)).count(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_global_limit_spans_sources() {
+        let mut limiter = QueryRateLimiter::new(5, 8);
+        let allowed = (1..=4u8)
+            .flat_map(|i| std::iter::repeat_n(IpAddr::from([10, 0, 0, i]), 5))
+            .filter(|ip| limiter.allow(*ip))
+            .count();
+        assert_eq!(allowed, 8);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_least_recently_seen_source_is_forgotten() {
+        let mut limiter = QueryRateLimiter::new(1, u32::MAX);
+        let source = |i: usize| IpAddr::from((i as u32 + 1).to_be_bytes());
+        for i in 0..MAX_TRACKED_SOURCES {
+            assert!(limiter.allow(source(i)));
+            tokio::time::advance(Duration::from_micros(1)).await;
+        }
+        // The first source is seen again, so the second is the one forgotten
+        assert!(!limiter.allow(source(0)));
+        assert!(limiter.allow(source(MAX_TRACKED_SOURCES)));
+
+        assert_eq!(limiter.sources.len(), MAX_TRACKED_SOURCES);
+        assert_eq!(limiter.by_use.len(), MAX_TRACKED_SOURCES);
+        assert!(limiter.sources.contains_key(&source(0)));
+        assert!(!limiter.sources.contains_key(&source(1)));
+    }
+}
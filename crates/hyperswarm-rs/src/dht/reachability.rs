@@ -0,0 +1,85 @@
+//! Whether nodes reach us without being asked first.
+//!
+//! A node that learns our address from another node's routing table queries
+//! us out of the blue. Such a query only gets through a NAT that lets in
+//! addresses we never sent to, so seeing one tells us we are directly
+//! reachable, with no extra sockets or non-standard queries.
+
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+
+/// Addresses remembered as contacted; older ones are forgotten.
+const MAX_CONTACTED: usize = 1024;
+
+/// The addresses we recently sent to, and the first that queried us unasked.
+#[derive(Default)]
+pub(crate) struct Contacts {
+    contacted: HashSet<SocketAddr>,
+    /// `contacted` in the order first sent to, oldest first.
+    order: VecDeque<SocketAddr>,
+    unsolicited: Option<SocketAddr>,
+}
+
+impl Contacts {
+    /// Record that we sent to `addr`.
+    pub(crate) fn sent_to(&mut self, addr: SocketAddr) {
+        let addr = canonical(addr);
+        if !self.contacted.insert(addr) {
+            return;
+        }
+        if self.order.len() == MAX_CONTACTED {
+            if let Some(oldest) = self.order.pop_front() {
+                self.contacted.remove(&oldest);
+            }
+        }
+        self.order.push_back(addr);
+    }
+
+    /// Record a query from `addr`, unsolicited unless we sent to it.
+    pub(crate) fn queried_by(&mut self, addr: SocketAddr) {
+        let addr = canonical(addr);
+        if self.unsolicited.is_none() && !self.contacted.contains(&addr) {
+            self.unsolicited = Some(addr);
+        }
+    }
+
+    /// The first address that queried us without us sending to it, if any.
+    pub(crate) fn unsolicited(&self) -> Option<SocketAddr> {
+        self.unsolicited
+    }
+}
+
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_only_uncontacted_queriers_count() {
+        let mut contacts = Contacts::default();
+        contacts.sent_to(addr("10.0.0.1:6881"));
+        contacts.queried_by(addr("[::ffff:10.0.0.1]:6881"));
+        assert_eq!(contacts.unsolicited(), None);
+
+        contacts.queried_by(addr("10.0.0.2:6881"));
+        assert_eq!(contacts.unsolicited(), Some(addr("10.0.0.2:6881")));
+    }
+
+    #[test]
+    fn test_oldest_contacts_are_forgotten() {
+        let mut contacts = Contacts::default();
+        for port in 0..=MAX_CONTACTED as u16 {
+            contacts.sent_to(SocketAddr::new([10, 0, 0, 1].into(), port));
+        }
+        assert_eq!(contacts.contacted.len(), MAX_CONTACTED);
+        contacts.queried_by(addr("10.0.0.1:0"));
+        assert_eq!(contacts.unsolicited(), Some(addr("10.0.0.1:0")));
+    }
+}
@@ -0,0 +1,28 @@
+//! Resolving bootstrap node names.
+//!
+//! A bootstrap entry such as `router.bittorrent.com:6881` may resolve to
+//! several addresses, each of them a node worth pinging. [`SystemResolver`]
+//! asks the operating system; tests and applications with their own name
+//! service plug in another [`Resolver`] with
+//! [`DhtClient::with_resolver`](super::DhtClient::with_resolver).
+
+use std::net::SocketAddr;
+
+use futures::future::BoxFuture;
+
+/// Turns a `host:port` bootstrap entry into socket addresses.
+pub trait Resolver: Send + Sync + 'static {
+    /// Every address `host_port` resolves to, in the order the name service
+    /// gave them.
+    fn resolve<'a>(&'a self, host_port: &'a str) -> BoxFuture<'a, std::io::Result<Vec<SocketAddr>>>;
+}
+
+/// Resolves names with the operating system's resolver.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host_port: &'a str) -> BoxFuture<'a, std::io::Result<Vec<SocketAddr>>> {
+        Box::pin(async move { Ok(tokio::net::lookup_host(host_port).await?.collect()) })
+    }
+}
@@ -0,0 +1,329 @@
+//! BEP 44: storing arbitrary data in the DHT.
+//!
+//! Immutable items are stored under the SHA-1 of their bencoded value, so
+//! anyone can check a value against its target. Mutable items are stored
+//! under the SHA-1 of an ed25519 public key and optional salt, and carry a
+//! signature over their sequence number and value; a node only replaces an
+//! item with one that has a higher sequence number.
+//!
+//! Values are always bencoded byte strings here.
+
+use std::collections::{BTreeSet, HashMap};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha1::{Digest, Sha1};
+use tokio::time::Instant;
+
+/// Largest bencoded value a node stores.
+pub const MAX_VALUE_SIZE: usize = 1000;
+/// Largest salt a node accepts.
+pub const MAX_SALT_SIZE: usize = 64;
+
+/// Most items a node stores; storing another evicts the one put longest ago.
+pub const MAX_ITEMS: usize = 1024;
+
+/// How long an item is kept without being put again.
+const ITEM_TTL: std::time::Duration = std::time::Duration::from_secs(2 * 60 * 60);
+
+// BEP 44 error codes
+pub(crate) const KRPC_ERROR_VALUE_TOO_BIG: i64 = 205;
+pub(crate) const KRPC_ERROR_INVALID_SIGNATURE: i64 = 206;
+pub(crate) const KRPC_ERROR_SALT_TOO_BIG: i64 = 207;
+pub(crate) const KRPC_ERROR_CAS_MISMATCH: i64 = 301;
+pub(crate) const KRPC_ERROR_SEQ_TOO_LOW: i64 = 302;
+
+/// A signed, versioned value stored under a public key (and salt).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MutableItem {
+    pub value: Vec<u8>,
+    /// ed25519 public key the item is signed with.
+    pub public_key: [u8; 32],
+    /// Salt distinguishing several items under one key; may be empty.
+    pub salt: Vec<u8>,
+    pub seq: i64,
+    pub signature: [u8; 64],
+}
+
+impl MutableItem {
+    /// Sign `value` as version `seq` of the item under `key` and `salt`.
+    pub fn sign(key: &SigningKey, salt: &[u8], seq: i64, value: &[u8]) -> Self {
+        let signature = key.sign(&signable(salt, seq, value));
+        Self {
+            value: value.to_vec(),
+            public_key: key.verifying_key().to_bytes(),
+            salt: salt.to_vec(),
+            seq,
+            signature: signature.to_bytes(),
+        }
+    }
+
+    /// Whether the signature covers this item's salt, sequence number and value.
+    pub fn verify(&self) -> bool {
+        let Ok(key) = VerifyingKey::from_bytes(&self.public_key) else {
+            return false;
+        };
+        let signature = Signature::from_bytes(&self.signature);
+        key.verify(&signable(&self.salt, self.seq, &self.value), &signature).is_ok()
+    }
+
+    /// The DHT target the item is stored under.
+    pub fn target(&self) -> [u8; 20] {
+        mutable_target(&self.public_key, &self.salt)
+    }
+}
+
+/// Target of an immutable item: the SHA-1 of its bencoded value.
+pub fn immutable_target(value: &[u8]) -> [u8; 20] {
+    Sha1::digest(encoded_value(value)).into()
+}
+
+/// Target of a mutable item: the SHA-1 of its public key and salt.
+pub fn mutable_target(public_key: &[u8; 32], salt: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.update(public_key);
+    hasher.update(salt);
+    hasher.finalize().into()
+}
+
+/// Size of `value` once bencoded, which is what the size limit applies to.
+pub(crate) fn encoded_len(value: &[u8]) -> usize {
+    value.len().to_string().len() + 1 + value.len()
+}
+
+/// `value` bencoded as a byte string.
+fn encoded_value(value: &[u8]) -> Vec<u8> {
+    let mut encoded = format!("{}:", value.len()).into_bytes();
+    encoded.extend_from_slice(value);
+    encoded
+}
+
+/// The bytes a mutable item's signature covers: the bencoded `salt`, `seq`
+/// and `v` entries, without the enclosing dictionary.
+fn signable(salt: &[u8], seq: i64, value: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    if !salt.is_empty() {
+        buf.extend_from_slice(b"4:salt");
+        buf.extend_from_slice(&encoded_value(salt));
+    }
+    buf.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+    buf.extend_from_slice(&encoded_value(value));
+    buf
+}
+
+/// Items this node stores for others.
+#[derive(Default)]
+pub(crate) struct ItemStore {
+    items: HashMap<[u8; 20], (Item, Instant)>,
+    /// The targets in `items` by when they were last put, oldest first.
+    by_age: BTreeSet<(Instant, [u8; 20])>,
+}
+
+enum Item {
+    Immutable(Vec<u8>),
+    Mutable(MutableItem),
+}
+
+/// A rejected put: the KRPC error code and message to answer with.
+pub(crate) type PutRejection = (i64, &'static str);
+
+impl ItemStore {
+    pub(crate) fn get_immutable(&mut self, target: &[u8; 20]) -> Option<Vec<u8>> {
+        self.expire();
+        match self.items.get(target) {
+            Some((Item::Immutable(value), _)) => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn get_mutable(&mut self, target: &[u8; 20]) -> Option<MutableItem> {
+        self.expire();
+        match self.items.get(target) {
+            Some((Item::Mutable(item), _)) => Some(item.clone()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn put_immutable(&mut self, value: Vec<u8>) -> Result<(), PutRejection> {
+        if encoded_len(&value) > MAX_VALUE_SIZE {
+            return Err((KRPC_ERROR_VALUE_TOO_BIG, "Value too big"));
+        }
+        self.expire();
+        self.insert(immutable_target(&value), Item::Immutable(value));
+        Ok(())
+    }
+
+    /// Store `item` if it is valid and newer than what we have. With `cas`,
+    /// only replace an item whose sequence number is exactly `cas`.
+    pub(crate) fn put_mutable(&mut self, item: MutableItem, cas: Option<i64>) -> Result<(), PutRejection> {
+        if encoded_len(&item.value) > MAX_VALUE_SIZE {
+            return Err((KRPC_ERROR_VALUE_TOO_BIG, "Value too big"));
+        }
+        if item.salt.len() > MAX_SALT_SIZE {
+            return Err((KRPC_ERROR_SALT_TOO_BIG, "Salt too big"));
+        }
+        if !item.verify() {
+            return Err((KRPC_ERROR_INVALID_SIGNATURE, "Invalid signature"));
+        }
+        self.expire();
+        let target = item.target();
+        if let Some((Item::Mutable(stored), _)) = self.items.get(&target) {
+            if cas.is_some_and(|cas| cas != stored.seq) {
+                return Err((KRPC_ERROR_CAS_MISMATCH, "CAS mismatch"));
+            }
+            // An equal sequence number only refreshes the same value
+            if item.seq < stored.seq || (item.seq == stored.seq && item.value != stored.value) {
+                return Err((KRPC_ERROR_SEQ_TOO_LOW, "Sequence number less than current"));
+            }
+        }
+        self.insert(target, Item::Mutable(item));
+        Ok(())
+    }
+
+    /// Store `item` under `target` as just put, evicting the item put
+    /// longest ago if the store is full.
+    fn insert(&mut self, target: [u8; 20], item: Item) {
+        let now = Instant::now();
+        if let Some((_, stored)) = self.items.insert(target, (item, now)) {
+            self.by_age.remove(&(stored, target));
+        } else if self.items.len() > MAX_ITEMS {
+            if let Some((_, oldest)) = self.by_age.pop_first() {
+                self.items.remove(&oldest);
+            }
+        }
+        self.by_age.insert((now, target));
+    }
+
+    /// Drop items that have not been put again within [`ITEM_TTL`].
+    fn expire(&mut self) {
+        while let Some(&(stored, target)) = self.by_age.first() {
+            if stored.elapsed() < ITEM_TTL {
+                break;
+            }
+            self.by_age.pop_first();
+            self.items.remove(&target);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_immutable_target_is_sha1_of_bencoded_value() {
+        // BEP 44 test vector: "12:Hello World!"
+        let target = immutable_target(b"Hello World!");
+        assert_eq!(
+            target,
+            [
+                0xe5, 0xf9, 0x6f, 0x6f, 0x38, 0x32, 0x0f, 0x0f, 0x33, 0x95, 0x9c, 0xb4, 0xd3, 0xd6, 0x56, 0x45, 0x21,
+                0x17, 0xaa, 0xdb
+            ]
+        );
+    }
+
+    /// Decode a hex string, for the published test vectors.
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_bep44_mutable_vectors() {
+        // BEP 44 test vectors 1 (no salt) and 2 (salt "foobar")
+        let public_key = hex("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548");
+        let vectors = [
+            (
+                &b""[..],
+                "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff\
+                 1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01",
+                "4a533d47ec9c7d95b1ad75f576cffc641853b750",
+            ),
+            (
+                &b"foobar"[..],
+                "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17d\
+                 df9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08",
+                "411eba73b6f087ca51a3795d9c8c938d365e32c1",
+            ),
+        ];
+        for (salt, signature, target) in vectors {
+            let mut item = MutableItem {
+                value: b"Hello World!".to_vec(),
+                public_key: public_key.clone().try_into().unwrap(),
+                salt: salt.to_vec(),
+                seq: 1,
+                signature: hex(signature).try_into().unwrap(),
+            };
+            assert!(item.verify(), "BEP 44 vector with salt {:?} should verify", salt);
+            assert_eq!(item.target().to_vec(), hex(target));
+            item.seq = 2;
+            assert!(!item.verify());
+        }
+    }
+
+    #[test]
+    fn test_signable_layout() {
+        assert_eq!(signable(b"", 1, b"Hello World!"), b"3:seqi1e1:v12:Hello World!");
+        assert_eq!(
+            signable(b"foobar", 1, b"Hello World!"),
+            b"4:salt6:foobar3:seqi1e1:v12:Hello World!"
+        );
+    }
+
+    #[test]
+    fn test_mutable_put_enforces_signature_and_sequence() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let mut store = ItemStore::default();
+
+        store.put_mutable(MutableItem::sign(&key, b"", 2, b"two"), None).unwrap();
+        let older = MutableItem::sign(&key, b"", 1, b"one");
+        assert_eq!(store.put_mutable(older, None).unwrap_err().0, KRPC_ERROR_SEQ_TOO_LOW);
+
+        let mut forged = MutableItem::sign(&key, b"", 3, b"three");
+        forged.value = b"evil".to_vec();
+        assert_eq!(store.put_mutable(forged, None).unwrap_err().0, KRPC_ERROR_INVALID_SIGNATURE);
+
+        let newer = MutableItem::sign(&key, b"", 3, b"three");
+        assert_eq!(store.put_mutable(newer.clone(), Some(1)).unwrap_err().0, KRPC_ERROR_CAS_MISMATCH);
+        store.put_mutable(newer.clone(), Some(2)).unwrap();
+        assert_eq!(store.get_mutable(&newer.target()), Some(newer));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_items_expire_without_a_new_put() {
+        let mut store = ItemStore::default();
+        store.put_immutable(b"old".to_vec()).unwrap();
+        tokio::time::advance(ITEM_TTL / 2).await;
+        store.put_immutable(b"new".to_vec()).unwrap();
+        tokio::time::advance(ITEM_TTL / 2).await;
+
+        assert_eq!(store.get_immutable(&immutable_target(b"old")), None);
+        assert_eq!(store.get_immutable(&immutable_target(b"new")), Some(b"new".to_vec()));
+        assert_eq!(store.by_age.len(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_full_store_evicts_the_item_put_longest_ago() {
+        let mut store = ItemStore::default();
+        for i in 0..MAX_ITEMS as u32 {
+            store.put_immutable(i.to_be_bytes().to_vec()).unwrap();
+            tokio::time::advance(std::time::Duration::from_millis(1)).await;
+        }
+        // Putting the first item again makes the second the oldest
+        store.put_immutable(0u32.to_be_bytes().to_vec()).unwrap();
+        store.put_immutable(b"one more".to_vec()).unwrap();
+
+        assert_eq!(store.items.len(), MAX_ITEMS);
+        assert_eq!(store.by_age.len(), MAX_ITEMS);
+        assert!(store.get_immutable(&immutable_target(&0u32.to_be_bytes())).is_some());
+        assert_eq!(store.get_immutable(&immutable_target(&1u32.to_be_bytes())), None);
+        assert!(store.get_immutable(&immutable_target(b"one more")).is_some());
+    }
+
+    #[test]
+    fn test_oversized_values_are_rejected() {
+        let mut store = ItemStore::default();
+        // 996 bytes bencode to "996:..." = exactly 1000 bytes
+        store.put_immutable(vec![0u8; 996]).unwrap();
+        assert_eq!(store.put_immutable(vec![0u8; 997]).unwrap_err().0, KRPC_ERROR_VALUE_TOO_BIG);
+    }
+}
@@ -2,60 +2,642 @@
 //!
 //! Coordinates the announce/lookup lifecycle across multiple topics and
 //! triggers connection establishment (holepunch + encrypted transport).
+//! Servers publish their holepunch [`candidates`] through the DHT for
+//! clients to fetch before connecting, and each discovered peer's connection [`peers`] lifecycle is
+//! tracked so it is reconnected, with backoff, when a connect fails or the
+//! connection drops.
 
-use std::collections::HashSet;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
 
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
 
+use crate::connection::{ConnectionError, ConnectionManager, InFlight, InFlightGuard};
+use crate::holepunch::Candidate;
+use crate::metrics::{MetricsHandle, SwarmMetrics};
+use crate::transport::EncryptedStream;
 use crate::{dht, Topic};
 
+pub mod candidates;
+pub mod peers;
+
+pub use peers::{BackoffConfig, PeerState};
+
+use peers::PeerSessions;
+
+/// Shared across topics so a peer has one session however it was found.
+type Sessions = Arc<std::sync::Mutex<PeerSessions>>;
+/// Candidates offered on top of those found automatically, by every topic.
+type AddedCandidates = Arc<std::sync::Mutex<Vec<Candidate>>>;
+
+/// Default for [`DiscoveryConfig::refresh_interval`].
+pub const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Default for [`DiscoveryConfig::refresh_jitter`]: ±20%.
+pub const DEFAULT_REFRESH_JITTER: f64 = 0.2;
+/// How often a client checks its connected peers for dropped connections.
+const RECONNECT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Refresh intervals a peer can go without being looked up before its
+/// session is forgotten.
+const STALE_AFTER_REFRESHES: u32 = 2;
+
 #[derive(Clone, Debug)]
 pub struct DiscoveryConfig {
+    /// Most peers from one lookup a topic tries to connect to. The
+    /// [`ConnectionManager`] caps the connections themselves.
     pub max_peers: usize,
+    /// How often a joined topic is re-announced and looked up again.
+    pub refresh_interval: Duration,
+    /// Fraction of `refresh_interval` by which each re-announce is moved
+    /// earlier or later at random, so nodes that joined together do not
+    /// keep announcing in waves. Zero announces on the interval exactly;
+    /// values above 1 count as 1.
+    pub refresh_jitter: f64,
+    /// Reconnect backoff for discovered peers.
+    pub backoff: BackoffConfig,
+}
+
+/// How a topic is joined, like the `server` / `client` options of JS
+/// Hyperswarm's `join`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JoinOpts {
+    /// Announce ourselves so peers can find and connect to us.
+    pub server: bool,
+    /// Look up peers and connect to them.
+    pub client: bool,
+}
+
+impl Default for JoinOpts {
+    fn default() -> Self {
+        Self {
+            server: true,
+            client: true,
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum DiscoveryError {
     #[error("dht: {0}")]
     Dht(#[from] dht::DhtError),
+    #[error("connection: {0}")]
+    Connection(#[from] ConnectionError),
+    #[error("join needs server or client mode")]
+    NoJoinMode,
     #[error("not implemented")]
     Unimplemented,
 }
 
 pub struct DiscoveryManager {
     config: DiscoveryConfig,
-    topics: RwLock<HashSet<Topic>>,
+    topics: RwLock<HashMap<Topic, JoinedTopic>>,
+    sessions: Sessions,
+    added_candidates: AddedCandidates,
+    metrics: MetricsHandle,
+    /// Refresh rounds in progress: announces, lookups and the connects they
+    /// lead to.
+    refreshing: InFlight,
+}
+
+/// A joined topic: its refresh task and where it is announced.
+struct JoinedTopic {
+    task: JoinHandle<()>,
+    port: u16,
+    /// Nodes that accepted the latest announcement, for unannouncing on leave.
+    announced_to: Arc<Mutex<Vec<SocketAddr>>>,
 }
 
 impl DiscoveryManager {
     pub fn new(config: DiscoveryConfig) -> Self {
         Self {
             config,
-            topics: RwLock::new(HashSet::new()),
+            topics: RwLock::new(HashMap::new()),
+            sessions: Sessions::default(),
+            added_candidates: AddedCandidates::default(),
+            metrics: MetricsHandle::default(),
+            refreshing: InFlight::default(),
         }
     }
 
-    pub async fn join(&self, dht: &dht::DhtClient, topic: Topic) -> Result<(), DiscoveryError> {
-        self.topics.write().await.insert(topic);
-        
+    /// Offer `candidate` to peers next to the addresses found automatically,
+    /// for example the external address of a
+    /// [`PortMapping`](crate::holepunch::PortMapping). Joined topics publish
+    /// it from their next refresh on.
+    pub fn add_candidate(&self, candidate: Candidate) {
+        let mut added = self.added_candidates.lock().expect("added candidates lock poisoned");
+        if !added.iter().any(|c| c.addr == candidate.addr) {
+            added.push(candidate);
+        }
+    }
+
+    /// Stop offering the candidate added at `addr`.
+    pub fn remove_candidate(&self, addr: SocketAddr) {
+        self.added_candidates
+            .lock()
+            .expect("added candidates lock poisoned")
+            .retain(|c| c.addr != addr);
+    }
+
+    /// Wait until no topic is in the middle of a refresh: joined topics have
+    /// finished their first lookup, and connected to and delivered the
+    /// peers it found, or failed to. Rounds that start meanwhile are waited
+    /// for too; a connect waiting for room under `max_peers` keeps this
+    /// waiting until it gets some.
+    pub async fn flush(&self) {
+        self.refreshing.idle().await;
+    }
+
+    /// Where the peer discovered at `addr` is in its connection lifecycle,
+    /// if it is tracked.
+    pub fn peer_state(&self, addr: &SocketAddr) -> Option<PeerState> {
+        self.sessions
+            .lock()
+            .expect("peer sessions lock poisoned")
+            .get(addr)
+            .map(|session| session.state())
+    }
+
+    /// Report the holepunch and handshake outcome of every connection
+    /// attempt to a discovered peer to `metrics`, including attempts by
+    /// topics joined earlier.
+    pub fn set_metrics(&self, metrics: Arc<dyn SwarmMetrics>) {
+        self.metrics.set(metrics);
+    }
+
+    /// Join `topic`. As a server, announce the swarm socket on the DHT with
+    /// the key our holepunch candidates are published under; as a client, keep looking
+    /// up peers and connecting to new ones in the background, reconnecting
+    /// with backoff. Either role is refreshed every `refresh_interval`.
+    pub async fn join(
+        &self,
+        dht: &Arc<dht::DhtClient>,
+        connections: &Arc<ConnectionManager>,
+        topic: Topic,
+        opts: JoinOpts,
+    ) -> Result<(), DiscoveryError> {
+        if !opts.server && !opts.client {
+            return Err(DiscoveryError::NoJoinMode);
+        }
+        // Peers connect to the swarm socket, so that is the port we announce
+        let local = connections.local_addr()?;
+
         // Announce our presence on the DHT for this topic
-        // Use port 0 to indicate we're interested but not listening on a specific port
-        dht.announce(topic, 0).await?;
-        
-        // Perform initial lookup to find peers
-        let peers = dht.lookup(topic).await?;
-        
-        tracing::debug!("Joined topic with {} peers found", peers.len());
-        
-        // TODO: periodically re-announce and lookup; connect to peers.
-        let _ = self.config.max_peers;
-        
+        let announced = if opts.server {
+            dht.announce_with_value(topic, local.port(), &candidates_value(connections)).await?
+        } else {
+            Vec::new()
+        };
+        self.start(dht, connections, topic, opts, local, announced).await;
         Ok(())
     }
 
-    pub async fn leave(&self, _dht: &dht::DhtClient, topic: Topic) -> Result<(), DiscoveryError> {
-        self.topics.write().await.remove(&topic);
-        // TODO: stop tasks for this topic.
+    /// Start the background refresh of `topic`, joined with `opts` and, as a
+    /// server, already announced to the nodes at `announced`.
+    async fn start(
+        &self,
+        dht: &Arc<dht::DhtClient>,
+        connections: &Arc<ConnectionManager>,
+        topic: Topic,
+        opts: JoinOpts,
+        local: SocketAddr,
+        announced: Vec<SocketAddr>,
+    ) {
+        let port = local.port();
+        let announced_to = Arc::new(Mutex::new(announced));
+        // Counted from now, so a flush right after joining waits for the
+        // first lookup even before the task gets to run
+        let first_round = self.refreshing.enter();
+
+        let refresh = Arc::new(TopicRefresh {
+            dht: dht.clone(),
+            connections: connections.clone(),
+            topic,
+            local,
+            opts,
+            announced_to: announced_to.clone(),
+            config: self.config.clone(),
+            sessions: self.sessions.clone(),
+            added_candidates: self.added_candidates.clone(),
+            metrics: self.metrics.clone(),
+            refreshing: self.refreshing.clone(),
+        });
+        let task = tokio::spawn(refresh.clone().run(first_round));
+        // The first lookup need not wait for this
+        if opts.server {
+            refresh.publish_candidates().await;
+        }
+        let joined = JoinedTopic {
+            task,
+            port,
+            announced_to,
+        };
+        if let Some(previous) = self.topics.write().await.insert(topic, joined) {
+            previous.task.abort();
+        }
+    }
+
+    /// Join every topic in `topics` with the same `opts`.
+    ///
+    /// The DHT is bootstrapped once up front instead of by each topic's
+    /// first query. As a server, the topics are announced together with
+    /// [`DhtClient::announce_all_with_value`](dht::DhtClient::announce_all_with_value),
+    /// which picks the nodes once and asks each for one token for all topics.
+    /// Lookups stay per topic, as each starts from the nodes closest to its
+    /// own topic, and run concurrently. Returns each topic's outcome, in
+    /// order; a topic that fails does not stop the others.
+    pub async fn join_all(
+        &self,
+        dht: &Arc<dht::DhtClient>,
+        connections: &Arc<ConnectionManager>,
+        topics: &[Topic],
+        opts: JoinOpts,
+    ) -> Vec<(Topic, Result<(), DiscoveryError>)> {
+        if let Err(e) = dht.bootstrap_if_empty().await {
+            // Each join retries and reports its own failure
+            tracing::debug!("Bootstrap before joining {} topics failed: {}", topics.len(), e);
+        }
+        if opts.server {
+            if let Ok(local) = connections.local_addr() {
+                match dht.announce_all_with_value(topics, local.port(), &candidates_value(connections)).await {
+                    Ok(announced) => {
+                        let starts = topics.iter().zip(announced).map(|(&topic, announced)| async move {
+                            let result = match announced {
+                                Ok(nodes) => {
+                                    self.start(dht, connections, topic, opts, local, nodes).await;
+                                    Ok(())
+                                }
+                                Err(e) => Err(e.into()),
+                            };
+                            (topic, result)
+                        });
+                        return futures::future::join_all(starts).await;
+                    }
+                    Err(e) => tracing::debug!("Announcing {} topics failed: {}", topics.len(), e),
+                }
+            }
+        }
+        // Nothing to announce, or no way to: each join reports its own failure
+        let joins = topics
+            .iter()
+            .map(|&topic| async move { (topic, self.join(dht, connections, topic, opts).await) });
+        futures::future::join_all(joins).await
+    }
+
+    /// Leave every topic in `topics` concurrently, returning each topic's
+    /// outcome in order.
+    pub async fn leave_all(&self, dht: &dht::DhtClient, topics: &[Topic]) -> Vec<(Topic, Result<(), DiscoveryError>)> {
+        let leaves = topics
+            .iter()
+            .map(|&topic| async move { (topic, self.leave(dht, topic).await) });
+        futures::future::join_all(leaves).await
+    }
+
+    /// Leave `topic`: stop its refresh task, forget its peers and withdraw
+    /// our announcement from the nodes that hold it.
+    pub async fn leave(&self, dht: &dht::DhtClient, topic: Topic) -> Result<(), DiscoveryError> {
+        let Some(joined) = self.topics.write().await.remove(&topic) else {
+            return Ok(());
+        };
+        joined.task.abort();
+        // Wait for the task to stop so it cannot re-announce behind our back
+        let _ = joined.task.await;
+        self.sessions.lock().expect("peer sessions lock poisoned").remove_topic(topic);
+
+        let nodes = joined.announced_to.lock().await.clone();
+        if !nodes.is_empty() {
+            dht.unannounce(topic, joined.port, &nodes).await?;
+        }
         Ok(())
     }
 }
+
+/// The background refresh of one joined topic.
+struct TopicRefresh {
+    dht: Arc<dht::DhtClient>,
+    connections: Arc<ConnectionManager>,
+    topic: Topic,
+    /// The swarm socket's local address; its port is the one we announce.
+    local: SocketAddr,
+    opts: JoinOpts,
+    announced_to: Arc<Mutex<Vec<SocketAddr>>>,
+    config: DiscoveryConfig,
+    sessions: Sessions,
+    added_candidates: AddedCandidates,
+    metrics: MetricsHandle,
+    refreshing: InFlight,
+}
+
+impl TopicRefresh {
+    /// Refresh until aborted. `first_round` is released once the first
+    /// lookup has connected to what it found.
+    async fn run(self: Arc<Self>, first_round: InFlightGuard) {
+        futures::future::join(self.lookups(first_round), self.reannounce()).await;
+    }
+
+    /// As a client, look up peers every `refresh_interval` and connect to
+    /// them, reconnecting those whose connect failed or whose connection
+    /// dropped once their backoff has passed. Runs apart from
+    /// [`reannounce`](Self::reannounce) since connecting may wait for room
+    /// under `max_peers`.
+    async fn lookups(&self, first_round: InFlightGuard) {
+        if !self.opts.client {
+            return;
+        }
+        let mut first_round = Some(first_round);
+        let mut next_lookup = Instant::now();
+        loop {
+            let round = first_round.take().unwrap_or_else(|| self.refreshing.enter());
+            if Instant::now() >= next_lookup {
+                self.discover_peers().await;
+                next_lookup = Instant::now() + self.config.refresh_interval;
+            }
+            self.connect_to_peers().await;
+            drop(round);
+
+            let next_due = self.sessions.lock().expect("peer sessions lock poisoned").next_due(self.topic);
+            let next_check = Instant::now() + RECONNECT_CHECK_INTERVAL;
+            let wake = next_due.into_iter().chain([next_lookup, next_check]).min().unwrap_or(next_check);
+            tokio::time::sleep_until(wake).await;
+        }
+    }
+
+    /// As a server, re-announce and republish our candidates every
+    /// `refresh_interval`, give or take `refresh_jitter`.
+    async fn reannounce(&self) {
+        if !self.opts.server {
+            return;
+        }
+        loop {
+            let delay = jittered(self.config.refresh_interval, self.config.refresh_jitter, &mut rand::thread_rng());
+            tokio::time::sleep(delay).await;
+            let _round = self.refreshing.enter();
+            let value = candidates_value(&self.connections);
+            match self.dht.announce_with_value(self.topic, self.local.port(), &value).await {
+                Ok(nodes) => *self.announced_to.lock().await = nodes,
+                Err(e) => tracing::debug!("Re-announce failed: {}", e),
+            }
+            self.publish_candidates().await;
+        }
+    }
+
+    /// Publish our candidates under the key we announce with, once the DHT
+    /// has told us our WAN address.
+    async fn publish_candidates(&self) {
+        let added = self.added_candidates.lock().expect("added candidates lock poisoned").clone();
+        let Some((_, ours)) = candidates::own_candidates(&self.dht, self.local, &added) else {
+            tracing::debug!("WAN address not known yet, not publishing candidates");
+            return;
+        };
+        if let Err(e) = candidates::publish(&self.dht, &self.connections.candidates_key(), &ours).await {
+            tracing::debug!("Publishing candidates failed: {}", e);
+        }
+    }
+
+    /// Fetch the candidates `peer` published under the key it announced
+    /// with; none if it announced no key.
+    async fn fetch_candidates(&self, peer: &dht::PeerAddress) -> Vec<Candidate> {
+        let Some(public_key) = peer.value.as_deref().and_then(|value| <[u8; 32]>::try_from(value).ok()) else {
+            return Vec::new();
+        };
+        candidates::fetch(&self.dht, &public_key).await.unwrap_or_else(|e| {
+            tracing::debug!("Fetching candidates of {} failed: {}", peer.addr, e);
+            Vec::new()
+        })
+    }
+
+    /// Look up peers for the topic, start tracking those we did not know and
+    /// forget those that have not been found for a while.
+    async fn discover_peers(&self) {
+        match self.dht.lookup(self.topic).await {
+            Ok(peers) => {
+                tracing::debug!("Found {} peers for topic", peers.len());
+                let now = Instant::now();
+                let mut sessions = self.sessions.lock().expect("peer sessions lock poisoned");
+                for peer in peers.into_iter().take(self.config.max_peers) {
+                    if !is_own_address(&peer.addr, self.local.port()) {
+                        sessions.discovered(peer, self.topic, now);
+                    }
+                }
+                let stale_after = self.config.refresh_interval.saturating_mul(STALE_AFTER_REFRESHES);
+                if let Some(cutoff) = now.checked_sub(stale_after) {
+                    sessions.prune(self.topic, cutoff);
+                }
+            }
+            Err(e) => tracing::debug!("Lookup failed: {}", e),
+        }
+    }
+
+    /// Connect to the topic's peers that are due, one at a time, after
+    /// backing off from those whose connection dropped.
+    async fn connect_to_peers(&self) {
+        self.sessions.lock().expect("peer sessions lock poisoned").check_connected(
+            self.topic,
+            |addr| self.connections.is_connected(addr),
+            Instant::now(),
+            &self.config.backoff,
+        );
+        peers::connect_due(&self.sessions, self.topic, &self.config.backoff, |peer| self.connect_peer(peer)).await;
+    }
+
+    /// Connect to `peer` and deliver the connection, returning whether we
+    /// are connected to it. While `max_peers` connections are open, wait for
+    /// one to close first.
+    async fn connect_peer(&self, peer: dht::PeerAddress) -> bool {
+        let connections = &self.connections;
+        // Already connected, e.g. because it connected to us
+        if connections.is_connected(&peer.addr) {
+            connections.add_topic(peer.addr, self.topic);
+            return true;
+        }
+        let Ok(permit) = connections.permit().await else {
+            return false;
+        };
+        // We may have connected to it while waiting
+        if connections.is_connected(&peer.addr) {
+            connections.add_topic(peer.addr, self.topic);
+            return true;
+        }
+        let candidates = self.fetch_candidates(&peer).await;
+        let result = connections.connect_with_permit(&peer, candidates, permit, Some(self.topic)).await;
+        report_connect(&*self.metrics.get(), peer.addr, &result);
+        match result {
+            Ok(stream) => {
+                connections.deliver_discovered(stream, self.topic).await;
+                true
+            }
+            // Connected over another path, or it connected to us meanwhile
+            Err(ConnectionError::Duplicate(addr) | ConnectionError::AlreadyConnected(addr)) => {
+                connections.add_topic(addr, self.topic);
+                true
+            }
+            Err(e) => {
+                tracing::debug!("Failed to connect to {}: {}", peer.addr, e);
+                false
+            }
+        }
+    }
+}
+
+impl Drop for DiscoveryManager {
+    fn drop(&mut self) {
+        for joined in self.topics.get_mut().values() {
+            joined.task.abort();
+        }
+    }
+}
+
+/// Report how far a connection attempt to `addr` got.
+fn report_connect(metrics: &dyn SwarmMetrics, addr: SocketAddr, result: &Result<EncryptedStream, ConnectionError>) {
+    match result {
+        // A duplicate got as far as a complete handshake
+        Ok(_) | Err(ConnectionError::Duplicate(_)) => {
+            metrics.on_punch_result(addr, true);
+            metrics.on_handshake_complete(addr);
+        }
+        Err(ConnectionError::Holepunch(_)) => metrics.on_punch_result(addr, false),
+        Err(ConnectionError::Transport(_)) => {
+            metrics.on_punch_result(addr, true);
+            metrics.on_handshake_failed(addr);
+        }
+        // Refused (peer limit, already connected) or failed locally
+        Err(_) => {}
+    }
+}
+
+/// What a server announces with: the public key its candidates are signed
+/// with, so clients can fetch them.
+fn candidates_value(connections: &ConnectionManager) -> [u8; 32] {
+    connections.candidates_key().verifying_key().to_bytes()
+}
+
+/// Whether a looked-up peer is our own announcement echoed back.
+fn is_own_address(addr: &SocketAddr, port: u16) -> bool {
+    addr.port() == port && (addr.ip().is_loopback() || addr.ip().is_unspecified())
+}
+
+/// `interval` moved by up to `jitter` of itself either way, at random.
+fn jittered(interval: Duration, jitter: f64, rng: &mut impl rand::Rng) -> Duration {
+    if jitter.is_nan() || jitter <= 0.0 {
+        return interval;
+    }
+    let jitter = jitter.min(1.0);
+    interval.mul_f64(1.0 + rng.gen_range(-jitter..=jitter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::ConnectionConfig;
+    use crate::dht::LookupOptions;
+
+    async fn local_dht(bootstrap: Vec<String>) -> Arc<dht::DhtClient> {
+        let config = dht::DhtConfig {
+            bootstrap,
+            bind_port: 0,
+            ipv6: false,
+            ..Default::default()
+        };
+        Arc::new(dht::DhtClient::new(config).await.unwrap())
+    }
+
+    fn loopback(dht: &dht::DhtClient) -> String {
+        format!("127.0.0.1:{}", dht.local_addr().unwrap().port())
+    }
+
+    #[test]
+    fn test_reannounce_intervals_are_jittered() {
+        use rand::SeedableRng;
+
+        let interval = DEFAULT_REFRESH_INTERVAL;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(95);
+        let delays: Vec<Duration> = (0..50).map(|_| jittered(interval, DEFAULT_REFRESH_JITTER, &mut rng)).collect();
+        assert!(delays.iter().all(|d| *d >= interval.mul_f64(0.8) && *d <= interval.mul_f64(1.2)));
+        assert!(delays.windows(2).all(|pair| pair[0] != pair[1]), "successive intervals should differ");
+        assert!(delays.iter().any(|d| *d < interval) && delays.iter().any(|d| *d > interval));
+
+        // No jitter announces on the interval exactly, and too much is capped
+        assert_eq!(jittered(interval, 0.0, &mut rng), interval);
+        assert_eq!(jittered(interval, f64::NAN, &mut rng), interval);
+        assert!((0..50).all(|_| jittered(interval, 5.0, &mut rng) <= interval * 2));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_leave_stops_refresh_and_unannounces() {
+        let bootstrap = local_dht(vec!["127.0.0.1:1".to_string()]).await;
+        let dht = local_dht(vec![loopback(&bootstrap)]).await;
+        let observer = local_dht(vec![loopback(&bootstrap)]).await;
+        let connections = Arc::new(
+            ConnectionManager::new(
+                "127.0.0.1:0".parse().unwrap(),
+                ConnectionConfig {
+                    max_peers: 8,
+                    transport: Default::default(),
+                    app_id: None,
+                },
+            )
+            .await
+            .unwrap(),
+        );
+        let manager = DiscoveryManager::new(DiscoveryConfig {
+            max_peers: 8,
+            refresh_interval: Duration::from_millis(50),
+            refresh_jitter: DEFAULT_REFRESH_JITTER,
+            backoff: BackoffConfig::default(),
+        });
+        let topic = Topic::from_key(b"leave-unannounces");
+
+        // Bypass the observer's peer cache: each lookup must see the DHT as it is now
+        let lookup = || observer.lookup_with(topic, LookupOptions { force: true, ..Default::default() });
+
+        manager.join(&dht, &connections, topic, JoinOpts::default()).await.unwrap();
+        assert!(!lookup().await.unwrap().is_empty(), "join should announce");
+
+        manager.leave(&dht, topic).await.unwrap();
+        assert!(manager.topics.read().await.is_empty(), "refresh task handle should be gone");
+        assert!(lookup().await.unwrap().is_empty(), "leave should unannounce");
+
+        // Several refresh intervals later nothing has been announced again
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(lookup().await.unwrap().is_empty(), "no announces after leave");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_join_all_and_leave_all() {
+        let bootstrap = local_dht(vec!["127.0.0.1:1".to_string()]).await;
+        let dht = local_dht(vec![loopback(&bootstrap)]).await;
+        let connections = Arc::new(
+            ConnectionManager::new(
+                "127.0.0.1:0".parse().unwrap(),
+                ConnectionConfig {
+                    max_peers: 8,
+                    transport: Default::default(),
+                    app_id: None,
+                },
+            )
+            .await
+            .unwrap(),
+        );
+        let manager = DiscoveryManager::new(DiscoveryConfig {
+            max_peers: 8,
+            refresh_interval: DEFAULT_REFRESH_INTERVAL,
+            refresh_jitter: DEFAULT_REFRESH_JITTER,
+            backoff: BackoffConfig::default(),
+        });
+        let topics = [b"batch-1", b"batch-2", b"batch-3"].map(|key| Topic::from_key(key));
+
+        let results = manager.join_all(&dht, &connections, &topics, JoinOpts::default()).await;
+        assert_eq!(results.iter().map(|(topic, _)| *topic).collect::<Vec<_>>(), topics);
+        for (topic, result) in &results {
+            assert!(result.is_ok(), "joining {:?} failed: {:?}", topic, result);
+        }
+        let joined = manager.topics.read().await;
+        assert!(topics.iter().all(|topic| joined.contains_key(topic)));
+        drop(joined);
+
+        let results = manager.leave_all(&dht, &topics).await;
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+        assert!(manager.topics.read().await.is_empty());
+    }
+}
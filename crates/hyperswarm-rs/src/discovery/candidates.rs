@@ -0,0 +1,238 @@
+//! Candidate exchange through the DHT.
+//!
+//! A `lookup` only yields the address a peer announced, which behind a NAT
+//! may not be where its punches come from. Peers therefore publish their
+//! holepunch candidates (LAN addresses and the WAN address the DHT observes)
+//! as a BEP 44 mutable item.
+//!
+//! The item is signed with a key derived from the peer's Noise static key,
+//! whose public half the peer announces as its [`PeerAddress::value`]. Nodes
+//! only record an announcement from the address it is made for, so the
+//! candidates found through a lookup are the ones the peer at the announced
+//! address published, and nobody else can overwrite them. They are still
+//! hints: a peer can publish any address, which is why the announced address
+//! is punched first, and the connection they lead to is authenticated by
+//! the Noise handshake.
+//!
+//! [`PeerAddress::value`]: crate::dht::PeerAddress::value
+
+use std::net::SocketAddr;
+
+use blake2::digest::{consts::U32, KeyInit, Mac};
+use blake2::Blake2bMac;
+use ed25519_dalek::SigningKey;
+
+use crate::dht::{self, DhtClient, DhtError};
+use crate::holepunch::{self, Candidate, CandidateKind};
+
+/// Most candidates kept in one item, well within the BEP 44 value limit.
+pub const MAX_CANDIDATES: usize = 32;
+/// Derivation label of the key a peer signs its candidates with.
+const CANDIDATES_LABEL: &[u8] = b"hyperswarm-rs/candidates";
+
+const KIND_LAN: u8 = 0;
+const KIND_WAN: u8 = 1;
+const KIND_RELAY: u8 = 2;
+
+/// Encode candidates as `kind (1) | length (1) | compact address`, keeping
+/// at most [`MAX_CANDIDATES`].
+pub fn encode(candidates: &[Candidate]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for candidate in candidates.iter().take(MAX_CANDIDATES) {
+        let addr = dht::encode_compact_peer(candidate.addr);
+        out.push(match candidate.kind {
+            CandidateKind::Lan => KIND_LAN,
+            CandidateKind::Wan => KIND_WAN,
+            CandidateKind::Relay => KIND_RELAY,
+        });
+        out.push(addr.len() as u8);
+        out.extend_from_slice(&addr);
+    }
+    out
+}
+
+/// Decode candidates written by [`encode`]. Entries of unknown kind are
+/// skipped; decoding stops at the first malformed one.
+pub fn decode(mut data: &[u8]) -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    while let [kind, len, rest @ ..] = data {
+        let Some((addr, remaining)) = rest.split_at_checked(usize::from(*len)) else {
+            break;
+        };
+        data = remaining;
+        let Some(addr) = dht::parse_compact_peer(addr) else {
+            break;
+        };
+        let kind = match *kind {
+            KIND_LAN => CandidateKind::Lan,
+            KIND_WAN => CandidateKind::Wan,
+            KIND_RELAY => CandidateKind::Relay,
+            _ => continue,
+        };
+        candidates.push(Candidate { addr, kind });
+    }
+    candidates
+}
+
+/// Our own candidates for the swarm socket bound at `local`: its LAN
+/// addresses, the `added` ones, such as a port mapping, and, once DHT
+/// responders have reported it, our WAN address. The
+/// WAN address is also the address our announcements are recorded under,
+/// which is returned alongside; `None` until it is known.
+///
+/// A socket bound to one interface only offers that interface's address.
+pub(crate) fn own_candidates(
+    dht: &DhtClient,
+    local: SocketAddr,
+    added: &[Candidate],
+) -> Option<(SocketAddr, Vec<Candidate>)> {
+    let announced = SocketAddr::new(dht.observed_address()?.ip(), local.port());
+    let mut candidates = vec![Candidate {
+        addr: announced,
+        kind: CandidateKind::Wan,
+    }];
+    if local.ip().is_unspecified() {
+        candidates.extend(holepunch::gather_local_candidates(local.port()));
+    } else if local != announced {
+        candidates.push(Candidate {
+            addr: local,
+            kind: CandidateKind::Lan,
+        });
+    }
+    for candidate in added {
+        if !candidates.iter().any(|c| c.addr == candidate.addr) {
+            candidates.push(candidate.clone());
+        }
+    }
+    Some((announced, candidates))
+}
+
+/// Publish `candidates` as ours, signed with `key`, versioned by the
+/// current time so a newer publish always replaces an older one.
+pub async fn publish(dht: &DhtClient, key: &SigningKey, candidates: &[Candidate]) -> Result<(), DhtError> {
+    let seq = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as i64)
+        .unwrap_or_default();
+    dht.put_mutable(key, None, seq, &encode(candidates), None).await?;
+    Ok(())
+}
+
+/// Fetch the candidates published with the key whose public half is
+/// `public_key`; empty if there are none.
+pub async fn fetch(dht: &DhtClient, public_key: &[u8; 32]) -> Result<Vec<Candidate>, DhtError> {
+    let item = dht.get_mutable(public_key, None).await?;
+    Ok(item.map(|item| decode(&item.value)).unwrap_or_default())
+}
+
+/// The key a swarm whose Noise static private key is `static_key` signs its
+/// candidates with: a MAC of [`CANDIDATES_LABEL`] under the static key, so
+/// only the swarm can compute it and it stays the same across restarts with
+/// the same identity.
+pub(crate) fn signing_key(static_key: &[u8; 32]) -> SigningKey {
+    let mut mac = <Blake2bMac<U32> as KeyInit>::new_from_slice(static_key)
+        .expect("32-byte keys are valid for Blake2bMac");
+    Mac::update(&mut mac, CANDIDATES_LABEL);
+    SigningKey::from_bytes(&Mac::finalize(mac).into_bytes().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode_roundtrip() {
+        let candidates = vec![
+            Candidate {
+                addr: "192.168.1.10:4000".parse().unwrap(),
+                kind: CandidateKind::Lan,
+            },
+            Candidate {
+                addr: "[2001:db8::1]:4000".parse().unwrap(),
+                kind: CandidateKind::Wan,
+            },
+            Candidate {
+                addr: "203.0.113.1:3478".parse().unwrap(),
+                kind: CandidateKind::Relay,
+            },
+        ];
+        let decoded = decode(&encode(&candidates));
+        assert_eq!(decoded.len(), candidates.len());
+        for (decoded, original) in decoded.iter().zip(&candidates) {
+            assert_eq!(decoded.addr, original.addr);
+            assert_eq!(decoded.kind, original.kind);
+        }
+
+        // A truncated entry ends decoding without panicking
+        let encoded = encode(&candidates);
+        assert_eq!(decode(&encoded[..encoded.len() - 1]).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_candidates_are_found_under_their_signing_key() {
+        let config = |bootstrap| dht::DhtConfig {
+            bootstrap,
+            bind_port: 0,
+            ipv6: false,
+            ..Default::default()
+        };
+        let node = DhtClient::new(config(Vec::new())).await.unwrap();
+        let node_addr = format!("127.0.0.1:{}", node.local_addr().unwrap().port());
+        let client = DhtClient::new(config(vec![node_addr])).await.unwrap();
+
+        let key = signing_key(&[1; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        let ours = vec![Candidate {
+            addr: "127.0.0.1:5000".parse().unwrap(),
+            kind: CandidateKind::Wan,
+        }];
+        assert!(fetch(&client, &public_key).await.unwrap().is_empty());
+
+        publish(&client, &key, &ours).await.unwrap();
+        let fetched = fetch(&client, &public_key).await.unwrap();
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].addr, ours[0].addr);
+
+        // Another identity's key finds nothing
+        let other = signing_key(&[2; 32]).verifying_key().to_bytes();
+        assert!(fetch(&client, &other).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_own_candidates_include_added_ones() {
+        let config = |bootstrap| dht::DhtConfig {
+            bootstrap,
+            bind_port: 0,
+            ipv6: false,
+            ..Default::default()
+        };
+        let node = DhtClient::new(config(Vec::new())).await.unwrap();
+        let node_addr = format!("127.0.0.1:{}", node.local_addr().unwrap().port());
+        let client = DhtClient::new(config(vec![node_addr])).await.unwrap();
+        let local: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let mapped = Candidate {
+            addr: "203.0.113.7:40000".parse().unwrap(),
+            kind: CandidateKind::Wan,
+        };
+        // Nothing is published before the WAN address is known
+        assert!(own_candidates(&client, local, std::slice::from_ref(&mapped)).is_none());
+
+        client.bootstrap().await.unwrap();
+        let (announced, ours) = own_candidates(&client, local, std::slice::from_ref(&mapped)).unwrap();
+        assert_eq!(announced, local);
+        let addrs: Vec<SocketAddr> = ours.iter().map(|c| c.addr).collect();
+        assert_eq!(addrs, vec![announced, mapped.addr]);
+        assert_eq!(ours[1].kind, CandidateKind::Wan);
+
+        // One already present is not repeated
+        let (_, ours) = own_candidates(&client, local, &[ours[0].clone()]).unwrap();
+        assert_eq!(ours.len(), 1);
+    }
+
+    #[test]
+    fn test_signing_key_depends_on_static_key_only() {
+        let key = signing_key(&[7; 32]).verifying_key();
+        assert_eq!(key, signing_key(&[7; 32]).verifying_key());
+        assert_ne!(key, signing_key(&[8; 32]).verifying_key());
+    }
+}
@@ -0,0 +1,391 @@
+//! Lifecycle of discovered peers.
+//!
+//! Every peer a lookup returns gets a session, which moves
+//! Discovered → Connecting → Connected. A failed connect, or a connection
+//! that is later dropped, makes it Failed and then Backoff, after which it is
+//! connected again, waiting twice as long after each consecutive failure.
+//! After [`BackoffConfig::max_attempts`] failures in a row the peer stays
+//! Failed; finding it again does not start it over.
+//!
+//! Sessions of peers that lookups have stopped returning are pruned, unless
+//! the peer is connected or being connected to. A pruned peer that turns up
+//! again later starts over.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use tokio::time::{Duration, Instant};
+
+use crate::dht::PeerAddress;
+use crate::Topic;
+
+/// How a peer is reconnected after a failure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackoffConfig {
+    /// Wait before the first retry; each further retry waits twice as long.
+    pub initial: Duration,
+    /// Longest wait between retries.
+    pub max: Duration,
+    /// Consecutive failed attempts after which the peer is given up on.
+    pub max_attempts: u32,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            max_attempts: 5,
+        }
+    }
+}
+
+impl BackoffConfig {
+    /// The wait after `failures` consecutive failures.
+    fn delay(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31);
+        self.initial.saturating_mul(1 << doublings).min(self.max)
+    }
+}
+
+/// Where a peer is in its lifecycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerState {
+    /// Found by a lookup, not connected to yet.
+    Discovered,
+    /// A connection attempt is under way.
+    Connecting,
+    /// Connected; watched for the connection being dropped.
+    Connected,
+    /// Given up on after too many failures in a row.
+    Failed,
+    /// Failed; retried once `until` has passed.
+    Backoff { until: Instant },
+}
+
+/// One discovered peer and how connecting to it has gone.
+#[derive(Clone, Debug)]
+pub(crate) struct PeerSession {
+    peer: PeerAddress,
+    topic: Topic,
+    state: PeerState,
+    failures: u32,
+    /// When a lookup last returned the peer.
+    seen: Instant,
+}
+
+impl PeerSession {
+    fn new(peer: PeerAddress, topic: Topic, now: Instant) -> Self {
+        Self {
+            peer,
+            topic,
+            state: PeerState::Discovered,
+            failures: 0,
+            seen: now,
+        }
+    }
+
+    pub(crate) fn state(&self) -> PeerState {
+        self.state
+    }
+
+    /// Whether a connection attempt should start at `now`.
+    fn is_due(&self, now: Instant) -> bool {
+        match self.state {
+            PeerState::Discovered => true,
+            PeerState::Backoff { until } => until <= now,
+            PeerState::Connecting | PeerState::Connected | PeerState::Failed => false,
+        }
+    }
+
+    fn connecting(&mut self) {
+        self.state = PeerState::Connecting;
+    }
+
+    fn connected(&mut self) {
+        self.state = PeerState::Connected;
+        self.failures = 0;
+    }
+
+    /// Record a failed attempt or a dropped connection at `now`: back off,
+    /// or give up once `backoff.max_attempts` failures have piled up.
+    fn failed(&mut self, now: Instant, backoff: &BackoffConfig) {
+        self.failures += 1;
+        self.state = if self.failures >= backoff.max_attempts {
+            PeerState::Failed
+        } else {
+            PeerState::Backoff {
+                until: now + backoff.delay(self.failures),
+            }
+        };
+    }
+}
+
+/// The sessions of every discovered peer, keyed by address.
+#[derive(Default)]
+pub(crate) struct PeerSessions {
+    sessions: HashMap<SocketAddr, PeerSession>,
+}
+
+impl PeerSessions {
+    /// Track `peer`, found under `topic` at `now`. A peer already tracked
+    /// keeps its state and failures, including one that was given up on.
+    pub(crate) fn discovered(&mut self, peer: PeerAddress, topic: Topic, now: Instant) {
+        match self.sessions.get_mut(&peer.addr) {
+            Some(session) => session.seen = now,
+            None => {
+                self.sessions.insert(peer.addr, PeerSession::new(peer, topic, now));
+            }
+        }
+    }
+
+    /// Forget the peers of `topic` no lookup has returned since `cutoff`,
+    /// except those connected or being connected to.
+    pub(crate) fn prune(&mut self, topic: Topic, cutoff: Instant) {
+        self.sessions.retain(|_, session| {
+            session.topic != topic
+                || session.seen >= cutoff
+                || matches!(session.state, PeerState::Connecting | PeerState::Connected)
+        });
+    }
+
+    pub(crate) fn get(&self, addr: &SocketAddr) -> Option<&PeerSession> {
+        self.sessions.get(addr)
+    }
+
+    /// Back off from connected peers of `topic` whose connection has gone.
+    pub(crate) fn check_connected(
+        &mut self,
+        topic: Topic,
+        is_connected: impl Fn(&SocketAddr) -> bool,
+        now: Instant,
+        backoff: &BackoffConfig,
+    ) {
+        for (addr, session) in self.of_topic(topic) {
+            if session.state == PeerState::Connected && !is_connected(addr) {
+                tracing::debug!("Connection to {} dropped, reconnecting", addr);
+                session.failed(now, backoff);
+            }
+        }
+    }
+
+    /// The peers of `topic` due for an attempt at `now`, marked Connecting.
+    pub(crate) fn take_due(&mut self, topic: Topic, now: Instant) -> Vec<PeerAddress> {
+        let mut due = Vec::new();
+        for (_, session) in self.of_topic(topic) {
+            if session.is_due(now) {
+                session.connecting();
+                due.push(session.peer.clone());
+            }
+        }
+        due
+    }
+
+    /// Record the outcome of an attempt to connect to `addr`.
+    pub(crate) fn finish(&mut self, addr: &SocketAddr, connected: bool, now: Instant, backoff: &BackoffConfig) {
+        if let Some(session) = self.sessions.get_mut(addr) {
+            if connected {
+                session.connected();
+            } else {
+                session.failed(now, backoff);
+            }
+        }
+    }
+
+    /// When the next peer of `topic` in backoff is due.
+    pub(crate) fn next_due(&self, topic: Topic) -> Option<Instant> {
+        self.sessions
+            .values()
+            .filter(|session| session.topic == topic)
+            .filter_map(|session| match session.state {
+                PeerState::Backoff { until } => Some(until),
+                _ => None,
+            })
+            .min()
+    }
+
+    /// Forget the peers of a topic we left.
+    pub(crate) fn remove_topic(&mut self, topic: Topic) {
+        self.sessions.retain(|_, session| session.topic != topic);
+    }
+
+    fn of_topic(&mut self, topic: Topic) -> impl Iterator<Item = (&SocketAddr, &mut PeerSession)> {
+        self.sessions.iter_mut().filter(move |(_, session)| session.topic == topic)
+    }
+}
+
+/// Connect to every peer of `topic` that is due, one at a time, recording
+/// each outcome. `connect` returns whether the peer ended up connected.
+pub(crate) async fn connect_due<F, Fut>(sessions: &Mutex<PeerSessions>, topic: Topic, backoff: &BackoffConfig, mut connect: F)
+where
+    F: FnMut(PeerAddress) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let due = sessions.lock().expect("peer sessions lock poisoned").take_due(topic, Instant::now());
+    for peer in due {
+        let addr = peer.addr;
+        let connected = connect(peer).await;
+        sessions
+            .lock()
+            .expect("peer sessions lock poisoned")
+            .finish(&addr, connected, Instant::now(), backoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> PeerAddress {
+        PeerAddress {
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            node_id: None,
+            value: None,
+        }
+    }
+
+    fn addrs(peers: Vec<PeerAddress>) -> Vec<SocketAddr> {
+        peers.into_iter().map(|peer| peer.addr).collect()
+    }
+
+    fn backoff() -> BackoffConfig {
+        BackoffConfig {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            max_attempts: 4,
+        }
+    }
+
+    #[test]
+    fn test_delay_doubles_up_to_max() {
+        let backoff = BackoffConfig {
+            max: Duration::from_millis(500),
+            ..backoff()
+        };
+        let delays: Vec<_> = (1..=5).map(|failures| backoff.delay(failures).as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 500, 500]);
+        assert_eq!(backoff.delay(u32::MAX), backoff.max);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_peer_accepting_third_connect_is_retried_with_backoff() {
+        let topic = Topic::from_key(b"backoff");
+        let sessions = Mutex::new(PeerSessions::default());
+        sessions.lock().unwrap().discovered(peer(9000), topic, Instant::now());
+
+        // The peer rejects the first two connects and accepts the third
+        let mut attempts = Vec::new();
+        let started = Instant::now();
+        for _ in 0..3 {
+            connect_due(&sessions, topic, &backoff(), |_| {
+                attempts.push(Instant::now());
+                let accept = attempts.len() == 3;
+                async move { accept }
+            })
+            .await;
+            let next_due = sessions.lock().unwrap().next_due(topic);
+            if let Some(due) = next_due {
+                tokio::time::sleep_until(due).await;
+            }
+        }
+
+        let offsets: Vec<_> = attempts.iter().map(|at| (*at - started).as_millis()).collect();
+        assert_eq!(offsets, [0, 100, 300]);
+        let sessions = sessions.lock().unwrap();
+        let session = sessions.get(&peer(9000).addr).unwrap();
+        assert_eq!(session.state(), PeerState::Connected);
+        assert_eq!(session.failures, 0);
+        assert_eq!(sessions.next_due(topic), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_peer_is_given_up_after_max_attempts() {
+        let topic = Topic::from_key(b"give-up");
+        let sessions = Mutex::new(PeerSessions::default());
+        sessions.lock().unwrap().discovered(peer(9000), topic, Instant::now());
+
+        let mut attempts = 0;
+        while attempts < 10 {
+            connect_due(&sessions, topic, &backoff(), |_| {
+                attempts += 1;
+                async { false }
+            })
+            .await;
+            let next_due = sessions.lock().unwrap().next_due(topic);
+            match next_due {
+                Some(due) => tokio::time::sleep_until(due).await,
+                None => break,
+            }
+        }
+        assert_eq!(attempts, backoff().max_attempts);
+        assert_eq!(sessions.lock().unwrap().get(&peer(9000).addr).unwrap().state(), PeerState::Failed);
+
+        // Found again by a later lookup, it stays given up on
+        sessions.lock().unwrap().discovered(peer(9000), topic, Instant::now());
+        let sessions = sessions.lock().unwrap();
+        let session = sessions.get(&peer(9000).addr).unwrap();
+        assert_eq!(session.state(), PeerState::Failed);
+        assert_eq!(session.failures, backoff().max_attempts);
+        assert!(!session.is_due(Instant::now()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_peers_no_longer_found_are_pruned() {
+        let topic = Topic::from_key(b"prune");
+        let other = Topic::from_key(b"other");
+        let mut sessions = PeerSessions::default();
+        let found = Instant::now();
+        for port in [9000, 9001, 9002] {
+            sessions.discovered(peer(port), topic, found);
+        }
+        sessions.discovered(peer(9003), other, found);
+        // 9001 is connected and 9002 being connected to
+        assert_eq!(sessions.take_due(topic, found).len(), 3);
+        sessions.finish(&peer(9000).addr, false, found, &backoff());
+        sessions.finish(&peer(9001).addr, true, found, &backoff());
+
+        // A later lookup finds only the failed peer again
+        tokio::time::advance(Duration::from_secs(60)).await;
+        let lookup = Instant::now();
+        sessions.discovered(peer(9000), topic, lookup);
+        sessions.prune(topic, lookup);
+        assert_eq!(sessions.get(&peer(9000).addr).unwrap().failures, 1);
+        assert!(sessions.get(&peer(9001).addr).is_some());
+        assert!(sessions.get(&peer(9002).addr).is_some());
+        assert!(sessions.get(&peer(9003).addr).is_some(), "other topics are left alone");
+
+        // Once it drops out of lookups too, it is forgotten and starts over
+        tokio::time::advance(Duration::from_secs(60)).await;
+        sessions.prune(topic, Instant::now());
+        assert!(sessions.get(&peer(9000).addr).is_none());
+        sessions.discovered(peer(9000), topic, Instant::now());
+        assert_eq!(sessions.get(&peer(9000).addr).unwrap().failures, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dropped_connection_backs_off_then_reconnects() {
+        let topic = Topic::from_key(b"dropped");
+        let other = Topic::from_key(b"other");
+        let mut sessions = PeerSessions::default();
+        sessions.discovered(peer(9000), topic, Instant::now());
+        sessions.discovered(peer(9001), other, Instant::now());
+
+        let now = Instant::now();
+        assert_eq!(addrs(sessions.take_due(topic, now)), [peer(9000).addr]);
+        sessions.finish(&peer(9000).addr, true, now, &backoff());
+        sessions.check_connected(topic, |_| true, now, &backoff());
+        assert_eq!(sessions.get(&peer(9000).addr).unwrap().state(), PeerState::Connected);
+
+        sessions.check_connected(topic, |_| false, now, &backoff());
+        assert_eq!(sessions.next_due(topic), Some(now + backoff().initial));
+        assert!(sessions.take_due(topic, now).is_empty());
+        assert_eq!(addrs(sessions.take_due(topic, now + backoff().initial)), [peer(9000).addr]);
+
+        // Leaving a topic forgets only its peers
+        sessions.remove_topic(topic);
+        assert!(sessions.get(&peer(9000).addr).is_none());
+        assert!(sessions.get(&peer(9001).addr).is_some());
+    }
+}
@@ -6,21 +6,75 @@
 //! 1) **Probe**: each peer sends outbound UDP packets to candidate addresses
 //!    to create NAT bindings and learn which candidates are viable.
 //! 2) **Exchange candidates**: peers exchange observed endpoints (via relay/DHT)
-//!    so both sides know where to punch.
+//!    so both sides know where to punch. Discovery does this through the DHT,
+//!    see [`crate::discovery::candidates`].
 //! 3) **Punch**: both peers simultaneously send packets to each other to
 //!    open the mapping and confirm reachability.
 //!
 //! # Security
-//! Punch packets are authenticated with a Blake2s MAC keyed on a pre-shared
-//! `session_key`.  Both peers must call [`HolepunchSession::new`] with the same
-//! key (derived from the topic or exchanged via the DHT relay).  Packets that
-//! fail MAC verification are silently ignored.
+//! Probe and punch packets are authenticated with a Blake2s MAC keyed on a
+//! pre-shared `session_key`.  Both peers must call [`HolepunchSession::new`]
+//! with the same key (derived from the topic or exchanged via the DHT relay).
+//! Packets that fail MAC verification are silently ignored, so a spoofed
+//! probe draws no traffic. The swarm's own connections share no secret
+//! before their Noise handshake, so their sessions send packets without a
+//! MAC: a punch there only opens a path, and the handshake that follows
+//! authenticates the peer.
+//!
+//! An authenticated probe also carries a nonce drawn for each attempt and
+//! the address it was sent to, both under the MAC, so a captured probe
+//! replayed to another host fails verification. A responder answers such a
+//! probe with a probe of its own, to open its NAT towards the peer, but only
+//! when it comes from one of the peer's candidates, once per address and at
+//! most 16 times an attempt: a spoofed source cannot turn it into a
+//! reflector.
+//!
+//! # Application namespaces
+//! Probe and punch packets start with `HYPERSWARM_PROBE` or
+//! `HYPERSWARM_PUNCH`. A session given an application id with
+//! [`HolepunchSession::with_app_id`] appends a tag derived from it to both,
+//! and ignores packets carrying any other tag, or none. Deployments with
+//! different ids on one network never answer each other, even if their
+//! session keys collide.
+//!
+//! # WAN candidates
+//! [`discover_wan`] asks a STUN server (RFC 5389 Binding request) for the
+//! address our socket is seen from, which is the `Wan` candidate to hand to
+//! the peer. Use the socket that will punch so the mapping is the same.
+//! Where the gateway maps ports on request, [`map_port`] gets a public
+//! address that needs no punching at all, see [`portmap`].
+//!
+//! # Simultaneous open
+//! Punch packets say whether they come from an initiator or a responder,
+//! and carry a tiebreak value the sender draws at random for each attempt.
+//! When two peers initiate towards each other over the same path, the one
+//! with the lower value stays initiator and the other answers as responder,
+//! so the path carries one connection. Equal values, which only a replayed
+//! or reflected punch is likely to produce, are drawn again.
+//!
+//! # Relay fallback
+//! When direct punching fails, a session configured with
+//! [`HolepunchSession::with_relay`] punches again through a [`relay`] server.
+//! The relayed punch packets carry the same MAC, and the connection that
+//! follows talks to the relay's address.
 
 use blake2::{Blake2sMac256, digest::{Mac, KeyInit}};
-use std::net::SocketAddr;
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
 use std::sync::Arc;
 use tokio::net::UdpSocket;
 use tokio::time::{timeout, Duration};
+use zeroize::Zeroizing;
+
+use crate::connection::PacketSource;
+use crate::dht;
+use crate::packet::PacketTransport;
+
+pub mod portmap;
+pub mod relay;
+
+pub use portmap::{map_port, PortMapping};
+use relay::{relay_packet, RELAY_SESSION_ID_SIZE};
 
 #[derive(Clone, Debug)]
 pub struct Candidate {
@@ -28,7 +82,7 @@ pub struct Candidate {
     pub kind: CandidateKind,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum CandidateKind {
     /// Private LAN address.
     Lan,
@@ -38,6 +92,79 @@ pub enum CandidateKind {
     Relay,
 }
 
+/// Port prediction for peers behind a symmetric NAT.
+///
+/// Such a NAT gives every destination a new port, usually a fixed stride
+/// away from the last one, so the port the peer reported is not the one its
+/// punches will come from. With prediction enabled, punches to each `Wan`
+/// candidate also go to the ports `window` strides on either side of it.
+#[derive(Clone, Debug)]
+pub struct PortPrediction {
+    /// How many predicted ports to try on each side of the reported port.
+    pub window: u16,
+    /// Distance between consecutive ports the peer's NAT allocates.
+    pub stride: u16,
+}
+
+impl Default for PortPrediction {
+    fn default() -> Self {
+        Self { window: 8, stride: 1 }
+    }
+}
+
+impl PortPrediction {
+    /// `candidate` followed by the predicted addresses around it, nearest first.
+    fn expand(&self, candidate: &Candidate) -> Vec<Candidate> {
+        let base = i64::from(candidate.addr.port());
+        let mut predicted = vec![candidate.clone()];
+        for step in 1..=i64::from(self.window) {
+            for offset in [step, -step] {
+                let port = base + offset * i64::from(self.stride);
+                let Ok(port) = u16::try_from(port) else { continue };
+                if port == 0 || predicted.iter().any(|c| c.addr.port() == port) {
+                    continue;
+                }
+                predicted.push(Candidate {
+                    addr: SocketAddr::new(candidate.addr.ip(), port),
+                    kind: candidate.kind.clone(),
+                });
+            }
+        }
+        predicted
+    }
+}
+
+/// The path a holepunch settled on.
+#[derive(Clone, Debug)]
+pub struct HolepunchResult {
+    /// The peer's address on this path.
+    pub addr: SocketAddr,
+    /// What kind of candidate `addr` is.
+    pub kind: CandidateKind,
+    /// Time from our first punch (or, when responding, probe) to that
+    /// address until its verified punch arrived.
+    pub rtt: Duration,
+    /// Whether we go on as the initiator of the connection: false when
+    /// responding, or when the peer initiated at the same time and won the
+    /// tiebreak.
+    pub initiator: bool,
+}
+
+/// Progress of [`HolepunchSession::initiate_with_events`], one candidate
+/// address at a time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HolepunchEvent {
+    /// A probe is about to go to this candidate.
+    Probing(SocketAddr),
+    /// The first punch went to this candidate.
+    Punching(SocketAddr),
+    /// This candidate answered with a verified punch; the punch is over.
+    Established(SocketAddr),
+    /// This candidate is given up on: its reply failed authentication, or
+    /// it had not answered by the punch deadline.
+    CandidateFailed(SocketAddr),
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum HolepunchError {
     #[error("io: {0}")]
@@ -48,23 +175,193 @@ pub enum HolepunchError {
     NoViableCandidates,
     #[error("authentication failed")]
     AuthenticationFailed,
+    #[error("stun: {0}")]
+    Stun(String),
+    #[error("port mapping: {0}")]
+    PortMapping(String),
 }
 
 const PROBE_MESSAGE: &[u8] = b"HYPERSWARM_PROBE";
 const PUNCH_MESSAGE: &[u8] = b"HYPERSWARM_PUNCH";
 const PUNCH_TIMEOUT: Duration = Duration::from_secs(10);
-/// Size of the Blake2s MAC tag appended to every punch packet (bytes).
+/// Size of the Blake2s MAC tag appended to every probe and punch packet (bytes).
 const PUNCH_MAC_SIZE: usize = 32;
+/// Size of the per-attempt nonce in an authenticated probe packet (bytes).
+pub(crate) const PROBE_NONCE_SIZE: usize = 16;
+/// Most probes a responder answers in one attempt.
+const MAX_PROBES_ANSWERED: usize = 16;
+/// Size of the tiebreak value in a punch packet (bytes).
+const PUNCH_TIEBREAK_SIZE: usize = 32;
+/// `PUNCH_MESSAGE || role || tiebreak || mac`.
+const PUNCH_PACKET_SIZE: usize = PUNCH_MESSAGE.len() + 1 + PUNCH_TIEBREAK_SIZE + PUNCH_MAC_SIZE;
+/// Size of the application tag appended to the magic prefixes (bytes).
+const APP_TAG_SIZE: usize = 8;
+/// Context for deriving the application tag from an application id.
+const APP_TAG_CONTEXT: &[u8] = b"HYPERSWARM_APP_ID";
+/// Receive buffer for punch packets, with room to tell truncated ones apart.
+const PUNCH_BUFFER_SIZE: usize = PUNCH_PACKET_SIZE + APP_TAG_SIZE + 16;
 /// How long to wait between punch retransmissions while waiting for a response.
 const PUNCH_RETRY_INTERVAL: Duration = Duration::from_millis(200);
+/// Default for [`HolepunchSession::with_punch_budget`].
+pub const DEFAULT_PUNCH_BUDGET: usize = 16;
+/// How long the initiator punches before giving up on its candidates.
+const PUNCH_DEADLINE: Duration = Duration::from_secs(2);
+/// How often a responder re-registers with its relay while waiting.
+const RELAY_REGISTER_INTERVAL: Duration = Duration::from_secs(1);
+/// Context for deriving the relay session id from the session key.
+const RELAY_SESSION_CONTEXT: &[u8] = b"HYPERSWARM_RELAY_SESSION";
+
+// STUN (RFC 5389)
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_SUCCESS: u16 = 0x0101;
+const STUN_BINDING_ERROR: u16 = 0x0111;
+const STUN_MAGIC_COOKIE: u32 = 0x2112_A442;
+const STUN_HEADER_SIZE: usize = 20;
+const STUN_TRANSACTION_ID_SIZE: usize = 12;
+const STUN_ATTR_MAPPED_ADDRESS: u16 = 0x0001;
+const STUN_ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const STUN_FAMILY_IPV4: u8 = 0x01;
+const STUN_FAMILY_IPV6: u8 = 0x02;
+/// Overall time allowed for a STUN Binding transaction.
+const STUN_TIMEOUT: Duration = Duration::from_secs(3);
+/// How often the Binding request is retransmitted until answered.
+const STUN_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+const STUN_MAX_MESSAGE_SIZE: usize = 1500;
+
+/// Which interface addresses [`gather_local_candidates_with`] includes.
+#[derive(Clone, Debug, Default)]
+pub struct GatherOptions {
+    /// Include loopback addresses (`127.0.0.1`, `::1`).
+    pub include_loopback: bool,
+    /// Include link-local addresses (`169.254.0.0/16`, `fe80::/10`).
+    pub include_link_local: bool,
+}
 
 pub struct HolepunchSession {
-    socket: Arc<UdpSocket>,
+    socket: Arc<dyn PacketTransport>,
+    /// Where punch replies are read from; the socket itself unless shared.
+    source: PacketSource,
     /// Pre-shared secret used to authenticate punch packets.
     ///
     /// Both the initiator and the responder must use the same key (typically
     /// derived from the shared topic or exchanged through the DHT relay).
-    session_key: [u8; 32],
+    /// Wiped from memory when the session is dropped. `None` for the swarm's
+    /// sessions, whose packets carry no MAC.
+    session_key: Option<Zeroizing<[u8; 32]>>,
+    /// Punch predicted ports around `Wan` candidates, when set.
+    port_prediction: Option<PortPrediction>,
+    /// Relay to fall back to when direct punching fails.
+    relay: Option<SocketAddr>,
+    /// Settles a simultaneous open in the current attempt; see
+    /// [`with_tiebreak`](Self::with_tiebreak).
+    tiebreak: [u8; 32],
+    /// Tiebreak every attempt starts from instead of a random one.
+    fixed_tiebreak: Option<[u8; 32]>,
+    /// Most punches sent per retransmit interval.
+    punch_budget: usize,
+    /// Prefixes of our probe and punch packets.
+    magic: PacketMagic,
+    /// Our public address, once [`wan_candidate`](Self::wan_candidate) has
+    /// learned it; probes sent there are meant for us too.
+    wan_addr: Option<SocketAddr>,
+}
+
+/// The prefixes probe and punch packets start with: the fixed messages,
+/// followed by the application tag when there is one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct PacketMagic {
+    probe: Vec<u8>,
+    punch: Vec<u8>,
+}
+
+impl Default for PacketMagic {
+    fn default() -> Self {
+        Self {
+            probe: PROBE_MESSAGE.to_vec(),
+            punch: PUNCH_MESSAGE.to_vec(),
+        }
+    }
+}
+
+impl PacketMagic {
+    /// The prefixes for `app_id`: the fixed messages followed by the first
+    /// [`APP_TAG_SIZE`] bytes of `Blake2s256(APP_TAG_CONTEXT || app_id)`.
+    pub(crate) fn for_app(app_id: &[u8]) -> Self {
+        use blake2::{Blake2s256, Digest};
+
+        let digest = Blake2s256::new().chain_update(APP_TAG_CONTEXT).chain_update(app_id).finalize();
+        let tag = &digest[..APP_TAG_SIZE];
+        Self {
+            probe: [PROBE_MESSAGE, tag].concat(),
+            punch: [PUNCH_MESSAGE, tag].concat(),
+        }
+    }
+
+    /// Length of a punch packet under these prefixes, with a MAC tag if
+    /// `keyed`.
+    fn punch_packet_size(&self, keyed: bool) -> usize {
+        PUNCH_PACKET_SIZE - PUNCH_MESSAGE.len() + self.punch.len() - if keyed { 0 } else { PUNCH_MAC_SIZE }
+    }
+
+    /// Whether `data` is shaped like our punch packets, authenticated or not.
+    pub(crate) fn is_punch(&self, data: &[u8], keyed: bool) -> bool {
+        data.len() == self.punch_packet_size(keyed) && data.starts_with(&self.punch)
+    }
+
+    /// Build a probe packet to `to`: `probe magic || nonce || to || mac_tag`,
+    /// with `to` in compact peer form, or the bare magic without a session
+    /// key.
+    ///
+    /// MAC = Blake2sMac256(key = session_key, msg = probe magic || nonce || to)
+    pub(crate) fn probe_packet(
+        &self,
+        session_key: Option<&[u8; 32]>,
+        nonce: &[u8; PROBE_NONCE_SIZE],
+        to: SocketAddr,
+    ) -> Vec<u8> {
+        let Some(session_key) = session_key else {
+            return self.probe.clone();
+        };
+        let mut packet = [self.probe.as_slice(), nonce, &dht::encode_compact_peer(to)].concat();
+        let tag = session_mac(session_key, &packet).finalize().into_bytes();
+        packet.extend_from_slice(&tag);
+        packet
+    }
+
+    /// Verify a probe packet, using a constant-time MAC check if there is a
+    /// session key, in which case the address it was sent to must also pass
+    /// `addressed_to_us`.
+    fn verify_probe(
+        &self,
+        session_key: Option<&[u8; 32]>,
+        data: &[u8],
+        addressed_to_us: impl Fn(SocketAddr) -> bool,
+    ) -> bool {
+        let Some(session_key) = session_key else {
+            return data == self.probe.as_slice();
+        };
+        if data.len() < self.probe.len() + PROBE_NONCE_SIZE + PUNCH_MAC_SIZE || !data.starts_with(&self.probe) {
+            return false;
+        }
+        let (body, tag) = data.split_at(data.len() - PUNCH_MAC_SIZE);
+        let Some(to) = dht::parse_compact_peer(&body[self.probe.len() + PROBE_NONCE_SIZE..]) else {
+            return false;
+        };
+        session_mac(session_key, body).verify_slice(tag).is_ok() && addressed_to_us(to)
+    }
+}
+
+/// Which side of a punch exchange a punch packet comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PunchRole {
+    Initiator = 0,
+    Responder = 1,
+}
+
+/// The authenticated contents of a punch packet.
+struct Punch {
+    role: PunchRole,
+    tiebreak: [u8; PUNCH_TIEBREAK_SIZE],
 }
 
 impl HolepunchSession {
@@ -75,184 +372,473 @@ impl HolepunchSession {
     /// key.  A good source for this key is the topic hash shared via the DHT.
     pub async fn new(bind_addr: SocketAddr, session_key: [u8; 32]) -> Result<Self, HolepunchError> {
         let socket = UdpSocket::bind(bind_addr).await?;
-        Ok(Self {
-            socket: Arc::new(socket),
-            session_key,
-        })
+        Ok(Self::with_transport(Arc::new(socket), session_key))
+    }
+
+    /// Create a session sending and receiving through `transport`, which the
+    /// session must be the only reader of.
+    pub fn with_transport(transport: Arc<dyn PacketTransport>, session_key: [u8; 32]) -> Self {
+        Self::with_source(transport, PacketSource::Socket, Some(session_key))
+    }
+
+    /// Create a session on an already bound UDP socket, such as the one the
+    /// DHT runs on, so peers are punched from the port the DHT sees us at.
+    ///
+    /// The session reads the socket while punching, so nothing else may
+    /// read it meanwhile; a [`Demux`](crate::packet::Demux) lets the DHT
+    /// keep running on it.
+    pub fn with_socket(socket: Arc<UdpSocket>, session_key: [u8; 32]) -> Self {
+        Self::with_transport(socket, session_key)
+    }
+
+    /// Create a session on a socket shared with other sessions, reading
+    /// datagrams from `source`. Without a `session_key` its packets carry no
+    /// MAC and it cannot use a relay.
+    pub(crate) fn with_source(socket: Arc<dyn PacketTransport>, source: PacketSource, session_key: Option<[u8; 32]>) -> Self {
+        Self {
+            socket,
+            source,
+            session_key: session_key.map(Zeroizing::new),
+            port_prediction: None,
+            relay: None,
+            tiebreak: rand::random(),
+            fixed_tiebreak: None,
+            punch_budget: DEFAULT_PUNCH_BUDGET,
+            magic: PacketMagic::default(),
+            wan_addr: None,
+        }
+    }
+
+    /// Namespace the session's probe and punch packets by `app_id`, so it
+    /// ignores those of sessions with another id or none, whatever their
+    /// session key. Both peers must use the same id.
+    ///
+    /// Without one, packets carry the plain `HYPERSWARM_PROBE` /
+    /// `HYPERSWARM_PUNCH` prefixes and interoperate with earlier versions.
+    pub fn with_app_id(mut self, app_id: &[u8]) -> Self {
+        self.magic = PacketMagic::for_app(app_id);
+        self
+    }
+
+    /// Settle a simultaneous open by `tiebreak`: when both peers initiate
+    /// towards each other, the one with the lower value stays initiator and
+    /// the other answers as responder.
+    ///
+    /// Without one, each attempt draws a random value. The value is sent in
+    /// the clear, so it should not identify the peer; a tie with the other
+    /// side is broken with random values all the same.
+    pub fn with_tiebreak(mut self, tiebreak: [u8; 32]) -> Self {
+        self.fixed_tiebreak = Some(tiebreak);
+        self
+    }
+
+    /// Enable port prediction for peers behind a symmetric NAT.
+    ///
+    /// Without it only the reported candidate ports are punched.
+    pub fn with_port_prediction(mut self, prediction: PortPrediction) -> Self {
+        self.port_prediction = Some(prediction);
+        self
+    }
+
+    /// Fall back to the [`relay::RelayServer`] at `relay` when direct
+    /// punching fails.
+    ///
+    /// Both peers must use the same relay. They meet in a relay session
+    /// derived from the session key, so a relay can carry one connection per
+    /// session key at a time. A session without a key does not fall back.
+    pub fn with_relay(mut self, relay: SocketAddr) -> Self {
+        self.relay = Some(relay);
+        self
+    }
+
+    /// Send at most `per_interval` punches (at least one) every 200 ms
+    /// retransmit interval, however many candidates there are.
+    ///
+    /// Each interval's punches go to the candidates punched least recently,
+    /// `Lan` before `Wan` before `Relay` among equals, so with more
+    /// candidates than the budget they take turns. Candidates left unpunched
+    /// when the 2-second deadline passes are reported failed.
+    /// [`DEFAULT_PUNCH_BUDGET`] unless set.
+    pub fn with_punch_budget(mut self, per_interval: usize) -> Self {
+        self.punch_budget = per_interval.max(1);
+        self
+    }
+
+    /// Give up the session, keeping its packet source for the encrypted stream
+    /// that follows the punch.
+    pub(crate) fn into_source(self) -> PacketSource {
+        self.source
     }
 
     // ---- MAC helpers --------------------------------------------------------
 
-    /// Compute the Blake2s MAC tag for a punch packet.
+    /// The Blake2s MAC over the unauthenticated part of a punch packet, if
+    /// there is a session key.
     ///
-    /// MAC = Blake2sMac256(key = session_key, msg = PUNCH_MESSAGE)
-    fn compute_punch_mac(&self) -> [u8; 32] {
-        let mut mac = <Blake2sMac256 as KeyInit>::new_from_slice(&self.session_key)
-            .expect("session_key is exactly 32 bytes, which is valid for Blake2sMac256");
-        Mac::update(&mut mac, PUNCH_MESSAGE);
-        Mac::finalize(mac).into_bytes().into()
+    /// MAC = Blake2sMac256(key = session_key, msg = punch magic || role || tiebreak)
+    fn punch_mac(&self, body: &[u8]) -> Option<Blake2sMac256> {
+        self.session_key.as_deref().map(|session_key| session_mac(session_key, body))
     }
 
-    /// Build an authenticated punch packet: `PUNCH_MESSAGE || mac_tag`.
-    fn build_punch_packet(&self) -> Vec<u8> {
-        let mac = self.compute_punch_mac();
-        let mut packet = Vec::with_capacity(PUNCH_MESSAGE.len() + PUNCH_MAC_SIZE);
-        packet.extend_from_slice(PUNCH_MESSAGE);
-        packet.extend_from_slice(&mac);
+    /// Build a punch packet: `punch magic || role || tiebreak || mac_tag`,
+    /// without the tag if there is no session key.
+    fn build_punch_packet(&self, role: PunchRole) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(self.magic.punch_packet_size(self.session_key.is_some()));
+        packet.extend_from_slice(&self.magic.punch);
+        packet.push(role as u8);
+        packet.extend_from_slice(&self.tiebreak);
+        if let Some(mac) = self.punch_mac(&packet) {
+            packet.extend_from_slice(&mac.finalize().into_bytes());
+        }
         packet
     }
 
+    /// The relay both peers meet at, with the session they register in: a
+    /// MAC of a fixed context under the session key, so the relay does not
+    /// learn the key. `None` without a relay or a key.
+    fn relay_session(&self) -> Option<(SocketAddr, [u8; RELAY_SESSION_ID_SIZE])> {
+        let relay = self.relay?;
+        let mut mac = <Blake2sMac256 as KeyInit>::new_from_slice(self.session_key.as_deref()?)
+            .expect("session_key is exactly 32 bytes, which is valid for Blake2sMac256");
+        Mac::update(&mut mac, RELAY_SESSION_CONTEXT);
+        Some((relay, Mac::finalize(mac).into_bytes().into()))
+    }
+
+    /// Whether a probe sent to an address was meant for us: our socket's
+    /// address (its port on any host address, if bound to all of them) or
+    /// our public address, once learned.
+    fn addressed_to_us(&self) -> impl Fn(SocketAddr) -> bool {
+        let local = self.socket.local_addr().ok();
+        let wan = self.wan_addr;
+        move |addr| {
+            wan == Some(addr)
+                || match local {
+                    Some(local) if local.ip().is_unspecified() => local.port() == addr.port(),
+                    Some(local) => local == addr,
+                    None => false,
+                }
+        }
+    }
+
     /// Verify an authenticated punch packet using a constant-time MAC check.
     fn verify_punch_packet(&self, data: &[u8]) -> bool {
-        if data.len() != PUNCH_MESSAGE.len() + PUNCH_MAC_SIZE {
-            return false;
+        self.open_punch_packet(data).is_some()
+    }
+
+    /// The role and tiebreak of an authenticated punch packet; `None` if it
+    /// is malformed or fails the MAC check.
+    fn open_punch_packet(&self, data: &[u8]) -> Option<Punch> {
+        let keyed = self.session_key.is_some();
+        if !self.magic.is_punch(data, keyed) {
+            return None;
         }
-        if &data[..PUNCH_MESSAGE.len()] != PUNCH_MESSAGE {
-            return false;
+        let (body, tag) = data.split_at(data.len() - if keyed { PUNCH_MAC_SIZE } else { 0 });
+        if let Some(mac) = self.punch_mac(body) {
+            // verify_slice performs a constant-time comparison.
+            mac.verify_slice(tag).ok()?;
         }
-        let mut mac = <Blake2sMac256 as KeyInit>::new_from_slice(&self.session_key)
-            .expect("session_key is exactly 32 bytes, which is valid for Blake2sMac256");
-        Mac::update(&mut mac, PUNCH_MESSAGE);
-        // verify_slice performs a constant-time comparison.
-        mac.verify_slice(&data[PUNCH_MESSAGE.len()..]).is_ok()
+        let role = match body[self.magic.punch.len()] {
+            0 => PunchRole::Initiator,
+            1 => PunchRole::Responder,
+            _ => return None,
+        };
+        let tiebreak = body[self.magic.punch.len() + 1..].try_into().ok()?;
+        Some(Punch { role, tiebreak })
     }
 
     /// Initiate a holepunch attempt to a remote peer.
-    pub async fn initiate(&mut self, remote_candidates: Vec<Candidate>) -> Result<SocketAddr, HolepunchError> {
+    ///
+    /// Cancellation safe: dropping the future, for example when it loses a
+    /// `tokio::select!`, leaves the session ready for another attempt. Each
+    /// datagram is either sent whole or not at all, and the session keeps no
+    /// state between attempts. Late replies to the abandoned attempt carry the
+    /// same MAC and only open the same path.
+    pub async fn initiate(&mut self, remote_candidates: Vec<Candidate>) -> Result<HolepunchResult, HolepunchError> {
+        self.initiate_with_events(remote_candidates, |_| {}).await
+    }
+
+    /// [`initiate`](Self::initiate), reporting progress to `on_event` as it
+    /// goes.
+    ///
+    /// Every candidate is reported `Probing`, then `Punching` when its first
+    /// punch is sent. The attempt ends with `Established` for the winner, or
+    /// `CandidateFailed` for each candidate still pending. A relay fallback
+    /// reports the relay's address the same way. The result is the same as
+    /// `initiate` would return.
+    pub async fn initiate_with_events<F>(
+        &mut self,
+        remote_candidates: Vec<Candidate>,
+        mut on_event: F,
+    ) -> Result<HolepunchResult, HolepunchError>
+    where
+        F: FnMut(HolepunchEvent) + Send,
+    {
         if remote_candidates.is_empty() {
             return Err(HolepunchError::NoViableCandidates);
         }
+        let remote_candidates = match &self.port_prediction {
+            Some(prediction) => remote_candidates
+                .iter()
+                .flat_map(|candidate| match candidate.kind {
+                    CandidateKind::Wan => prediction.expand(candidate),
+                    _ => vec![candidate.clone()],
+                })
+                .collect(),
+            None => remote_candidates,
+        };
 
         // Probe all candidates to create NAT bindings
+        for candidate in &remote_candidates {
+            on_event(HolepunchEvent::Probing(candidate.addr));
+        }
         self.probe(&remote_candidates).await?;
 
-        // Try to establish connection with each candidate
-        for candidate in &remote_candidates {
-            // Send punch message
-            if let Ok(established_addr) = self.punch_to(candidate.addr).await {
-                return Ok(established_addr);
+        // Punch every candidate at once; the first to answer wins
+        self.tiebreak = self.fixed_tiebreak.unwrap_or_else(rand::random);
+        let targets = remote_candidates
+            .into_iter()
+            .map(|candidate| PunchTarget {
+                candidate,
+                relay_session: None,
+                first_sent: None,
+                last_sent: None,
+            })
+            .collect();
+        match (self.punch_all(targets, &mut on_event).await, self.relay_session()) {
+            (Err(HolepunchError::Timeout), Some((relay, session_id))) => {
+                tracing::debug!("Direct punch timed out, trying relay {}", relay);
+                let target = PunchTarget {
+                    candidate: Candidate {
+                        addr: relay,
+                        kind: CandidateKind::Relay,
+                    },
+                    relay_session: Some(session_id),
+                    first_sent: None,
+                    last_sent: None,
+                };
+                self.punch_all(vec![target], &mut on_event).await
             }
+            (result, _) => result,
         }
-
-        Err(HolepunchError::Timeout)
     }
 
     /// Respond to a remote initiation.
-    pub async fn respond(&mut self, remote_candidates: Vec<Candidate>) -> Result<SocketAddr, HolepunchError> {
+    ///
+    /// The punch may arrive from an address that is not among
+    /// `remote_candidates` (the peer's NAT picked another mapping); its kind is
+    /// then guessed from the address and its RTT is zero. With a relay
+    /// configured, we also wait for the peer's punch through the relay.
+    ///
+    /// Cancellation safe in the same way as [`initiate`](Self::initiate). A
+    /// punch received just before the future is dropped may go unanswered;
+    /// the initiator retransmits, so the next attempt answers it instead.
+    pub async fn respond(&mut self, remote_candidates: Vec<Candidate>) -> Result<HolepunchResult, HolepunchError> {
         if remote_candidates.is_empty() {
             return Err(HolepunchError::NoViableCandidates);
         }
 
         // Probe all candidates
+        let probed_at = tokio::time::Instant::now();
         self.probe(&remote_candidates).await?;
 
         // Listen for incoming punch messages and respond
-        match timeout(PUNCH_TIMEOUT, self.recv_and_respond()).await {
-            Ok(Ok(addr)) => Ok(addr),
-            Ok(Err(e)) => Err(e),
-            Err(_) => Err(HolepunchError::Timeout),
-        }
+        let addr = match timeout(PUNCH_TIMEOUT, self.recv_and_respond(&remote_candidates)).await {
+            Ok(Ok(addr)) => addr,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(HolepunchError::Timeout),
+        };
+        let result = match remote_candidates.iter().find(|c| c.addr == addr) {
+            _ if self.relay == Some(addr) => HolepunchResult {
+                addr,
+                kind: CandidateKind::Relay,
+                rtt: probed_at.elapsed(),
+                initiator: false,
+            },
+            Some(candidate) => HolepunchResult {
+                addr,
+                kind: candidate.kind.clone(),
+                rtt: probed_at.elapsed(),
+                initiator: false,
+            },
+            None => HolepunchResult {
+                addr,
+                kind: kind_of_address(&addr),
+                rtt: Duration::ZERO,
+                initiator: false,
+            },
+        };
+        Ok(result)
     }
 
-    /// Send probe packets to candidates.
+    /// Send authenticated probe packets to candidates.
     pub async fn probe(&mut self, candidates: &[Candidate]) -> Result<(), HolepunchError> {
-        let mut success_count = 0usize;
-        let mut last_error: Option<std::io::Error> = None;
-
-        for candidate in candidates {
-            // Send probe message to create NAT binding
-            match self.socket.send_to(PROBE_MESSAGE, candidate.addr).await {
-                Ok(_) => {
-                    success_count += 1;
-                }
-                Err(e) => {
-                    tracing::debug!("Probe attempt unsuccessful for candidate {}: {}", candidate.addr, e);
-                    last_error = Some(e);
-                }
-            }
-        }
-
-        if success_count == 0 {
-            if let Some(e) = last_error {
-                return Err(HolepunchError::Io(e));
-            } else {
-                // Defensive fallback: no successful probes and no captured error.
-                return Err(HolepunchError::NoViableCandidates);
-            }
-        }
-        Ok(())
+        probe_candidates(&*self.socket, &self.magic, self.session_key.as_deref(), candidates).await
     }
 
-    /// Attempt to punch through to a specific address.
+    /// Punch all `candidates` at once.
     ///
-    /// Sends an authenticated punch packet and retransmits every
-    /// [`PUNCH_RETRY_INTERVAL`] until the peer responds with a valid
-    /// authenticated punch packet or the 2-second deadline expires.
+    /// Sends authenticated punch packets to the candidates and retransmits to
+    /// those still pending every [`PUNCH_RETRY_INTERVAL`], until one of them
+    /// responds with a valid authenticated punch packet or the 2-second
+    /// deadline expires. Each interval sends at most the punch budget, to
+    /// the candidates punched least recently (see
+    /// [`with_punch_budget`](Self::with_punch_budget)).
     ///
-    /// Returns [`HolepunchError::AuthenticationFailed`] if a packet arrives from
-    /// the expected peer address but fails the MAC check (wrong session key).
-    async fn punch_to(&self, addr: SocketAddr) -> Result<SocketAddr, HolepunchError> {
-        let punch_packet = self.build_punch_packet();
-
-        // Buffer large enough for authenticated punch packet (PUNCH_MESSAGE + MAC).
-        let mut buf = vec![0u8; PUNCH_MESSAGE.len() + PUNCH_MAC_SIZE + 16];
-        let deadline = tokio::time::Instant::now() + Duration::from_secs(2);
-
-        // Send the first punch immediately.
-        self.socket.send_to(&punch_packet, addr).await?;
-
-        loop {
-            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
-            if remaining.is_zero() {
-                return Err(HolepunchError::Timeout);
+    /// A candidate whose punch reply fails the MAC check (wrong session key)
+    /// is given up on. If no candidate succeeds and any failed that way,
+    /// [`HolepunchError::AuthenticationFailed`] is returned.
+    ///
+    /// A punch from a peer that is initiating too settles the simultaneous
+    /// open: with the lower tiebreak the peer stays initiator, so we answer
+    /// it as responder; otherwise we keep punching until it answers us. On a
+    /// tie we draw a new tiebreak and keep punching with it.
+    ///
+    /// Progress goes to `on_event`, see [`HolepunchEvent`].
+    async fn punch_all<F>(&mut self, targets: Vec<PunchTarget>, on_event: &mut F) -> Result<HolepunchResult, HolepunchError>
+    where
+        F: FnMut(HolepunchEvent) + Send,
+    {
+        // Buffer large enough for an authenticated punch packet.
+        let mut buf = [0u8; PUNCH_BUFFER_SIZE];
+        let deadline = tokio::time::Instant::now() + PUNCH_DEADLINE;
+        let mut pending: Vec<PunchTarget> = Vec::with_capacity(targets.len());
+        for target in targets {
+            if !pending.iter().any(|p| p.candidate.addr == target.candidate.addr) {
+                pending.push(target);
             }
+        }
+        let mut auth_failed = false;
+        let mut punch_packet = self.build_punch_packet(PunchRole::Initiator);
 
+        // The first tick fires immediately, sending the first round of punches.
+        let mut retry = tokio::time::interval(PUNCH_RETRY_INTERVAL);
+        while !pending.is_empty() {
             // Use tokio::select! so the retransmit timer fires independently of
             // how many invalid/unauthenticated packets arrive on the socket.
             // Without this a flood of junk packets could starve the retry timer.
             tokio::select! {
-                result = self.socket.recv_from(&mut buf) => {
-                    match result {
-                        Ok((len, from_addr)) => {
-                            if from_addr == addr {
-                                if self.verify_punch_packet(&buf[..len]) {
-                                    return Ok(addr);
-                                } else if buf[..len].starts_with(PUNCH_MESSAGE) {
-                                    // Packet has the PUNCH_MESSAGE prefix but the
-                                    // MAC is wrong — the peer is using a different
-                                    // session key.
-                                    return Err(HolepunchError::AuthenticationFailed);
-                                }
-                                // Other packets from the expected peer (e.g. probes)
-                                // are silently ignored.
-                            }
-                            // Packets from other addresses are also ignored.
+                _ = tokio::time::sleep_until(deadline) => break,
+                _ = retry.tick() => {
+                    let mut turn: Vec<usize> = (0..pending.len()).collect();
+                    turn.sort_by_key(|&i| (pending[i].last_sent, punch_priority(&pending[i].candidate.kind)));
+                    turn.truncate(self.punch_budget);
+                    let now = tokio::time::Instant::now();
+                    for i in turn {
+                        let target = &mut pending[i];
+                        let addr = target.candidate.addr;
+                        if target.first_sent.is_none() {
+                            target.first_sent = Some(now);
+                            on_event(HolepunchEvent::Punching(addr));
+                        }
+                        target.last_sent = Some(now);
+                        let sent = match &target.relay_session {
+                            Some(session_id) => self.socket.send_to(&relay_packet(session_id, &punch_packet), addr).await,
+                            None => self.socket.send_to(&punch_packet, addr).await,
+                        };
+                        if let Err(e) = sent {
+                            tracing::debug!("Punch to {} unsuccessful: {}", addr, e);
                         }
-                        Err(e) => return Err(HolepunchError::Io(e)),
                     }
                 }
-                _ = tokio::time::sleep(PUNCH_RETRY_INTERVAL) => {
-                    // Retry interval elapsed — retransmit and loop.
-                    self.socket.send_to(&punch_packet, addr).await?;
+                result = self.source.recv_from(&*self.socket, &mut buf) => {
+                    let (len, from_addr) = result?;
+                    // Packets from addresses we are not punching are ignored,
+                    // as are ones too long to be a punch (truncated to fit).
+                    let Some(index) = pending.iter().position(|target| target.candidate.addr == from_addr) else {
+                        continue;
+                    };
+                    if len == buf.len() {
+                        continue;
+                    }
+                    if let Some(punch) = self.open_punch_packet(&buf[..len]) {
+                        let initiator = match punch.role {
+                            PunchRole::Initiator if punch.tiebreak == self.tiebreak => {
+                                tracing::debug!("Tiebreak tie with {}, drawing a new one", from_addr);
+                                self.tiebreak = rand::random();
+                                punch_packet = self.build_punch_packet(PunchRole::Initiator);
+                                continue;
+                            }
+                            PunchRole::Initiator if punch.tiebreak > self.tiebreak => continue,
+                            PunchRole::Initiator if punch.tiebreak < self.tiebreak => {
+                                let reply = self.build_punch_packet(PunchRole::Responder);
+                                self.socket.send_to(&reply, from_addr).await?;
+                                false
+                            }
+                            PunchRole::Initiator | PunchRole::Responder => true,
+                        };
+                        let target = pending.swap_remove(index);
+                        on_event(HolepunchEvent::Established(from_addr));
+                        return Ok(HolepunchResult {
+                            addr: from_addr,
+                            rtt: target.first_sent.map(|sent| sent.elapsed()).unwrap_or_default(),
+                            kind: target.candidate.kind,
+                            initiator,
+                        });
+                    } else if self.magic.is_punch(&buf[..len], self.session_key.is_some()) {
+                        // Packet has our punch prefix but the MAC is wrong —
+                        // this peer is using a different session key.
+                        auth_failed = true;
+                        pending.swap_remove(index);
+                        on_event(HolepunchEvent::CandidateFailed(from_addr));
+                    }
+                    // Other packets from the peer (e.g. probes) are silently ignored.
                 }
             }
         }
+
+        for target in &pending {
+            on_event(HolepunchEvent::CandidateFailed(target.candidate.addr));
+        }
+        if auth_failed {
+            Err(HolepunchError::AuthenticationFailed)
+        } else {
+            Err(HolepunchError::Timeout)
+        }
     }
 
     /// Receive an authenticated punch packet and respond in kind.
-    async fn recv_and_respond(&self) -> Result<SocketAddr, HolepunchError> {
-        let punch_packet = self.build_punch_packet();
-        let mut buf = vec![0u8; PUNCH_MESSAGE.len() + PUNCH_MAC_SIZE + 16];
+    ///
+    /// Authenticated probes met while waiting are answered with a probe, so
+    /// our NAT lets their source in too. Only probes addressed to us and
+    /// coming from one of `remote_candidates` are answered, once per address
+    /// and at most [`MAX_PROBES_ANSWERED`] in all.
+    async fn recv_and_respond(&mut self, remote_candidates: &[Candidate]) -> Result<SocketAddr, HolepunchError> {
+        let punch_packet = self.build_punch_packet(PunchRole::Responder);
+        let nonce: [u8; PROBE_NONCE_SIZE] = rand::random();
+        let addressed_to_us = self.addressed_to_us();
+        let mut probed_back: HashSet<SocketAddr> = HashSet::new();
+        let mut buf = [0u8; PUNCH_BUFFER_SIZE];
+        // Registering with the relay lets the peer's relayed punch reach us
+        let registration = self.relay_session().map(|(relay, session_id)| (relay, relay_packet(&session_id, &[])));
+        let mut register = tokio::time::interval(RELAY_REGISTER_INTERVAL);
         
         loop {
-            let (len, from_addr) = self.socket.recv_from(&mut buf).await?;
-            
-            if self.verify_punch_packet(&buf[..len]) {
-                // Respond with our own authenticated punch message.
-                self.socket.send_to(&punch_packet, from_addr).await?;
-                return Ok(from_addr);
+            tokio::select! {
+                _ = register.tick(), if registration.is_some() => {
+                    if let Some((relay, packet)) = &registration {
+                        self.socket.send_to(packet, *relay).await?;
+                    }
+                }
+                result = self.source.recv_from(&*self.socket, &mut buf) => {
+                    let (len, from_addr) = result?;
+                    // A packet filling the buffer was truncated: not a punch
+                    if len < buf.len() && self.verify_punch_packet(&buf[..len]) {
+                        // Respond with our own authenticated punch message.
+                        // Through a relay it goes back to the paired peer as is.
+                        self.socket.send_to(&punch_packet, from_addr).await?;
+                        return Ok(from_addr);
+                    }
+                    if probed_back.len() < MAX_PROBES_ANSWERED
+                        && remote_candidates.iter().any(|candidate| candidate.addr == from_addr)
+                        && self.magic.verify_probe(self.session_key.as_deref(), &buf[..len], &addressed_to_us)
+                        && probed_back.insert(from_addr)
+                    {
+                        let probe_packet = self.magic.probe_packet(self.session_key.as_deref(), &nonce, from_addr);
+                        self.socket.send_to(&probe_packet, from_addr).await?;
+                    }
+                    // Ignore unauthenticated or unexpected packets.
+                }
             }
-            // Ignore unauthenticated or unexpected packets.
         }
     }
 
@@ -260,6 +846,321 @@ impl HolepunchSession {
     pub fn local_addr(&self) -> Result<SocketAddr, HolepunchError> {
         Ok(self.socket.local_addr()?)
     }
+
+    /// Learn this session's public address from `stun_server`, as a `Wan`
+    /// candidate for the peer to punch. Authenticated probes the peer sends
+    /// there are answered from then on.
+    pub async fn wan_candidate(&mut self, stun_server: SocketAddr) -> Result<Candidate, HolepunchError> {
+        let addr = stun_binding(&*self.socket, &mut self.source, stun_server).await?;
+        self.wan_addr = Some(addr);
+        Ok(Candidate {
+            addr,
+            kind: CandidateKind::Wan,
+        })
+    }
+}
+
+/// Learn the public address `socket` is seen from by sending a STUN Binding
+/// request to `stun_server`.
+///
+/// The request is retransmitted until the server answers, for up to 3
+/// seconds, after which [`HolepunchError::Timeout`] is returned.
+pub async fn discover_wan(socket: &dyn PacketTransport, stun_server: SocketAddr) -> Result<SocketAddr, HolepunchError> {
+    stun_binding(socket, &mut PacketSource::Socket, stun_server).await
+}
+
+async fn stun_binding(
+    socket: &dyn PacketTransport,
+    source: &mut PacketSource,
+    stun_server: SocketAddr,
+) -> Result<SocketAddr, HolepunchError> {
+    let transaction_id: [u8; STUN_TRANSACTION_ID_SIZE] = rand::random();
+    let request = build_binding_request(&transaction_id);
+    let mut buf = [0u8; STUN_MAX_MESSAGE_SIZE];
+
+    let exchange = async {
+        let mut retry = tokio::time::interval(STUN_RETRY_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = retry.tick() => {
+                    socket.send_to(&request, stun_server).await?;
+                }
+                result = source.recv_from(socket, &mut buf) => {
+                    let (len, from_addr) = result?;
+                    // A packet filling the buffer may have been truncated
+                    if from_addr != stun_server || len == buf.len() {
+                        continue;
+                    }
+                    // Anything but the answer to our transaction is ignored
+                    if let Some(addr) = parse_binding_response(&buf[..len], &transaction_id)? {
+                        return Ok(addr);
+                    }
+                }
+            }
+        }
+    };
+    match timeout(STUN_TIMEOUT, exchange).await {
+        Ok(result) => result,
+        Err(_) => Err(HolepunchError::Timeout),
+    }
+}
+
+/// LAN candidates for every usable address on this host's interfaces,
+/// paired with `port`.
+///
+/// Loopback and link-local addresses are left out; see
+/// [`gather_local_candidates_with`] to include them.
+pub fn gather_local_candidates(port: u16) -> Vec<Candidate> {
+    gather_local_candidates_with(port, GatherOptions::default())
+}
+
+/// Like [`gather_local_candidates`], choosing which kinds of address to include.
+///
+/// IPv6 temporary and deprecated addresses are skipped where the host
+/// reports them. A host without usable interfaces yields no candidates.
+pub fn gather_local_candidates_with(port: u16, options: GatherOptions) -> Vec<Candidate> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            tracing::debug!("Failed to enumerate network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+    let unusable_v6 = unusable_ipv6_addrs();
+
+    let mut seen = HashSet::new();
+    let mut candidates = Vec::new();
+    for interface in interfaces {
+        if interface.is_loopback() && !options.include_loopback {
+            continue;
+        }
+        if interface.is_link_local() && !options.include_link_local {
+            continue;
+        }
+        let addr = match interface.ip() {
+            IpAddr::V4(ip) => SocketAddr::new(IpAddr::V4(ip), port),
+            IpAddr::V6(ip) if unusable_v6.contains(&ip) => continue,
+            IpAddr::V6(ip) => {
+                // Link-local addresses are only meaningful with their interface
+                let scope_id = if interface.is_link_local() { interface.index.unwrap_or(0) } else { 0 };
+                SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id))
+            }
+        };
+        if seen.insert(addr) {
+            candidates.push(Candidate {
+                addr,
+                kind: CandidateKind::Lan,
+            });
+        }
+    }
+    candidates
+}
+
+/// IPv6 addresses marked temporary or deprecated, which should not be handed
+/// to peers.
+#[cfg(target_os = "linux")]
+fn unusable_ipv6_addrs() -> HashSet<Ipv6Addr> {
+    const IFA_F_TEMPORARY: u8 = 0x01;
+    const IFA_F_DEPRECATED: u8 = 0x20;
+
+    // Lines look like: <32 hex digit address> <index> <prefix> <scope> <flags> <name>
+    let Ok(table) = std::fs::read_to_string("/proc/net/if_inet6") else {
+        return HashSet::new();
+    };
+    table
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let ip = u128::from_str_radix(fields.first()?, 16).ok()?;
+            let flags = u8::from_str_radix(fields.get(4)?, 16).ok()?;
+            (flags & (IFA_F_TEMPORARY | IFA_F_DEPRECATED) != 0).then(|| Ipv6Addr::from(ip))
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unusable_ipv6_addrs() -> HashSet<Ipv6Addr> {
+    HashSet::new()
+}
+
+/// Build a Binding request without attributes.
+fn build_binding_request(transaction_id: &[u8; STUN_TRANSACTION_ID_SIZE]) -> Vec<u8> {
+    let mut request = Vec::with_capacity(STUN_HEADER_SIZE);
+    request.extend_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes()); // message length
+    request.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(transaction_id);
+    request
+}
+
+/// Extract the mapped address from a Binding response.
+///
+/// Returns `Ok(None)` for datagrams that are not a response to
+/// `transaction_id`. `XOR-MAPPED-ADDRESS` is preferred over the legacy
+/// `MAPPED-ADDRESS`.
+fn parse_binding_response(
+    data: &[u8],
+    transaction_id: &[u8; STUN_TRANSACTION_ID_SIZE],
+) -> Result<Option<SocketAddr>, HolepunchError> {
+    if data.len() < STUN_HEADER_SIZE
+        || data[4..8] != STUN_MAGIC_COOKIE.to_be_bytes()
+        || data[8..STUN_HEADER_SIZE] != transaction_id[..]
+    {
+        return Ok(None);
+    }
+    match u16::from_be_bytes([data[0], data[1]]) {
+        STUN_BINDING_SUCCESS => {}
+        STUN_BINDING_ERROR => return Err(HolepunchError::Stun("binding error response".to_string())),
+        _ => return Ok(None),
+    }
+    let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+    let attributes = data
+        .get(STUN_HEADER_SIZE..STUN_HEADER_SIZE + length)
+        .ok_or_else(|| HolepunchError::Stun("truncated message".to_string()))?;
+
+    let mut mapped = None;
+    let mut offset = 0;
+    while offset + 4 <= attributes.len() {
+        let attr_type = u16::from_be_bytes([attributes[offset], attributes[offset + 1]]);
+        let attr_len = u16::from_be_bytes([attributes[offset + 2], attributes[offset + 3]]) as usize;
+        let value = attributes
+            .get(offset + 4..offset + 4 + attr_len)
+            .ok_or_else(|| HolepunchError::Stun("truncated attribute".to_string()))?;
+        match attr_type {
+            STUN_ATTR_XOR_MAPPED_ADDRESS => return decode_stun_address(value, Some(transaction_id)).map(Some),
+            STUN_ATTR_MAPPED_ADDRESS => mapped = Some(decode_stun_address(value, None)?),
+            _ => {}
+        }
+        // Attribute values are padded to a multiple of 4 bytes
+        offset += 4 + attr_len.div_ceil(4) * 4;
+    }
+    mapped
+        .map(Some)
+        .ok_or_else(|| HolepunchError::Stun("no mapped address".to_string()))
+}
+
+/// Decode a (XOR-)MAPPED-ADDRESS value; `transaction_id` is given for the
+/// XOR variant, whose port and address are masked with the magic cookie
+/// (and, for IPv6, the transaction id).
+fn decode_stun_address(
+    value: &[u8],
+    transaction_id: Option<&[u8; STUN_TRANSACTION_ID_SIZE]>,
+) -> Result<SocketAddr, HolepunchError> {
+    let invalid = || HolepunchError::Stun("malformed address attribute".to_string());
+    if value.len() < 4 {
+        return Err(invalid());
+    }
+    let mut mask = [0u8; 16];
+    if let Some(transaction_id) = transaction_id {
+        mask[..4].copy_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+        mask[4..].copy_from_slice(transaction_id);
+    }
+    let port = u16::from_be_bytes([value[2] ^ mask[0], value[3] ^ mask[1]]);
+    let ip = match (value[1], &value[4..]) {
+        (STUN_FAMILY_IPV4, octets) if octets.len() == 4 => {
+            let mut ip = [0u8; 4];
+            for (i, byte) in ip.iter_mut().enumerate() {
+                *byte = octets[i] ^ mask[i];
+            }
+            IpAddr::V4(Ipv4Addr::from(ip))
+        }
+        (STUN_FAMILY_IPV6, octets) if octets.len() == 16 => {
+            let mut ip = [0u8; 16];
+            for (i, byte) in ip.iter_mut().enumerate() {
+                *byte = octets[i] ^ mask[i];
+            }
+            IpAddr::V6(Ipv6Addr::from(ip))
+        }
+        _ => return Err(invalid()),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// A candidate being punched by [`HolepunchSession::punch_all`].
+struct PunchTarget {
+    candidate: Candidate,
+    /// The relay session to wrap its punches for, if it is a relay.
+    relay_session: Option<[u8; RELAY_SESSION_ID_SIZE]>,
+    /// When the first punch went to it, for the RTT.
+    first_sent: Option<tokio::time::Instant>,
+    /// When the latest punch went to it, for taking turns.
+    last_sent: Option<tokio::time::Instant>,
+}
+
+/// The order candidates of equal standing are punched in: the nearest
+/// paths first.
+fn punch_priority(kind: &CandidateKind) -> u8 {
+    match kind {
+        CandidateKind::Lan => 0,
+        CandidateKind::Wan => 1,
+        CandidateKind::Relay => 2,
+    }
+}
+
+/// Classify an address that was not among the known candidates.
+fn kind_of_address(addr: &SocketAddr) -> CandidateKind {
+    let local = match addr.ip() {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        // Unique local (fc00::/7) and link-local (fe80::/10)
+        IpAddr::V6(ip) => ip.is_loopback() || (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80,
+    };
+    if local {
+        CandidateKind::Lan
+    } else {
+        CandidateKind::Wan
+    }
+}
+
+/// The Blake2s MAC of `body` under `session_key`.
+fn session_mac(session_key: &[u8; 32], body: &[u8]) -> Blake2sMac256 {
+    let mut mac = <Blake2sMac256 as KeyInit>::new_from_slice(session_key)
+        .expect("session_key is exactly 32 bytes, which is valid for Blake2sMac256");
+    Mac::update(&mut mac, body);
+    mac
+}
+
+/// Send a probe, authenticated if there is a `session_key`, to every
+/// candidate from `socket`, creating NAT bindings towards them. The probes
+/// share a nonce drawn for this call. Succeeds if any probe could be sent.
+pub(crate) async fn probe_candidates(
+    socket: &dyn PacketTransport,
+    magic: &PacketMagic,
+    session_key: Option<&[u8; 32]>,
+    candidates: &[Candidate],
+) -> Result<(), HolepunchError> {
+    let mut success_count = 0usize;
+    let mut last_error: Option<std::io::Error> = None;
+    let nonce: [u8; PROBE_NONCE_SIZE] = rand::random();
+
+    for candidate in candidates {
+        // Send probe message to create NAT binding
+        let probe_packet = magic.probe_packet(session_key, &nonce, candidate.addr);
+        match socket.send_to(&probe_packet, candidate.addr).await {
+            Ok(_) => {
+                success_count += 1;
+            }
+            Err(e) => {
+                tracing::debug!("Probe attempt unsuccessful for candidate {}: {}", candidate.addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    if success_count == 0 {
+        if let Some(e) = last_error {
+            return Err(HolepunchError::Io(e));
+        } else {
+            // Defensive fallback: no successful probes and no captured error.
+            return Err(HolepunchError::NoViableCandidates);
+        }
+    }
+    Ok(())
+}
+
+/// Whether `data` is a probe or punch packet (authenticated or not, under
+/// any application tag).
+pub(crate) fn is_holepunch_packet(data: &[u8]) -> bool {
+    data.starts_with(PROBE_MESSAGE) || data.starts_with(PUNCH_MESSAGE)
 }
 
 #[cfg(test)]
@@ -319,7 +1220,7 @@ mod tests {
             .await
             .unwrap();
 
-        let packet = session.build_punch_packet();
+        let packet = session.build_punch_packet(PunchRole::Initiator);
         assert!(session.verify_punch_packet(&packet), "valid packet should pass MAC check");
     }
 
@@ -336,7 +1237,7 @@ mod tests {
             .unwrap();
 
         // A packet built with key_a must be rejected by a session using key_b.
-        let packet = session_a.build_punch_packet();
+        let packet = session_a.build_punch_packet(PunchRole::Initiator);
         assert!(
             !session_b.verify_punch_packet(&packet),
             "packet from a different key should fail MAC check"
@@ -356,19 +1257,481 @@ mod tests {
         );
     }
 
+    // Transaction id of the RFC 5769 sample responses
+    const RFC5769_TRANSACTION_ID: [u8; 12] = [
+        0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+    ];
+
+    /// An RFC 5769 sample response carrying a SOFTWARE attribute and the given
+    /// XOR-MAPPED-ADDRESS value.
+    fn rfc5769_response(xor_mapped_address: &[u8]) -> Vec<u8> {
+        let mut attributes = Vec::new();
+        attributes.extend_from_slice(&[0x80, 0x22, 0x00, 0x0b]);
+        attributes.extend_from_slice(b"test vector ");
+        attributes.extend_from_slice(&STUN_ATTR_XOR_MAPPED_ADDRESS.to_be_bytes());
+        attributes.extend_from_slice(&(xor_mapped_address.len() as u16).to_be_bytes());
+        attributes.extend_from_slice(xor_mapped_address);
+
+        let mut response = Vec::new();
+        response.extend_from_slice(&STUN_BINDING_SUCCESS.to_be_bytes());
+        response.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+        response.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+        response.extend_from_slice(&RFC5769_TRANSACTION_ID);
+        response.extend_from_slice(&attributes);
+        response
+    }
+
+    #[test]
+    fn test_parse_xor_mapped_address_ipv4() {
+        // RFC 5769 section 2.2: 192.0.2.1:32853
+        let response = rfc5769_response(&[0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]);
+        let addr = parse_binding_response(&response, &RFC5769_TRANSACTION_ID).unwrap();
+        assert_eq!(addr, Some("192.0.2.1:32853".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_xor_mapped_address_ipv6() {
+        // RFC 5769 section 2.3: [2001:db8:1234:5678:11:2233:4455:6677]:32853
+        let response = rfc5769_response(&[
+            0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5,
+            0xbe, 0xd2, 0xb9, 0xd9,
+        ]);
+        let addr = parse_binding_response(&response, &RFC5769_TRANSACTION_ID).unwrap();
+        assert_eq!(addr, Some("[2001:db8:1234:5678:11:2233:4455:6677]:32853".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_ignores_other_transactions() {
+        let response = rfc5769_response(&[0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]);
+        assert_eq!(parse_binding_response(&response, &[0u8; 12]).unwrap(), None);
+        assert_eq!(parse_binding_response(PROBE_MESSAGE, &RFC5769_TRANSACTION_ID).unwrap(), None);
+
+        // A truncated attribute is an error once the transaction matches
+        let mut truncated = response.clone();
+        truncated.truncate(truncated.len() - 2);
+        let length = (truncated.len() - STUN_HEADER_SIZE) as u16;
+        truncated[2..4].copy_from_slice(&length.to_be_bytes());
+        assert!(parse_binding_response(&truncated, &RFC5769_TRANSACTION_ID).is_err());
+    }
+
+    /// A STUN server that answers every Binding request with the sender's
+    /// address, masked as XOR-MAPPED-ADDRESS.
+    async fn spawn_mock_stun_server() -> SocketAddr {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            loop {
+                let Ok((len, from)) = server.recv_from(&mut buf).await else { break };
+                if len < STUN_HEADER_SIZE || buf[..2] != STUN_BINDING_REQUEST.to_be_bytes() {
+                    continue;
+                }
+                let SocketAddr::V4(from_v4) = from else { continue };
+                let cookie = STUN_MAGIC_COOKIE.to_be_bytes();
+                let mut value = vec![0x00, STUN_FAMILY_IPV4];
+                value.extend_from_slice(&(from.port() ^ (STUN_MAGIC_COOKIE >> 16) as u16).to_be_bytes());
+                value.extend(from_v4.ip().octets().iter().zip(cookie).map(|(b, m)| b ^ m));
+
+                let mut response = STUN_BINDING_SUCCESS.to_be_bytes().to_vec();
+                response.extend_from_slice(&(4 + value.len() as u16).to_be_bytes());
+                response.extend_from_slice(&buf[4..STUN_HEADER_SIZE]);
+                response.extend_from_slice(&STUN_ATTR_XOR_MAPPED_ADDRESS.to_be_bytes());
+                response.extend_from_slice(&(value.len() as u16).to_be_bytes());
+                response.extend_from_slice(&value);
+                let _ = server.send_to(&response, from).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_discover_wan_against_mock_server() {
+        let stun_server = spawn_mock_stun_server().await;
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let observed = discover_wan(&socket, stun_server).await.unwrap();
+        assert_eq!(observed, socket.local_addr().unwrap());
+
+        // Sessions learn the mapping of their own socket
+        let mut session = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_SESSION_KEY)
+            .await
+            .unwrap();
+        let candidate = session.wan_candidate(stun_server).await.unwrap();
+        assert_eq!(candidate.addr, session.local_addr().unwrap());
+        assert!(matches!(candidate.kind, CandidateKind::Wan));
+    }
+
+    #[test]
+    fn test_port_prediction_expands_around_base() {
+        let candidate = Candidate {
+            addr: "198.51.100.7:1000".parse().unwrap(),
+            kind: CandidateKind::Wan,
+        };
+        let ports: Vec<u16> = PortPrediction { window: 2, stride: 4 }
+            .expand(&candidate)
+            .iter()
+            .map(|c| c.addr.port())
+            .collect();
+        assert_eq!(ports, vec![1000, 1004, 996, 1008, 992]);
+
+        // Ports past either end of the range are dropped
+        let low = Candidate {
+            addr: "198.51.100.7:1".parse().unwrap(),
+            kind: CandidateKind::Wan,
+        };
+        let ports: Vec<u16> = PortPrediction { window: 2, stride: 1 }
+            .expand(&low)
+            .iter()
+            .map(|c| c.addr.port())
+            .collect();
+        assert_eq!(ports, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_gather_local_candidates_includes_loopback_on_request() {
+        let options = GatherOptions {
+            include_loopback: true,
+            ..Default::default()
+        };
+        let candidates = gather_local_candidates_with(4000, options);
+        assert!(candidates.iter().any(|c| c.addr == "127.0.0.1:4000".parse().unwrap()));
+        assert!(candidates.iter().all(|c| matches!(c.kind, CandidateKind::Lan) && c.addr.port() == 4000));
+
+        // The default mode leaves loopback out
+        assert!(gather_local_candidates(4000).iter().all(|c| !c.addr.ip().is_loopback()));
+    }
+
     #[tokio::test]
     async fn test_punch_mac_tampered_payload_rejected() {
         let session = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_SESSION_KEY)
             .await
             .unwrap();
 
-        let mut packet = session.build_punch_packet();
+        let mut packet = session.build_punch_packet(PunchRole::Initiator);
         // Flip a bit in the MAC portion.
-        let mac_start = PUNCH_MESSAGE.len();
+        let mac_start = packet.len() - PUNCH_MAC_SIZE;
         packet[mac_start] ^= 0xFF;
         assert!(
             !session.verify_punch_packet(&packet),
             "tampered MAC should be rejected"
         );
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_silent_candidate_is_reported_failed() {
+        let network = crate::packet::MemoryNetwork::new();
+        let socket = network.bind("10.0.0.1:5000".parse().unwrap()).unwrap();
+        let silent = "10.0.0.2:5000".parse().unwrap();
+        let _peer = network.bind(silent).unwrap();
+        let mut session = HolepunchSession::with_transport(socket, TEST_SESSION_KEY);
+
+        let mut events = Vec::new();
+        let candidates = vec![Candidate { addr: silent, kind: CandidateKind::Lan }];
+        let result = session.initiate_with_events(candidates, |event| events.push(event)).await;
+        assert!(matches!(result, Err(HolepunchError::Timeout)), "got {:?}", result);
+        assert_eq!(
+            events,
+            vec![
+                HolepunchEvent::Probing(silent),
+                HolepunchEvent::Punching(silent),
+                HolepunchEvent::CandidateFailed(silent),
+            ]
+        );
+    }
+
+    /// A transport logging when each punch packet goes out, and where to.
+    struct PunchLog {
+        inner: Arc<dyn PacketTransport>,
+        punches: std::sync::Mutex<Vec<(tokio::time::Instant, SocketAddr)>>,
+    }
+
+    impl PacketTransport for PunchLog {
+        fn poll_send_to(
+            &self,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+            target: SocketAddr,
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            let sent = self.inner.poll_send_to(cx, buf, target);
+            if sent.is_ready() && buf.starts_with(PUNCH_MESSAGE) {
+                self.punches.lock().unwrap().push((tokio::time::Instant::now(), target));
+            }
+            sent
+        }
+
+        fn poll_recv_from(
+            &self,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<SocketAddr>> {
+            self.inner.poll_recv_from(cx, buf)
+        }
+
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            self.inner.local_addr()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_punch_retransmits_stay_within_the_budget() {
+        let network = crate::packet::MemoryNetwork::new();
+        let log = Arc::new(PunchLog {
+            inner: network.bind("10.0.0.1:5000".parse().unwrap()).unwrap(),
+            punches: std::sync::Mutex::new(Vec::new()),
+        });
+        let mut session = HolepunchSession::with_transport(log.clone(), TEST_SESSION_KEY).with_punch_budget(10);
+
+        // 60 silent candidates, the kinds interleaved
+        let kinds = [CandidateKind::Relay, CandidateKind::Wan, CandidateKind::Lan];
+        let candidates: Vec<Candidate> = (0..60u8)
+            .map(|i| Candidate {
+                addr: SocketAddr::from(([10, 0, 1, i], 5000)),
+                kind: kinds[usize::from(i) % 3].clone(),
+            })
+            .collect();
+        let kind_of = |addr: &SocketAddr| candidates.iter().find(|c| c.addr == *addr).unwrap().kind.clone();
+        let result = session.initiate(candidates.clone()).await;
+        assert!(matches!(result, Err(HolepunchError::Timeout)), "got {:?}", result);
+
+        let punches = log.punches.lock().unwrap().clone();
+        let start = punches[0].0;
+        let mut per_interval: Vec<Vec<SocketAddr>> = Vec::new();
+        for (at, addr) in &punches {
+            let interval = ((*at - start).as_millis() / PUNCH_RETRY_INTERVAL.as_millis()) as usize;
+            per_interval.resize(per_interval.len().max(interval + 1), Vec::new());
+            per_interval[interval].push(*addr);
+        }
+        assert!(per_interval.iter().all(|round| round.len() <= 10), "{:?}", per_interval);
+        assert!(per_interval.len() >= 10, "punching should go on until the deadline");
+
+        // Everyone gets a turn, nearest paths first, before anyone gets a second
+        let first_punches: Vec<CandidateKind> = per_interval.concat()[..60].iter().map(kind_of).collect();
+        assert!(first_punches[..20].iter().all(|kind| *kind == CandidateKind::Lan));
+        assert!(first_punches[20..40].iter().all(|kind| *kind == CandidateKind::Wan));
+        assert!(first_punches[40..].iter().all(|kind| *kind == CandidateKind::Relay));
+        let distinct: HashSet<SocketAddr> = per_interval.concat()[..60].iter().copied().collect();
+        assert_eq!(distinct.len(), 60);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_each_attempt_draws_a_new_tiebreak() {
+        let network = crate::packet::MemoryNetwork::new();
+        let socket = network.bind("10.0.0.1:5000".parse().unwrap()).unwrap();
+        let silent = Candidate {
+            addr: "10.0.0.2:5000".parse().unwrap(),
+            kind: CandidateKind::Lan,
+        };
+        let mut session = HolepunchSession::with_transport(socket, TEST_SESSION_KEY);
+
+        let mut drawn = HashSet::new();
+        for _ in 0..3 {
+            assert!(session.initiate(vec![silent.clone()]).await.is_err());
+            drawn.insert(session.tiebreak);
+        }
+        assert_eq!(drawn.len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tied_tiebreaks_are_drawn_again() {
+        let network = crate::packet::MemoryNetwork::new();
+        let addr1: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let addr2: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let session = |addr| HolepunchSession::with_transport(network.bind(addr).unwrap(), TEST_SESSION_KEY).with_tiebreak([1; 32]);
+        let (mut session1, mut session2) = (session(addr1), session(addr2));
+        let lan = |addr| vec![Candidate { addr, kind: CandidateKind::Lan }];
+
+        let (punched1, punched2) = tokio::join!(session1.initiate(lan(addr2)), session2.initiate(lan(addr1)));
+        let (punched1, punched2) = (punched1.unwrap(), punched2.unwrap());
+        assert_ne!(session1.tiebreak, session2.tiebreak);
+        assert_ne!(punched1.initiator, punched2.initiator, "exactly one side initiates");
+        assert_eq!(punched1.initiator, session1.tiebreak < session2.tiebreak);
+    }
+
+    #[tokio::test]
+    async fn test_session_key_is_held_in_zeroizing() {
+        let session = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_SESSION_KEY).await.unwrap();
+        let _: &Option<Zeroizing<[u8; 32]>> = &session.session_key;
+        assert_eq!(session.session_key.as_deref(), Some(&TEST_SESSION_KEY));
+    }
+
+    #[tokio::test]
+    async fn test_sessions_without_a_key_send_no_mac() {
+        let socket = || async { Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()) };
+        let keyless = HolepunchSession::with_source(socket().await, PacketSource::Socket, None);
+        let keyed = HolepunchSession::with_transport(socket().await, TEST_SESSION_KEY);
+
+        let punch = keyless.build_punch_packet(PunchRole::Initiator);
+        assert_eq!(punch.len(), PUNCH_PACKET_SIZE - PUNCH_MAC_SIZE);
+        assert!(keyless.verify_punch_packet(&punch));
+        assert!(!keyed.verify_punch_packet(&punch));
+        assert!(!keyless.verify_punch_packet(&keyed.build_punch_packet(PunchRole::Initiator)));
+        assert_eq!(keyless.magic.probe_packet(None, &[0u8; PROBE_NONCE_SIZE], keyed.local_addr().unwrap()), PROBE_MESSAGE);
+
+        // Nor can it meet its peer at a relay
+        assert_eq!(keyless.with_relay("127.0.0.1:1".parse().unwrap()).relay_session(), None);
+    }
+
+    #[test]
+    fn test_probe_mac_is_verified() {
+        let magic = PacketMagic::default();
+        let to: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let ours = |addr| addr == to;
+        let packet = magic.probe_packet(Some(&TEST_SESSION_KEY), &[7u8; PROBE_NONCE_SIZE], to);
+        assert!(magic.verify_probe(Some(&TEST_SESSION_KEY), &packet, ours));
+        assert!(!magic.verify_probe(Some(&[0x13u8; 32]), &packet, ours), "other key");
+        assert!(!magic.verify_probe(Some(&TEST_SESSION_KEY), PROBE_MESSAGE, ours), "no MAC");
+        assert!(!magic.verify_probe(None, &packet, ours), "MAC without a key");
+        let mut tampered = packet.clone();
+        *tampered.last_mut().unwrap() ^= 0xFF;
+        assert!(!magic.verify_probe(Some(&TEST_SESSION_KEY), &tampered, ours), "tampered MAC");
+
+        // The nonce and address are covered by the MAC
+        let mut renonced = packet.clone();
+        renonced[PROBE_MESSAGE.len()] ^= 0xFF;
+        assert!(!magic.verify_probe(Some(&TEST_SESSION_KEY), &renonced, ours), "tampered nonce");
+        let mut readdressed = packet.clone();
+        readdressed[PROBE_MESSAGE.len() + PROBE_NONCE_SIZE + 3] ^= 0x01;
+        assert!(!magic.verify_probe(Some(&TEST_SESSION_KEY), &readdressed, |_| true), "tampered address");
+        // A genuine probe sent to someone else is not for us
+        assert!(!magic.verify_probe(Some(&TEST_SESSION_KEY), &packet, |addr| addr != to), "replayed elsewhere");
+    }
+
+    #[tokio::test]
+    async fn test_probes_are_meant_for_the_socket_address() {
+        let session = HolepunchSession::new("0.0.0.0:0".parse().unwrap(), TEST_SESSION_KEY).await.unwrap();
+        let port = session.local_addr().unwrap().port();
+        let addressed_to_us = session.addressed_to_us();
+        // Bound to every address, any of them with our port will do
+        assert!(addressed_to_us(SocketAddr::from(([192, 168, 1, 7], port))));
+        assert!(!addressed_to_us(SocketAddr::from(([192, 168, 1, 7], port.wrapping_add(1)))));
+
+        let mut session = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_SESSION_KEY).await.unwrap();
+        let local = session.local_addr().unwrap();
+        let public = SocketAddr::from(([203, 0, 113, 5], 40000));
+        assert!(session.addressed_to_us()(local));
+        assert!(!session.addressed_to_us()(public));
+        // A learned public address counts too
+        session.wan_addr = Some(public);
+        assert!(session.addressed_to_us()(public));
+    }
+
+    #[tokio::test]
+    async fn test_app_id_namespaces_packets() {
+        let mut sessions = Vec::new();
+        for app_id in [None, Some(b"app-a"), Some(b"app-b")] {
+            let session = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_SESSION_KEY).await.unwrap();
+            sessions.push(match app_id {
+                Some(app_id) => session.with_app_id(app_id),
+                None => session,
+            });
+        }
+        for (i, ours) in sessions.iter().enumerate() {
+            let probe = ours.magic.probe_packet(Some(&TEST_SESSION_KEY), &[0u8; PROBE_NONCE_SIZE], ours.local_addr().unwrap());
+            let punch = ours.build_punch_packet(PunchRole::Initiator);
+            // Whatever the tag, the shared socket still routes them to holepunching
+            assert!(is_holepunch_packet(&probe) && is_holepunch_packet(&punch));
+            assert!(punch.starts_with(PUNCH_MESSAGE));
+            for (j, theirs) in sessions.iter().enumerate() {
+                assert_eq!(theirs.magic.verify_probe(Some(&TEST_SESSION_KEY), &probe, |_| true), i == j);
+                assert_eq!(theirs.verify_punch_packet(&punch), i == j);
+                assert_eq!(theirs.magic.is_punch(&punch, true), i == j, "not mistaken for a wrongly keyed punch");
+            }
+        }
+        // The id is all the tag depends on
+        assert_eq!(PacketMagic::for_app(b"app-a"), sessions[1].magic);
+        assert_eq!(sessions[0].magic.probe, PROBE_MESSAGE, "no id, no tag");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sessions_with_other_app_ids_ignore_each_other() {
+        let network = crate::packet::MemoryNetwork::new();
+        let attempt = |responder: Option<&'static [u8]>, initiator: Option<&'static [u8]>, subnet: u8| {
+            let responder_addr = SocketAddr::from(([10, 0, subnet, 1], 5000));
+            let initiator_addr = SocketAddr::from(([10, 0, subnet, 2], 5000));
+            let session = |addr, app_id: Option<&[u8]>| {
+                let session = HolepunchSession::with_transport(network.bind(addr).unwrap(), TEST_SESSION_KEY);
+                match app_id {
+                    Some(app_id) => session.with_app_id(app_id),
+                    None => session,
+                }
+            };
+            let mut responder = session(responder_addr, responder);
+            let mut initiator = session(initiator_addr, initiator);
+            async move {
+                let responding = tokio::spawn(async move {
+                    responder.respond(vec![Candidate { addr: initiator_addr, kind: CandidateKind::Lan }]).await
+                });
+                let initiated = initiator.initiate(vec![Candidate { addr: responder_addr, kind: CandidateKind::Lan }]).await;
+                let responded = timeout(Duration::from_secs(1), responding).await.map(|joined| joined.unwrap());
+                (initiated, responded)
+            }
+        };
+
+        // Other ids, or an id against none: no answer to probes or punches,
+        // and no authentication failure either
+        for (subnet, (a, b)) in [(Some(b"app-a".as_slice()), Some(b"app-b".as_slice())), (Some(b"app-a"), None), (None, Some(b"app-b"))]
+            .into_iter()
+            .enumerate()
+        {
+            let (initiated, responded) = attempt(a, b, subnet as u8).await;
+            assert!(matches!(initiated, Err(HolepunchError::Timeout)), "{:?} against {:?}", a, b);
+            assert!(responded.is_err(), "responder should still be waiting");
+        }
+
+        // The same id punches through
+        let (initiated, responded) = attempt(Some(b"app-a"), Some(b"app-a"), 10).await;
+        assert_eq!(initiated.expect("same app id should punch").addr, SocketAddr::from(([10, 0, 10, 1], 5000)));
+        responded.expect("responder should finish").expect("responder should accept the punch");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_responder_answers_only_authenticated_probes() {
+        let network = crate::packet::MemoryNetwork::new();
+        let responder_addr: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let peer_addr: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let mut session =
+            HolepunchSession::with_transport(network.bind(responder_addr).unwrap(), TEST_SESSION_KEY);
+        let peer = network.bind(peer_addr).unwrap();
+        let stranger = network.bind("10.0.0.3:5000".parse().unwrap()).unwrap();
+        let responding = tokio::spawn(async move {
+            let candidates = vec![Candidate { addr: peer_addr, kind: CandidateKind::Lan }];
+            session.respond(candidates).await
+        });
+
+        let recv = || async {
+            let mut buf = [0u8; 256];
+            let received = timeout(Duration::from_secs(1), peer.recv_from(&mut buf)).await;
+            received.ok().map(|r| buf[..r.unwrap().0].to_vec())
+        };
+        // The responder's own probe is authenticated and addressed to the peer
+        let magic = PacketMagic::default();
+        let nonce = [7u8; PROBE_NONCE_SIZE];
+        let probe = recv().await.expect("responder should probe its candidates");
+        assert!(magic.verify_probe(Some(&TEST_SESSION_KEY), &probe, |addr| addr == peer_addr));
+
+        // Bare, wrongly keyed and misaddressed probes draw nothing
+        peer.send_to(PROBE_MESSAGE, responder_addr).await.unwrap();
+        peer.send_to(&magic.probe_packet(Some(&[0x13u8; 32]), &nonce, responder_addr), responder_addr).await.unwrap();
+        peer.send_to(&magic.probe_packet(Some(&TEST_SESSION_KEY), &nonce, peer_addr), responder_addr).await.unwrap();
+        assert_eq!(recv().await, None);
+
+        // Nor does a valid probe from outside the peer's candidates, as a
+        // replay with a spoofed source would be
+        let valid = magic.probe_packet(Some(&TEST_SESSION_KEY), &nonce, responder_addr);
+        stranger.send_to(&valid, responder_addr).await.unwrap();
+        let mut buf = [0u8; 256];
+        assert!(timeout(Duration::from_secs(1), stranger.recv_from(&mut buf)).await.is_err());
+
+        // From the peer it is answered with a probe, once per address
+        peer.send_to(&valid, responder_addr).await.unwrap();
+        let reply = recv().await.expect("authenticated probe should be answered");
+        assert!(magic.verify_probe(Some(&TEST_SESSION_KEY), &reply, |addr| addr == peer_addr));
+        peer.send_to(&valid, responder_addr).await.unwrap();
+        assert_eq!(recv().await, None);
+
+        // The punch still completes the exchange
+        let punch = HolepunchSession::with_transport(peer.clone(), TEST_SESSION_KEY).build_punch_packet(PunchRole::Initiator);
+        peer.send_to(&punch, responder_addr).await.unwrap();
+        let result = responding.await.unwrap().expect("responder should accept the punch");
+        assert_eq!(result.addr, peer_addr);
+    }
 }
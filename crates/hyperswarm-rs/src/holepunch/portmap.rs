@@ -0,0 +1,625 @@
+//! Port mappings requested from the gateway.
+//!
+//! A peer whose gateway forwards a public port to it can be connected to
+//! directly, without punching. [`map_port`] asks the default gateway for a
+//! UDP mapping with NAT-PMP (RFC 6886) and, when that fails, searches the
+//! LAN for a UPnP Internet Gateway Device and asks that instead. Either way
+//! the lease is renewed until the returned [`PortMapping`] is dropped. The
+//! external address is a `Wan` candidate; hand it to
+//! [`Hyperswarm::add_candidate`](crate::Hyperswarm::add_candidate) so
+//! discovery publishes it.
+//!
+//! The default gateway is read from `/proc/net/route`, so [`map_port`] only
+//! tries NAT-PMP on Linux and goes straight to UPnP elsewhere. Given the
+//! gateway's address, [`map_port_with_gateway`] speaks NAT-PMP anywhere.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+
+use igd_next::aio::tokio::Tokio;
+use igd_next::aio::Gateway;
+use igd_next::{PortMappingProtocol, SearchError, SearchOptions};
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Duration};
+use tokio_util::sync::CancellationToken;
+
+use super::{Candidate, CandidateKind, HolepunchError};
+
+/// Port NAT-PMP gateways listen on.
+pub const NAT_PMP_PORT: u16 = 5351;
+const NAT_PMP_VERSION: u8 = 0;
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+/// Set in the opcode of every response.
+const OP_RESPONSE: u8 = 0x80;
+const RESULT_SUCCESS: u16 = 0;
+const EXTERNAL_ADDRESS_RESPONSE_SIZE: usize = 12;
+const MAP_RESPONSE_SIZE: usize = 16;
+/// Wait before the first retransmission; each further one waits twice as
+/// long (RFC 6886 section 3.1).
+const INITIAL_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+/// Overall time allowed for one request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+/// Route flag of a route through a gateway, in `/proc/net/route`.
+#[cfg(target_os = "linux")]
+const RTF_GATEWAY: u32 = 0x2;
+/// Where UPnP devices listen for SSDP searches.
+pub const SSDP_MULTICAST_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 1900));
+/// Time allowed for an Internet Gateway Device to answer the search.
+const IGD_SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+/// How our mappings are labelled in the gateway's table.
+const IGD_DESCRIPTION: &str = "hyperswarm";
+
+/// A UDP port mapping on the gateway, renewed until dropped.
+///
+/// Dropping it stops the renewals and asks the gateway to remove the
+/// mapping.
+#[derive(Debug)]
+pub struct PortMapping {
+    external: Arc<Mutex<SocketAddr>>,
+    internal_port: u16,
+    stop: CancellationToken,
+}
+
+impl PortMapping {
+    /// The public address the gateway forwards to the internal port. A
+    /// renewal may move it, if the gateway changes its mind.
+    pub fn external_addr(&self) -> SocketAddr {
+        *self.external.lock().expect("external address lock poisoned")
+    }
+
+    pub fn internal_port(&self) -> u16 {
+        self.internal_port
+    }
+
+    /// The external address as a holepunch candidate.
+    pub fn candidate(&self) -> Candidate {
+        Candidate {
+            addr: self.external_addr(),
+            kind: CandidateKind::Wan,
+        }
+    }
+}
+
+impl Drop for PortMapping {
+    fn drop(&mut self) {
+        // The renewal task removes the mapping and ends; should the runtime
+        // be gone first, the lease simply expires.
+        self.stop.cancel();
+    }
+}
+
+/// Map `internal_port` on this host for `lease_secs` seconds through the
+/// default gateway, renewing the lease until the mapping is dropped.
+///
+/// Tries NAT-PMP first, then UPnP IGD. When neither works, fails with the
+/// UPnP error: [`HolepunchError::Timeout`] when no gateway answers the
+/// search, or [`HolepunchError::PortMapping`] when one refuses.
+pub async fn map_port(internal_port: u16, lease_secs: u32) -> Result<PortMapping, HolepunchError> {
+    let nat_pmp = default_gateway().map(|gateway| SocketAddr::new(gateway.into(), NAT_PMP_PORT));
+    map_port_via(nat_pmp, SSDP_MULTICAST_ADDR, internal_port, lease_secs).await
+}
+
+/// Map with NAT-PMP at `nat_pmp`, if there is a gateway to ask, and fall
+/// back to UPnP IGD found by searching at `ssdp`.
+async fn map_port_via(
+    nat_pmp: Result<SocketAddr, HolepunchError>,
+    ssdp: SocketAddr,
+    internal_port: u16,
+    lease_secs: u32,
+) -> Result<PortMapping, HolepunchError> {
+    let nat_pmp = match nat_pmp {
+        Ok(gateway) => map_port_with_gateway(gateway, internal_port, lease_secs).await,
+        Err(e) => Err(e),
+    };
+    match nat_pmp {
+        Ok(mapping) => Ok(mapping),
+        Err(e) => {
+            tracing::debug!("NAT-PMP mapping of {} failed, trying UPnP IGD: {}", internal_port, e);
+            map_port_with_igd(ssdp, internal_port, lease_secs).await
+        }
+    }
+}
+
+/// Like [`map_port`], asking the NAT-PMP server at `gateway`.
+pub async fn map_port_with_gateway(
+    gateway: SocketAddr,
+    internal_port: u16,
+    lease_secs: u32,
+) -> Result<PortMapping, HolepunchError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let response = request(&socket, gateway, &[NAT_PMP_VERSION, OP_EXTERNAL_ADDRESS]).await?;
+    let external_ip = parse_external_address(&response)?;
+    let (external_port, lifetime) = map_once(&socket, gateway, internal_port, internal_port, lease_secs).await?;
+
+    let external = Arc::new(Mutex::new(SocketAddr::new(IpAddr::V4(external_ip), external_port)));
+    let stop = CancellationToken::new();
+    tokio::spawn(renew(
+        socket,
+        gateway,
+        internal_port,
+        lease_secs,
+        lifetime,
+        external.clone(),
+        stop.clone(),
+    ));
+    Ok(PortMapping {
+        external,
+        internal_port,
+        stop,
+    })
+}
+
+/// Like [`map_port`], asking the Internet Gateway Device that answers an
+/// SSDP search sent to `ssdp`, usually [`SSDP_MULTICAST_ADDR`].
+///
+/// The gateway picks the external port. A `lease_secs` of 0 asks for a
+/// mapping without expiry, which is never renewed.
+pub async fn map_port_with_igd(ssdp: SocketAddr, internal_port: u16, lease_secs: u32) -> Result<PortMapping, HolepunchError> {
+    let options = SearchOptions {
+        broadcast_address: ssdp,
+        timeout: Some(IGD_SEARCH_TIMEOUT),
+        single_search_timeout: Some(IGD_SEARCH_TIMEOUT),
+        ..SearchOptions::default()
+    };
+    let gateway = igd_next::aio::tokio::search_gateway(options).await.map_err(|e| match e {
+        SearchError::NoResponseWithinTimeout => HolepunchError::Timeout,
+        e => igd_error(e),
+    })?;
+    // The gateway forwards to the address we reach it from
+    let route = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    route.connect(gateway.addr).await?;
+    let local = SocketAddr::new(route.local_addr()?.ip(), internal_port);
+
+    let external_ip = gateway.get_external_ip().await.map_err(igd_error)?;
+    let external_port = gateway
+        .add_any_port(PortMappingProtocol::UDP, local, lease_secs, IGD_DESCRIPTION)
+        .await
+        .map_err(igd_error)?;
+
+    let external = Arc::new(Mutex::new(SocketAddr::new(external_ip, external_port)));
+    let stop = CancellationToken::new();
+    tokio::spawn(renew_igd(gateway, local, lease_secs, external.clone(), stop.clone()));
+    Ok(PortMapping {
+        external,
+        internal_port,
+        stop,
+    })
+}
+
+fn igd_error(e: impl std::fmt::Display) -> HolepunchError {
+    HolepunchError::PortMapping(format!("UPnP IGD: {}", e))
+}
+
+/// Renew the mapping at half its granted lifetime until `stop`, then remove
+/// it. A renewal the gateway does not answer is tried again at half the
+/// time left, and a later one re-creates a lapsed mapping.
+async fn renew(
+    socket: UdpSocket,
+    gateway: SocketAddr,
+    internal_port: u16,
+    lease_secs: u32,
+    mut lifetime: u32,
+    external: Arc<Mutex<SocketAddr>>,
+    stop: CancellationToken,
+) {
+    let mut wait = renew_after(lifetime);
+    loop {
+        tokio::select! {
+            _ = stop.cancelled() => break,
+            _ = tokio::time::sleep(wait) => {}
+        }
+        let suggested = external.lock().expect("external address lock poisoned").port();
+        match map_once(&socket, gateway, internal_port, suggested, lease_secs).await {
+            Ok((port, granted)) => {
+                external.lock().expect("external address lock poisoned").set_port(port);
+                lifetime = granted;
+                wait = renew_after(lifetime);
+            }
+            Err(e) => {
+                tracing::debug!("Renewing the port mapping of {} failed: {}", internal_port, e);
+                wait = (wait / 2).max(INITIAL_RETRY_INTERVAL);
+            }
+        }
+    }
+    // A lifetime of 0 removes the mapping
+    if let Err(e) = map_once(&socket, gateway, internal_port, 0, 0).await {
+        tracing::debug!("Removing the port mapping of {} failed: {}", internal_port, e);
+    }
+}
+
+/// Renew the UPnP mapping of `local` at half its lease until `stop`, then
+/// remove it. A failed renewal is tried again at half the time left.
+async fn renew_igd(
+    gateway: Gateway<Tokio>,
+    local: SocketAddr,
+    lease_secs: u32,
+    external: Arc<Mutex<SocketAddr>>,
+    stop: CancellationToken,
+) {
+    let external_port = external.lock().expect("external address lock poisoned").port();
+    let mut wait = renew_after(lease_secs);
+    loop {
+        tokio::select! {
+            _ = stop.cancelled() => break,
+            // A lease of 0 does not expire
+            _ = tokio::time::sleep(wait), if lease_secs > 0 => {}
+        }
+        let renewal = gateway
+            .add_port(PortMappingProtocol::UDP, external_port, local, lease_secs, IGD_DESCRIPTION)
+            .await;
+        match renewal {
+            Ok(()) => wait = renew_after(lease_secs),
+            Err(e) => {
+                tracing::debug!("Renewing the UPnP mapping of {} failed: {}", local.port(), e);
+                wait = (wait / 2).max(INITIAL_RETRY_INTERVAL);
+            }
+        }
+    }
+    if let Err(e) = gateway.remove_port(PortMappingProtocol::UDP, external_port).await {
+        tracing::debug!("Removing the UPnP mapping of {} failed: {}", local.port(), e);
+    }
+}
+
+fn renew_after(lifetime: u32) -> Duration {
+    (Duration::from_secs(u64::from(lifetime)) / 2).max(INITIAL_RETRY_INTERVAL)
+}
+
+/// Ask for a UDP mapping of `internal_port`, returning the external port
+/// and lifetime the gateway granted.
+async fn map_once(
+    socket: &UdpSocket,
+    gateway: SocketAddr,
+    internal_port: u16,
+    suggested_port: u16,
+    lease_secs: u32,
+) -> Result<(u16, u32), HolepunchError> {
+    let mut packet = [0u8; 12];
+    packet[0] = NAT_PMP_VERSION;
+    packet[1] = OP_MAP_UDP;
+    packet[4..6].copy_from_slice(&internal_port.to_be_bytes());
+    packet[6..8].copy_from_slice(&suggested_port.to_be_bytes());
+    packet[8..12].copy_from_slice(&lease_secs.to_be_bytes());
+    let response = request(socket, gateway, &packet).await?;
+    parse_map_response(&response, internal_port)
+}
+
+/// Send `packet` to `gateway` until it answers with the matching response
+/// opcode, retransmitting with doubling intervals.
+async fn request(socket: &UdpSocket, gateway: SocketAddr, packet: &[u8]) -> Result<Vec<u8>, HolepunchError> {
+    let opcode = packet[1] | OP_RESPONSE;
+    let mut buf = [0u8; 64];
+    let exchange = async {
+        let mut interval = INITIAL_RETRY_INTERVAL;
+        loop {
+            socket.send_to(packet, gateway).await?;
+            let retry = tokio::time::sleep(interval);
+            tokio::pin!(retry);
+            loop {
+                tokio::select! {
+                    _ = &mut retry => break,
+                    result = socket.recv_from(&mut buf) => {
+                        let (len, from_addr) = result?;
+                        if from_addr == gateway && len >= 4 && buf[1] == opcode {
+                            return Ok(buf[..len].to_vec());
+                        }
+                    }
+                }
+            }
+            interval *= 2;
+        }
+    };
+    match timeout(REQUEST_TIMEOUT, exchange).await {
+        Ok(result) => result,
+        Err(_) => Err(HolepunchError::Timeout),
+    }
+}
+
+/// Check a response's version and result code.
+fn check_response(response: &[u8], size: usize) -> Result<(), HolepunchError> {
+    if response[0] != NAT_PMP_VERSION {
+        return Err(HolepunchError::PortMapping(format!(
+            "gateway answered with version {}",
+            response[0]
+        )));
+    }
+    let result = u16::from_be_bytes([response[2], response[3]]);
+    if result != RESULT_SUCCESS {
+        return Err(HolepunchError::PortMapping(format!("gateway refused with result code {}", result)));
+    }
+    if response.len() < size {
+        return Err(HolepunchError::PortMapping("truncated response".into()));
+    }
+    Ok(())
+}
+
+fn parse_external_address(response: &[u8]) -> Result<Ipv4Addr, HolepunchError> {
+    check_response(response, EXTERNAL_ADDRESS_RESPONSE_SIZE)?;
+    Ok(Ipv4Addr::new(response[8], response[9], response[10], response[11]))
+}
+
+fn parse_map_response(response: &[u8], internal_port: u16) -> Result<(u16, u32), HolepunchError> {
+    check_response(response, MAP_RESPONSE_SIZE)?;
+    if u16::from_be_bytes([response[8], response[9]]) != internal_port {
+        return Err(HolepunchError::PortMapping("response for another internal port".into()));
+    }
+    let external_port = u16::from_be_bytes([response[10], response[11]]);
+    let lifetime = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+    Ok((external_port, lifetime))
+}
+
+/// The IPv4 default gateway, from the kernel's routing table.
+#[cfg(target_os = "linux")]
+fn default_gateway() -> Result<Ipv4Addr, HolepunchError> {
+    std::fs::read_to_string("/proc/net/route")
+        .ok()
+        .and_then(|routes| parse_default_gateway(&routes))
+        .ok_or_else(|| HolepunchError::PortMapping("no default gateway found".into()))
+}
+
+/// The default gateway is only looked up on Linux.
+#[cfg(not(target_os = "linux"))]
+fn default_gateway() -> Result<Ipv4Addr, HolepunchError> {
+    Err(HolepunchError::PortMapping("default gateway lookup needs /proc/net/route".into()))
+}
+
+/// The gateway of the default route in a `/proc/net/route` table, whose
+/// addresses are hex in host byte order.
+#[cfg(target_os = "linux")]
+fn parse_default_gateway(routes: &str) -> Option<Ipv4Addr> {
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [_, destination, gateway, flags, ..] = fields[..] else {
+            return None;
+        };
+        let flags = u32::from_str_radix(flags, 16).ok()?;
+        if destination != "00000000" || flags & RTF_GATEWAY == 0 {
+            return None;
+        }
+        Some(Ipv4Addr::from(u32::from_str_radix(gateway, 16).ok()?.to_ne_bytes()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    /// A NAT-PMP gateway mapping every port to `203.0.113.7:40000`, or
+    /// refusing everything with `result`. Counts the map requests it gets
+    /// and records the lifetime of the latest.
+    async fn mock_gateway(result: u16) -> (SocketAddr, Arc<AtomicUsize>, Arc<Mutex<u32>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let maps = Arc::new(AtomicUsize::new(0));
+        let last_lifetime = Arc::new(Mutex::new(u32::MAX));
+        let (counter, lifetimes) = (maps.clone(), last_lifetime.clone());
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            loop {
+                let Ok((len, from)) = socket.recv_from(&mut buf).await else { break };
+                let mut reply = vec![NAT_PMP_VERSION, buf[1] | OP_RESPONSE];
+                reply.extend_from_slice(&result.to_be_bytes());
+                reply.extend_from_slice(&7u32.to_be_bytes()); // seconds since epoch
+                match buf[1] {
+                    OP_EXTERNAL_ADDRESS if len == 2 => reply.extend_from_slice(&[203, 0, 113, 7]),
+                    OP_MAP_UDP if len == 12 => {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        *lifetimes.lock().unwrap() = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+                        reply.extend_from_slice(&buf[4..6]);
+                        reply.extend_from_slice(&40000u16.to_be_bytes());
+                        reply.extend_from_slice(&buf[8..12]);
+                    }
+                    _ => continue,
+                }
+                let _ = socket.send_to(&reply, from).await;
+            }
+        });
+        (addr, maps, last_lifetime)
+    }
+
+    /// A UPnP Internet Gateway Device answering SSDP searches at the returned
+    /// address and mapping every port to `203.0.113.7:40000`. Counts the
+    /// mappings added or renewed and records whether one was deleted.
+    async fn mock_igd() -> (SocketAddr, Arc<AtomicUsize>, Arc<AtomicBool>) {
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let location = format!("http://{}/rootDesc.xml", http.local_addr().unwrap());
+        let ssdp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let ssdp_addr = ssdp.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            while let Ok((_, from)) = ssdp.recv_from(&mut buf).await {
+                let reply = format!(
+                    "HTTP/1.1 200 OK\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nLOCATION: {}\r\n\r\n",
+                    location
+                );
+                let _ = ssdp.send_to(reply.as_bytes(), from).await;
+            }
+        });
+        let maps = Arc::new(AtomicUsize::new(0));
+        let removed = Arc::new(AtomicBool::new(false));
+        let (counter, removals) = (maps.clone(), removed.clone());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = http.accept().await {
+                let (counter, removals) = (counter.clone(), removals.clone());
+                tokio::spawn(async move { serve_igd(stream, &counter, &removals).await });
+            }
+        });
+        (ssdp_addr, maps, removed)
+    }
+
+    /// Answer one HTTP request to the mock IGD: its device or service
+    /// description, or a SOAP action on its WANIPConnection service.
+    async fn serve_igd(stream: TcpStream, maps: &AtomicUsize, removed: &AtomicBool) {
+        let mut stream = BufReader::new(stream);
+        let (mut request_line, mut action, mut length) = (String::new(), String::new(), 0);
+        stream.read_line(&mut request_line).await.unwrap();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let Some((name, value)) = line.trim_end().split_once(':') else { break };
+            match name.to_ascii_lowercase().as_str() {
+                "soapaction" => action = value.trim().trim_matches('"').to_string(),
+                "content-length" => length = value.trim().parse().unwrap(),
+                _ => {}
+            }
+        }
+        let mut request_body = vec![0; length];
+        stream.read_exact(&mut request_body).await.unwrap();
+
+        let body = if request_line.starts_with("GET /rootDesc.xml") {
+            "<?xml version=\"1.0\"?><root xmlns=\"urn:schemas-upnp-org:device-1-0\"><device>\
+             <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType><serviceList><service>\
+             <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+             <SCPDURL>/scpd.xml</SCPDURL><controlURL>/ctl</controlURL>\
+             </service></serviceList></device></root>"
+                .to_string()
+        } else if request_line.starts_with("GET /scpd.xml") {
+            let action = |name: &str, arguments: &[&str]| {
+                let arguments: String = arguments
+                    .iter()
+                    .map(|arg| format!("<argument><name>{}</name><direction>in</direction></argument>", arg))
+                    .collect();
+                format!("<action><name>{}</name><argumentList>{}</argumentList></action>", name, arguments)
+            };
+            let mapping = [
+                "NewRemoteHost",
+                "NewExternalPort",
+                "NewProtocol",
+                "NewInternalPort",
+                "NewInternalClient",
+                "NewEnabled",
+                "NewPortMappingDescription",
+                "NewLeaseDuration",
+            ];
+            format!(
+                "<?xml version=\"1.0\"?><scpd xmlns=\"urn:schemas-upnp-org:service-1-0\"><actionList>{}{}{}</actionList></scpd>",
+                action("AddAnyPortMapping", &mapping),
+                action("AddPortMapping", &mapping),
+                action("DeletePortMapping", &mapping[..3]),
+            )
+        } else {
+            let name = action.rsplit('#').next().unwrap_or_default();
+            let result = match name {
+                "GetExternalIPAddress" => "<NewExternalIPAddress>203.0.113.7</NewExternalIPAddress>",
+                "AddAnyPortMapping" => {
+                    maps.fetch_add(1, Ordering::SeqCst);
+                    "<NewReservedPort>40000</NewReservedPort>"
+                }
+                "AddPortMapping" => {
+                    maps.fetch_add(1, Ordering::SeqCst);
+                    ""
+                }
+                "DeletePortMapping" => {
+                    removed.store(true, Ordering::SeqCst);
+                    ""
+                }
+                _ => "",
+            };
+            format!(
+                "<?xml version=\"1.0\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>\
+                 <u:{name}Response xmlns:u=\"urn:schemas-upnp-org:service:WANIPConnection:1\">{result}</u:{name}Response>\
+                 </s:Body></s:Envelope>",
+                name = name,
+                result = result
+            )
+        };
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_map_port_returns_the_external_address() {
+        let (gateway, maps, _) = mock_gateway(RESULT_SUCCESS).await;
+
+        let mapping = map_port_with_gateway(gateway, 4000, 3600).await.unwrap();
+        assert_eq!(mapping.external_addr(), "203.0.113.7:40000".parse::<SocketAddr>().unwrap());
+        assert_eq!(mapping.internal_port(), 4000);
+        let candidate = mapping.candidate();
+        assert_eq!(candidate.addr, mapping.external_addr());
+        assert_eq!(candidate.kind, CandidateKind::Wan);
+        assert_eq!(maps.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_mapping_is_renewed_until_dropped() {
+        let (gateway, maps, last_lifetime) = mock_gateway(RESULT_SUCCESS).await;
+
+        // A one second lease is renewed every half second
+        let mapping = map_port_with_gateway(gateway, 4000, 1).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1300)).await;
+        assert!(maps.load(Ordering::SeqCst) >= 3, "only {} map requests", maps.load(Ordering::SeqCst));
+
+        // Dropping it removes the mapping, and renewals stop
+        drop(mapping);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(*last_lifetime.lock().unwrap(), 0);
+        let after_drop = maps.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        assert_eq!(maps.load(Ordering::SeqCst), after_drop);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_gateway_fails_cleanly() {
+        // Result code 5: unsupported opcode
+        let (refusing, _, _) = mock_gateway(5).await;
+        let result = map_port_with_gateway(refusing, 4000, 3600).await;
+        assert!(matches!(result, Err(HolepunchError::PortMapping(_))), "got {:?}", result);
+
+        // A gateway that does not speak NAT-PMP never answers
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let result = map_port_with_gateway(silent.local_addr().unwrap(), 4000, 3600).await;
+        assert!(matches!(result, Err(HolepunchError::Timeout)), "got {:?}", result);
+    }
+
+    #[tokio::test]
+    async fn test_igd_mapping_is_renewed_until_dropped() {
+        let (ssdp, maps, removed) = mock_igd().await;
+
+        let mapping = map_port_with_igd(ssdp, 4000, 1).await.unwrap();
+        assert_eq!(mapping.external_addr(), "203.0.113.7:40000".parse::<SocketAddr>().unwrap());
+        assert_eq!(mapping.candidate().kind, CandidateKind::Wan);
+        tokio::time::sleep(Duration::from_millis(1300)).await;
+        assert!(maps.load(Ordering::SeqCst) >= 3, "only {} map requests", maps.load(Ordering::SeqCst));
+
+        drop(mapping);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(removed.load(Ordering::SeqCst));
+        let after_drop = maps.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        assert_eq!(maps.load(Ordering::SeqCst), after_drop);
+    }
+
+    #[tokio::test]
+    async fn test_map_port_falls_back_to_igd() {
+        let (ssdp, maps, _) = mock_igd().await;
+        let external: SocketAddr = "203.0.113.7:40000".parse().unwrap();
+
+        // Without a default gateway, NAT-PMP is skipped
+        let no_gateway = Err(HolepunchError::PortMapping("no default gateway found".into()));
+        let mapping = map_port_via(no_gateway, ssdp, 4000, 3600).await.unwrap();
+        assert_eq!(mapping.external_addr(), external);
+
+        // A gateway that does not speak NAT-PMP never answers
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mapping = map_port_via(Ok(silent.local_addr().unwrap()), ssdp, 4001, 3600).await.unwrap();
+        assert_eq!(mapping.external_addr(), external);
+        assert_eq!(maps.load(Ordering::SeqCst), 2);
+    }
+
+    #[cfg(all(target_os = "linux", target_endian = "little"))]
+    #[test]
+    fn test_parse_default_gateway() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+                      eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n\
+                      eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0\n";
+        assert_eq!(parse_default_gateway(routes), Some(Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(parse_default_gateway("Iface\tDestination\tGateway\tFlags\n"), None);
+    }
+}
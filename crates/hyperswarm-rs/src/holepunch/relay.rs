@@ -0,0 +1,205 @@
+//! Minimal UDP relay for peers that cannot holepunch each other.
+//!
+//! Peers meet in a relay session named by a 32-byte id. A datagram of the
+//! form `RELAY_MAGIC || session_id || payload` registers its sender in that
+//! session, and the payload, if any, is forwarded to the other peer of the
+//! session. Once two peers share a session, any other datagram from one of
+//! them is forwarded verbatim to the other, so an encrypted stream runs over
+//! the relay unchanged. The relay only ever sees MAC'd punch packets and
+//! Noise ciphertext.
+//!
+//! The relay cannot tell when a connection ends, so a session nothing was
+//! relayed through for [`RELAY_IDLE_TIMEOUT`] is forgotten, along with its
+//! pairing; a session whose second peer never shows up goes the same way.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+use super::HolepunchError;
+
+pub(crate) const RELAY_MAGIC: &[u8] = b"HYPERSWARM_RELAY";
+pub(crate) const RELAY_SESSION_ID_SIZE: usize = 32;
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// How long a relay session lasts without traffic.
+pub const RELAY_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often idle sessions are looked for.
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Wrap `payload` for delivery through the relay session `session_id`.
+pub(crate) fn relay_packet(session_id: &[u8; RELAY_SESSION_ID_SIZE], payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(RELAY_MAGIC.len() + RELAY_SESSION_ID_SIZE + payload.len());
+    packet.extend_from_slice(RELAY_MAGIC);
+    packet.extend_from_slice(session_id);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Split a relay control packet into its session id and payload.
+fn parse_relay_packet(data: &[u8]) -> Option<([u8; RELAY_SESSION_ID_SIZE], &[u8])> {
+    let rest = data.strip_prefix(RELAY_MAGIC)?;
+    if rest.len() < RELAY_SESSION_ID_SIZE {
+        return None;
+    }
+    let (session_id, payload) = rest.split_at(RELAY_SESSION_ID_SIZE);
+    Some((session_id.try_into().ok()?, payload))
+}
+
+type SessionId = [u8; RELAY_SESSION_ID_SIZE];
+
+/// The peers registered in one relay session.
+struct RelaySession {
+    /// At most two.
+    members: Vec<SocketAddr>,
+    /// When anything from a member last arrived.
+    last_active: Instant,
+}
+
+/// Forwards datagrams between the two peers of each relay session.
+pub struct RelayServer {
+    socket: UdpSocket,
+    sessions: HashMap<SessionId, RelaySession>,
+    /// The other peer of each paired address, and their session.
+    partners: HashMap<SocketAddr, (SocketAddr, SessionId)>,
+}
+
+impl RelayServer {
+    pub async fn bind(bind_addr: SocketAddr) -> Result<Self, HolepunchError> {
+        Ok(Self {
+            socket: UdpSocket::bind(bind_addr).await?,
+            sessions: HashMap::new(),
+            partners: HashMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, HolepunchError> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Relay datagrams until the socket fails.
+    pub async fn run(mut self) -> Result<(), HolepunchError> {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut expire = tokio::time::interval(EXPIRE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = expire.tick() => self.expire(Instant::now()),
+                received = self.socket.recv_from(&mut buf) => {
+                    let (len, from_addr) = received?;
+                    if let Some((payload, to_addr)) = self.route(&buf[..len], from_addr, Instant::now()) {
+                        if let Err(e) = self.socket.send_to(payload, to_addr).await {
+                            tracing::debug!("Relay to {} unsuccessful: {}", to_addr, e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Decide what to forward for a datagram from `from_addr`, arriving at
+    /// `now`, registering the sender if it is a control packet.
+    fn route<'a>(&mut self, data: &'a [u8], from_addr: SocketAddr, now: Instant) -> Option<(&'a [u8], SocketAddr)> {
+        let Some((session_id, payload)) = parse_relay_packet(data) else {
+            // Plain datagrams only flow between paired peers
+            let (to_addr, session_id) = *self.partners.get(&from_addr)?;
+            if let Some(session) = self.sessions.get_mut(&session_id) {
+                session.last_active = now;
+            }
+            return Some((data, to_addr));
+        };
+
+        let session = self.sessions.entry(session_id).or_insert_with(|| RelaySession {
+            members: Vec::new(),
+            last_active: now,
+        });
+        if !session.members.contains(&from_addr) {
+            if session.members.len() == 2 {
+                tracing::debug!("Relay session full, ignoring {}", from_addr);
+                return None;
+            }
+            session.members.push(from_addr);
+            if let [a, b] = session.members[..] {
+                self.partners.insert(a, (b, session_id));
+                self.partners.insert(b, (a, session_id));
+            }
+        }
+        session.last_active = now;
+        let (to_addr, _) = *self.partners.get(&from_addr)?;
+        (!payload.is_empty()).then_some((payload, to_addr))
+    }
+
+    /// Forget the sessions idle for [`RELAY_IDLE_TIMEOUT`] at `now`.
+    fn expire(&mut self, now: Instant) {
+        let partners = &mut self.partners;
+        self.sessions.retain(|_, session| {
+            let live = now.duration_since(session.last_active) < RELAY_IDLE_TIMEOUT;
+            if !live {
+                for member in &session.members {
+                    partners.remove(member);
+                }
+            }
+            live
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_route_pairs_peers_of_a_session() {
+        let mut relay = RelayServer::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let (a, b, c): (SocketAddr, SocketAddr, SocketAddr) = (
+            "10.0.0.1:1000".parse().unwrap(),
+            "10.0.0.2:2000".parse().unwrap(),
+            "10.0.0.3:3000".parse().unwrap(),
+        );
+        let session = [7u8; RELAY_SESSION_ID_SIZE];
+        let now = Instant::now();
+
+        // Nobody to forward to until the second peer registers
+        assert_eq!(relay.route(&relay_packet(&session, b"hi"), a, now), None);
+        assert_eq!(relay.route(b"raw", a, now), None);
+        assert_eq!(relay.route(&relay_packet(&session, &[]), b, now), None);
+
+        let packet = relay_packet(&session, b"hi");
+        assert_eq!(relay.route(&packet, a, now), Some((&b"hi"[..], b)));
+        assert_eq!(relay.route(b"raw", b, now), Some((&b"raw"[..], a)));
+
+        // A third peer cannot join or inject
+        assert_eq!(relay.route(&relay_packet(&session, b"x"), c, now), None);
+        assert_eq!(relay.route(b"raw", c, now), None);
+    }
+
+    #[tokio::test]
+    async fn test_idle_sessions_expire() {
+        let mut relay = RelayServer::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let (a, b, c): (SocketAddr, SocketAddr, SocketAddr) = (
+            "10.0.0.1:1000".parse().unwrap(),
+            "10.0.0.2:2000".parse().unwrap(),
+            "10.0.0.3:3000".parse().unwrap(),
+        );
+        let (paired, waiting) = ([7u8; RELAY_SESSION_ID_SIZE], [8u8; RELAY_SESSION_ID_SIZE]);
+        let start = Instant::now();
+        relay.route(&relay_packet(&paired, &[]), a, start);
+        relay.route(&relay_packet(&paired, &[]), b, start);
+        relay.route(&relay_packet(&waiting, &[]), c, start);
+
+        // Traffic keeps the paired session alive; the unpaired one times out
+        let later = start + RELAY_IDLE_TIMEOUT - Duration::from_secs(1);
+        assert_eq!(relay.route(b"raw", a, later), Some((&b"raw"[..], b)));
+        relay.expire(start + RELAY_IDLE_TIMEOUT);
+        assert_eq!(relay.sessions.len(), 1);
+        assert_eq!(relay.route(b"raw", b, start + RELAY_IDLE_TIMEOUT), Some((&b"raw"[..], a)));
+
+        // Once idle, the pairing is gone too and the session can be reused
+        relay.expire(start + 3 * RELAY_IDLE_TIMEOUT);
+        assert!(relay.sessions.is_empty() && relay.partners.is_empty());
+        assert_eq!(relay.route(b"raw", a, start + 3 * RELAY_IDLE_TIMEOUT), None);
+        relay.route(&relay_packet(&paired, &[]), c, start + 3 * RELAY_IDLE_TIMEOUT);
+        assert_eq!(relay.route(&relay_packet(&paired, b"x"), a, start + 3 * RELAY_IDLE_TIMEOUT), Some((&b"x"[..], c)));
+    }
+}
@@ -5,27 +5,89 @@
 //!
 //! Status: scaffold / work-in-progress (PluresDB sync prerequisite).
 
+pub mod connection;
 pub mod dht;
 pub mod discovery;
 pub mod holepunch;
+pub mod metrics;
+pub mod mux;
+pub mod packet;
 pub mod protocol;
+pub mod reliable;
+#[cfg(feature = "test-util")]
+pub mod testing;
 pub mod transport;
 
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use connection::{ConnectionFailure, PeerConnection, PeerInfo};
+pub use discovery::JoinOpts;
+
+/// Labels separating the keys derived from [`SwarmConfig::seed`].
+const SEED_NODE_ID_LABEL: &[u8] = b"hyperswarm-rs/seed/dht-node-id";
+const SEED_NOISE_KEY_LABEL: &[u8] = b"hyperswarm-rs/seed/noise-static-key";
+
 pub struct Hyperswarm {
-    dht: dht::DhtClient,
+    dht: Arc<dht::DhtClient>,
     discovery: discovery::DiscoveryManager,
-    // TODO: transport / connection manager
+    connections: Arc<connection::ConnectionManager>,
 }
 
 /// Configuration for [`Hyperswarm`].
-#[derive(Clone, Debug)]
+///
+/// Build one field by field, or start from [`SwarmConfig::builder`], which
+/// fills in the defaults for anything not set.
+#[derive(Clone, PartialEq)]
 pub struct SwarmConfig {
     /// Bootstrap nodes in `host:port` form.
     pub bootstrap: Vec<String>,
+    /// Interface the DHT and connection sockets bind to. `0.0.0.0` means all.
+    pub bind_addr: IpAddr,
     /// Local UDP port to bind. `0` means random.
     pub port: u16,
+    /// Port peer connections are accepted on and announced on the DHT, for
+    /// a node reachable there directly, e.g. through a static port mapping.
+    /// `None` binds the connection socket to a random port, which peers
+    /// reach by holepunching.
+    pub announce_port: Option<u16>,
     /// Upper bound on concurrent peer connections.
     pub max_peers: usize,
+    /// How discovered peers are reconnected after a failed connect or a
+    /// dropped connection.
+    pub backoff: discovery::BackoffConfig,
+    /// Fraction of the refresh interval by which each re-announce is moved
+    /// at random, see [`DiscoveryConfig::refresh_jitter`](discovery::DiscoveryConfig::refresh_jitter).
+    pub refresh_jitter: f64,
+    /// Secret seed the swarm's identity is derived from: its DHT node id and
+    /// Noise static keypair. Without one, both are random on every run.
+    pub seed: Option<[u8; 32]>,
+    /// Tunables for every peer connection's encrypted stream, such as its
+    /// handshake timeout.
+    pub transport: transport::TransportConfig,
+    /// Application id namespacing the swarm's holepunch traffic, see
+    /// [`HolepunchSession::with_app_id`](holepunch::HolepunchSession::with_app_id).
+    /// Only swarms with the same id connect to each other; `None` talks to
+    /// swarms without one.
+    pub app_id: Option<Vec<u8>>,
+}
+
+impl std::fmt::Debug for SwarmConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SwarmConfig")
+            .field("bootstrap", &self.bootstrap)
+            .field("bind_addr", &self.bind_addr)
+            .field("port", &self.port)
+            .field("announce_port", &self.announce_port)
+            .field("max_peers", &self.max_peers)
+            .field("backoff", &self.backoff)
+            .field("refresh_jitter", &self.refresh_jitter)
+            .field("seed", &self.seed.map(|_| "<redacted>"))
+            .field("transport", &self.transport)
+            .field("app_id", &self.app_id)
+            .finish()
+    }
 }
 
 impl Default for SwarmConfig {
@@ -36,18 +98,107 @@ impl Default for SwarmConfig {
                 "node2.hyperdht.org:49737".into(),
                 "node3.hyperdht.org:49737".into(),
             ],
+            bind_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
             port: 0,
+            announce_port: None,
             max_peers: 64,
+            backoff: discovery::BackoffConfig::default(),
+            refresh_jitter: discovery::DEFAULT_REFRESH_JITTER,
+            seed: None,
+            transport: transport::TransportConfig::default(),
+            app_id: None,
         }
     }
 }
 
+impl SwarmConfig {
+    /// Start building a config from the defaults.
+    pub fn builder() -> SwarmConfigBuilder {
+        SwarmConfigBuilder::default()
+    }
+}
+
+/// Builder for [`SwarmConfig`]; fields left unset keep their default.
+#[derive(Clone, Debug, Default)]
+pub struct SwarmConfigBuilder {
+    config: SwarmConfig,
+}
+
+impl SwarmConfigBuilder {
+    /// Bootstrap nodes in `host:port` form, replacing the default list.
+    pub fn bootstrap<I, S>(mut self, nodes: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.config.bootstrap = nodes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn bind_addr(mut self, bind_addr: IpAddr) -> Self {
+        self.config.bind_addr = bind_addr;
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    pub fn announce_port(mut self, port: u16) -> Self {
+        self.config.announce_port = Some(port);
+        self
+    }
+
+    pub fn max_peers(mut self, max_peers: usize) -> Self {
+        self.config.max_peers = max_peers;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: discovery::BackoffConfig) -> Self {
+        self.config.backoff = backoff;
+        self
+    }
+
+    pub fn refresh_jitter(mut self, refresh_jitter: f64) -> Self {
+        self.config.refresh_jitter = refresh_jitter;
+        self
+    }
+
+    pub fn seed(mut self, seed: [u8; 32]) -> Self {
+        self.config.seed = Some(seed);
+        self
+    }
+
+    pub fn transport(mut self, transport: transport::TransportConfig) -> Self {
+        self.config.transport = transport;
+        self
+    }
+
+    pub fn app_id(mut self, app_id: impl Into<Vec<u8>>) -> Self {
+        self.config.app_id = Some(app_id.into());
+        self
+    }
+
+    pub fn build(self) -> SwarmConfig {
+        self.config
+    }
+}
+
 /// A topic to announce / lookup on the DHT.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Topic(pub [u8; 32]);
 
+/// Message hypercore-crypto's `discoveryKey` hashes under a core's public key.
+const DISCOVERY_KEY_MESSAGE: &[u8] = b"hypercore";
+
 impl Topic {
     /// Derive a topic from a shared secret key.
+    ///
+    /// This is BLAKE2b-512 truncated to 32 bytes, which is *not* what the
+    /// JavaScript stack produces for the same input; Rust and JS peers
+    /// meeting on a topic should use [`from_key_compat`](Self::from_key_compat)
+    /// instead.
     pub fn from_key(key: &[u8]) -> Self {
         use blake2::{Blake2b512, Digest};
 
@@ -59,31 +210,268 @@ impl Topic {
         topic.copy_from_slice(&result[..32]);
         Topic(topic)
     }
+
+    /// Derive a topic the way JS hyperswarm applications do,
+    /// `crypto.hash(key)` from hypercore-crypto: BLAKE2b with a 32-byte
+    /// output (libsodium's `crypto_generichash`). A JS peer joining
+    /// `crypto.hash(Buffer.from('room'))` and a Rust peer joining
+    /// `Topic::from_key_compat(b"room")` land on the same DHT key.
+    pub fn from_key_compat(key: &[u8]) -> Self {
+        use blake2::{digest::consts::U32, Blake2b, Digest};
+
+        Topic(Blake2b::<U32>::digest(key).into())
+    }
+
+    /// The discovery key of a hypercore with `public_key`, matching
+    /// hypercore-crypto's `discoveryKey`: BLAKE2b-256 of `"hypercore"` keyed
+    /// by the public key. Hypercores are replicated over this topic, so it
+    /// lets Rust peers find JS peers sharing a core without revealing the
+    /// core's key to the DHT.
+    pub fn discovery_key(public_key: &[u8; 32]) -> Self {
+        use blake2::{digest::{consts::U32, KeyInit, Mac}, Blake2bMac};
+
+        let mut mac = <Blake2bMac<U32> as KeyInit>::new_from_slice(public_key)
+            .expect("32-byte keys are valid for Blake2bMac");
+        Mac::update(&mut mac, DISCOVERY_KEY_MESSAGE);
+        Topic(Mac::finalize(mac).into_bytes().into())
+    }
+
+    /// Parse a topic written as 64 hex digits, as [`to_hex`](Self::to_hex)
+    /// and other tools print it. Either case is accepted.
+    pub fn from_hex(hex: &str) -> Result<Self, TopicError> {
+        let digits: Vec<(usize, char)> = hex.chars().enumerate().collect();
+        if digits.len() != 64 {
+            return Err(TopicError::InvalidLength(digits.len()));
+        }
+        let mut topic = [0u8; 32];
+        for (byte, pair) in topic.iter_mut().zip(digits.chunks(2)) {
+            for &(position, digit) in pair {
+                let value = digit.to_digit(16).ok_or(TopicError::InvalidCharacter(digit, position))?;
+                *byte = (*byte << 4) | value as u8;
+            }
+        }
+        Ok(Topic(topic))
+    }
+
+    /// The topic as 64 lowercase hex digits.
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+impl std::fmt::Display for Topic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl std::str::FromStr for Topic {
+    type Err = TopicError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Topic::from_hex(s)
+    }
+}
+
+/// Topics serialize as their hex string.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Topic {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Topic {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        Topic::from_hex(&hex).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Derive a 32-byte key for `label` from a swarm seed.
+fn derive_from_seed(seed: &[u8; 32], label: &[u8]) -> [u8; 32] {
+    use blake2::{digest::{KeyInit, Mac}, Blake2sMac256};
+
+    let mut mac = <Blake2sMac256 as KeyInit>::new_from_slice(seed)
+        .expect("seed is exactly 32 bytes, which is valid for Blake2sMac256");
+    Mac::update(&mut mac, label);
+    Mac::finalize(mac).into_bytes().into()
 }
 
 impl Hyperswarm {
     pub async fn new(config: SwarmConfig) -> Result<Self, SwarmError> {
+        let node_id = config.seed.map(|seed| {
+            let mut node_id = [0u8; 20];
+            node_id.copy_from_slice(&derive_from_seed(&seed, SEED_NODE_ID_LABEL)[..20]);
+            node_id
+        });
         let dht = dht::DhtClient::new(dht::DhtConfig {
             bootstrap: config.bootstrap.clone(),
+            bind_addr: config.bind_addr,
             bind_port: config.port,
+            node_id,
+            ..Default::default()
         })
         .await
         .map_err(|e| SwarmError::Dht(e.to_string()))?;
 
         let discovery = discovery::DiscoveryManager::new(discovery::DiscoveryConfig {
             max_peers: config.max_peers,
+            refresh_interval: discovery::DEFAULT_REFRESH_INTERVAL,
+            refresh_jitter: config.refresh_jitter,
+            backoff: config.backoff,
         });
 
-        Ok(Self { dht, discovery })
+        // Joins announce the connection socket's port
+        let bind_addr = SocketAddr::new(config.bind_addr, config.announce_port.unwrap_or(0));
+        let connection_config = connection::ConnectionConfig {
+            max_peers: config.max_peers,
+            transport: config.transport.clone(),
+            app_id: config.app_id.clone(),
+        };
+        let connections = match config.seed {
+            Some(seed) => {
+                let private_key = derive_from_seed(&seed, SEED_NOISE_KEY_LABEL);
+                connection::ConnectionManager::with_keypair(bind_addr, connection_config, private_key).await
+            }
+            None => connection::ConnectionManager::new(bind_addr, connection_config).await,
+        }
+        .map_err(SwarmError::Connection)?;
+
+        Ok(Self {
+            dht: Arc::new(dht),
+            discovery,
+            connections: Arc::new(connections),
+        })
+    }
+
+    /// The swarm's DHT node.
+    pub fn dht(&self) -> &dht::DhtClient {
+        &self.dht
+    }
+
+    /// Report DHT queries, discovered peers and connection attempts to
+    /// `metrics`.
+    pub fn set_metrics(&self, metrics: Arc<dyn metrics::SwarmMetrics>) {
+        self.dht.set_metrics(metrics.clone());
+        self.discovery.set_metrics(metrics);
+    }
+
+    /// Keep only connections to peers whose Noise static public key
+    /// `authorize` accepts, e.g. an allowlist; see
+    /// [`ConnectionManager::set_authorize`](connection::ConnectionManager::set_authorize).
+    /// A rejected peer fails with [`ConnectionFailure::Unauthorized`].
+    pub fn set_authorize(&self, authorize: impl Fn([u8; 32]) -> bool + Send + Sync + 'static) {
+        self.connections.set_authorize(authorize);
+    }
+
+    /// Take the stream of established connections, like the `connection`
+    /// event of JS Hyperswarm.
+    ///
+    /// Yields connections made to peers discovered through [`join`](Self::join)
+    /// and, from the moment this is called, inbound connections. Returns `None`
+    /// after the first call.
+    ///
+    /// The channel is bounded: while the receiver lags, discovery stops
+    /// connecting and inbound handshakes wait until it catches up. A swarm
+    /// that never takes the receiver stops making discovery connections once
+    /// the buffer is full.
+    pub fn connections(&self) -> Option<tokio::sync::mpsc::Receiver<PeerConnection>> {
+        self.connections.connections()
+    }
+
+    /// The peers we are connected to for `topic`, whichever side connected.
+    ///
+    /// A peer that connected to us is listed once our own discovery of the
+    /// topic has found it too.
+    pub fn peers(&self, topic: Topic) -> Vec<PeerInfo> {
+        self.connections.peers(&topic)
+    }
+
+    /// The Noise static public key every connection of this swarm presents,
+    /// as peers see it in [`EncryptedStream::remote_static_key`](transport::EncryptedStream::remote_static_key).
+    ///
+    /// Stable for the life of the swarm, and across runs when
+    /// [`SwarmConfig::seed`] is set. Hand it to a peer out of band as the
+    /// `node_id` of the [`dht::PeerAddress`] it connects to, and the
+    /// connection fails unless it reaches this swarm.
+    pub fn public_key(&self) -> [u8; 32] {
+        self.connections.public_key()
+    }
+
+    /// Local address of the socket peer connections are made over.
+    pub fn local_addr(&self) -> Result<std::net::SocketAddr, SwarmError> {
+        self.connections.local_addr().map_err(SwarmError::Connection)
+    }
+
+    /// Connect to a peer found through [`dht::DhtClient::lookup`].
+    ///
+    /// Holepunches to the peer's address, then performs the Noise XX handshake
+    /// as initiator. When the peer's `node_id` is known it is pinned as the
+    /// expected static key. Failures come back as [`SwarmError::Connection`]
+    /// carrying the [`ConnectionError`](connection::ConnectionError), e.g.
+    /// `Transport(PeerAuthenticationFailed)` for a peer presenting another
+    /// key; [`SwarmError::failure`] says what that means for retrying.
+    pub async fn connect(&self, peer: dht::PeerAddress) -> Result<transport::EncryptedStream, SwarmError> {
+        self.connections.connect(&peer).await.map_err(SwarmError::from)
+    }
+
+    /// Wait for a peer to [`connect`](Self::connect) to us and complete the
+    /// handshake as responder.
+    ///
+    /// Not to be mixed with [`connections`](Self::connections), which accepts
+    /// inbound peers itself.
+    pub async fn accept(&self) -> Result<transport::EncryptedStream, SwarmError> {
+        self.connections.accept().await.map_err(SwarmError::from)
+    }
+
+    /// Publish `candidate` to peers next to the addresses found
+    /// automatically, such as the external address of a
+    /// [`holepunch::map_port`] mapping. Joined topics publish it from their
+    /// next refresh on.
+    pub fn add_candidate(&self, candidate: holepunch::Candidate) {
+        self.discovery.add_candidate(candidate);
     }
 
-    pub async fn join(&self, topic: Topic) -> Result<(), SwarmError> {
+    /// Stop publishing the candidate added at `addr`, e.g. once its port
+    /// mapping is dropped.
+    pub fn remove_candidate(&self, addr: SocketAddr) {
+        self.discovery.remove_candidate(addr);
+    }
+
+    /// Join `topic`, announcing ourselves and/or looking up peers as `opts`
+    /// asks. [`JoinOpts::default`] does both.
+    pub async fn join(&self, topic: Topic, opts: JoinOpts) -> Result<(), SwarmError> {
         self.discovery
-            .join(&self.dht, topic)
+            .join(&self.dht, &self.connections, topic, opts)
             .await
             .map_err(|e| SwarmError::Dht(e.to_string()))
     }
 
+    /// Join all of `topics` at once, like calling [`join`](Self::join) for
+    /// each, but bootstrapping once and announcing to one set of nodes with
+    /// one token per node; lookups run concurrently. Returns each topic's
+    /// outcome; one failure does not fail the batch.
+    pub async fn join_all(&self, topics: &[Topic], opts: JoinOpts) -> Vec<(Topic, Result<(), SwarmError>)> {
+        self.discovery
+            .join_all(&self.dht, &self.connections, topics, opts)
+            .await
+            .into_iter()
+            .map(|(topic, result)| (topic, result.map_err(|e| SwarmError::Dht(e.to_string()))))
+            .collect()
+    }
+
+    /// Leave all of `topics` concurrently, returning each topic's outcome.
+    pub async fn leave_all(&self, topics: &[Topic]) -> Vec<(Topic, Result<(), SwarmError>)> {
+        self.discovery
+            .leave_all(&self.dht, topics)
+            .await
+            .into_iter()
+            .map(|(topic, result)| (topic, result.map_err(|e| SwarmError::Dht(e.to_string()))))
+            .collect()
+    }
+
     pub async fn leave(&self, topic: Topic) -> Result<(), SwarmError> {
         self.discovery
             .leave(&self.dht, topic)
@@ -91,12 +479,24 @@ impl Hyperswarm {
             .map_err(|e| SwarmError::Dht(e.to_string()))
     }
 
-    /// Wait until all pending DHT operations complete.
-    pub async fn flush(&self) -> Result<(), SwarmError> {
-        self.dht
-            .flush()
-            .await
-            .map_err(|e| SwarmError::Dht(e.to_string()))
+    /// Wait until the swarm has settled: joined topics have finished their
+    /// announces and lookups, the peers those found are connected (and
+    /// delivered on [`connections`](Self::connections)) or have failed,
+    /// inbound handshakes in progress are done, and pending DHT queries are
+    /// answered.
+    ///
+    /// An inbound attempt counts until it succeeds or fails, even one bound
+    /// to fail, such as a punch the peer sent down a path it then gave up
+    /// on. Gives up with [`SwarmError::Timeout`] after `timeout`, e.g. while
+    /// such an attempt times out or a discovered peer waits for room under
+    /// `max_peers`.
+    pub async fn flush(&self, timeout: Duration) -> Result<(), SwarmError> {
+        let settled = async {
+            self.discovery.flush().await;
+            self.connections.flush().await;
+            self.dht.flush().await.map_err(|e| SwarmError::Dht(e.to_string()))
+        };
+        tokio::time::timeout(timeout, settled).await.map_err(|_| SwarmError::Timeout)?
     }
 
     pub async fn destroy(self) -> Result<(), SwarmError> {
@@ -112,7 +512,165 @@ pub enum SwarmError {
     #[error("DHT error: {0}")]
     Dht(String),
     #[error("Connection error: {0}")]
-    Connection(String),
+    Connection(#[source] connection::ConnectionError),
     #[error("Transport error: {0}")]
     Transport(String),
+    #[error("Timed out")]
+    Timeout,
+}
+
+/// Why a string is not a [`Topic`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TopicError {
+    #[error("expected 64 hex digits, got {0} characters")]
+    InvalidLength(usize),
+    #[error("invalid hex digit {0:?} at position {1}")]
+    InvalidCharacter(char, usize),
+}
+
+impl From<connection::ConnectionError> for SwarmError {
+    fn from(e: connection::ConnectionError) -> Self {
+        SwarmError::Connection(e)
+    }
+}
+
+impl SwarmError {
+    /// Why a connection attempt failed, for connection errors that say
+    /// something about the peer or the path to it; see
+    /// [`ConnectionError::failure`](connection::ConnectionError::failure).
+    pub fn failure(&self) -> Option<ConnectionFailure> {
+        match self {
+            SwarmError::Connection(e) => e.failure(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(hex: &str) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+        }
+        bytes
+    }
+
+    // Outputs of hypercore-crypto's `hash` for the same inputs. These are
+    // plain BLAKE2b-256 digests ("" and "abc" are the standard test vectors).
+    #[test]
+    fn test_from_key_compat_matches_hypercore_crypto_hash() {
+        let vectors: [(&[u8], &str); 4] = [
+            (b"", "0e5751c026e543b2e8ab2eb06099daa1d1e5df47778f7787faab45cdf12fe3a8"),
+            (b"abc", "bddd813c634239723171ef3fee98579b94964e3bb1cb3e427262c8c068d52319"),
+            (b"hello world", "256c83b297114d201b30179f3f0ef0cace9783622da5974326b436178aeef610"),
+            (b"my-app/chat-room", "92bd8c6fc8db396c14ef13a9a98efca089ee2cc0bb71cdcecb4a422a19b87580"),
+        ];
+        for (key, expected) in vectors {
+            assert_eq!(Topic::from_key_compat(key), Topic(unhex(expected)));
+            // The truncated BLAKE2b-512 derivation does not interoperate
+            assert_ne!(Topic::from_key(key), Topic::from_key_compat(key));
+        }
+    }
+
+    #[test]
+    fn test_topic_hex_round_trip() {
+        let hex = "92bd8c6fc8db396c14ef13a9a98efca089ee2cc0bb71cdcecb4a422a19b87580";
+        let topic = Topic::from_hex(hex).unwrap();
+        assert_eq!(topic, Topic::from_key_compat(b"my-app/chat-room"));
+        assert_eq!(topic.to_hex(), hex);
+        assert_eq!(topic.to_string(), hex);
+        assert_eq!(hex.parse::<Topic>(), Ok(topic));
+        assert_eq!(Topic::from_hex(&hex.to_uppercase()), Ok(topic));
+
+        assert_eq!(Topic::from_hex(&hex[..62]), Err(TopicError::InvalidLength(62)));
+        assert_eq!(Topic::from_hex(""), Err(TopicError::InvalidLength(0)));
+        let bad = format!("{}g{}", &hex[..10], &hex[11..]);
+        assert_eq!(Topic::from_hex(&bad), Err(TopicError::InvalidCharacter('g', 10)));
+        let accented = format!("{}é", &hex[..63]);
+        assert_eq!(Topic::from_hex(&accented), Err(TopicError::InvalidCharacter('é', 63)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_topic_serde_round_trip() {
+        let topic = Topic::from_key_compat(b"my-app/chat-room");
+        let json = serde_json::to_string(&topic).unwrap();
+        assert_eq!(json, format!("\"{}\"", topic.to_hex()));
+        assert_eq!(serde_json::from_str::<Topic>(&json).unwrap(), topic);
+        assert!(serde_json::from_str::<Topic>("\"abcd\"").is_err());
+    }
+
+    // Outputs of hypercore-crypto's `discoveryKey` for the same public keys.
+    #[test]
+    fn test_discovery_key_matches_hypercore_crypto() {
+        let sequential: [u8; 32] = std::array::from_fn(|i| i as u8);
+        let vectors = [
+            ([0u8; 32], "6b7ecf9ce456d56e5145ec4c5661e0c79ee47ddcc9c0504e90aa6fd60746365c"),
+            (sequential, "b74b6d642892501cca569ff03d3bd21d9db9768a3c12a5516a4a80a7bebad901"),
+        ];
+        for (public_key, expected) in vectors {
+            assert_eq!(Topic::discovery_key(&public_key), Topic(unhex(expected)));
+        }
+    }
+
+    #[test]
+    fn test_builder_matches_hand_built_config() {
+        let backoff = discovery::BackoffConfig {
+            max_attempts: 2,
+            ..Default::default()
+        };
+        let transport = transport::TransportConfig {
+            handshake_timeout: std::time::Duration::from_secs(5),
+            ..Default::default()
+        };
+        let built = SwarmConfig::builder()
+            .bootstrap(["127.0.0.1:49737"])
+            .bind_addr(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .port(4000)
+            .announce_port(4001)
+            .max_peers(8)
+            .backoff(backoff)
+            .refresh_jitter(0.5)
+            .seed([1; 32])
+            .transport(transport.clone())
+            .app_id("pluresdb")
+            .build();
+        let by_hand = SwarmConfig {
+            bootstrap: vec!["127.0.0.1:49737".to_string()],
+            bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 4000,
+            announce_port: Some(4001),
+            max_peers: 8,
+            backoff,
+            refresh_jitter: 0.5,
+            seed: Some([1; 32]),
+            transport,
+            app_id: Some(b"pluresdb".to_vec()),
+        };
+        assert_eq!(built, by_hand);
+    }
+
+    #[test]
+    fn test_builder_defaults_omitted_fields() {
+        assert_eq!(SwarmConfig::builder().build(), SwarmConfig::default());
+
+        let config = SwarmConfig::builder().max_peers(2).build();
+        assert_eq!(config.max_peers, 2);
+        assert_eq!(config.bootstrap, SwarmConfig::default().bootstrap);
+        assert!(config.bind_addr.is_unspecified());
+        assert_eq!(config.port, 0);
+        assert_eq!(config.announce_port, None);
+        assert_eq!(config.refresh_jitter, discovery::DEFAULT_REFRESH_JITTER);
+        assert_eq!(config.seed, None);
+        assert_eq!(config.app_id, None);
+    }
+
+    #[test]
+    fn test_debug_redacts_seed() {
+        let config = SwarmConfig::builder().seed([0xab; 32]).build();
+        assert!(!format!("{:?}", config).contains("171"));
+    }
 }
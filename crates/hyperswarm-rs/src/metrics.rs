@@ -0,0 +1,64 @@
+//! Observability hooks.
+//!
+//! Implement [`SwarmMetrics`] to count what the DHT and discovery layers do,
+//! for example to feed Prometheus counters, and install it with
+//! [`Hyperswarm::set_metrics`](crate::Hyperswarm::set_metrics) or the
+//! `set_metrics` of [`DhtClient`](crate::dht::DhtClient) and
+//! [`DiscoveryManager`](crate::discovery::DiscoveryManager). Every callback
+//! defaults to doing nothing, so an implementation only overrides the events
+//! it cares about. Callbacks run inline on the task that saw the event and
+//! should return quickly.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use crate::dht::PeerAddress;
+use crate::protocol::KrpcQueryKind;
+use crate::Topic;
+
+/// Receives swarm events as they happen.
+pub trait SwarmMetrics: Send + Sync {
+    /// A KRPC query of `kind` was sent to `to`.
+    fn on_query_sent(&self, _kind: &KrpcQueryKind, _to: SocketAddr) {}
+
+    /// A query of `kind` to `to` got no answer within the query timeout.
+    fn on_query_timeout(&self, _kind: &KrpcQueryKind, _to: SocketAddr) {}
+
+    /// A lookup for `topic` found `peer`, once per peer per lookup.
+    fn on_peer_discovered(&self, _topic: &Topic, _peer: &PeerAddress) {}
+
+    /// Holepunching to a discovered peer at `peer` succeeded or failed.
+    fn on_punch_result(&self, _peer: SocketAddr, _success: bool) {}
+
+    /// The Noise handshake with `peer` completed.
+    fn on_handshake_complete(&self, _peer: SocketAddr) {}
+
+    /// The Noise handshake with `peer` failed after a successful punch.
+    fn on_handshake_failed(&self, _peer: SocketAddr) {}
+}
+
+/// Ignores every event; the default until metrics are installed.
+pub struct NoopMetrics;
+
+impl SwarmMetrics for NoopMetrics {}
+
+/// The installed [`SwarmMetrics`], shared with background tasks so it can be
+/// replaced after they have started.
+#[derive(Clone)]
+pub(crate) struct MetricsHandle(Arc<RwLock<Arc<dyn SwarmMetrics>>>);
+
+impl Default for MetricsHandle {
+    fn default() -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(NoopMetrics))))
+    }
+}
+
+impl MetricsHandle {
+    pub(crate) fn set(&self, metrics: Arc<dyn SwarmMetrics>) {
+        *self.0.write().expect("metrics lock poisoned") = metrics;
+    }
+
+    pub(crate) fn get(&self) -> Arc<dyn SwarmMetrics> {
+        self.0.read().expect("metrics lock poisoned").clone()
+    }
+}
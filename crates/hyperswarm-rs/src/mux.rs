@@ -0,0 +1,500 @@
+//! Stream multiplexing over a single encrypted connection.
+//!
+//! A [`Muxer`] carries independent [`ChannelStream`]s over one
+//! [`EncryptedStream`], so several logical channels share a punched UDP path
+//! without handshaking again. Every message on the stream is one mux frame:
+//! a varint channel id, a frame type byte and the payload.
+//!
+//! Each channel has its own receive window. A writer may have at most
+//! [`CHANNEL_WINDOW`] bytes in flight that the reader has not consumed, so a
+//! channel whose reader falls behind stalls only its own writer.
+//!
+//! At most [`MAX_CHANNELS`] channels exist at a time, counting those the peer
+//! wrote to before we opened them, which bounds the memory a peer can make
+//! us buffer. A peer going past the limit ends the muxer.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use bytes::{Buf, Bytes};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+
+use crate::transport::{EncryptedStream, TransportError};
+
+/// Bytes a channel may have in flight before its reader makes room.
+pub const CHANNEL_WINDOW: u32 = 256 * 1024;
+/// Most channels open at once, up to `CHANNEL_WINDOW` buffered in each.
+pub const MAX_CHANNELS: usize = 128;
+/// Largest payload of one data frame, so busy channels interleave.
+const MAX_FRAME_PAYLOAD: usize = 16 * 1024;
+/// Consumed bytes are handed back to the writer once this many accumulate.
+const WINDOW_UPDATE_THRESHOLD: u32 = CHANNEL_WINDOW / 4;
+const MAX_VARINT_SIZE: usize = 10; // LEB128 encoding of a u64
+
+// Frame types, following the channel id
+const MUX_DATA: u8 = 0; // channel bytes
+const MUX_CLOSE: u8 = 1; // the sender will write no more to the channel
+const MUX_WINDOW: u8 = 2; // big-endian u32: bytes the reader consumed
+
+#[derive(thiserror::Error, Debug)]
+pub enum MuxError {
+    #[error("transport: {0}")]
+    Transport(#[from] TransportError),
+    #[error("channel {0} is already open")]
+    ChannelInUse(u64),
+    #[error("too many open channels")]
+    TooManyChannels,
+    #[error("invalid mux frame")]
+    InvalidFrame,
+    #[error("muxer closed")]
+    Closed,
+}
+
+/// Multiplexes logical channels over one [`EncryptedStream`].
+///
+/// A background task owns the stream: it sends frames queued by the channels
+/// and files received frames into per-channel buffers. The task ends when the
+/// peer closes the stream, or once the `Muxer` and all of its channels are
+/// dropped, in which case the stream is shut down.
+pub struct Muxer {
+    shared: Shared,
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl Muxer {
+    /// Start multiplexing over a stream whose handshake has completed.
+    pub fn new(stream: EncryptedStream) -> Self {
+        let shared = Shared::default();
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        tokio::spawn(Self::drive(stream, commands_rx, shared.clone()));
+        Self { shared, commands }
+    }
+
+    /// Open channel `id`.
+    ///
+    /// Both peers open the same id to talk over it; data the peer sends
+    /// before we open the channel is buffered. An id can be opened again once
+    /// its previous `ChannelStream` is dropped and the peer has closed it.
+    ///
+    /// Fails with [`MuxError::TooManyChannels`] if `id` would be channel
+    /// number [`MAX_CHANNELS`] + 1.
+    pub fn open_channel(&self, id: u64) -> Result<ChannelStream, MuxError> {
+        let mut state = self.shared.lock();
+        if state.ended {
+            return Err(MuxError::Closed);
+        }
+        let channel = state.channel(id)?;
+        if channel.opened {
+            return Err(MuxError::ChannelInUse(id));
+        }
+        channel.opened = true;
+        Ok(ChannelStream {
+            id,
+            shared: self.shared.clone(),
+            commands: self.commands.clone(),
+            close_sent: false,
+        })
+    }
+
+    async fn drive(mut stream: EncryptedStream, mut commands: mpsc::UnboundedReceiver<Command>, shared: Shared) {
+        let result = Self::run(&mut stream, &mut commands, &shared).await;
+        if let Err(e) = &result {
+            tracing::debug!("Muxer stopped: {}", e);
+        }
+
+        let mut state = shared.lock();
+        state.ended = true;
+        state.failure = result.err().map(|e| e.to_string());
+        for channel in state.channels.values_mut() {
+            channel.wake_all();
+        }
+    }
+
+    async fn run(
+        stream: &mut EncryptedStream,
+        commands: &mut mpsc::UnboundedReceiver<Command>,
+        shared: &Shared,
+    ) -> Result<(), MuxError> {
+        loop {
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(command) => stream.send(command.encode()).await?,
+                    None => {
+                        // Nobody can write any more
+                        stream.shutdown().await.map_err(TransportError::from)?;
+                        return Ok(());
+                    }
+                },
+                message = stream.recv() => match message {
+                    Ok(frame) => Self::dispatch(shared, frame)?,
+                    Err(TransportError::Closed) => return Ok(()),
+                    Err(e) => return Err(e.into()),
+                },
+            }
+        }
+    }
+
+    /// File one received frame with its channel.
+    fn dispatch(shared: &Shared, mut frame: Bytes) -> Result<(), MuxError> {
+        let id = get_varint(&mut frame).ok_or(MuxError::InvalidFrame)?;
+        if !frame.has_remaining() {
+            return Err(MuxError::InvalidFrame);
+        }
+        let frame_type = frame.get_u8();
+
+        let mut state = shared.lock();
+        if frame_type == MUX_WINDOW && !state.channels.contains_key(&id) {
+            // Window for a channel already gone
+            return Ok(());
+        }
+        let channel = state.channel(id)?;
+        match frame_type {
+            MUX_DATA => {
+                if channel.released {
+                    // Nobody will read it
+                    return Ok(());
+                }
+                if channel.buffered + frame.len() > CHANNEL_WINDOW as usize {
+                    // The peer ignored our window
+                    return Err(MuxError::InvalidFrame);
+                }
+                channel.buffered += frame.len();
+                channel.recv_buf.push_back(frame);
+                wake(&mut channel.read_waker);
+            }
+            MUX_CLOSE => {
+                channel.remote_closed = true;
+                wake(&mut channel.read_waker);
+                if channel.released {
+                    state.channels.remove(&id);
+                }
+            }
+            MUX_WINDOW => {
+                let bytes: [u8; 4] = frame.as_ref().try_into().map_err(|_| MuxError::InvalidFrame)?;
+                channel.send_credit = channel
+                    .send_credit
+                    .checked_add(u32::from_be_bytes(bytes))
+                    .ok_or(MuxError::InvalidFrame)?;
+                wake(&mut channel.write_waker);
+            }
+            _ => return Err(MuxError::InvalidFrame),
+        }
+        Ok(())
+    }
+}
+
+/// One logical channel of a [`Muxer`], usable as a byte stream.
+///
+/// Writes are handed to the muxer's task in frames of up to 16 KiB, so
+/// `poll_flush` has nothing left to do. `poll_shutdown` closes our side of
+/// the channel; the peer's reads then reach end of stream. Dropping the
+/// channel closes it too.
+pub struct ChannelStream {
+    id: u64,
+    shared: Shared,
+    commands: mpsc::UnboundedSender<Command>,
+    close_sent: bool,
+}
+
+impl ChannelStream {
+    /// The channel id this stream was opened with.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn send_command(&self, command: Command) -> io::Result<()> {
+        self.commands
+            .send(command)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, MuxError::Closed))
+    }
+}
+
+impl AsyncRead for ChannelStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, out: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let mut state = this.shared.lock();
+        let MuxState { channels, ended, failure } = &mut *state;
+        let Some(channel) = channels.get_mut(&this.id) else {
+            return Poll::Ready(Ok(()));
+        };
+
+        if let Some(chunk) = channel.recv_buf.front_mut() {
+            let n = out.remaining().min(chunk.len());
+            out.put_slice(&chunk[..n]);
+            chunk.advance(n);
+            if chunk.is_empty() {
+                channel.recv_buf.pop_front();
+            }
+            channel.buffered -= n;
+            channel.unacked += n as u32;
+            if channel.unacked >= WINDOW_UPDATE_THRESHOLD {
+                // Let the writer use the room we just made
+                let _ = this.commands.send(Command::Window(this.id, channel.unacked));
+                channel.unacked = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if channel.remote_closed {
+            // End of stream
+            return Poll::Ready(Ok(()));
+        }
+        if *ended {
+            return Poll::Ready(match failure {
+                Some(reason) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, reason.clone())),
+                None => Ok(()),
+            });
+        }
+        channel.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for ChannelStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.close_sent {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, MuxError::Closed)));
+        }
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let mut state = this.shared.lock();
+        if state.ended {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, MuxError::Closed)));
+        }
+        let Some(channel) = state.channels.get_mut(&this.id) else {
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::BrokenPipe, MuxError::Closed)));
+        };
+        if channel.send_credit == 0 {
+            // Wait for the peer's reader to catch up
+            channel.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = data.len().min(channel.send_credit as usize).min(MAX_FRAME_PAYLOAD);
+        channel.send_credit -= n as u32;
+        drop(state);
+
+        this.send_command(Command::Data(this.id, Bytes::copy_from_slice(&data[..n])))?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.close_sent {
+            this.send_command(Command::Close(this.id))?;
+            this.close_sent = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for ChannelStream {
+    fn drop(&mut self) {
+        if !self.close_sent {
+            let _ = self.commands.send(Command::Close(self.id));
+        }
+        let mut state = self.shared.lock();
+        let Some(channel) = state.channels.get_mut(&self.id) else {
+            return;
+        };
+        if channel.remote_closed {
+            state.channels.remove(&self.id);
+        } else {
+            // Keep discarding its data until the peer closes it
+            channel.released = true;
+            channel.recv_buf.clear();
+            channel.buffered = 0;
+        }
+    }
+}
+
+/// Channel state shared between the muxer task and the channel handles.
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<MuxState>>);
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, MuxState> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Default)]
+struct MuxState {
+    channels: HashMap<u64, Channel>,
+    /// Whether the muxer task has stopped.
+    ended: bool,
+    /// Why the muxer task stopped, unless the stream closed cleanly.
+    failure: Option<String>,
+}
+
+impl MuxState {
+    /// Channel `id`, created unless that would exceed [`MAX_CHANNELS`].
+    fn channel(&mut self, id: u64) -> Result<&mut Channel, MuxError> {
+        if self.channels.len() >= MAX_CHANNELS && !self.channels.contains_key(&id) {
+            return Err(MuxError::TooManyChannels);
+        }
+        Ok(self.channels.entry(id).or_default())
+    }
+}
+
+struct Channel {
+    /// Received data not yet read.
+    recv_buf: VecDeque<Bytes>,
+    /// Total bytes in `recv_buf`.
+    buffered: usize,
+    /// Bytes read since we last returned window to the peer.
+    unacked: u32,
+    /// Bytes we may still write before the peer returns window.
+    send_credit: u32,
+    /// Whether a `ChannelStream` was handed out for this channel.
+    opened: bool,
+    /// Whether that `ChannelStream` was dropped.
+    released: bool,
+    /// Whether the peer closed its side.
+    remote_closed: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            recv_buf: VecDeque::new(),
+            buffered: 0,
+            unacked: 0,
+            send_credit: CHANNEL_WINDOW,
+            opened: false,
+            released: false,
+            remote_closed: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+}
+
+impl Channel {
+    fn wake_all(&mut self) {
+        wake(&mut self.read_waker);
+        wake(&mut self.write_waker);
+    }
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+/// A frame queued by a channel for the muxer task to send.
+enum Command {
+    Data(u64, Bytes),
+    Window(u64, u32),
+    Close(u64),
+}
+
+impl Command {
+    fn encode(&self) -> Bytes {
+        match self {
+            Command::Data(id, data) => encode_frame(*id, MUX_DATA, data),
+            Command::Window(id, bytes) => encode_frame(*id, MUX_WINDOW, &bytes.to_be_bytes()),
+            Command::Close(id) => encode_frame(*id, MUX_CLOSE, &[]),
+        }
+    }
+}
+
+fn encode_frame(id: u64, frame_type: u8, payload: &[u8]) -> Bytes {
+    let mut frame = Vec::with_capacity(MAX_VARINT_SIZE + 1 + payload.len());
+    put_varint(&mut frame, id);
+    frame.push(frame_type);
+    frame.extend_from_slice(payload);
+    frame.into()
+}
+
+/// Append `value` as an unsigned LEB128 varint.
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Read an unsigned LEB128 varint off the front of `buf`.
+fn get_varint(buf: &mut Bytes) -> Option<u64> {
+    let mut value = 0u64;
+    for i in 0..MAX_VARINT_SIZE {
+        let byte = *buf.get(i)?;
+        let bits = u64::from(byte & 0x7f);
+        if i == MAX_VARINT_SIZE - 1 && byte > 1 {
+            // More than 64 bits
+            return None;
+        }
+        value |= bits << (7 * i);
+        if byte & 0x80 == 0 {
+            buf.advance(i + 1);
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varint_round_trip() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, value);
+            buf.push(0xAA);
+            let mut bytes = Bytes::from(buf);
+            assert_eq!(get_varint(&mut bytes), Some(value));
+            assert_eq!(bytes.as_ref(), &[0xAA]);
+        }
+    }
+
+    #[test]
+    fn test_varint_rejects_truncated_and_overlong() {
+        assert_eq!(get_varint(&mut Bytes::from_static(&[0x80, 0x80])), None);
+        assert_eq!(get_varint(&mut Bytes::from_static(&[0xff; 11])), None);
+    }
+
+    #[test]
+    fn test_dispatch_rejects_window_overrun() {
+        let shared = Shared::default();
+        let frame = encode_frame(7, MUX_DATA, &vec![0u8; CHANNEL_WINDOW as usize]);
+        Muxer::dispatch(&shared, frame).unwrap();
+        assert_eq!(shared.lock().channels[&7].buffered, CHANNEL_WINDOW as usize);
+
+        // One byte more than the peer was allowed to send
+        let frame = encode_frame(7, MUX_DATA, b"x");
+        assert!(matches!(Muxer::dispatch(&shared, frame), Err(MuxError::InvalidFrame)));
+    }
+
+    #[test]
+    fn test_dispatch_rejects_channels_over_the_limit() {
+        let shared = Shared::default();
+        for id in 0..MAX_CHANNELS as u64 {
+            Muxer::dispatch(&shared, encode_frame(id, MUX_DATA, b"x")).unwrap();
+        }
+        // Existing channels still take frames, and stray windows are dropped
+        Muxer::dispatch(&shared, encode_frame(0, MUX_DATA, b"x")).unwrap();
+        Muxer::dispatch(&shared, encode_frame(u64::MAX, MUX_WINDOW, &1u32.to_be_bytes())).unwrap();
+
+        let frame = encode_frame(MAX_CHANNELS as u64, MUX_DATA, b"x");
+        assert!(matches!(Muxer::dispatch(&shared, frame), Err(MuxError::TooManyChannels)));
+        let frame = encode_frame(MAX_CHANNELS as u64, MUX_CLOSE, &[]);
+        assert!(matches!(Muxer::dispatch(&shared, frame), Err(MuxError::TooManyChannels)));
+        assert_eq!(shared.lock().channels.len(), MAX_CHANNELS);
+    }
+}
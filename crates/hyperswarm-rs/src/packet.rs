@@ -0,0 +1,116 @@
+//! Packet transports.
+//!
+//! The DHT, holepunch sessions and encrypted streams exchange datagrams
+//! through a [`PacketTransport`] rather than a UDP socket directly. A
+//! `tokio::net::UdpSocket` is one; [`MemoryNetwork`] provides another that
+//! delivers packets in memory, so tests run deterministically without real
+//! sockets, and other datagram transports (QUIC datagrams) can slot in the
+//! same way.
+//!
+//! Receive loops read through [`PacketTransport::recv_batch`], which takes
+//! several waiting datagrams per system call where the platform allows; see
+//! [`RecvBatch`].
+
+use std::future::poll_fn;
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+
+use futures::future::BoxFuture;
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+
+mod batch;
+mod demux;
+mod memory;
+
+pub use batch::{RecvBatch, DEFAULT_RECV_BATCH_SIZE, MAX_RECV_BATCH_SIZE};
+pub use demux::{classify, Demux, DemuxedTransport, PacketKind};
+pub use memory::{MemoryNetwork, MemoryTransport};
+
+/// An unreliable, unordered datagram transport, like a UDP socket.
+///
+/// The `poll_*` methods mirror `UdpSocket::poll_send_to` / `poll_recv_from`,
+/// so callers can use a transport from inside their own `poll` functions.
+/// Like those, they wake only the task that polled most recently, so tasks
+/// sharing one transport through an `Arc<dyn PacketTransport>` should use
+/// the async [`send_to`](Self::send_to) / [`recv_from`](Self::recv_from)
+/// instead, which implementations override when they can wake every waiter.
+pub trait PacketTransport: Send + Sync + 'static {
+    /// Attempt to send `buf` to `target`, returning the number of bytes sent.
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<std::io::Result<usize>>;
+
+    /// Attempt to receive one datagram into `buf`, returning its sender.
+    ///
+    /// Like UDP, a datagram longer than `buf` is truncated.
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<SocketAddr>>;
+
+    /// The address other endpoints reach this one at.
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+
+    /// Send `buf` to `target`, like `UdpSocket::send_to`.
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> BoxFuture<'a, std::io::Result<usize>> {
+        Box::pin(poll_fn(move |cx| self.poll_send_to(cx, buf, target)))
+    }
+
+    /// Receive one datagram, like `UdpSocket::recv_from`.
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, std::io::Result<(usize, SocketAddr)>> {
+        Box::pin(async move {
+            let mut read_buf = ReadBuf::new(buf);
+            let addr = poll_fn(|cx| self.poll_recv_from(cx, &mut read_buf)).await?;
+            Ok((read_buf.filled().len(), addr))
+        })
+    }
+
+    /// Receive the datagrams already waiting, up to the capacity of `batch`,
+    /// after waiting for the first; returns how many. `batch` is cleared
+    /// first.
+    ///
+    /// Reads one datagram with [`recv_from`](Self::recv_from) unless
+    /// overridden. A `UdpSocket` on Linux reads the whole batch with one
+    /// `recvmmsg` call.
+    fn recv_batch<'a>(&'a self, batch: &'a mut RecvBatch) -> BoxFuture<'a, std::io::Result<usize>> {
+        Box::pin(async move {
+            batch.clear();
+            let slot = batch.free_slot().expect("an empty batch has a free slot");
+            let (len, from) = self.recv_from(slot).await?;
+            batch.fill(len, from);
+            Ok(1)
+        })
+    }
+}
+
+impl PacketTransport for UdpSocket {
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<std::io::Result<usize>> {
+        UdpSocket::poll_send_to(self, cx, buf, target)
+    }
+
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<SocketAddr>> {
+        UdpSocket::poll_recv_from(self, cx, buf)
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> BoxFuture<'a, std::io::Result<usize>> {
+        Box::pin(UdpSocket::send_to(self, buf, target))
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> BoxFuture<'a, std::io::Result<(usize, SocketAddr)>> {
+        Box::pin(UdpSocket::recv_from(self, buf))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn recv_batch<'a>(&'a self, batch: &'a mut RecvBatch) -> BoxFuture<'a, std::io::Result<usize>> {
+        Box::pin(async move {
+            loop {
+                self.readable().await?;
+                match self.try_io(tokio::io::Interest::READABLE, || batch::recv_mmsg(self, batch)) {
+                    // Another reader got there first
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                    received => return received,
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,302 @@
+//! Reading several datagrams per receive.
+//!
+//! Under load a receive loop spends much of its time in one `recvfrom`
+//! system call per datagram. [`PacketTransport::recv_batch`] instead takes
+//! every datagram already waiting, up to a [`RecvBatch`]'s capacity, at
+//! once: a `tokio::net::UdpSocket` on Linux reads them with a single
+//! `recvmmsg` call. Elsewhere, and on other transports, it falls back to one
+//! `recv_from`, so each batch holds one datagram and nothing else changes.
+//!
+//! [`PacketTransport::recv_batch`]: super::PacketTransport::recv_batch
+
+use std::net::SocketAddr;
+
+/// Default capacity of the DHT's receive batches.
+pub const DEFAULT_RECV_BATCH_SIZE: usize = 32;
+/// Largest batch; the kernel reads at most this many messages per `recvmmsg`.
+pub const MAX_RECV_BATCH_SIZE: usize = 1024;
+
+/// Buffers for datagrams received together, and the datagrams the last
+/// receive filled them with.
+pub struct RecvBatch {
+    /// `capacity` slots of `slot_size` bytes each, back to back.
+    buf: Vec<u8>,
+    capacity: usize,
+    slot_size: usize,
+    /// Length and sender of the datagram in each filled slot, in order.
+    received: Vec<(usize, SocketAddr)>,
+}
+
+impl RecvBatch {
+    /// Room for `capacity` datagrams (1 to [`MAX_RECV_BATCH_SIZE`]) of up to
+    /// `slot_size` bytes each. Like UDP, a longer datagram is truncated to
+    /// fill its slot.
+    pub fn new(capacity: usize, slot_size: usize) -> Self {
+        let capacity = capacity.clamp(1, MAX_RECV_BATCH_SIZE);
+        let slot_size = slot_size.max(1);
+        Self {
+            buf: vec![0; capacity * slot_size],
+            capacity,
+            slot_size,
+            received: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Most datagrams one receive takes.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Size of each datagram's buffer.
+    pub fn slot_size(&self) -> usize {
+        self.slot_size
+    }
+
+    /// Number of datagrams received.
+    pub fn len(&self) -> usize {
+        self.received.len()
+    }
+
+    /// Whether no datagram has been received.
+    pub fn is_empty(&self) -> bool {
+        self.received.is_empty()
+    }
+
+    /// The datagrams received, with their senders, in arrival order.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> + '_ {
+        self.received
+            .iter()
+            .enumerate()
+            .map(|(i, &(len, from))| (&self.buf[i * self.slot_size..][..len], from))
+    }
+
+    /// Forget the datagrams received, freeing every slot.
+    pub fn clear(&mut self) {
+        self.received.clear();
+    }
+
+    /// The next free slot, for a transport to receive a datagram into;
+    /// `None` once the batch is full.
+    pub fn free_slot(&mut self) -> Option<&mut [u8]> {
+        let i = self.received.len();
+        (i < self.capacity).then(|| &mut self.buf[i * self.slot_size..][..self.slot_size])
+    }
+
+    /// Record that the free slot now holds a `len`-byte datagram from `from`.
+    ///
+    /// # Panics
+    ///
+    /// If the batch is full, or `len` is larger than a slot.
+    pub fn fill(&mut self, len: usize, from: SocketAddr) {
+        assert!(self.received.len() < self.capacity, "receive batch is full");
+        assert!(len <= self.slot_size, "datagram larger than its slot");
+        self.received.push((len, from));
+    }
+}
+
+/// Read the datagrams waiting on `socket`, up to the capacity of `batch`,
+/// with one non-blocking `recvmmsg` call.
+///
+/// Fails with `WouldBlock` when none are waiting.
+#[cfg(target_os = "linux")]
+pub(super) fn recv_mmsg(socket: &impl std::os::fd::AsRawFd, batch: &mut RecvBatch) -> std::io::Result<usize> {
+    use std::mem::{size_of, zeroed};
+
+    batch.clear();
+    // SAFETY: all-zero bytes are a valid sockaddr_storage.
+    let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { zeroed() }; batch.capacity];
+    let mut iovecs: Vec<libc::iovec> = batch
+        .buf
+        .chunks_exact_mut(batch.slot_size)
+        .map(|slot| libc::iovec {
+            iov_base: slot.as_mut_ptr().cast(),
+            iov_len: slot.len(),
+        })
+        .collect();
+    let mut headers: Vec<libc::mmsghdr> = addrs
+        .iter_mut()
+        .zip(iovecs.iter_mut())
+        .map(|(addr, iovec)| {
+            // SAFETY: all-zero bytes are a valid mmsghdr, with no control
+            // buffer and no flags.
+            let mut header: libc::mmsghdr = unsafe { zeroed() };
+            header.msg_hdr.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+            header.msg_hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+            header
+        })
+        .collect();
+
+    // SAFETY: each header points at its own address buffer and at one iovec
+    // over its own slot of `batch.buf`, all of which outlive the call, and
+    // there are exactly `capacity` headers.
+    let received = unsafe {
+        libc::recvmmsg(
+            socket.as_raw_fd(),
+            headers.as_mut_ptr(),
+            batch.capacity as libc::c_uint,
+            libc::MSG_DONTWAIT,
+            std::ptr::null_mut(),
+        )
+    };
+    if received < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    for (header, addr) in headers.iter().zip(&addrs).take(received as usize) {
+        // A truncated datagram reports the bytes that fit, as recvfrom does
+        batch.fill(header.msg_len as usize, socket_addr(addr)?);
+    }
+    Ok(batch.len())
+}
+
+/// The address in `storage`, as filled in by the kernel.
+#[cfg(target_os = "linux")]
+fn socket_addr(storage: &libc::sockaddr_storage) -> std::io::Result<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV6};
+
+    let family = libc::c_int::from(storage.ss_family);
+    let storage: *const libc::sockaddr_storage = storage;
+    match family {
+        libc::AF_INET => {
+            // SAFETY: the family says the storage holds a sockaddr_in, which
+            // sockaddr_storage is large and aligned enough for.
+            let addr = unsafe { &*storage.cast::<libc::sockaddr_in>() };
+            Ok(SocketAddr::from((
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            // SAFETY: as above, for sockaddr_in6.
+            let addr = unsafe { &*storage.cast::<libc::sockaddr_in6>() };
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        family => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("unexpected address family {}", family),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{MemoryNetwork, PacketTransport};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    /// Receive batches until `count` datagrams have arrived, returning them
+    /// and how many receives it took.
+    async fn receive(transport: &dyn PacketTransport, batch: &mut RecvBatch, count: usize) -> (Vec<(Vec<u8>, SocketAddr)>, usize) {
+        let mut datagrams = Vec::new();
+        let mut receives = 0;
+        while datagrams.len() < count {
+            let received = tokio::time::timeout(Duration::from_secs(1), transport.recv_batch(batch))
+                .await
+                .expect("datagrams should arrive")
+                .unwrap();
+            assert_eq!(received, batch.len());
+            datagrams.extend(batch.iter().map(|(data, from)| (data.to_vec(), from)));
+            receives += 1;
+        }
+        (datagrams, receives)
+    }
+
+    #[test]
+    fn test_slots_fill_in_order() {
+        let mut batch = RecvBatch::new(2, 4);
+        assert!(batch.is_empty());
+        batch.free_slot().unwrap()[..3].copy_from_slice(b"one");
+        batch.fill(3, addr("10.0.0.1:1"));
+        batch.free_slot().unwrap().copy_from_slice(b"four");
+        batch.fill(4, addr("10.0.0.2:2"));
+        assert!(batch.free_slot().is_none(), "two slots");
+        let received: Vec<_> = batch.iter().collect();
+        assert_eq!(received, [(b"one".as_slice(), addr("10.0.0.1:1")), (b"four".as_slice(), addr("10.0.0.2:2"))]);
+
+        batch.clear();
+        assert!(batch.is_empty() && batch.free_slot().is_some());
+        assert_eq!(RecvBatch::new(0, 0).capacity(), 1);
+        assert_eq!(RecvBatch::new(usize::MAX, 1).capacity(), MAX_RECV_BATCH_SIZE);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_other_transports_receive_one_datagram_at_a_time() {
+        let network = MemoryNetwork::new();
+        let receiver = network.bind(addr("10.0.0.1:1000")).unwrap();
+        let sender = network.bind(addr("10.0.0.2:1000")).unwrap();
+        for datagram in [b"first".as_slice(), b"second", b"third"] {
+            sender.send_to(datagram, addr("10.0.0.1:1000")).await.unwrap();
+        }
+
+        let mut batch = RecvBatch::new(8, 64);
+        let (datagrams, receives) = receive(&*receiver, &mut batch, 3).await;
+        assert_eq!(receives, 3, "the fallback reads one datagram per receive");
+        let expected: Vec<_> = [b"first".as_slice(), b"second", b"third"]
+            .iter()
+            .map(|data| (data.to_vec(), addr("10.0.0.2:1000")))
+            .collect();
+        assert_eq!(datagrams, expected);
+    }
+
+    #[tokio::test]
+    async fn test_udp_socket_receives_waiting_datagrams_together() {
+        let receiver = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let target = receiver.local_addr().unwrap();
+        let senders = [
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let mut expected = Vec::new();
+        for i in 0..6u8 {
+            let sender = &senders[usize::from(i % 2)];
+            let datagram = vec![i; usize::from(i) + 1];
+            sender.send_to(&datagram, target).await.unwrap();
+            expected.push((datagram, sender.local_addr().unwrap()));
+        }
+        // Longer than a slot: truncated to fill it
+        senders[0].send_to(&[0xAA; 100], target).await.unwrap();
+        expected.push((vec![0xAA; 16], senders[0].local_addr().unwrap()));
+
+        let mut batch = RecvBatch::new(32, 16);
+        let (datagrams, receives) = receive(&*receiver, &mut batch, expected.len()).await;
+        assert_eq!(datagrams, expected);
+        if cfg!(target_os = "linux") {
+            // Loopback queues each datagram before send_to returns
+            assert_eq!(receives, 1, "recvmmsg should take every waiting datagram");
+        }
+
+        // A batch smaller than the queue leaves the rest for the next receive
+        for sender in &senders {
+            sender.send_to(b"again", target).await.unwrap();
+        }
+        let mut small = RecvBatch::new(1, 16);
+        let (datagrams, receives) = receive(&*receiver, &mut small, 2).await;
+        assert_eq!(receives, 2);
+        assert_eq!(datagrams[0].1, senders[0].local_addr().unwrap());
+        assert_eq!(datagrams[1].1, senders[1].local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_udp_socket_reports_ipv6_senders() {
+        let Ok(receiver) = UdpSocket::bind("[::1]:0").await else {
+            return; // no IPv6 on this host
+        };
+        let sender = UdpSocket::bind("[::1]:0").await.unwrap();
+        sender.send_to(b"v6", receiver.local_addr().unwrap()).await.unwrap();
+        let mut batch = RecvBatch::new(4, 16);
+        let (datagrams, _) = receive(&receiver, &mut batch, 1).await;
+        assert_eq!(datagrams, [(b"v6".to_vec(), sender.local_addr().unwrap())]);
+    }
+}
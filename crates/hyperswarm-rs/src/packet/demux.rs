@@ -0,0 +1,350 @@
+//! Demultiplexing one transport between the DHT, holepunching and streams.
+//!
+//! When the DHT, holepunch sessions and encrypted streams share a port,
+//! every datagram arrives on the same socket. [`Demux`] owns the only
+//! receive loop on it and sorts each datagram by its shape:
+//!
+//! - a bencoded KRPC message (`d...e`) goes to the DHT,
+//! - a probe or punch packet (`HYPERSWARM_PROBE` / `HYPERSWARM_PUNCH`) to
+//!   holepunching,
+//! - anything else long enough to be a Noise message to the transport.
+//!
+//! Each subsystem gets a [`DemuxedTransport`] that sends through the shared
+//! socket and receives only its own datagrams, so it runs unchanged on top.
+//! Datagrams that fit none of the shapes, or that look like bencode without
+//! decoding as KRPC, are dropped and counted.
+//!
+//! Noise datagrams are sorted by shape only, not by peer: every one reaches
+//! the single [`Demux::transport`], whichever peer sent it. An
+//! `EncryptedStream` on it drops datagrams from other addresses, so a demux
+//! carries one stream at a time; a second one would lose its datagrams to
+//! the first. Several peers on one port need a router by source address,
+//! like the connection manager's.
+//!
+//! A failing read is retried after a pause that doubles while the failures
+//! last, from 10 ms up to a second, so an error that keeps recurring does
+//! not spin the loop.
+//!
+//! The loop reads one datagram at a time unless built with
+//! [`Demux::with_batch_size`], which reads several per system call where
+//! the socket allows (see [`RecvBatch`]).
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::io::ReadBuf;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use super::{PacketTransport, RecvBatch};
+use crate::{holepunch, protocol, transport};
+
+/// Receive buffer for one datagram, larger than any UDP payload.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// Datagrams buffered per subsystem before further ones are dropped.
+const DEMUX_QUEUE_SIZE: usize = 256;
+/// Pause before reading again after a failed read.
+const RECV_ERROR_BACKOFF: Duration = Duration::from_millis(10);
+/// Longest pause between reads while they keep failing.
+const MAX_RECV_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+type DatagramSender = mpsc::Sender<(SocketAddr, Bytes)>;
+
+/// The subsystem a datagram on a shared socket belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketKind {
+    /// A KRPC query, response or error.
+    Dht,
+    /// A holepunch probe or punch packet.
+    Holepunch,
+    /// A Noise handshake or transport message.
+    Transport,
+}
+
+/// Classify a datagram by its shape; `None` for one that fits no subsystem,
+/// or is ambiguous.
+pub fn classify(data: &[u8]) -> Option<PacketKind> {
+    if holepunch::is_holepunch_packet(data) {
+        return Some(PacketKind::Holepunch);
+    }
+    if data.starts_with(b"d") && data.ends_with(b"e") {
+        // A Noise message looks like this one time in 65536, so only a
+        // datagram that decodes is taken for KRPC. One that does not could
+        // be either, and is dropped.
+        return protocol::decode_krpc(data).is_ok().then_some(PacketKind::Dht);
+    }
+    transport::could_be_noise_datagram(data.len()).then_some(PacketKind::Transport)
+}
+
+/// Aborts the receive loop once the [`Demux`] and all of its transports are
+/// dropped.
+struct RecvTask(JoinHandle<()>);
+
+impl Drop for RecvTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// The receive loop over a shared transport, and the per-subsystem
+/// transports it feeds.
+pub struct Demux {
+    dht: Arc<DemuxedTransport>,
+    holepunch: Arc<DemuxedTransport>,
+    transport: Arc<DemuxedTransport>,
+    dropped_packets: Arc<AtomicU64>,
+    _task: Arc<RecvTask>,
+}
+
+impl Demux {
+    /// Start reading `socket`, which nothing else may read from now on.
+    pub fn new(socket: Arc<dyn PacketTransport>) -> Self {
+        Self::with_batch_size(socket, 1)
+    }
+
+    /// Start reading `socket` like [`new`](Self::new), taking up to
+    /// `batch_size` waiting datagrams per read.
+    ///
+    /// Each one gets a 64 KiB buffer, so a batch of `n` holds `n` × 64 KiB
+    /// for as long as the demux runs.
+    pub fn with_batch_size(socket: Arc<dyn PacketTransport>, batch_size: usize) -> Self {
+        let dropped_packets = Arc::new(AtomicU64::new(0));
+        let (dht_tx, dht_rx) = mpsc::channel(DEMUX_QUEUE_SIZE);
+        let (holepunch_tx, holepunch_rx) = mpsc::channel(DEMUX_QUEUE_SIZE);
+        let (transport_tx, transport_rx) = mpsc::channel(DEMUX_QUEUE_SIZE);
+        let task = Arc::new(RecvTask(tokio::spawn(Self::recv_loop(
+            socket.clone(),
+            [dht_tx, holepunch_tx, transport_tx],
+            dropped_packets.clone(),
+            RecvBatch::new(batch_size, MAX_DATAGRAM_SIZE),
+        ))));
+        let handle = |rx| {
+            Arc::new(DemuxedTransport {
+                socket: socket.clone(),
+                rx: Mutex::new(rx),
+                _task: task.clone(),
+            })
+        };
+        Self {
+            dht: handle(dht_rx),
+            holepunch: handle(holepunch_rx),
+            transport: handle(transport_rx),
+            dropped_packets,
+            _task: task.clone(),
+        }
+    }
+
+    /// The transport receiving KRPC messages, for `DhtClient::with_transport`.
+    pub fn dht(&self) -> Arc<DemuxedTransport> {
+        self.dht.clone()
+    }
+
+    /// The transport receiving probe and punch packets, for
+    /// `HolepunchSession::with_transport`.
+    pub fn holepunch(&self) -> Arc<DemuxedTransport> {
+        self.holepunch.clone()
+    }
+
+    /// The transport receiving Noise messages, for `EncryptedStream::new`.
+    ///
+    /// It receives the Noise messages of every peer, while a stream drops
+    /// those from anyone but its own peer, so only one stream can run on it
+    /// at a time.
+    pub fn transport(&self) -> Arc<DemuxedTransport> {
+        self.transport.clone()
+    }
+
+    /// Number of datagrams dropped so far because they fit no subsystem, or
+    /// their subsystem had fallen `DEMUX_QUEUE_SIZE` datagrams behind.
+    pub fn dropped_packets(&self) -> u64 {
+        self.dropped_packets.load(Ordering::Relaxed)
+    }
+
+    /// Read datagrams and hand each to its subsystem, in [`PacketKind`] order
+    /// of `senders`, backing off while reads fail.
+    async fn recv_loop(
+        socket: Arc<dyn PacketTransport>,
+        senders: [DatagramSender; 3],
+        dropped_packets: Arc<AtomicU64>,
+        mut batch: RecvBatch,
+    ) {
+        let mut backoff = RECV_ERROR_BACKOFF;
+        loop {
+            if let Err(e) = socket.recv_batch(&mut batch).await {
+                tracing::debug!("Shared socket receive failed, retrying in {:?}: {}", backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_RECV_ERROR_BACKOFF);
+                continue;
+            }
+            backoff = RECV_ERROR_BACKOFF;
+            for (datagram, addr) in batch.iter() {
+                let Some(kind) = classify(datagram) else {
+                    tracing::debug!("Dropping unrecognized {} byte packet from {}", datagram.len(), addr);
+                    dropped_packets.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                };
+                let tx = &senders[kind as usize];
+                if tx.try_send((addr, Bytes::copy_from_slice(datagram))).is_err() {
+                    tracing::debug!("{:?} queue full, dropping packet from {}", kind, addr);
+                    dropped_packets.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+/// One subsystem's view of a shared transport: sends go out through the
+/// shared socket, and only that subsystem's datagrams are received.
+pub struct DemuxedTransport {
+    socket: Arc<dyn PacketTransport>,
+    rx: Mutex<mpsc::Receiver<(SocketAddr, Bytes)>>,
+    _task: Arc<RecvTask>,
+}
+
+impl PacketTransport for DemuxedTransport {
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<std::io::Result<usize>> {
+        self.socket.poll_send_to(cx, buf, target)
+    }
+
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<SocketAddr>> {
+        let mut rx = self.rx.lock().expect("demux receiver lock poisoned");
+        // The receive loop lives as long as this transport, so the channel
+        // never closes
+        let Some((from, data)) = ready!(rx.poll_recv(cx)) else {
+            return Poll::Pending;
+        };
+        let len = data.len().min(buf.remaining());
+        buf.put_slice(&data[..len]);
+        Poll::Ready(Ok(from))
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> futures::future::BoxFuture<'a, std::io::Result<usize>> {
+        self.socket.send_to(buf, target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::MemoryNetwork;
+    use std::time::Duration;
+
+    const KEY: [u8; 32] = [7u8; 32];
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    const PING: &[u8] = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+
+    async fn recv(transport: &DemuxedTransport) -> Option<Vec<u8>> {
+        let mut buf = [0u8; MAX_DATAGRAM_SIZE];
+        let (len, _) = tokio::time::timeout(Duration::from_millis(100), transport.recv_from(&mut buf))
+            .await
+            .ok()?
+            .unwrap();
+        Some(buf[..len].to_vec())
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(PING), Some(PacketKind::Dht));
+        assert_eq!(classify(&holepunch::PacketMagic::default().probe_packet(Some(&KEY), &[0u8; holepunch::PROBE_NONCE_SIZE], addr("10.0.0.1:1000"))), Some(PacketKind::Holepunch));
+        assert_eq!(classify(&[0x42; 48]), Some(PacketKind::Transport));
+        // Bencode-shaped but not KRPC: could be a Noise message too
+        assert_eq!(classify(&[b"d".as_slice(), &[0x42; 30], b"e"].concat()), None);
+        assert_eq!(classify(b"short"), None);
+        assert_eq!(classify(&[]), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_mixed_packets_reach_their_subsystems() {
+        let network = MemoryNetwork::new();
+        let shared = network.bind(addr("10.0.0.1:1000")).unwrap();
+        let peer = network.bind(addr("10.0.0.2:1000")).unwrap();
+        let demux = Demux::new(shared);
+        let (dht, punch, noise) = (demux.dht(), demux.holepunch(), demux.transport());
+        assert_eq!(dht.local_addr().unwrap(), addr("10.0.0.1:1000"));
+
+        let probe = holepunch::PacketMagic::default().probe_packet(Some(&KEY), &[0u8; holepunch::PROBE_NONCE_SIZE], addr("10.0.0.1:1000"));
+        let noise_message = vec![0x42u8; 64];
+        let junk = [b"d".as_slice(), &[0x42; 30], b"e"].concat();
+        for packet in [PING, &junk, &noise_message, &probe, b"tiny".as_slice()] {
+            PacketTransport::send_to(&*peer, packet, addr("10.0.0.1:1000")).await.unwrap();
+        }
+
+        assert_eq!(recv(&dht).await, Some(PING.to_vec()));
+        assert_eq!(recv(&punch).await, Some(probe));
+        assert_eq!(recv(&noise).await, Some(noise_message));
+        for transport in [&dht, &punch, &noise] {
+            assert_eq!(recv(transport).await, None);
+        }
+        assert_eq!(demux.dropped_packets(), 2);
+
+        // Replies leave from the shared address
+        punch.send_to(b"reply", addr("10.0.0.2:1000")).await.unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(peer.recv_from(&mut buf).await.unwrap(), (5, addr("10.0.0.1:1000")));
+    }
+
+    #[tokio::test]
+    async fn test_batched_reads_sort_every_datagram() {
+        let shared = Arc::new(tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let shared_addr = shared.local_addr().unwrap();
+        let peer = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // Queued before the loop starts, so a batch takes several at once
+        let probe = holepunch::PacketMagic::default().probe_packet(Some(&KEY), &[0u8; holepunch::PROBE_NONCE_SIZE], addr("10.0.0.1:1000"));
+        let noise_message = vec![0x42u8; 64];
+        for packet in [PING, &noise_message, b"tiny".as_slice(), &probe, PING] {
+            peer.send_to(packet, shared_addr).await.unwrap();
+        }
+        let demux = Demux::with_batch_size(shared, 8);
+
+        assert_eq!(recv(&demux.dht()).await, Some(PING.to_vec()));
+        assert_eq!(recv(&demux.dht()).await, Some(PING.to_vec()));
+        assert_eq!(recv(&demux.holepunch()).await, Some(probe));
+        assert_eq!(recv(&demux.transport()).await, Some(noise_message));
+        assert_eq!(demux.dropped_packets(), 1);
+    }
+
+    /// A transport whose every read fails, counting the attempts.
+    struct FailingTransport(std::sync::atomic::AtomicUsize);
+
+    impl PacketTransport for FailingTransport {
+        fn poll_send_to(&self, _cx: &mut Context<'_>, buf: &[u8], _target: SocketAddr) -> Poll<std::io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_recv_from(&self, _cx: &mut Context<'_>, _buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<SocketAddr>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Poll::Ready(Err(std::io::ErrorKind::ConnectionRefused.into()))
+        }
+
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            Ok(addr("10.0.0.1:1000"))
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recurring_read_errors_back_off() {
+        let socket = Arc::new(FailingTransport(Default::default()));
+        let _demux = Demux::new(socket.clone());
+
+        // Reads at 0, 10, 30, 70, ... ms: a handful a second, not a spin
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(socket.0.load(Ordering::Relaxed), 4);
+        // Capped at one a second
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let reads = socket.0.load(Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(socket.0.load(Ordering::Relaxed) - reads, 10);
+    }
+}
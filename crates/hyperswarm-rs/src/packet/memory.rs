@@ -0,0 +1,143 @@
+//! In-memory packet transport.
+//!
+//! Endpoints bound on the same [`MemoryNetwork`] exchange datagrams through
+//! channels: every packet sent to a bound address arrives, in order, and
+//! packets to unbound addresses vanish the way UDP would drop them. Nothing
+//! touches the operating system, so tests built on it are deterministic.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use bytes::Bytes;
+use tokio::io::ReadBuf;
+use tokio::sync::mpsc;
+
+use super::PacketTransport;
+
+/// First port handed out when binding to port 0, as in the IANA dynamic range.
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+type Inboxes = Arc<Mutex<HashMap<SocketAddr, mpsc::UnboundedSender<(SocketAddr, Bytes)>>>>;
+
+/// A set of in-memory endpoints that can reach each other.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    inboxes: Inboxes,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind an endpoint at `addr`. Port 0 picks a free port.
+    ///
+    /// Fails with `AddrInUse` if another endpoint holds the address. Dropping
+    /// the transport frees it.
+    pub fn bind(&self, addr: SocketAddr) -> std::io::Result<Arc<MemoryTransport>> {
+        let mut inboxes = self.inboxes.lock().expect("memory network lock poisoned");
+        let addr = if addr.port() == 0 {
+            (FIRST_EPHEMERAL_PORT..=u16::MAX)
+                .map(|port| SocketAddr::new(addr.ip(), port))
+                .find(|candidate| !inboxes.contains_key(candidate))
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::AddrInUse, "no free port"))?
+        } else if inboxes.contains_key(&addr) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("{} is already bound", addr),
+            ));
+        } else {
+            addr
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        inboxes.insert(addr, tx);
+        Ok(Arc::new(MemoryTransport {
+            addr,
+            inboxes: self.inboxes.clone(),
+            rx: Mutex::new(rx),
+        }))
+    }
+}
+
+/// One endpoint on a [`MemoryNetwork`].
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    inboxes: Inboxes,
+    rx: Mutex<mpsc::UnboundedReceiver<(SocketAddr, Bytes)>>,
+}
+
+impl PacketTransport for MemoryTransport {
+    fn poll_send_to(&self, _cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<std::io::Result<usize>> {
+        let inboxes = self.inboxes.lock().expect("memory network lock poisoned");
+        if let Some(inbox) = inboxes.get(&target) {
+            // The receiver only goes away while its endpoint unbinds
+            let _ = inbox.send((self.addr, Bytes::copy_from_slice(buf)));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<SocketAddr>> {
+        let mut rx = self.rx.lock().expect("memory transport lock poisoned");
+        // Our own sender stays in the network until drop, so the channel never closes
+        let Some((from, data)) = ready!(rx.poll_recv(cx)) else {
+            return Poll::Pending;
+        };
+        let len = data.len().min(buf.remaining());
+        buf.put_slice(&data[..len]);
+        Poll::Ready(Ok(from))
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        if let Ok(mut inboxes) = self.inboxes.lock() {
+            inboxes.remove(&self.addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_packets_reach_bound_endpoints() {
+        let network = MemoryNetwork::new();
+        let a: Arc<dyn PacketTransport> = network.bind(addr("10.0.0.1:1000")).unwrap();
+        let b: Arc<dyn PacketTransport> = network.bind(addr("10.0.0.2:0")).unwrap();
+        let b_addr = b.local_addr().unwrap();
+        assert_eq!(b_addr, addr("10.0.0.2:49152"));
+
+        a.send_to(b"hello", b_addr).await.unwrap();
+        // Nobody listens here; the packet is dropped
+        a.send_to(b"lost", addr("10.0.0.3:1000")).await.unwrap();
+        a.send_to(b"world", b_addr).await.unwrap();
+
+        let mut buf = [0u8; 16];
+        assert_eq!(b.recv_from(&mut buf).await.unwrap(), (5, addr("10.0.0.1:1000")));
+        assert_eq!(&buf[..5], b"hello");
+        // Truncated like a UDP datagram
+        let mut small = [0u8; 3];
+        assert_eq!(b.recv_from(&mut small).await.unwrap(), (3, addr("10.0.0.1:1000")));
+        assert_eq!(&small, b"wor");
+    }
+
+    #[test]
+    fn test_address_is_freed_on_drop() {
+        let network = MemoryNetwork::new();
+        let a = network.bind(addr("10.0.0.1:1000")).unwrap();
+        assert!(network.bind(addr("10.0.0.1:1000")).is_err());
+        drop(a);
+        assert!(network.bind(addr("10.0.0.1:1000")).is_ok());
+    }
+}
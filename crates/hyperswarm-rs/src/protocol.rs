@@ -1,17 +1,37 @@
-//! Wire protocol definitions (scaffold).
+//! Wire protocol definitions.
 //!
 //! Hyperswarm's discovery layer uses KRPC-style messages over UDP.
 //! This module defines message types and (de)serialization helpers.
+//!
+//! The encoding follows BEP 5 byte for byte: a bencoded dictionary with
+//! sorted keys, `y` as the single byte string `q`, `r` or `e`, binary values
+//! (ids, tokens, compact nodes and peers) as byte strings, and errors as a
+//! `[code, message]` list.
+//!
+//! Handlers `match` on [`KrpcMessage::body`] rather than checking which of
+//! the envelope's optional keys are set.
+//!
+//! Besides the mainline queries, [`KrpcQueryKind`] has kinds named after
+//! hyperdht's commands (`find_peer`, `lookup`, `announce`, `unannounce`),
+//! carried in the same bencoded envelope with their arguments as extra
+//! [`KrpcArgs`] keys. This is not hyperdht's wire format: hyperdht frames its
+//! commands with dht-rpc's compact encoding, so a hyperdht node cannot read
+//! these messages, nor this module read its. Only nodes running this crate
+//! understand them.
 
 use serde::{Deserialize, Serialize};
 use serde_bencode::{de, ser};
 
+/// Longest transaction id accepted. BEP 5 ids are a couple of bytes; this
+/// leaves room for other implementations without accepting arbitrary blobs.
+pub const MAX_TRANSACTION_ID_SIZE: usize = 16;
+/// Size of a node id.
+const NODE_ID_SIZE: usize = 20;
+
 /// KRPC message envelope.
-///
-/// NOTE: Real KRPC is bencode-based. This scaffold uses serde-friendly
-/// representations; the on-the-wire encoding will be implemented later.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KrpcMessage {
+    #[serde(with = "serde_bytes")]
     pub t: Vec<u8>, // transaction id
     pub y: KrpcMessageType,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -22,58 +42,235 @@ pub struct KrpcMessage {
     pub r: Option<KrpcResponse>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub e: Option<(i64, String)>,
+    /// The querier's address as the responder saw it, in compact peer form
+    /// (BEP 42). It sits next to `r`, not inside it.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub ip: Option<Vec<u8>>,
 }
 
+impl KrpcMessage {
+    /// Check the envelope is well formed: a non-empty transaction id of at
+    /// most [`MAX_TRANSACTION_ID_SIZE`] bytes, exactly the body `y` calls for
+    /// (`q` for queries, `r` for responses, `e` for errors), and 20-byte node
+    /// ids.
+    pub fn validate(&self) -> Result<(), ProtocolError> {
+        if self.t.is_empty() || self.t.len() > MAX_TRANSACTION_ID_SIZE {
+            return Err(ProtocolError::Malformed(format!(
+                "transaction id of {} bytes",
+                self.t.len()
+            )));
+        }
+        let expected = [
+            ("q", self.q.is_some(), matches!(self.y, KrpcMessageType::Query)),
+            ("r", self.r.is_some(), matches!(self.y, KrpcMessageType::Response)),
+            ("e", self.e.is_some(), matches!(self.y, KrpcMessageType::Error)),
+        ];
+        for (key, present, wanted) in expected {
+            if present != wanted {
+                let state = if present { "unexpected" } else { "missing" };
+                return Err(ProtocolError::Malformed(format!("{} `{}` in {:?} message", state, key, self.y)));
+            }
+        }
+        let id = self
+            .a
+            .as_ref()
+            .and_then(|a| a.id.as_ref())
+            .or_else(|| self.r.as_ref().and_then(|r| r.id.as_ref()));
+        if let Some(id) = id.filter(|id| id.len() != NODE_ID_SIZE) {
+            return Err(ProtocolError::Malformed(format!("node id of {} bytes", id.len())));
+        }
+        Ok(())
+    }
+
+    /// The body `y` calls for. Fails if it is missing, which
+    /// [`validate`](Self::validate) rules out for decoded messages, or for a
+    /// query without arguments.
+    pub fn body(&self) -> Result<KrpcBody<'_>, ProtocolError> {
+        let missing = |key| ProtocolError::Malformed(format!("missing `{}` in {:?} message", key, self.y));
+        match self.y {
+            KrpcMessageType::Query => Ok(KrpcBody::Query {
+                kind: self.q.as_ref().ok_or_else(|| missing("q"))?,
+                args: self.a.as_ref().ok_or_else(|| missing("a"))?,
+            }),
+            KrpcMessageType::Response => self.r.as_ref().map(KrpcBody::Response).ok_or_else(|| missing("r")),
+            KrpcMessageType::Error => self
+                .e
+                .as_ref()
+                .map(|(code, message)| KrpcBody::Error { code: *code, message })
+                .ok_or_else(|| missing("e")),
+        }
+    }
+}
+
+/// What a [`KrpcMessage`] carries, by message type.
+#[derive(Debug, Clone, Copy)]
+pub enum KrpcBody<'a> {
+    /// `y` = `q`: the query kind and its arguments.
+    Query { kind: &'a KrpcQueryKind, args: &'a KrpcArgs },
+    /// `y` = `r`.
+    Response(&'a KrpcResponse),
+    /// `y` = `e`.
+    Error { code: i64, message: &'a str },
+}
+
+/// The `y` key: `q`, `r` or `e` on the wire.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "lowercase")]
 pub enum KrpcMessageType {
+    #[serde(rename = "q")]
     Query,
+    #[serde(rename = "r")]
     Response,
+    #[serde(rename = "e")]
     Error,
 }
 
-/// Query kinds: ping, find_node, get_peers, announce_peer.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Query kinds: ping, find_node, get_peers, announce_peer, BEP 44 get /
+/// put, and find_peer, lookup, announce and unannounce, named after
+/// hyperdht's commands but bencoded like the rest (see the module docs).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum KrpcQueryKind {
     Ping,
     FindNode,
     GetPeers,
     AnnouncePeer,
+    /// Withdraw an earlier `announce_peer` or `announce` (same arguments and
+    /// token).
+    Unannounce,
+    /// Fetch a stored item (BEP 44).
+    Get,
+    /// Store an item (BEP 44).
+    Put,
+    /// Find the peer whose public key hashes to `target`.
+    FindPeer,
+    /// Find the peers announced under the topic `target`.
+    Lookup,
+    /// Announce `peer` under the topic `target`, signed with `sig`.
+    Announce,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct KrpcArgs {
     /// Node id.
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
     pub id: Option<Vec<u8>>,
-    /// Target node id (find_node).
-    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Target node id (find_node, get), or the 32-byte topic or public key
+    /// hash a hyperdht command is about.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
     pub target: Option<Vec<u8>>,
     /// Info-hash / topic (get_peers/announce_peer).
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
     pub info_hash: Option<Vec<u8>>,
     /// Announced port.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub port: Option<u16>,
-    /// Token from get_peers.
+    /// `1` to announce the port the query was sent from instead of `port`.
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub implied_port: Option<u8>,
+    /// Token from get_peers.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
     pub token: Option<Vec<u8>>,
+    /// Requested node families, `"n4"` and/or `"n6"` (BEP 32).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub want: Option<Vec<String>>,
+    /// Stored value (BEP 44 put).
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub v: Option<Vec<u8>>,
+    /// ed25519 public key of a mutable item (BEP 44).
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub k: Option<Vec<u8>>,
+    /// ed25519 signature of a mutable item (BEP 44) or of a hyperdht
+    /// announce record.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub sig: Option<Vec<u8>>,
+    /// The announcing peer (hyperdht announce / unannounce): its 32-byte
+    /// public key followed by compact peer info, like a response's `peers`.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub peer: Option<Vec<u8>>,
+    /// Compact addresses of relays the announcing peer is reachable through
+    /// (hyperdht announce).
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "byte_strings")]
+    pub relay_addresses: Option<Vec<Vec<u8>>>,
+    /// Refresh value of a hyperdht announce, letting the peer renew it.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub refresh: Option<Vec<u8>>,
+    /// Sequence number of a mutable item; on get, the one the querier already has.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    /// Salt of a mutable item (BEP 44).
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub salt: Option<Vec<u8>>,
+    /// Compare-and-swap: only replace an item stored under this sequence number.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cas: Option<i64>,
+    /// Opaque data announced along with the peer (announce_peer), handed
+    /// to whoever looks it up.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub value: Option<Vec<u8>>,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct KrpcResponse {
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
     pub id: Option<Vec<u8>>,
     /// Compact node info.
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
     pub nodes: Option<Vec<u8>>,
+    /// Compact IPv6 node info (BEP 32).
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub nodes6: Option<Vec<u8>>,
     /// Peer values.
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "byte_strings")]
     pub values: Option<Vec<Vec<u8>>>,
+    /// Peers with their public key (hyperdht): a 32-byte key followed by
+    /// compact peer info.
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "byte_strings")]
+    pub peers: Option<Vec<Vec<u8>>>,
     /// Token for announce_peer.
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
     pub token: Option<Vec<u8>>,
+    /// Stored value (BEP 44 get).
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub v: Option<Vec<u8>>,
+    /// ed25519 public key of a mutable item (BEP 44).
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub k: Option<Vec<u8>>,
+    /// ed25519 signature of a mutable item (BEP 44).
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub sig: Option<Vec<u8>>,
+    /// Sequence number of a mutable item (BEP 44).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    /// Data announced along with some of the peers in `values`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer_values: Option<Vec<PeerValue>>,
+}
+
+/// Data a peer announced, keyed on its compact peer info.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerValue {
+    #[serde(with = "serde_bytes")]
+    pub peer: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pub value: Vec<u8>,
+}
+
+/// Serde adapter for an optional list of byte strings, such as `values`.
+mod byte_strings {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    pub fn serialize<S: Serializer>(values: &Option<Vec<Vec<u8>>>, serializer: S) -> Result<S::Ok, S::Error> {
+        values
+            .as_ref()
+            .map(|values| values.iter().map(|v| Bytes::new(v)).collect::<Vec<_>>())
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<Vec<u8>>>, D::Error> {
+        let values = Option::<Vec<ByteBuf>>::deserialize(deserializer)?;
+        Ok(values.map(|values| values.into_iter().map(ByteBuf::into_vec).collect()))
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -84,14 +281,20 @@ pub enum ProtocolError {
     BencodeEncode(String),
     #[error("bencode decode error: {0}")]
     BencodeDecode(String),
+    #[error("malformed message: {0}")]
+    Malformed(String),
 }
 
 pub fn encode_krpc(msg: &KrpcMessage) -> Result<Vec<u8>, ProtocolError> {
     ser::to_bytes(msg).map_err(|e| ProtocolError::BencodeEncode(e.to_string()))
 }
 
+/// Decode a KRPC message, rejecting malformed ones (see
+/// [`KrpcMessage::validate`]).
 pub fn decode_krpc(data: &[u8]) -> Result<KrpcMessage, ProtocolError> {
-    de::from_bytes(data).map_err(|e| ProtocolError::BencodeDecode(e.to_string()))
+    let msg: KrpcMessage = de::from_bytes(data).map_err(|e| ProtocolError::BencodeDecode(e.to_string()))?;
+    msg.validate()?;
+    Ok(msg)
 }
 
 #[cfg(test)]
@@ -110,6 +313,7 @@ mod tests {
             }),
             r: None,
             e: None,
+            ip: None,
         };
 
         // Encode
@@ -138,6 +342,7 @@ mod tests {
                 ..Default::default()
             }),
             e: None,
+            ip: None,
         };
 
         // Encode
@@ -152,4 +357,361 @@ mod tests {
         }
         assert!(decoded.r.is_some());
     }
+
+    // Golden vectors: the BEP 5 examples, which are also the exact bytes the
+    // JS `bencode` / `k-rpc` libraries produce for these messages.
+    const PING_QUERY: &[u8] = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+    const GET_PEERS_RESPONSE: &[u8] =
+        b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
+    const ERROR_RESPONSE: &[u8] = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+
+    #[test]
+    fn test_ping_query_matches_golden_bytes() {
+        let msg = KrpcMessage {
+            t: b"aa".to_vec(),
+            y: KrpcMessageType::Query,
+            q: Some(KrpcQueryKind::Ping),
+            a: Some(KrpcArgs {
+                id: Some(b"abcdefghij0123456789".to_vec()),
+                ..Default::default()
+            }),
+            r: None,
+            e: None,
+            ip: None,
+        };
+        assert_eq!(encode_krpc(&msg).unwrap(), PING_QUERY);
+
+        let decoded = decode_krpc(PING_QUERY).unwrap();
+        assert!(matches!(decoded.y, KrpcMessageType::Query));
+        assert!(matches!(decoded.q, Some(KrpcQueryKind::Ping)));
+        assert_eq!(decoded.a.unwrap().id.unwrap(), b"abcdefghij0123456789");
+    }
+
+    #[test]
+    fn test_get_peers_response_matches_golden_bytes() {
+        let msg = KrpcMessage {
+            t: b"aa".to_vec(),
+            y: KrpcMessageType::Response,
+            q: None,
+            a: None,
+            r: Some(KrpcResponse {
+                id: Some(b"abcdefghij0123456789".to_vec()),
+                token: Some(b"aoeusnth".to_vec()),
+                values: Some(vec![b"axje.u".to_vec(), b"idhtnm".to_vec()]),
+                ..Default::default()
+            }),
+            e: None,
+            ip: None,
+        };
+        assert_eq!(encode_krpc(&msg).unwrap(), GET_PEERS_RESPONSE);
+
+        let decoded = decode_krpc(GET_PEERS_RESPONSE).unwrap();
+        let r = decoded.r.unwrap();
+        assert_eq!(r.token.unwrap(), b"aoeusnth");
+        assert_eq!(r.values.unwrap(), vec![b"axje.u".to_vec(), b"idhtnm".to_vec()]);
+    }
+
+    #[test]
+    fn test_error_matches_golden_bytes() {
+        let msg = KrpcMessage {
+            t: b"aa".to_vec(),
+            y: KrpcMessageType::Error,
+            q: None,
+            a: None,
+            r: None,
+            e: Some((201, "A Generic Error Ocurred".to_string())),
+            ip: None,
+        };
+        assert_eq!(encode_krpc(&msg).unwrap(), ERROR_RESPONSE);
+        assert_eq!(decode_krpc(ERROR_RESPONSE).unwrap().e, msg.e);
+    }
+
+    // Fixed encodings of the hyperdht-named commands: a lookup of a topic, an
+    // announce of a keyed peer with one relay, and the lookup's answer
+    // listing that peer. These are this crate's own bytes, pinned so the
+    // encoding does not drift, not captures of hyperdht traffic.
+    const HYPERDHT_TOPIC: &[u8; 32] = b"0123456789abcdef0123456789abcdef";
+    const HYPERDHT_PEER: &[u8; 38] = b"kkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkaxje.u";
+    const HYPERDHT_SIG: &[u8; 64] = b"ssssssssssssssssssssssssssssssssssssssssssssssssssssssssssssssss";
+    const LOOKUP_QUERY: &[u8] = b"d1:ad2:id20:abcdefghij01234567896:target32:0123456789abcdef0123456789abcdefe1:q6:lookup1:t2:aa1:y1:qe";
+    const ANNOUNCE_QUERY: &[u8] = b"d1:ad2:id20:abcdefghij01234567894:peer38:kkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkaxje.u\
+15:relay_addressesl6:idhtnme3:sig64:ssssssssssssssssssssssssssssssssssssssssssssssssssssssssssssssss6:target32:0123456789abcdef0123456789abcdef5:token8:aoeusnthe\
+1:q8:announce1:t2:aa1:y1:qe";
+    const LOOKUP_RESPONSE: &[u8] =
+        b"d1:rd2:id20:abcdefghij01234567895:peersl38:kkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkkaxje.ue5:token8:aoeusnthe1:t2:aa1:y1:re";
+
+    fn hyperdht_query(kind: KrpcQueryKind, args: KrpcArgs) -> KrpcMessage {
+        KrpcMessage {
+            t: b"aa".to_vec(),
+            y: KrpcMessageType::Query,
+            q: Some(kind),
+            a: Some(KrpcArgs {
+                id: Some(b"abcdefghij0123456789".to_vec()),
+                target: Some(HYPERDHT_TOPIC.to_vec()),
+                ..args
+            }),
+            r: None,
+            e: None,
+            ip: None,
+        }
+    }
+
+    #[test]
+    fn test_lookup_encoding_is_stable() {
+        let msg = hyperdht_query(KrpcQueryKind::Lookup, KrpcArgs::default());
+        assert_eq!(encode_krpc(&msg).unwrap(), LOOKUP_QUERY);
+        let decoded = decode_krpc(LOOKUP_QUERY).unwrap();
+        assert_eq!(decoded.q, Some(KrpcQueryKind::Lookup));
+        assert_eq!(decoded.a.unwrap().target.unwrap(), HYPERDHT_TOPIC);
+
+        let response = KrpcMessage {
+            t: b"aa".to_vec(),
+            y: KrpcMessageType::Response,
+            q: None,
+            a: None,
+            r: Some(KrpcResponse {
+                id: Some(b"abcdefghij0123456789".to_vec()),
+                peers: Some(vec![HYPERDHT_PEER.to_vec()]),
+                token: Some(b"aoeusnth".to_vec()),
+                ..Default::default()
+            }),
+            e: None,
+            ip: None,
+        };
+        assert_eq!(encode_krpc(&response).unwrap(), LOOKUP_RESPONSE);
+        assert_eq!(decode_krpc(LOOKUP_RESPONSE).unwrap().r.unwrap().peers.unwrap(), vec![HYPERDHT_PEER.to_vec()]);
+    }
+
+    #[test]
+    fn test_announce_encoding_is_stable() {
+        let args = KrpcArgs {
+            token: Some(b"aoeusnth".to_vec()),
+            peer: Some(HYPERDHT_PEER.to_vec()),
+            relay_addresses: Some(vec![b"idhtnm".to_vec()]),
+            sig: Some(HYPERDHT_SIG.to_vec()),
+            ..Default::default()
+        };
+        let msg = hyperdht_query(KrpcQueryKind::Announce, args);
+        assert_eq!(encode_krpc(&msg).unwrap(), ANNOUNCE_QUERY);
+
+        let decoded = decode_krpc(ANNOUNCE_QUERY).unwrap();
+        let Ok(KrpcBody::Query { kind, args }) = decoded.body() else {
+            panic!("expected a query");
+        };
+        assert_eq!(*kind, KrpcQueryKind::Announce);
+        assert_eq!(args.peer.as_deref(), Some(&HYPERDHT_PEER[..]));
+        assert_eq!(args.relay_addresses.as_deref(), Some(&[b"idhtnm".to_vec()][..]));
+        assert_eq!(args.sig.as_deref(), Some(&HYPERDHT_SIG[..]));
+        assert_eq!(args.token.as_deref(), Some(&b"aoeusnth"[..]));
+        assert!(args.refresh.is_none());
+    }
+
+    #[test]
+    fn test_extension_query_kinds_use_their_command_names() {
+        for (kind, name) in [
+            (KrpcQueryKind::FindPeer, "find_peer"),
+            (KrpcQueryKind::Lookup, "lookup"),
+            (KrpcQueryKind::Announce, "announce"),
+            (KrpcQueryKind::Unannounce, "unannounce"),
+        ] {
+            let encoded = encode_krpc(&hyperdht_query(kind.clone(), KrpcArgs::default())).unwrap();
+            let expected = format!("1:q{}:{}", name.len(), name);
+            assert!(encoded.windows(expected.len()).any(|w| w == expected.as_bytes()), "{:?}", kind);
+            assert_eq!(decode_krpc(&encoded).unwrap().q, Some(kind));
+        }
+    }
+
+    #[test]
+    fn test_each_message_type_decodes_to_its_body() {
+        let query = decode_krpc(PING_QUERY).unwrap();
+        match query.body().unwrap() {
+            KrpcBody::Query { kind, args } => {
+                assert_eq!(*kind, KrpcQueryKind::Ping);
+                assert_eq!(args.id.as_deref(), Some(&b"abcdefghij0123456789"[..]));
+            }
+            other => panic!("expected a query, got {:?}", other),
+        }
+
+        let response = decode_krpc(GET_PEERS_RESPONSE).unwrap();
+        match response.body().unwrap() {
+            KrpcBody::Response(r) => assert_eq!(r.token.as_deref(), Some(&b"aoeusnth"[..])),
+            other => panic!("expected a response, got {:?}", other),
+        }
+
+        let error = decode_krpc(ERROR_RESPONSE).unwrap();
+        match error.body().unwrap() {
+            KrpcBody::Error { code, message } => assert_eq!((code, message), (201, "A Generic Error Ocurred")),
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_body_of_query_without_arguments_fails() {
+        let query = decode_krpc(b"d1:q4:ping1:t2:aa1:y1:qe").unwrap();
+        assert!(matches!(query.body(), Err(ProtocolError::Malformed(_))));
+    }
+
+    // BEP 44 test vector 1 as a `put` and the matching `get` answer, and a
+    // BEP 42 response telling the querier its address is 124.31.75.21:6881.
+    const BEP44_PUBLIC_KEY: &str = "77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548";
+    const BEP44_SIGNATURE: &str = "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff\
+                                   1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01";
+    const BEP42_RESPONSE: &[u8] = b"d2:ip6:\x7c\x1f\x4b\x15\x1a\xe11:rd2:id20:abcdefghij0123456789e1:t2:aa1:y1:re";
+
+    /// Decode a hex string, for the published test vectors.
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    /// `prefix`, then BEP 44 vector 1's `k`, `seq` and `sig` entries, then `suffix`.
+    fn bep44_golden(prefix: &[u8], suffix: &[u8]) -> Vec<u8> {
+        let mut bytes = prefix.to_vec();
+        bytes.extend_from_slice(b"1:k32:");
+        bytes.extend_from_slice(&hex(BEP44_PUBLIC_KEY));
+        bytes.extend_from_slice(b"3:seqi1e3:sig64:");
+        bytes.extend_from_slice(&hex(BEP44_SIGNATURE));
+        bytes.extend_from_slice(suffix);
+        bytes
+    }
+
+    #[test]
+    fn test_bep44_put_matches_golden_bytes() {
+        let golden = bep44_golden(
+            b"d1:ad2:id20:abcdefghij0123456789",
+            b"5:token8:aoeusnth1:v12:Hello World!e1:q3:put1:t2:aa1:y1:qe",
+        );
+        let msg = KrpcMessage {
+            t: b"aa".to_vec(),
+            y: KrpcMessageType::Query,
+            q: Some(KrpcQueryKind::Put),
+            a: Some(KrpcArgs {
+                id: Some(b"abcdefghij0123456789".to_vec()),
+                k: Some(hex(BEP44_PUBLIC_KEY)),
+                seq: Some(1),
+                sig: Some(hex(BEP44_SIGNATURE)),
+                token: Some(b"aoeusnth".to_vec()),
+                v: Some(b"Hello World!".to_vec()),
+                ..Default::default()
+            }),
+            r: None,
+            e: None,
+            ip: None,
+        };
+        assert_eq!(encode_krpc(&msg).unwrap(), golden);
+
+        let decoded = decode_krpc(&golden).unwrap();
+        let Ok(KrpcBody::Query { kind: KrpcQueryKind::Put, args }) = decoded.body() else {
+            panic!("expected a put");
+        };
+        let item = crate::dht::storage::MutableItem {
+            value: args.v.clone().unwrap(),
+            public_key: args.k.clone().unwrap().try_into().unwrap(),
+            salt: Vec::new(),
+            seq: args.seq.unwrap(),
+            signature: args.sig.clone().unwrap().try_into().unwrap(),
+        };
+        assert!(item.verify());
+    }
+
+    #[test]
+    fn test_bep44_get_response_matches_golden_bytes() {
+        let golden = bep44_golden(
+            b"d1:rd2:id20:abcdefghij0123456789",
+            b"5:token8:aoeusnth1:v12:Hello World!e1:t2:aa1:y1:re",
+        );
+        let msg = KrpcMessage {
+            t: b"aa".to_vec(),
+            y: KrpcMessageType::Response,
+            q: None,
+            a: None,
+            r: Some(KrpcResponse {
+                id: Some(b"abcdefghij0123456789".to_vec()),
+                k: Some(hex(BEP44_PUBLIC_KEY)),
+                seq: Some(1),
+                sig: Some(hex(BEP44_SIGNATURE)),
+                token: Some(b"aoeusnth".to_vec()),
+                v: Some(b"Hello World!".to_vec()),
+                ..Default::default()
+            }),
+            e: None,
+            ip: None,
+        };
+        assert_eq!(encode_krpc(&msg).unwrap(), golden);
+        let r = decode_krpc(&golden).unwrap().r.unwrap();
+        assert_eq!(r.v.unwrap(), b"Hello World!");
+        assert_eq!(r.sig.unwrap(), hex(BEP44_SIGNATURE));
+    }
+
+    #[test]
+    fn test_bep42_ip_matches_golden_bytes() {
+        let msg = KrpcMessage {
+            t: b"aa".to_vec(),
+            y: KrpcMessageType::Response,
+            q: None,
+            a: None,
+            r: Some(KrpcResponse {
+                id: Some(b"abcdefghij0123456789".to_vec()),
+                ..Default::default()
+            }),
+            e: None,
+            ip: Some(vec![124, 31, 75, 21, 0x1a, 0xe1]),
+        };
+        assert_eq!(encode_krpc(&msg).unwrap(), BEP42_RESPONSE);
+
+        // The querier takes its address from `ip`; BEP 42's first vector is
+        // an id that address may use.
+        let ip = decode_krpc(BEP42_RESPONSE).unwrap().ip.unwrap();
+        let ip = std::net::IpAddr::from([ip[0], ip[1], ip[2], ip[3]]);
+        let mut id = [0u8; 20];
+        id[..3].copy_from_slice(&[0x5f, 0xbf, 0xbf]);
+        id[19] = 1;
+        assert!(crate::dht::node_id::is_secure_node_id(&id, ip));
+    }
+
+    #[test]
+    fn test_decode_ignores_unknown_keys() {
+        // Real nodes add keys such as `v` (client version)
+        let data = b"d1:rd2:id20:abcdefghij0123456789e1:t2:aa1:v4:LT\x01\x021:y1:re";
+        let decoded = decode_krpc(data).unwrap();
+        assert!(matches!(decoded.y, KrpcMessageType::Response));
+        assert_eq!(decoded.r.unwrap().id.unwrap(), b"abcdefghij0123456789");
+    }
+
+    /// Decoding `data` fails as malformed.
+    fn assert_malformed(data: &[u8]) {
+        match decode_krpc(data) {
+            Err(ProtocolError::Malformed(_)) => {}
+            other => panic!("expected Malformed for {:?}, got {:?}", String::from_utf8_lossy(data), other),
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_bad_transaction_ids() {
+        assert_malformed(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t0:1:y1:qe");
+        assert_malformed(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t17:aaaaaaaaaaaaaaaaa1:y1:qe");
+        // 16 bytes is still fine
+        decode_krpc(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t16:aaaaaaaaaaaaaaaa1:y1:qe").unwrap();
+    }
+
+    #[test]
+    fn test_decode_rejects_missing_body() {
+        // Query without `q`, response without `r`, error without `e`
+        assert_malformed(b"d1:ad2:id20:abcdefghij0123456789e1:t2:aa1:y1:qe");
+        assert_malformed(b"d1:t2:aa1:y1:re");
+        assert_malformed(b"d1:t2:aa1:y1:ee");
+    }
+
+    #[test]
+    fn test_decode_rejects_body_of_another_type() {
+        // Response carrying `q`, query carrying `r`, response carrying `e`
+        assert_malformed(b"d1:q4:ping1:rd2:id20:abcdefghij0123456789e1:t2:aa1:y1:re");
+        assert_malformed(b"d1:q4:ping1:rd2:id20:abcdefghij0123456789e1:t2:aa1:y1:qe");
+        assert_malformed(b"d1:eli201e1:xe1:rd2:id20:abcdefghij0123456789e1:t2:aa1:y1:re");
+    }
+
+    #[test]
+    fn test_decode_rejects_bad_node_ids() {
+        assert_malformed(b"d1:ad2:id19:abcdefghij012345678e1:q4:ping1:t2:aa1:y1:qe");
+        assert_malformed(b"d1:rd2:id21:abcdefghij0123456789Xe1:t2:aa1:y1:re");
+    }
 }
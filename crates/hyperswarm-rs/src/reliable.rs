@@ -0,0 +1,738 @@
+//! Reliable, ordered delivery over an encrypted session.
+//!
+//! An [`EncryptedStream`] sends each message as Noise messages, one per
+//! datagram, and Noise only opens messages in the order they were sealed,
+//! so a single lost or reordered datagram breaks the stream. A
+//! [`ReliableStream`] runs the same Noise handshake, then carries messages
+//! itself above the session: it cuts each message into numbered segments,
+//! seals every segment and ack as a Noise message of its own, with its nonce
+//! alongside so packets open in any order, acknowledges what arrives with
+//! cumulative acks, retransmits what is not acknowledged in time, waiting
+//! twice as long after each retry, and hands messages to the application in
+//! the order they were sent. Packets of at most [`MAX_SEGMENT_SIZE`] bytes
+//! go on the wire; those that do not authenticate are dropped, so only the
+//! peer can fill in a segment or acknowledge one.
+//!
+//! The sender keeps at most [`ReliableConfig::window`] segments
+//! unacknowledged, and the receiver buffers as many that arrive out of
+//! order, and as many complete messages the application has not read yet.
+//! Once that many are waiting, the receiver stops acknowledging new
+//! segments until the application reads, and the sender waits.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+
+use crate::packet::PacketTransport;
+use crate::transport::{EncryptedStream, PacketCipher, TransportError, DEFAULT_MAX_MESSAGE_SIZE, PACKET_OVERHEAD};
+
+/// Largest packet the link sends, small enough to cross common path MTUs
+/// without IP fragmentation.
+pub const MAX_SEGMENT_SIZE: usize = 1200;
+const SEGMENT_HEADER_SIZE: usize = 9; // kind byte + big-endian u64 sequence number
+const MAX_SEGMENT_PAYLOAD: usize = MAX_SEGMENT_SIZE - PACKET_OVERHEAD - SEGMENT_HEADER_SIZE;
+
+// Segment kinds: the first byte of every sealed segment, before the sequence number
+const SEGMENT_LAST: u8 = 0; // the last (or only) piece of a message
+const SEGMENT_MORE: u8 = 1; // a piece the next segment continues
+const SEGMENT_ACK: u8 = 2; // no payload; the sequence number is the next one missing
+const SEGMENT_CLOSE: u8 = 3; // no payload; the peer will send no more messages
+
+/// Tunables for a [`ReliableStream`].
+#[derive(Clone, Debug)]
+pub struct ReliableConfig {
+    /// Segments sent and not yet acknowledged before sending waits; also how
+    /// many out-of-order segments, and how many unread messages, are
+    /// buffered. At least 1.
+    pub window: usize,
+    /// Wait for an ack before the first retransmission of a segment.
+    pub rto: Duration,
+    /// Longest wait between retransmissions.
+    pub max_rto: Duration,
+    /// Retransmissions of one segment after which the peer is given up on.
+    pub max_retransmits: u32,
+    /// Largest message, in bytes, sent or accepted. Sending a larger one
+    /// fails with [`TransportError::InvalidMessage`]; receiving one stops
+    /// the stream. At least 1.
+    pub max_message_size: usize,
+}
+
+impl Default for ReliableConfig {
+    fn default() -> Self {
+        Self {
+            window: 64,
+            rto: Duration::from_millis(200),
+            max_rto: Duration::from_secs(5),
+            max_retransmits: 8,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+impl ReliableConfig {
+    /// The wait for an ack after a segment's `retransmits`-th retransmission.
+    fn rto(&self, retransmits: u32) -> Duration {
+        self.rto.saturating_mul(1 << retransmits.min(31)).min(self.max_rto)
+    }
+}
+
+/// An encrypted stream whose messages survive lost, duplicated and
+/// reordered datagrams.
+///
+/// Used like an [`EncryptedStream`]: handshake, then [`send`](Self::send) /
+/// [`recv`](Self::recv). Both peers need a `ReliableStream`. Once the
+/// handshake completes, a background task moves segments between the socket
+/// and the stream; it is the socket's only reader and stops when the
+/// `ReliableStream` is dropped.
+///
+/// A segment still unacknowledged after
+/// [`max_retransmits`](ReliableConfig::max_retransmits) retransmissions
+/// means the peer is gone: `send` and `recv` then fail with a `TimedOut`
+/// I/O error.
+pub struct ReliableStream {
+    /// Runs the handshake, then hands its session keys to the link.
+    handshake: Option<EncryptedStream>,
+    link: Option<Arc<Link>>,
+    driver: Option<JoinHandle<()>>,
+    socket: Arc<dyn PacketTransport>,
+    remote_addr: SocketAddr,
+    config: ReliableConfig,
+    local_static_pubkey: [u8; 32],
+    remote_static_key: Option<[u8; 32]>,
+}
+
+impl ReliableStream {
+    /// Create a stream to `remote_addr` over `socket` with a fresh static
+    /// keypair.
+    pub async fn new(socket: Arc<dyn PacketTransport>, remote_addr: SocketAddr, config: ReliableConfig) -> Result<Self, TransportError> {
+        let stream = EncryptedStream::new(socket.clone(), remote_addr).await?;
+        Self::start(stream, socket, remote_addr, config)
+    }
+
+    /// Like [`new`](Self::new), with a persistent static keypair as in
+    /// [`EncryptedStream::with_keypair`].
+    pub fn with_keypair(
+        socket: Arc<dyn PacketTransport>,
+        remote_addr: SocketAddr,
+        private_key: [u8; 32],
+        config: ReliableConfig,
+    ) -> Result<Self, TransportError> {
+        let stream = EncryptedStream::with_keypair(socket.clone(), remote_addr, private_key)?;
+        Self::start(stream, socket, remote_addr, config)
+    }
+
+    fn start(
+        stream: EncryptedStream,
+        socket: Arc<dyn PacketTransport>,
+        remote_addr: SocketAddr,
+        config: ReliableConfig,
+    ) -> Result<Self, TransportError> {
+        if config.max_message_size == 0 {
+            return Err(TransportError::InvalidConfig("max message size must be non-zero".to_string()));
+        }
+        Ok(Self {
+            local_static_pubkey: stream.local_static_pubkey(),
+            handshake: Some(stream),
+            link: None,
+            driver: None,
+            socket,
+            remote_addr,
+            config,
+            remote_static_key: None,
+        })
+    }
+
+    /// Run the Noise handshake as initiator; see
+    /// [`EncryptedStream::handshake_initiator`].
+    pub async fn handshake_initiator(&mut self, remote_static_pubkey: Option<[u8; 32]>) -> Result<(), TransportError> {
+        if let Some(stream) = &mut self.handshake {
+            stream.handshake_initiator(remote_static_pubkey).await?;
+        }
+        self.establish()
+    }
+
+    /// Run the Noise handshake as responder; see
+    /// [`EncryptedStream::handshake_responder`].
+    pub async fn handshake_responder(&mut self, expected_initiator_key: Option<[u8; 32]>) -> Result<(), TransportError> {
+        if let Some(stream) = &mut self.handshake {
+            stream.handshake_responder(expected_initiator_key).await?;
+        }
+        self.establish()
+    }
+
+    /// Move the completed handshake's session to a link, and start driving it.
+    fn establish(&mut self) -> Result<(), TransportError> {
+        let Some(stream) = self.handshake.take() else {
+            return Ok(());
+        };
+        self.remote_static_key = stream.remote_static_key();
+        let cipher = stream.into_packet_cipher()?;
+        let link = Arc::new(Link::new(self.socket.clone(), self.remote_addr, cipher, self.config.clone()));
+        self.driver = Some(tokio::spawn(Link::drive(link.clone())));
+        self.link = Some(link);
+        Ok(())
+    }
+
+    /// The peer's static public key, once the handshake has completed.
+    pub fn remote_static_key(&self) -> Option<[u8; 32]> {
+        self.remote_static_key
+    }
+
+    /// Our static public key, the one the peer sees in the handshake.
+    pub fn local_static_pubkey(&self) -> [u8; 32] {
+        self.local_static_pubkey
+    }
+
+    /// The link, once the handshake has completed.
+    fn link(&self) -> Result<&Link, TransportError> {
+        self.link.as_deref().ok_or(TransportError::HandshakeIncomplete)
+    }
+
+    /// Send `data` as one message, waiting while the window is full.
+    ///
+    /// Data longer than [`max_message_size`](ReliableConfig::max_message_size)
+    /// is [`TransportError::InvalidMessage`]; after [`close`](Self::close),
+    /// sending is [`TransportError::Closed`].
+    pub async fn send(&mut self, data: Bytes) -> Result<(), TransportError> {
+        if data.len() > self.config.max_message_size {
+            return Err(TransportError::InvalidMessage);
+        }
+        let link = self.link()?;
+        std::future::poll_fn(|cx| link.poll_send(cx, &data, SEGMENT_LAST)).await
+    }
+
+    /// The next message, in the order the peer sent them. Once the peer has
+    /// closed its side, or we closed ours, and every message before the
+    /// close has been read, this is [`TransportError::Closed`].
+    pub async fn recv(&mut self) -> Result<Bytes, TransportError> {
+        let link = self.link()?;
+        std::future::poll_fn(|cx| link.poll_recv(cx)).await
+    }
+
+    /// Tell the peer no more messages follow, then wait until it has
+    /// acknowledged everything sent, the close included. Afterwards `send`
+    /// and `recv` are [`TransportError::Closed`]. Closing twice does nothing
+    /// more.
+    pub async fn close(&mut self) -> Result<(), TransportError> {
+        let link = self.link()?;
+        if !link.lock().closed {
+            std::future::poll_fn(|cx| link.poll_send(cx, &[], SEGMENT_CLOSE)).await?;
+        }
+        Ok(link.flushed().await?)
+    }
+}
+
+impl Drop for ReliableStream {
+    fn drop(&mut self) {
+        if let Some(driver) = &self.driver {
+            driver.abort();
+        }
+    }
+}
+
+/// Carries one stream's messages to its peer, reliably and in order.
+struct Link {
+    socket: Arc<dyn PacketTransport>,
+    remote_addr: SocketAddr,
+    config: ReliableConfig,
+    cipher: Mutex<PacketCipher>,
+    state: Mutex<LinkState>,
+    /// Wakes the driver when segments or an ack are queued.
+    queued: Notify,
+    /// Wakes [`flushed`](Self::flushed) on acks and when the link stops.
+    acked: Notify,
+}
+
+impl Link {
+    fn new(socket: Arc<dyn PacketTransport>, remote_addr: SocketAddr, cipher: PacketCipher, config: ReliableConfig) -> Self {
+        Self {
+            socket,
+            remote_addr,
+            config,
+            cipher: Mutex::new(cipher),
+            state: Mutex::new(LinkState::default()),
+            queued: Notify::new(),
+            acked: Notify::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LinkState> {
+        self.state.lock().expect("reliable link lock poisoned")
+    }
+
+    fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, TransportError> {
+        self.cipher.lock().expect("reliable link cipher lock poisoned").seal(plaintext)
+    }
+
+    async fn drive(link: Arc<Link>) {
+        if let Err(e) = link.run().await {
+            tracing::debug!("Reliable link to {} stopped: {}", link.remote_addr, e);
+            link.lock().fail(e.kind());
+        }
+        link.acked.notify_waiters();
+    }
+
+    /// Send queued and overdue segments, and acks, and file what the peer
+    /// sends, until the link fails.
+    async fn run(&self) -> io::Result<()> {
+        // One byte spare, so an oversized packet shows as filling the buffer
+        let mut buf = vec![0u8; MAX_SEGMENT_SIZE + 1];
+        loop {
+            let (ack, due, next_due) = {
+                let mut state = self.lock();
+                let due = state.take_due(Instant::now(), &self.config);
+                if let Some(kind) = state.failure {
+                    return Err(kind.into());
+                }
+                (state.take_ack(), due, state.next_due())
+            };
+            if let Some(next) = ack {
+                let packet = self.seal(&segment(SEGMENT_ACK, next, &[]))?;
+                self.send(&packet).await;
+            }
+            for packet in &due {
+                self.send(packet).await;
+            }
+
+            let retransmit = async {
+                match next_due {
+                    Some(at) => tokio::time::sleep_until(at).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                received = self.socket.recv_from(&mut buf) => {
+                    let (len, from) = received?;
+                    if from == self.remote_addr && len <= MAX_SEGMENT_SIZE {
+                        if let Some(reply) = self.file(&buf[..len]) {
+                            self.send(&reply).await;
+                        }
+                    }
+                }
+                _ = self.queued.notified() => {}
+                _ = retransmit => {}
+            }
+        }
+    }
+
+    /// Send one packet. A packet that fails to go out is as good as lost,
+    /// and is retransmitted like one.
+    async fn send(&self, packet: &[u8]) {
+        if let Err(e) = self.socket.send_to(packet, self.remote_addr).await {
+            tracing::debug!("Failed to send segment to {}: {}", self.remote_addr, e);
+        }
+    }
+
+    /// Handle a packet from the peer. Returns our last handshake message
+    /// when the packet is the peer's handshake message resent; anything
+    /// else that does not authenticate is dropped.
+    fn file(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let plaintext = {
+            let mut cipher = self.cipher.lock().expect("reliable link cipher lock poisoned");
+            match cipher.open(packet) {
+                Ok(plaintext) => plaintext,
+                Err(_) => return cipher.handshake_reply(packet).map(<[u8]>::to_vec),
+            }
+        };
+        let (kind, seq, piece) = parse_segment(&plaintext)?;
+        let mut state = self.lock();
+        match kind {
+            SEGMENT_ACK if state.acked(seq) => self.acked.notify_waiters(),
+            SEGMENT_LAST | SEGMENT_MORE | SEGMENT_CLOSE => {
+                state.received(seq, kind, piece, &self.config);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Queue `data` as one message ending in a segment of kind `last`,
+    /// once the window has room.
+    fn poll_send(&self, cx: &mut Context<'_>, data: &[u8], last: u8) -> Poll<Result<(), TransportError>> {
+        let mut state = self.lock();
+        if let Some(kind) = state.failure {
+            return Poll::Ready(Err(io::Error::from(kind).into()));
+        }
+        if state.closed {
+            return Poll::Ready(Err(TransportError::Closed));
+        }
+        if state.unacked.len() >= self.config.window.max(1) {
+            state.send_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        state.queue(data, last, |segment| self.seal(segment))?;
+        drop(state);
+        self.queued.notify_one();
+        Poll::Ready(Ok(()))
+    }
+
+    /// Take the next message delivered, acknowledging the segments the
+    /// room it leaves lets in.
+    fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Result<Bytes, TransportError>> {
+        let mut state = self.lock();
+        if let Some(message) = state.delivered.pop_front() {
+            if state.advance(&self.config) {
+                drop(state);
+                self.queued.notify_one();
+            }
+            return Poll::Ready(Ok(message));
+        }
+        if state.closed || state.peer_closed {
+            return Poll::Ready(Err(TransportError::Closed));
+        }
+        if let Some(kind) = state.failure {
+            return Poll::Ready(Err(io::Error::from(kind).into()));
+        }
+        state.recv_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Wait until every segment sent has been acknowledged.
+    async fn flushed(&self) -> io::Result<()> {
+        loop {
+            let acked = self.acked.notified();
+            tokio::pin!(acked);
+            acked.as_mut().enable();
+            {
+                let state = self.lock();
+                if let Some(kind) = state.failure {
+                    return Err(kind.into());
+                }
+                if state.unacked.is_empty() {
+                    return Ok(());
+                }
+            }
+            acked.await;
+        }
+    }
+}
+
+/// Both directions of a link: segments in flight, and segments received.
+#[derive(Default)]
+struct LinkState {
+    /// Sequence number of the next segment we send.
+    next_seq: u64,
+    /// Segments queued or sent, and not yet acknowledged, oldest first.
+    unacked: VecDeque<Segment>,
+    /// Whether we queued a close; nothing is sent after it.
+    closed: bool,
+    /// The stream, waiting for room in the window.
+    send_waker: Option<Waker>,
+    /// The first sequence number we have not taken in from the peer.
+    recv_next: u64,
+    /// Segments received from `recv_next` on, not yet taken in: their kind
+    /// and piece.
+    out_of_order: BTreeMap<u64, (u8, Bytes)>,
+    /// The message being put back together.
+    partial: BytesMut,
+    /// Messages complete and in order, waiting for the stream.
+    delivered: VecDeque<Bytes>,
+    /// Whether the peer's close was taken in; nothing follows it.
+    peer_closed: bool,
+    /// Whether the peer is owed an ack.
+    ack_due: bool,
+    /// The stream, waiting for a message.
+    recv_waker: Option<Waker>,
+    /// Why the link stopped, once it has.
+    failure: Option<io::ErrorKind>,
+}
+
+/// A segment awaiting its ack.
+struct Segment {
+    seq: u64,
+    /// Sealed once: a retransmission is the same packet, never the same
+    /// nonce over other plaintext.
+    packet: Bytes,
+    /// When to retransmit; `None` until first sent.
+    due: Option<Instant>,
+    retransmits: u32,
+}
+
+impl LinkState {
+    /// Cut `message` into segments, the last of kind `last`, and queue them
+    /// as `seal` seals them. An empty message is still one segment.
+    fn queue(
+        &mut self,
+        message: &[u8],
+        last: u8,
+        mut seal: impl FnMut(&[u8]) -> Result<Vec<u8>, TransportError>,
+    ) -> Result<(), TransportError> {
+        let count = message.len().div_ceil(MAX_SEGMENT_PAYLOAD).max(1);
+        for i in 0..count {
+            let piece = &message[i * MAX_SEGMENT_PAYLOAD..message.len().min((i + 1) * MAX_SEGMENT_PAYLOAD)];
+            let kind = if i + 1 < count { SEGMENT_MORE } else { last };
+            let packet = seal(&segment(kind, self.next_seq, piece))?;
+            self.unacked.push_back(Segment {
+                seq: self.next_seq,
+                packet: packet.into(),
+                due: None,
+                retransmits: 0,
+            });
+            self.next_seq += 1;
+        }
+        self.closed = last == SEGMENT_CLOSE;
+        Ok(())
+    }
+
+    /// The segments to send at `now`: those never sent, and those whose ack
+    /// is overdue. Fails the link once a segment has been retransmitted
+    /// `max_retransmits` times.
+    fn take_due(&mut self, now: Instant, config: &ReliableConfig) -> Vec<Bytes> {
+        let mut due = Vec::new();
+        let mut gave_up = false;
+        for segment in &mut self.unacked {
+            match segment.due {
+                Some(at) if at > now => continue,
+                Some(_) if segment.retransmits >= config.max_retransmits => {
+                    gave_up = true;
+                    break;
+                }
+                Some(_) => segment.retransmits += 1,
+                None => {}
+            }
+            segment.due = Some(now + config.rto(segment.retransmits));
+            due.push(segment.packet.clone());
+        }
+        if gave_up {
+            self.fail(io::ErrorKind::TimedOut);
+            return Vec::new();
+        }
+        due
+    }
+
+    /// When the next segment is due for retransmission.
+    fn next_due(&self) -> Option<Instant> {
+        self.unacked.iter().filter_map(|segment| segment.due).min()
+    }
+
+    /// Drop the segments the peer has acknowledged: all before `next`.
+    /// Returns whether any were. An ack for segments never sent is ignored.
+    fn acked(&mut self, next: u64) -> bool {
+        if next > self.next_seq {
+            return false;
+        }
+        let before = self.unacked.len();
+        while self.unacked.front().is_some_and(|segment| segment.seq < next) {
+            self.unacked.pop_front();
+        }
+        let progressed = self.unacked.len() < before;
+        if progressed {
+            if let Some(waker) = self.send_waker.take() {
+                waker.wake();
+            }
+        }
+        progressed
+    }
+
+    /// File a segment of kind `kind`, delivering every message it
+    /// completes, and owe the peer an ack. Duplicates, and segments further
+    /// ahead than the window, are dropped.
+    fn received(&mut self, seq: u64, kind: u8, piece: &[u8], config: &ReliableConfig) {
+        self.ack_due = true;
+        if seq < self.recv_next || seq - self.recv_next >= config.window.max(1) as u64 {
+            return;
+        }
+        self.out_of_order
+            .entry(seq)
+            .or_insert_with(|| (kind, Bytes::copy_from_slice(piece)));
+        self.advance(config);
+    }
+
+    /// Take in the segments that follow on from `recv_next`, while fewer
+    /// than a window of messages wait to be read. Returns whether any were.
+    fn advance(&mut self, config: &ReliableConfig) -> bool {
+        let start = self.recv_next;
+        while self.delivered.len() < config.window.max(1) && !self.peer_closed {
+            let Some((kind, piece)) = self.out_of_order.remove(&self.recv_next) else {
+                break;
+            };
+            self.recv_next += 1;
+            if kind == SEGMENT_CLOSE {
+                self.peer_closed = true;
+                break;
+            }
+            self.partial.extend_from_slice(&piece);
+            if self.partial.len() > config.max_message_size {
+                self.fail(io::ErrorKind::InvalidData);
+                break;
+            }
+            if kind == SEGMENT_LAST {
+                let message = self.partial.split().freeze();
+                self.delivered.push_back(message);
+            }
+        }
+        let progressed = self.recv_next > start;
+        if progressed {
+            self.ack_due = true;
+            if let Some(waker) = self.recv_waker.take() {
+                waker.wake();
+            }
+        }
+        progressed
+    }
+
+    /// The ack to send, if one is owed: the first sequence number we have
+    /// not taken in.
+    fn take_ack(&mut self) -> Option<u64> {
+        std::mem::take(&mut self.ack_due).then_some(self.recv_next)
+    }
+
+    /// Stop the link, waking the stream so it sees why.
+    fn fail(&mut self, kind: io::ErrorKind) {
+        self.failure.get_or_insert(kind);
+        for waker in [self.send_waker.take(), self.recv_waker.take()].into_iter().flatten() {
+            waker.wake();
+        }
+    }
+}
+
+/// The plaintext of a segment: its kind, sequence number and piece.
+fn segment(kind: u8, seq: u64, piece: &[u8]) -> Vec<u8> {
+    let mut segment = BytesMut::with_capacity(SEGMENT_HEADER_SIZE + piece.len());
+    segment.put_u8(kind);
+    segment.put_u64(seq);
+    segment.put_slice(piece);
+    segment.to_vec()
+}
+
+/// Split an opened segment into its kind, sequence number and piece.
+fn parse_segment(segment: &[u8]) -> Option<(u8, u64, &[u8])> {
+    let (&kind, rest) = segment.split_first()?;
+    let (seq, piece) = rest.split_first_chunk::<8>()?;
+    Some((kind, u64::from_be_bytes(*seq), piece))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ReliableConfig {
+        ReliableConfig {
+            window: 16,
+            rto: Duration::from_millis(100),
+            max_rto: Duration::from_millis(250),
+            max_retransmits: 3,
+            ..Default::default()
+        }
+    }
+
+    /// Queue `message` unsealed, as the tests read segments back directly.
+    fn queue(state: &mut LinkState, message: &[u8]) {
+        state.queue(message, SEGMENT_LAST, |segment| Ok(segment.to_vec())).unwrap();
+    }
+
+    /// File `packets` with `receiver`, returning the ack it would send.
+    fn deliver(receiver: &mut LinkState, packets: &[Bytes]) -> u64 {
+        for packet in packets {
+            let (kind, seq, piece) = parse_segment(packet).unwrap();
+            receiver.received(seq, kind, piece, &config());
+        }
+        receiver.take_ack().unwrap()
+    }
+
+    #[test]
+    fn test_segments_reassemble_in_order_despite_reordering_and_duplicates() {
+        let mut sender = LinkState::default();
+        let large: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        queue(&mut sender, &large);
+        queue(&mut sender, b"small");
+        queue(&mut sender, b"");
+        let packets = sender.take_due(Instant::now(), &config());
+        assert_eq!(packets.len(), 5, "3000 bytes take three segments");
+        assert!(packets.iter().all(|packet| packet.len() + PACKET_OVERHEAD <= MAX_SEGMENT_SIZE));
+
+        // The second segment is lost, the rest arrive reversed and twice
+        let mut receiver = LinkState::default();
+        let mut arriving: Vec<Bytes> = packets.iter().rev().filter(|p| **p != packets[1]).cloned().collect();
+        arriving.extend(arriving.clone());
+        assert_eq!(deliver(&mut receiver, &arriving), 1);
+        assert!(receiver.delivered.is_empty());
+
+        assert_eq!(deliver(&mut receiver, &packets[1..2]), 5);
+        let delivered: Vec<Bytes> = receiver.delivered.drain(..).collect();
+        assert_eq!(delivered, [Bytes::from(large), Bytes::from_static(b"small"), Bytes::new()]);
+
+        assert!(sender.acked(5));
+        assert!(sender.unacked.is_empty());
+        assert!(!sender.acked(5));
+    }
+
+    #[test]
+    fn test_acks_beyond_what_was_sent_are_ignored() {
+        let mut sender = LinkState::default();
+        queue(&mut sender, b"one");
+        queue(&mut sender, b"two");
+        assert!(!sender.acked(u64::MAX));
+        assert!(!sender.acked(3));
+        assert_eq!(sender.unacked.len(), 2);
+        assert!(sender.acked(2));
+    }
+
+    #[test]
+    fn test_unread_messages_hold_back_acks() {
+        let config = config();
+        let mut sender = LinkState::default();
+        for i in 0..config.window + 2 {
+            queue(&mut sender, &[i as u8]);
+        }
+        let packets = sender.take_due(Instant::now(), &config);
+
+        // A window of messages waits unread, so the rest are not acknowledged
+        let mut receiver = LinkState::default();
+        assert_eq!(deliver(&mut receiver, &packets), config.window as u64);
+        assert_eq!(receiver.delivered.len(), config.window);
+        assert_eq!(receiver.out_of_order.len(), 2);
+
+        // Reading lets the buffered ones in
+        receiver.delivered.pop_front();
+        assert!(receiver.advance(&config));
+        assert_eq!(receiver.take_ack(), Some(config.window as u64 + 1));
+        assert_eq!(receiver.delivered.len(), config.window);
+    }
+
+    #[test]
+    fn test_close_ends_the_messages() {
+        let mut sender = LinkState::default();
+        queue(&mut sender, b"last words");
+        sender.queue(&[], SEGMENT_CLOSE, |segment| Ok(segment.to_vec())).unwrap();
+        assert!(sender.closed);
+        let packets = sender.take_due(Instant::now(), &config());
+
+        let mut receiver = LinkState::default();
+        assert_eq!(deliver(&mut receiver, &packets), 2);
+        assert!(receiver.peer_closed);
+        assert_eq!(receiver.delivered.pop_front(), Some(Bytes::from_static(b"last words")));
+    }
+
+    #[test]
+    fn test_unacked_segments_back_off_then_fail_the_link() {
+        let config = config();
+        let mut state = LinkState::default();
+        queue(&mut state, b"lost");
+        let start = Instant::now();
+        assert_eq!(state.take_due(start, &config).len(), 1);
+        assert!(state.take_due(start + Duration::from_millis(99), &config).is_empty());
+
+        // Retransmitted after 100ms, then 200ms, then 250ms (the cap)
+        let mut at = start;
+        let mut waits = Vec::new();
+        for _ in 0..config.max_retransmits {
+            let next = state.next_due().unwrap();
+            waits.push((next - at).as_millis());
+            at = next;
+            assert_eq!(state.take_due(at, &config).len(), 1);
+        }
+        assert_eq!(waits, [100, 200, 250]);
+        assert_eq!(state.failure, None);
+
+        assert!(state.take_due(state.next_due().unwrap(), &config).is_empty());
+        assert_eq!(state.failure, Some(io::ErrorKind::TimedOut));
+    }
+}
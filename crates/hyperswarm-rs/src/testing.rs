@@ -0,0 +1,118 @@
+//! Test harness: whole DHT networks in one process.
+//!
+//! [`LocalSwarm`] starts any number of [`DhtClient`]s on a [`MemoryNetwork`]
+//! and joins them the way nodes join the real DHT: each through a node that
+//! is already in it. Node 0 starts the network and node `i` bootstraps from
+//! node `(i - 1) / 2`, so the nodes form a tree in which nobody knows more
+//! than its parent and children. Lookups and announces find the rest of the
+//! graph hop by hop.
+//!
+//! Each node sits in its own /24, so the routing table's per-prefix limit
+//! does not thin the graph out, and has a node id derived from its index,
+//! so every run builds the same graph. Nothing touches the operating system,
+//! and the network runs under `#[tokio::test(start_paused = true)]` too.
+//!
+//! Available with the `test-util` feature.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use sha1::{Digest, Sha1};
+
+use crate::dht::{DhtClient, DhtConfig, DhtError};
+use crate::packet::MemoryNetwork;
+
+/// Port every node of a [`LocalSwarm`] listens on.
+const NODE_PORT: u16 = 6881;
+/// Most nodes a [`LocalSwarm`] holds: one per /24 under 10.0.0.0/8.
+const MAX_NODES: usize = 1 << 16;
+
+/// A DHT of in-process nodes, each joined through an earlier one.
+pub struct LocalSwarm {
+    network: MemoryNetwork,
+    nodes: Vec<Arc<DhtClient>>,
+}
+
+impl LocalSwarm {
+    /// Start `n` nodes with the default [`DhtConfig`]; see
+    /// [`with_config`](Self::with_config).
+    pub async fn new(n: usize) -> Result<Self, DhtError> {
+        Self::with_config(n, DhtConfig::default()).await
+    }
+
+    /// Start `n` nodes from `config` and bootstrap each but the first from
+    /// its parent, in order. `config.bootstrap` and `config.node_id` are
+    /// replaced per node.
+    ///
+    /// Fails with [`DhtError::InvalidConfig`] for more than 65536 nodes, or a
+    /// config [`DhtClient`] rejects.
+    pub async fn with_config(n: usize, config: DhtConfig) -> Result<Self, DhtError> {
+        if n > MAX_NODES {
+            return Err(DhtError::InvalidConfig(format!("at most {} nodes", MAX_NODES)));
+        }
+        let network = MemoryNetwork::new();
+        let mut nodes = Vec::with_capacity(n);
+        for i in 0..n {
+            let config = DhtConfig {
+                bootstrap: Self::parent(i).map(|p| Self::node_addr(p).to_string()).into_iter().collect(),
+                node_id: Some(Self::node_id(i)),
+                ..config.clone()
+            };
+            let transport = network.bind(Self::node_addr(i))?;
+            let node = Arc::new(DhtClient::with_transport(config, transport)?);
+            if i > 0 {
+                node.bootstrap().await?;
+            }
+            nodes.push(node);
+        }
+        Ok(Self { network, nodes })
+    }
+
+    /// Where node `i` listens: 10.x.y.1, in a /24 of its own.
+    pub fn node_addr(i: usize) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::new(10, (i >> 8) as u8, i as u8, 1), NODE_PORT))
+    }
+
+    /// The node node `i` bootstraps from, `None` for node 0.
+    pub fn parent(i: usize) -> Option<usize> {
+        i.checked_sub(1).map(|i| i / 2)
+    }
+
+    /// The id of node `i`: the SHA-1 of its index.
+    pub fn node_id(i: usize) -> [u8; 20] {
+        Sha1::digest((i as u64).to_be_bytes()).into()
+    }
+
+    /// The nodes, node 0 first.
+    pub fn nodes(&self) -> &[Arc<DhtClient>] {
+        &self.nodes
+    }
+
+    /// Node `i`.
+    ///
+    /// # Panics
+    ///
+    /// If there is no node `i`.
+    pub fn node(&self, i: usize) -> &Arc<DhtClient> {
+        &self.nodes[i]
+    }
+
+    /// The address of node 0, the root of the tree, to bootstrap further
+    /// clients from.
+    pub fn bootstrap_addr(&self) -> SocketAddr {
+        Self::node_addr(0)
+    }
+
+    /// The network the nodes are on, for binding more endpoints to it.
+    pub fn network(&self) -> &MemoryNetwork {
+        &self.network
+    }
+
+    /// Stop every node.
+    pub async fn shutdown(&self) {
+        for node in &self.nodes {
+            // Shutting down an in-memory node does not fail
+            let _ = node.shutdown().await;
+        }
+    }
+}
@@ -1,22 +1,79 @@
 //! Encrypted transport scaffold.
 //!
 //! Hyperswarm uses end-to-end encryption. This module provides an encrypted
-//! stream abstraction on top of UDP using Noise XX handshake pattern.
+//! stream abstraction on top of UDP using Noise XX handshake pattern, or
+//! XXpsk2 when both ends share a secret.
 
-use bytes::Bytes;
+use blake2::{Blake2sMac256, digest::{Mac, KeyInit}};
+use bytes::{Buf, Bytes, BytesMut};
 use snow::{Builder, HandshakeState, TransportState};
+use std::collections::VecDeque;
+use std::future::Future;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::task::{ready, Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, Sleep};
 use zeroize::Zeroizing;
 
+use crate::connection::PacketSource;
+use crate::packet::PacketTransport;
+
+/// Parameters for key generation; the DH function is the same in every suite.
 const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
-const MAX_MESSAGE_SIZE: usize = 65535;
-/// Maximum time allowed to complete a Noise handshake (both roles).
-/// Bounded to prevent an adversary from stalling a handshake indefinitely
-/// by continuously sending spoofed packets from unexpected addresses.
-const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// Position of the PSK token: the end of the responder's first message.
+const PSK_LOCATION: u8 = 2;
+/// Receive buffer for one datagram, larger than any UDP payload.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const NOISE_TAG_SIZE: usize = 16; // ChaChaPoly or AES-GCM authentication tag
+/// Largest plaintext carried by one Noise message: the largest UDP payload
+/// over IPv4 (65507 bytes) minus the authentication tag.
+const MAX_NOISE_PLAINTEXT: usize = 65507 - NOISE_TAG_SIZE;
+const FRAME_TYPE_SIZE: usize = 1;
+/// Largest chunk of a logical message carried by one data frame.
+const MAX_CHUNK_SIZE: usize = MAX_NOISE_PLAINTEXT - FRAME_TYPE_SIZE;
+const LENGTH_PREFIX_SIZE: usize = 4; // big-endian length of a logical message
+/// Bytes written through `AsyncWrite` are sent once this many are buffered,
+/// so each batch fits a single data frame. A lower
+/// [`TransportConfig::max_message_size`] makes batches smaller still.
+const MAX_WRITE_BATCH: usize = MAX_CHUNK_SIZE - LENGTH_PREFIX_SIZE;
+/// Sent datagram buffers kept for sealing later frames into.
+const SPARE_DATAGRAMS: usize = 4;
+
+// Frame types: the first plaintext byte of every Noise transport message
+const FRAME_DATA: u8 = 0; // a chunk of a length-prefixed message
+const FRAME_CLOSE: u8 = 1; // the peer will send no more data
+const FRAME_REKEY: u8 = 2; // frames after this one use the next sending key
+const FRAME_KEEPALIVE: u8 = 3; // carries nothing; keeps the path open
+/// Default for [`TransportConfig::rekey_after`].
+pub const DEFAULT_REKEY_AFTER: u64 = 250_000;
+/// Default for [`TransportConfig::max_message_size`], bounding what one
+/// connection buffers for a message.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 65535;
+/// Default for [`TransportConfig::handshake_timeout`]. Bounded to prevent an
+/// adversary from stalling a handshake indefinitely by continuously sending
+/// spoofed packets from unexpected addresses.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+/// Largest payload [`EncryptedStream::with_handshake_payload`] accepts.
+pub const MAX_HANDSHAKE_PAYLOAD_SIZE: usize = 256;
+/// How often a handshake message is sent again while its answer is missing.
+const HANDSHAKE_RETRANSMIT_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+/// Size of a bare `-> e` handshake message (an X25519 public key, no payload).
+/// In PSK mode the empty payload is encrypted and carries a tag as well.
+const HANDSHAKE_INIT_SIZE: usize = 32;
+// Cookie exchange ahead of the Noise handshake: the responder answers `-> e`
+// with COOKIE_REPLY || cookie, and the initiator resends COOKIE_ECHO || cookie || e.
+const COOKIE_REPLY: &[u8; 4] = b"HSCR";
+const COOKIE_ECHO: &[u8; 4] = b"HSCE";
+const COOKIE_SIZE: usize = 32;
+/// A [`PacketCipher`] packet's nonce: 8 bytes, big-endian, ahead of the
+/// Noise message.
+const PACKET_NONCE_SIZE: usize = 8;
+/// What a [`PacketCipher`] adds to the plaintext it seals.
+pub(crate) const PACKET_OVERHEAD: usize = PACKET_NONCE_SIZE + NOISE_TAG_SIZE;
 
 #[derive(thiserror::Error, Debug)]
 pub enum TransportError {
@@ -28,344 +85,2461 @@ pub enum TransportError {
     HandshakeIncomplete,
     #[error("invalid message")]
     InvalidMessage,
-    #[error("peer authentication failed: remote static key does not match expected key")]
+    #[error("peer authentication failed: the peer does not hold the expected static or pre-shared key")]
     PeerAuthenticationFailed,
+    #[error("stream closed")]
+    Closed,
+    #[error("nothing received from the peer within the idle timeout")]
+    IdleTimeout,
+    #[error("operation timed out")]
+    Timeout,
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+    /// A Noise cipher used up its 2^64 - 1 nonces. Rekeying keeps the
+    /// counter, so the stream cannot go on: drop it and connect again.
+    #[error("noise nonce exhausted")]
+    NonceExhausted,
+}
+
+/// Map a `snow` error, singling out nonce exhaustion.
+fn noise_error(e: snow::Error) -> TransportError {
+    match e {
+        snow::Error::State(snow::error::StateProblem::Exhausted) => TransportError::NonceExhausted,
+        e => TransportError::Noise(format!("{:?}", e)),
+    }
+}
+
+impl From<TransportError> for std::io::Error {
+    fn from(e: TransportError) -> Self {
+        use std::io::ErrorKind;
+        match e {
+            TransportError::Io(e) => e,
+            TransportError::HandshakeIncomplete => std::io::Error::new(ErrorKind::NotConnected, e),
+            TransportError::Closed => std::io::Error::new(ErrorKind::BrokenPipe, e),
+            TransportError::NonceExhausted => std::io::Error::new(ErrorKind::ConnectionAborted, e),
+            TransportError::IdleTimeout | TransportError::Timeout => std::io::Error::new(ErrorKind::TimedOut, e),
+            other => std::io::Error::new(ErrorKind::InvalidData, other),
+        }
+    }
+}
+
+/// The AEAD cipher a stream's Noise handshake and transport messages use.
+///
+/// Both peers must select the same suite. A mismatch fails the handshake the
+/// way a wrong pre-shared key does: the initiator cannot decrypt the
+/// responder's static key, and gets [`TransportError::PeerAuthenticationFailed`]
+/// once the handshake timeout passes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CipherSuite {
+    /// ChaCha20-Poly1305, fast everywhere without hardware support.
+    #[default]
+    ChaChaPoly,
+    /// AES-256-GCM, for peers and hardware that prefer it.
+    AesGcm,
+}
+
+impl CipherSuite {
+    /// The `snow` parameters for this suite, in XXpsk2 mode when `psk`.
+    fn noise_params(self, psk: bool) -> &'static str {
+        match (self, psk) {
+            (CipherSuite::ChaChaPoly, false) => "Noise_XX_25519_ChaChaPoly_BLAKE2s",
+            (CipherSuite::ChaChaPoly, true) => "Noise_XXpsk2_25519_ChaChaPoly_BLAKE2s",
+            (CipherSuite::AesGcm, false) => "Noise_XX_25519_AESGCM_BLAKE2s",
+            (CipherSuite::AesGcm, true) => "Noise_XXpsk2_25519_AESGCM_BLAKE2s",
+        }
+    }
+}
+
+/// Tunables for an [`EncryptedStream`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransportConfig {
+    /// Rotate the sending key after this many Noise messages; 0 never rotates.
+    pub rekey_after: u64,
+    /// Send a keepalive frame once nothing has been sent for this long, so
+    /// NAT mappings on the path do not expire. `None` sends no keepalives.
+    pub keepalive_interval: Option<Duration>,
+    /// Fail with [`TransportError::IdleTimeout`] once nothing has arrived
+    /// from the peer for this long. `None` waits forever.
+    pub idle_timeout: Option<Duration>,
+    /// Largest logical message, in bytes, sent or accepted. Sending a larger
+    /// one fails with [`TransportError::InvalidMessage`], and so does
+    /// receiving one, as soon as its length prefix arrives and before it is
+    /// buffered. At least 1; [`DEFAULT_MAX_MESSAGE_SIZE`] by default.
+    pub max_message_size: usize,
+    /// Follow the peer to a new source address, as after its NAT remapped
+    /// the port, once a frame from there decrypts under the session keys.
+    /// Frames that do not decrypt never move the stream. Off, datagrams from
+    /// any address but [`remote_addr`](EncryptedStream::remote_addr) are
+    /// ignored. Only streams reading the socket themselves see other
+    /// addresses; the connection manager routes by address.
+    pub address_migration: bool,
+    /// Time the handshake is given to complete, in either role: short on a
+    /// LAN, longer over slow relays. Non-zero; [`DEFAULT_HANDSHAKE_TIMEOUT`]
+    /// by default.
+    pub handshake_timeout: Duration,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            rekey_after: DEFAULT_REKEY_AFTER,
+            keepalive_interval: None,
+            idle_timeout: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            address_migration: false,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        }
+    }
+}
+
+impl TransportConfig {
+    /// Fail with [`TransportError::InvalidConfig`] for a zero
+    /// `max_message_size` or `handshake_timeout`.
+    pub(crate) fn validate(&self) -> Result<(), TransportError> {
+        if self.max_message_size == 0 {
+            return Err(TransportError::InvalidConfig("max message size must be non-zero".to_string()));
+        }
+        if self.handshake_timeout.is_zero() {
+            return Err(TransportError::InvalidConfig("handshake timeout must be non-zero".to_string()));
+        }
+        Ok(())
+    }
 }
 
 /// An encrypted stream wrapper using Noise protocol.
+///
+/// Once the handshake is complete the stream can be used message by message
+/// with [`send`](Self::send) / [`recv`](Self::recv), or as a byte stream
+/// through its [`AsyncRead`] / [`AsyncWrite`] implementations. Bytes written
+/// through `AsyncWrite` are buffered and go out on `poll_flush`;
+/// `poll_shutdown` tells the peer, whose reads then reach end of stream.
+/// [`close`](Self::close) does the same and stops reading as well.
+///
+/// Each side rotates its sending key every
+/// [`rekey_after`](TransportConfig::rekey_after) messages, announcing it with
+/// a rekey frame; the peer rotates its receiving key when it reads that
+/// frame. The two directions rotate independently, so frames crossing on the
+/// wire never disagree about which key is in use.
+///
+/// Keepalives configured with
+/// [`keepalive_interval`](TransportConfig::keepalive_interval) are sent by a
+/// background task whenever nothing else was sent for that long, whether or
+/// not the application is reading; `recv` filters them out on the other end.
+/// The task ends once the stream, or both of its halves, are dropped.
+///
+/// [`split`](Self::split) turns an established stream into a [`ReadHalf`]
+/// and a [`WriteHalf`], so one task can receive while another sends.
+///
+/// Buffers are kept and reused, so once a stream is established, sending and
+/// receiving allocate nothing as long as the application drops each received
+/// message before the next one outgrows the space left in the receive buffer.
 pub struct EncryptedStream {
-    socket: Arc<UdpSocket>,
+    socket: Arc<dyn PacketTransport>,
+    /// Where datagrams are read from; the socket itself unless shared.
+    source: PacketSource,
     remote_addr: SocketAddr,
-    state: Arc<Mutex<StreamState>>,
+    state: StreamState,
     /// The remote peer's static public key, populated after a successful handshake.
     remote_static_key: Option<[u8; 32]>,
+    /// The Noise handshake hash, populated after a successful handshake.
+    handshake_hash: Option<[u8; 32]>,
+    /// Whether we completed the handshake as initiator.
+    initiator: bool,
     /// The local static public key for this stream (constant for the lifetime of the stream).
     local_static_pubkey: [u8; 32],
     /// The local static private key.  Stored as a fixed-size array inside a
     /// `Zeroizing` wrapper so the secret bytes are automatically zeroed when the
     /// stream is dropped.
     local_static_privkey: Zeroizing<[u8; 32]>,
+    /// Pre-shared key mixed into the handshake; `None` runs plain XX.
+    psk: Option<Zeroizing<[u8; 32]>>,
+    /// The cipher both ends of the handshake must agree on.
+    cipher_suite: CipherSuite,
+    /// Receives one datagram at a time; the handshake's buffer, kept.
+    datagram_buf: Vec<u8>,
+    /// Received plaintext not yet handed to the application.
+    inbox: Inbox,
+    /// Plaintext written through `AsyncWrite`, sent as one message on flush.
+    write_buf: Vec<u8>,
+    /// See [`TransportConfig::rekey_after`]; handed to the session's outbox.
+    rekey_after: u64,
+    /// Whether we sent a close frame.
+    close_sent: bool,
+    /// Keepalive interval and idle-timeout timer.
+    liveness: Liveness,
+    /// Whether authentic frames from a new address move `remote_addr` there.
+    address_migration: bool,
+    /// Time allowed for the whole handshake, in either role.
+    handshake_timeout: Duration,
+    /// Sent along with our static key during the handshake.
+    handshake_payload: Vec<u8>,
+    /// What the peer sent along with its static key.
+    remote_handshake_payload: Vec<u8>,
+    /// Sends keepalives while the stream is established.
+    keepalive_task: Option<JoinHandle<()>>,
+}
+
+/// Derive the static public key that belongs to a Noise private key.
+///
+/// Lets an application that persists its private key hand the matching
+/// public key to peers for pinning.
+pub fn public_key_from_private(private_key: &[u8; 32]) -> Result<[u8; 32], TransportError> {
+    use snow::resolvers::{CryptoResolver, DefaultResolver};
+
+    let params: snow::params::NoiseParams =
+        NOISE_PARAMS.parse().map_err(noise_error)?;
+    let mut dh = DefaultResolver
+        .resolve_dh(&params.dh)
+        .ok_or_else(|| TransportError::Noise("unsupported DH function".to_string()))?;
+    dh.set(private_key);
+
+    let mut pubkey = [0u8; 32];
+    pubkey.copy_from_slice(&dh.pubkey()[..32]);
+    Ok(pubkey)
+}
+
+/// Build a handshake state for either role around a static private key,
+/// in XXpsk2 mode when a pre-shared key is given.
+fn build_handshake_state(
+    private_key: &[u8; 32],
+    psk: Option<&[u8; 32]>,
+    cipher_suite: CipherSuite,
+    initiator: bool,
+) -> Result<HandshakeState, TransportError> {
+    let params = cipher_suite.noise_params(psk.is_some());
+    let mut builder = Builder::new(
+        params.parse().map_err(noise_error)?,
+    )
+    .local_private_key(private_key);
+    if let Some(psk) = psk {
+        builder = builder.psk(PSK_LOCATION, psk);
+    }
+    let state = if initiator {
+        builder.build_initiator()
+    } else {
+        builder.build_responder()
+    };
+    state.map_err(noise_error)
+}
+
+/// MAC binding a handshake cookie to the address the initiator sent from.
+fn cookie_mac(secret: &[u8; 32], addr: SocketAddr) -> Blake2sMac256 {
+    let mut mac = <Blake2sMac256 as KeyInit>::new_from_slice(secret)
+        .expect("cookie secret is exactly 32 bytes, which is valid for Blake2sMac256");
+    match addr.ip() {
+        std::net::IpAddr::V4(ip) => Mac::update(&mut mac, &ip.octets()),
+        std::net::IpAddr::V6(ip) => Mac::update(&mut mac, &ip.octets()),
+    }
+    Mac::update(&mut mac, &addr.port().to_be_bytes());
+    mac
+}
+
+/// A handshake message we sent, and when it is due to go out again.
+struct Resend<'a> {
+    message: &'a [u8],
+    at: Instant,
+}
+
+impl<'a> Resend<'a> {
+    /// Schedule `message`, just sent, for its first retransmission.
+    fn new(message: &'a [u8]) -> Self {
+        Self {
+            message,
+            at: Instant::now() + HANDSHAKE_RETRANSMIT_INTERVAL,
+        }
+    }
+}
+
+/// Why a handshake ended with `error`, given whether messages from the
+/// peer's address failed to decrypt meanwhile.
+///
+/// Running out of time after such a message most likely means the peer
+/// holds another pre-shared key or cipher suite, so it is reported as
+/// [`TransportError::PeerAuthenticationFailed`]. The message itself is never
+/// fatal: a spoofed one would otherwise abort the handshake.
+fn handshake_failure(error: TransportError, undecryptable: bool) -> TransportError {
+    match error {
+        TransportError::HandshakeIncomplete if undecryptable => TransportError::PeerAuthenticationFailed,
+        error => error,
+    }
+}
+
+/// The initiator's last handshake message, and the responder's message it
+/// answered.
+struct HandshakeAnswer {
+    message: Vec<u8>,
+    reply: Vec<u8>,
 }
 
 enum StreamState {
-    Handshaking(HandshakeState),
-    Established(TransportState),
+    Handshaking(Box<HandshakeState>),
+    Established(Arc<Mutex<Session>>),
 }
 
 impl EncryptedStream {
     /// Create a new encrypted stream with a freshly-generated static keypair.
-    pub async fn new(socket: Arc<UdpSocket>, remote_addr: SocketAddr) -> Result<Self, TransportError> {
-        let (handshake, local_static_pubkey, local_static_privkey) =
-            Self::generate_keypair_and_initiator()?;
+    pub async fn new(socket: Arc<dyn PacketTransport>, remote_addr: SocketAddr) -> Result<Self, TransportError> {
+        let private_key = Self::generate_private_key()?;
+        Self::with_keypair(socket, remote_addr, *private_key)
+    }
+
+    /// Create a new encrypted stream using an existing static private key.
+    ///
+    /// Reusing the same key across runs gives this end a stable identity that
+    /// peers can pin with [`handshake_initiator`](Self::handshake_initiator).
+    /// The matching public key is available from [`public_key_from_private`].
+    pub fn with_keypair(socket: Arc<dyn PacketTransport>, remote_addr: SocketAddr, private_key: [u8; 32]) -> Result<Self, TransportError> {
+        Self::with_source_and_keypair(socket, remote_addr, PacketSource::Socket, Zeroizing::new(private_key))
+    }
+
+    /// Create a new encrypted stream, with a freshly-generated static keypair,
+    /// whose handshake only completes with a peer holding the same `psk`.
+    ///
+    /// Both ends must use this constructor with the same key. Against a
+    /// different key the handshake fails with
+    /// [`TransportError::PeerAuthenticationFailed`] once it times out; a
+    /// peer without one never gets past the cookie exchange, so the handshake
+    /// ends in [`TransportError::HandshakeIncomplete`].
+    pub fn with_psk(socket: Arc<dyn PacketTransport>, remote_addr: SocketAddr, psk: [u8; 32]) -> Result<Self, TransportError> {
+        let private_key = Self::generate_private_key()?;
+        let mut stream = Self::with_source_and_keypair(socket, remote_addr, PacketSource::Socket, private_key)?;
+        stream.psk = Some(Zeroizing::new(psk));
+        stream.state = StreamState::Handshaking(Box::new(stream.make_initiator_state()?));
+        Ok(stream)
+    }
+
+    /// Create a stream on a socket shared with other streams, reading
+    /// datagrams from `source`. Without `private_key` a fresh static keypair
+    /// is generated.
+    pub(crate) fn with_source(
+        socket: Arc<dyn PacketTransport>,
+        remote_addr: SocketAddr,
+        source: PacketSource,
+        private_key: Option<&[u8; 32]>,
+    ) -> Result<Self, TransportError> {
+        let private_key = match private_key {
+            Some(key) => Zeroizing::new(*key),
+            None => Self::generate_private_key()?,
+        };
+        Self::with_source_and_keypair(socket, remote_addr, source, private_key)
+    }
+
+    fn with_source_and_keypair(
+        socket: Arc<dyn PacketTransport>,
+        remote_addr: SocketAddr,
+        source: PacketSource,
+        local_static_privkey: Zeroizing<[u8; 32]>,
+    ) -> Result<Self, TransportError> {
+        let local_static_pubkey = public_key_from_private(&local_static_privkey)?;
+        let handshake = build_handshake_state(&local_static_privkey, None, CipherSuite::default(), true)?;
         Ok(Self {
             socket,
+            source,
             remote_addr,
-            state: Arc::new(Mutex::new(StreamState::Handshaking(handshake))),
+            state: StreamState::Handshaking(Box::new(handshake)),
             remote_static_key: None,
+            handshake_hash: None,
+            initiator: false,
             local_static_pubkey,
             local_static_privkey,
+            psk: None,
+            cipher_suite: CipherSuite::default(),
+            datagram_buf: Vec::new(),
+            inbox: Inbox::default(),
+            write_buf: Vec::new(),
+            rekey_after: TransportConfig::default().rekey_after,
+            close_sent: false,
+            liveness: Liveness::default(),
+            address_migration: false,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            handshake_payload: Vec::new(),
+            remote_handshake_payload: Vec::new(),
+            keepalive_task: None,
         })
     }
 
-    /// Generate a static keypair, return an initiator handshake state together
-    /// with the public and private key bytes.
-    fn generate_keypair_and_initiator() -> Result<(HandshakeState, [u8; 32], Zeroizing<[u8; 32]>), TransportError> {
+    /// Generate a fresh static private key.
+    pub(crate) fn generate_private_key() -> Result<Zeroizing<[u8; 32]>, TransportError> {
         let builder = Builder::new(
-            NOISE_PARAMS.parse().map_err(|e| TransportError::Noise(format!("{:?}", e)))?,
+            NOISE_PARAMS.parse().map_err(noise_error)?,
         );
         let keypair = builder
             .generate_keypair()
-            .map_err(|e| TransportError::Noise(format!("{:?}", e)))?;
-
-        let mut pubkey = [0u8; 32];
-        pubkey.copy_from_slice(&keypair.public[..32]);
+            .map_err(noise_error)?;
 
         let mut privkey_arr = Zeroizing::new([0u8; 32]);
         privkey_arr.copy_from_slice(&keypair.private[..32]);
+        Ok(privkey_arr)
+    }
 
-        let handshake = Builder::new(
-            NOISE_PARAMS.parse().map_err(|e| TransportError::Noise(format!("{:?}", e)))?,
-        )
-        .local_private_key(&*privkey_arr)
-        .build_initiator()
-        .map_err(|e| TransportError::Noise(format!("{:?}", e)))?;
+    /// Run the handshake, and encrypt everything after it, with
+    /// `cipher_suite` instead of the default ChaChaPoly.
+    ///
+    /// Call before the handshake; the peer must select the same suite.
+    pub fn with_cipher_suite(mut self, cipher_suite: CipherSuite) -> Result<Self, TransportError> {
+        self.cipher_suite = cipher_suite;
+        self.state = StreamState::Handshaking(Box::new(self.make_initiator_state()?));
+        Ok(self)
+    }
+
+    /// The cipher suite this stream's handshake uses.
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.cipher_suite
+    }
+
+    /// The time the handshake is given to complete, see
+    /// [`TransportConfig::handshake_timeout`].
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    /// Send `payload` to the peer in the handshake message that carries our
+    /// static key, in either role. The peer reads it back with
+    /// [`remote_handshake_payload`](Self::remote_handshake_payload) once the
+    /// handshake completes, encrypted and bound to our key like the session.
+    /// Fails with [`TransportError::InvalidConfig`] for payloads over
+    /// [`MAX_HANDSHAKE_PAYLOAD_SIZE`] bytes.
+    pub fn with_handshake_payload(mut self, payload: &[u8]) -> Result<Self, TransportError> {
+        if payload.len() > MAX_HANDSHAKE_PAYLOAD_SIZE {
+            return Err(TransportError::InvalidConfig(format!(
+                "handshake payload of {} bytes",
+                payload.len()
+            )));
+        }
+        self.handshake_payload = payload.to_vec();
+        Ok(self)
+    }
+
+    /// The payload the peer sent with its static key; empty until the
+    /// handshake completes, or if it sent none.
+    pub fn remote_handshake_payload(&self) -> &[u8] {
+        &self.remote_handshake_payload
+    }
+
+    /// Apply `config` to this stream. Fails with
+    /// [`TransportError::InvalidConfig`] if its `max_message_size` or
+    /// `handshake_timeout` is zero.
+    pub fn with_config(mut self, config: TransportConfig) -> Result<Self, TransportError> {
+        config.validate()?;
+        self.rekey_after = config.rekey_after;
+        self.liveness.keepalive_interval = config.keepalive_interval;
+        self.liveness.idle_timeout = config.idle_timeout;
+        self.inbox.max_message_size = config.max_message_size;
+        self.address_migration = config.address_migration;
+        self.handshake_timeout = config.handshake_timeout;
+        if let Ok(mut session) = self.session() {
+            session.outbox.rekey_after = config.rekey_after;
+        }
+        self.start_keepalives();
+        Ok(self)
+    }
+
+    /// Enter transport mode once the handshake has produced `transport`.
+    fn establish(&mut self, transport: TransportState) {
+        let session = Session {
+            transport,
+            remote_addr: self.remote_addr,
+            outbox: Outbox::new(self.rekey_after),
+            flush_waiters: Vec::new(),
+        };
+        self.state = StreamState::Established(Arc::new(Mutex::new(session)));
+        self.start_keepalives();
+    }
+
+    /// (Re)start the keepalive task, if the stream is established and
+    /// configured to send keepalives.
+    fn start_keepalives(&mut self) {
+        if let Some(task) = self.keepalive_task.take() {
+            task.abort();
+        }
+        if let (StreamState::Established(session), Some(interval)) = (&self.state, self.liveness.keepalive_interval) {
+            let task = send_keepalives(self.socket.clone(), Arc::downgrade(session), interval);
+            self.keepalive_task = Some(tokio::spawn(task));
+        }
+    }
 
-        Ok((handshake, pubkey, privkey_arr))
+    /// Give up the stream for its session keys, to seal and open packets
+    /// one at a time with a [`PacketCipher`].
+    ///
+    /// Fails with [`TransportError::HandshakeIncomplete`] before the
+    /// handshake has completed.
+    pub(crate) fn into_packet_cipher(mut self) -> Result<PacketCipher, TransportError> {
+        if let Some(task) = self.keepalive_task.take() {
+            task.abort();
+        }
+        let StreamState::Established(session) = self.state else {
+            return Err(TransportError::HandshakeIncomplete);
+        };
+        let session = Arc::try_unwrap(session)
+            .map_err(|_| TransportError::InvalidConfig("the session is still shared with keepalives".to_string()))?;
+        let session = session.into_inner().expect("session lock poisoned");
+        Ok(PacketCipher {
+            transport: session.transport,
+            handshake_answer: self.inbox.handshake_answer.take(),
+        })
+    }
+
+    /// The Noise session, once the handshake has completed.
+    fn session(&self) -> Result<MutexGuard<'_, Session>, TransportError> {
+        match &self.state {
+            StreamState::Established(session) => Ok(lock_session(session)),
+            StreamState::Handshaking(_) => Err(TransportError::HandshakeIncomplete),
+        }
     }
 
     /// Build an initiator handshake state reusing the stored static keypair.
     fn make_initiator_state(&self) -> Result<HandshakeState, TransportError> {
-        Builder::new(
-            NOISE_PARAMS.parse().map_err(|e| TransportError::Noise(format!("{:?}", e)))?,
-        )
-        .local_private_key(self.local_static_privkey.as_ref())
-        .build_initiator()
-        .map_err(|e| TransportError::Noise(format!("{:?}", e)))
+        build_handshake_state(&self.local_static_privkey, self.psk.as_deref(), self.cipher_suite, true)
+    }
+
+    /// Build a responder handshake state reusing the stored static keypair.
+    fn make_responder_state(&self) -> Result<HandshakeState, TransportError> {
+        build_handshake_state(&self.local_static_privkey, self.psk.as_deref(), self.cipher_suite, false)
+    }
+
+    /// Returns the local static public key for this stream.
+    ///
+    /// This key is stable for the lifetime of the `EncryptedStream` and can be
+    /// shared with a peer out-of-band so the peer can authenticate this end.
+    pub fn local_static_pubkey(&self) -> [u8; 32] {
+        self.local_static_pubkey
+    }
+
+    /// Copy the handshake hash out of a finished `HandshakeState`.
+    fn extract_handshake_hash(handshake: &snow::HandshakeState) -> Option<[u8; 32]> {
+        handshake.get_handshake_hash().try_into().ok()
+    }
+
+    /// Read the peer's next handshake message, returning its payload, or
+    /// `None` if it fails to decrypt.
+    ///
+    /// Such a message was not sent by a peer holding the keys this handshake
+    /// expects, such as the same pre-shared key, or not by the peer at all,
+    /// since anyone can spoof its address. The handshake state is left as it
+    /// was, so the caller can wait for the peer's retransmission instead.
+    fn read_handshake_message(handshake: &mut HandshakeState, message: &[u8]) -> Result<Option<Vec<u8>>, TransportError> {
+        let mut payload = vec![0u8; message.len()];
+        match handshake.read_message(message, &mut payload) {
+            Ok(len) => {
+                payload.truncate(len);
+                Ok(Some(payload))
+            }
+            Err(snow::Error::Decrypt) => Ok(None),
+            Err(e) => Err(noise_error(e)),
+        }
+    }
+
+    /// Copy the remote static public key out of a completed `HandshakeState`.
+    ///
+    /// Returns `None` if the handshake has not yet revealed the remote key (which
+    /// should not happen at the expected call sites in the XX flow).
+    fn extract_remote_static(handshake: &snow::HandshakeState) -> Option<[u8; 32]> {
+        handshake.get_remote_static().and_then(|k| {
+            if k.len() >= 32 {
+                let mut arr = [0u8; 32];
+                arr.copy_from_slice(&k[..32]);
+                Some(arr)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Perform Noise XX handshake as initiator.
+    ///
+    /// The responder first answers `-> e` with a cookie, which is echoed
+    /// back together with `-> e` before the handshake proceeds. Both waits
+    /// share one handshake deadline; a responder that never answers, or goes
+    /// quiet after its cookie, yields [`TransportError::HandshakeIncomplete`]
+    /// once it passes, however many packets arrive from other addresses.
+    /// Until then each message is resent every 500 ms while its answer is
+    /// missing, so a lossy path costs a retransmission rather than the
+    /// handshake.
+    ///
+    /// If `remote_static_pubkey` is provided, the handshake will verify that the
+    /// responder's static public key (obtained from the `<- e, ee, s, es` message)
+    /// matches the supplied value, and return [`TransportError::PeerAuthenticationFailed`]
+    /// if it does not.  This defends against man-in-the-middle attacks.
+    ///
+    /// After a successful handshake the peer's static key is stored and accessible via
+    /// [`EncryptedStream::remote_static_key`].
+    pub async fn handshake_initiator(&mut self, remote_static_pubkey: Option<[u8; 32]>) -> Result<(), TransportError> {
+        // Extract the handshake state temporarily
+        let handshake = match &self.state {
+            StreamState::Established(_) => return Ok(()),
+            StreamState::Handshaking(_) => {
+                // Leave a fresh placeholder behind so the stream stays usable
+                // for another attempt if this one fails.
+                let placeholder = StreamState::Handshaking(Box::new(self.make_initiator_state()?));
+                match std::mem::replace(&mut self.state, placeholder) {
+                    StreamState::Handshaking(h) => *h,
+                    _ => unreachable!(),
+                }
+            }
+        };
+        
+        let mut handshake = handshake;
+        
+        // -> e
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let len = handshake
+            .write_message(&[], &mut buf)
+            .map_err(noise_error)?;
+        let hello = buf[..len].to_vec();
+        
+        self.socket.send_to(&hello, self.remote_addr).await?;
+
+        // <- cookie
+        // Apply the same shared deadline as the responder to prevent an adversary
+        // from stalling the initiator indefinitely by flooding from wrong addresses.
+        let deadline = tokio::time::Instant::now() + self.handshake_timeout;
+        let mut resend = Resend::new(&hello);
+        let cookie = loop {
+            let len = self.recv_handshake_packet(&mut buf, deadline, Some(&mut resend)).await?;
+            match buf[..len].strip_prefix(COOKIE_REPLY) {
+                Some(cookie) if cookie.len() == COOKIE_SIZE => break cookie.to_vec(),
+                _ => {} // not a cookie; the responder has not seen `-> e` yet
+            }
+        };
+
+        // -> cookie, e
+        let echo = [COOKIE_ECHO.as_slice(), &cookie, &hello].concat();
+        self.socket.send_to(&echo, self.remote_addr).await?;
+
+        // <- e, ee, s, es
+        let mut resend = Resend::new(&echo);
+        let mut undecryptable = false;
+        let (remote_payload, response) = loop {
+            let len = self
+                .recv_handshake_packet(&mut buf, deadline, Some(&mut resend))
+                .await
+                .map_err(|e| handshake_failure(e, undecryptable))?;
+            if buf[..len].starts_with(COOKIE_REPLY) {
+                continue; // A late duplicate cookie
+            }
+            match Self::read_handshake_message(&mut handshake, &buf[..len])? {
+                Some(payload) => break (payload, buf[..len].to_vec()),
+                None => undecryptable = true,
+            }
+        };
+
+        // The remote static key ('s') is now revealed by the XX handshake.
+        // Copy it out before consuming the handshake state.
+        let remote_static = Self::extract_remote_static(&handshake);
+
+        // Validate the remote key if the caller supplied an expected value.
+        if let Some(expected) = remote_static_pubkey {
+            match remote_static {
+                Some(actual) if actual == expected => {}
+                _ => return Err(TransportError::PeerAuthenticationFailed),
+            }
+        }
+
+        // -> s, se
+        let len = handshake
+            .write_message(&self.handshake_payload, &mut buf)
+            .map_err(noise_error)?;
+        
+        self.socket.send_to(&buf[..len], self.remote_addr).await?;
+        // Nothing answers this one, so the responder resends its message
+        // until it gets through; each resend is answered from the inbox
+        self.inbox.handshake_answer = Some(HandshakeAnswer {
+            message: response,
+            reply: buf[..len].to_vec(),
+        });
+
+        let handshake_hash = Self::extract_handshake_hash(&handshake);
+
+        // Transition to transport mode
+        let transport = handshake
+            .into_transport_mode()
+            .map_err(noise_error)?;
+        
+        // Update state and store the authenticated remote key
+        self.establish(transport);
+        self.remote_static_key = remote_static;
+        self.remote_handshake_payload = remote_payload;
+        self.handshake_hash = handshake_hash;
+        self.initiator = true;
+        self.datagram_buf = buf;
+        self.liveness.last_received = Instant::now();
+        
+        Ok(())
+    }
+
+    /// Perform Noise XX handshake as responder.
+    ///
+    /// A bare `-> e` is answered with a cookie MAC'd over the sender's
+    /// address; no Noise state is touched until the initiator echoes it. A
+    /// spoofed or replayed first message therefore never reaches the DH
+    /// operations, since its sender cannot see the cookie.
+    ///
+    /// `<- e, ee, s, es` is resent every 500 ms until the initiator's last
+    /// message arrives; the cookie needs no resending, as the initiator
+    /// resends `-> e` instead.
+    ///
+    /// If `expected_initiator_key` is provided, the initiator's static public
+    /// key (revealed by its final `-> s, se` message) must match it, or
+    /// [`TransportError::PeerAuthenticationFailed`] is returned and the stream
+    /// never enters transport mode. This refuses peers off an allowlist
+    /// during the handshake itself.
+    ///
+    /// After a successful handshake the initiator's static public key is stored
+    /// and accessible via [`EncryptedStream::remote_static_key`].
+    pub async fn handshake_responder(&mut self, expected_initiator_key: Option<[u8; 32]>) -> Result<(), TransportError> {
+        // Fresh per attempt, so cookies from an earlier handshake are useless
+        let cookie_secret: [u8; 32] = rand::random();
+        let hello_size = match self.psk {
+            Some(_) => HANDSHAKE_INIT_SIZE + NOISE_TAG_SIZE,
+            None => HANDSHAKE_INIT_SIZE,
+        };
+
+        // <- e, then -> cookie until it comes back
+        // Use a shared deadline so continuous packets from unexpected sources cannot
+        // stall the handshake indefinitely (DoS mitigation).
+        // Build a responder state reusing the stored static keypair so that
+        // local_static_pubkey() remains consistent regardless of which role
+        // this stream takes.
+        let mut handshake = self.make_responder_state()?;
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let deadline = tokio::time::Instant::now() + self.handshake_timeout;
+        let mut undecryptable = false;
+        loop {
+            let len = self
+                .recv_handshake_packet(&mut buf, deadline, None)
+                .await
+                .map_err(|e| handshake_failure(e, undecryptable))?;
+            let packet = &buf[..len];
+            if let Some(echo) = packet.strip_prefix(COOKIE_ECHO) {
+                if echo.len() == COOKIE_SIZE + hello_size
+                    && cookie_mac(&cookie_secret, self.remote_addr).verify_slice(&echo[..COOKIE_SIZE]).is_ok()
+                {
+                    match Self::read_handshake_message(&mut handshake, &echo[COOKIE_SIZE..])? {
+                        Some(_) => break,
+                        None => undecryptable = true,
+                    }
+                }
+            } else if len == hello_size {
+                let cookie = cookie_mac(&cookie_secret, self.remote_addr).finalize().into_bytes();
+                let reply = [COOKIE_REPLY.as_slice(), &cookie].concat();
+                self.socket.send_to(&reply, self.remote_addr).await?;
+            }
+        }
+
+        // -> e, ee, s, es
+        let len = handshake
+            .write_message(&self.handshake_payload, &mut buf)
+            .map_err(noise_error)?;
+        
+        let response = buf[..len].to_vec();
+        self.socket.send_to(&response, self.remote_addr).await?;
+
+        // <- s, se
+        let mut resend = Resend::new(&response);
+        let remote_payload = loop {
+            let len = self
+                .recv_handshake_packet(&mut buf, deadline, Some(&mut resend))
+                .await
+                .map_err(|e| handshake_failure(e, undecryptable))?;
+            if buf[..len].starts_with(COOKIE_ECHO) || len == hello_size {
+                continue; // `-> e` or its echo again, sent before our answer got through
+            }
+            match Self::read_handshake_message(&mut handshake, &buf[..len])? {
+                Some(payload) => break payload,
+                None => undecryptable = true,
+            }
+        };
+
+        // The initiator's static key ('s') is now revealed by the XX handshake.
+        let remote_static = Self::extract_remote_static(&handshake);
+
+        // Validate the remote key if the caller supplied an expected value.
+        if let Some(expected) = expected_initiator_key {
+            match remote_static {
+                Some(actual) if actual == expected => {}
+                _ => return Err(TransportError::PeerAuthenticationFailed),
+            }
+        }
+
+        let handshake_hash = Self::extract_handshake_hash(&handshake);
+
+        // Transition to transport mode
+        let transport = handshake
+            .into_transport_mode()
+            .map_err(noise_error)?;
+        
+        self.establish(transport);
+        self.remote_static_key = remote_static;
+        self.remote_handshake_payload = remote_payload;
+        self.handshake_hash = handshake_hash;
+        self.datagram_buf = buf;
+        self.liveness.last_received = Instant::now();
+        
+        Ok(())
+    }
+
+    /// Receive the next handshake packet from `remote_addr`, ignoring
+    /// packets from anyone else, failing once `deadline` passes.
+    ///
+    /// Meanwhile the message in `resend` goes out again whenever it is due.
+    /// Its schedule carries over between calls, so packets that do not
+    /// answer it cannot hold the retransmission back.
+    async fn recv_handshake_packet(
+        &mut self,
+        buf: &mut [u8],
+        deadline: Instant,
+        mut resend: Option<&mut Resend<'_>>,
+    ) -> Result<usize, TransportError> {
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(TransportError::HandshakeIncomplete);
+            }
+            if let Some(resend) = resend.as_deref_mut() {
+                if now >= resend.at {
+                    if let Err(e) = self.socket.send_to(resend.message, self.remote_addr).await {
+                        tracing::debug!("Handshake retransmission to {} failed: {}", self.remote_addr, e);
+                    }
+                    resend.at = now + HANDSHAKE_RETRANSMIT_INTERVAL;
+                }
+            }
+            let wake = resend.as_ref().map_or(deadline, |resend| resend.at.min(deadline));
+            match tokio::time::timeout_at(wake, self.recv_packet(buf)).await {
+                Ok(Ok((len, addr))) if addr == self.remote_addr => return Ok(len),
+                Ok(Ok(_)) => {} // ignore packets from unexpected sources
+                Err(_) => {} // due to resend, or past the deadline
+                Ok(Err(_)) => return Err(TransportError::HandshakeIncomplete),
+            }
+        }
+    }
+
+    /// Receive the next datagram that is not a stray holepunch packet.
+    ///
+    /// The peer may still be retransmitting punch packets when the handshake
+    /// starts on the same path; those are skipped.
+    fn poll_recv_from(
+        source: &mut PacketSource,
+        socket: &dyn PacketTransport,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<(usize, SocketAddr)>> {
+        loop {
+            let (len, addr) = ready!(source.poll_recv_from(socket, cx, buf))?;
+            if !crate::holepunch::is_holepunch_packet(&buf[..len]) {
+                return Poll::Ready(Ok((len, addr)));
+            }
+        }
+    }
+
+    /// Like [`Self::poll_recv_from`], ignoring packets from anyone but
+    /// `remote_addr` unless `address_migration` is on.
+    fn poll_recv_from_remote(
+        source: &mut PacketSource,
+        socket: &dyn PacketTransport,
+        remote_addr: SocketAddr,
+        address_migration: bool,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<(usize, SocketAddr)>> {
+        loop {
+            let (len, addr) = ready!(Self::poll_recv_from(source, socket, cx, buf))?;
+            if addr == remote_addr || address_migration {
+                return Poll::Ready(Ok((len, addr)));
+            }
+            // Ignore packets from unexpected peers and wait for the correct one
+        }
+    }
+
+    async fn recv_packet(&mut self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        std::future::poll_fn(|cx| Self::poll_recv_from(&mut self.source, &*self.socket, cx, buf)).await
+    }
+
+    /// Returns the remote peer's static public key.
+    ///
+    /// This is available only after a successful handshake (either as initiator or
+    /// responder).  Returns `None` if the handshake has not yet completed.
+    pub fn remote_static_key(&self) -> Option<[u8; 32]> {
+        self.remote_static_key
+    }
+
+    /// The address this stream exchanges datagrams with. With
+    /// [`address_migration`](TransportConfig::address_migration) on, it
+    /// follows the peer when the peer's address changes.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// Whether we ran the handshake as initiator; false until it completes.
+    pub fn is_initiator(&self) -> bool {
+        self.initiator
+    }
+
+    /// Returns the Noise handshake hash of this session.
+    ///
+    /// Both ends see the same value, and no other session has it, so
+    /// application-layer authentication can sign it to bind itself to this
+    /// encrypted channel. Returns `None` until the handshake has completed.
+    pub fn handshake_hash(&self) -> Option<[u8; 32]> {
+        self.handshake_hash
+    }
+
+    /// Send encrypted data
+    ///
+    /// `data` is sent as one logical message: a 4-byte big-endian length
+    /// followed by the data, split over as many Noise messages as needed.
+    /// Data longer than [`max_message_size`](TransportConfig::max_message_size)
+    /// is [`TransportError::InvalidMessage`].
+    pub async fn send(&mut self, data: Bytes) -> Result<(), TransportError> {
+        if self.close_sent {
+            return Err(TransportError::Closed);
+        }
+        self.inbox.check_message_size(data.len())?;
+        if self.liveness.idle_timeout.is_some() {
+            // Count anything the peer already sent before judging it idle
+            std::future::poll_fn(|cx| Poll::Ready(self.drain_received(cx))).await?;
+            self.liveness.check_idle()?;
+        }
+        // Bytes written through AsyncWrite go first
+        self.pack_write_buf()?;
+        std::future::poll_fn(|cx| self.poll_send_outbox(cx)).await?;
+        
+        let prefix = length_prefix(&data)?;
+        
+        // Encrypt and send one frame at a time rather than bursting the whole
+        // message at the receiver
+        for parts in data_frames(&prefix, &data) {
+            self.session()?.push_frame(FRAME_DATA, &parts)?;
+            std::future::poll_fn(|cx| self.poll_send_outbox(cx)).await?;
+        }
+        Ok(())
+    }
+
+    /// Receive encrypted data
+    ///
+    /// Returns the next complete logical message sent with
+    /// [`EncryptedStream::send`], reassembled from its Noise messages.
+    /// Returns [`TransportError::Closed`] once the peer has shut down, and
+    /// [`TransportError::InvalidMessage`] for a message longer than
+    /// [`max_message_size`](TransportConfig::max_message_size), after which
+    /// message boundaries are lost and the stream should be dropped.
+    pub async fn recv(&mut self) -> Result<Bytes, TransportError> {
+        loop {
+            if !matches!(self.state, StreamState::Established(_)) {
+                return Err(TransportError::HandshakeIncomplete);
+            }
+            if let Some(message) = self.inbox.take_message()? {
+                return Ok(message);
+            }
+            if self.inbox.closed {
+                return Err(TransportError::Closed);
+            }
+            
+            std::future::poll_fn(|cx| self.poll_ingest(cx)).await?;
+        }
+    }
+
+    /// Close the stream: send what was written through `AsyncWrite`, then a
+    /// close frame, and stop reading.
+    ///
+    /// Afterwards [`send`](Self::send) and [`recv`](Self::recv) return
+    /// [`TransportError::Closed`]. The peer's `recv` returns the messages
+    /// sent before the close, then `Closed`. Closing twice does nothing more.
+    /// To keep reading until the peer closes its side too, use
+    /// `poll_shutdown` instead.
+    pub async fn close(&mut self) -> Result<(), TransportError> {
+        self.queue_close()?;
+        std::future::poll_fn(|cx| self.poll_send_outbox(cx)).await?;
+        self.inbox.close();
+        Ok(())
+    }
+
+    /// Like [`send`](Self::send), failing with [`TransportError::Timeout`]
+    /// if the message is not sent within `timeout`.
+    ///
+    /// A timed-out send may have sent part of the message, after which the
+    /// peer can no longer find message boundaries; drop the stream.
+    pub async fn send_timeout(&mut self, data: Bytes, timeout: Duration) -> Result<(), TransportError> {
+        tokio::time::timeout(timeout, self.send(data))
+            .await
+            .map_err(|_| TransportError::Timeout)?
+    }
+
+    /// Like [`recv`](Self::recv), failing with [`TransportError::Timeout`]
+    /// if no complete message arrives within `timeout`.
+    ///
+    /// Part of a message received before the timeout is kept, so the stream
+    /// stays usable and a later `recv` picks up where this one stopped.
+    pub async fn recv_timeout(&mut self, timeout: Duration) -> Result<Bytes, TransportError> {
+        tokio::time::timeout(timeout, self.recv())
+            .await
+            .map_err(|_| TransportError::Timeout)?
+    }
+
+    /// Wait for the next datagram from the peer, watching the idle timeout
+    /// meanwhile.
+    fn poll_recv_datagram(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<(usize, SocketAddr), TransportError>> {
+        if let Poll::Ready(Err(e)) = self.poll_send_outbox(cx) {
+            return Poll::Ready(Err(e.into()));
+        }
+        let received =
+            Self::poll_recv_from_remote(&mut self.source, &*self.socket, self.remote_addr, self.address_migration, cx, buf)?;
+        if let Poll::Ready(received) = received {
+            return Poll::Ready(Ok(received));
+        }
+        self.liveness.poll_idle(cx).map(Err)
+    }
+
+    /// Wait for the next datagram from the peer and take it in.
+    fn poll_ingest(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), TransportError>> {
+        let mut buf = self.take_datagram_buf();
+        let result = match self.poll_recv_datagram(cx, &mut buf) {
+            Poll::Ready(Ok((len, from))) => Poll::Ready(self.ingest(&buf[..len], from)),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        };
+        self.datagram_buf = buf;
+        result
+    }
+
+    /// Take in every datagram the peer has already sent, without waiting.
+    fn drain_received(&mut self, cx: &mut Context<'_>) -> Result<(), TransportError> {
+        let mut buf = self.take_datagram_buf();
+        let result = loop {
+            match Self::poll_recv_from_remote(&mut self.source, &*self.socket, self.remote_addr, self.address_migration, cx, &mut buf) {
+                Poll::Ready(Ok((len, from))) => {
+                    if let Err(e) = self.ingest(&buf[..len], from) {
+                        break Err(e);
+                    }
+                }
+                Poll::Ready(Err(e)) => break Err(e.into()),
+                Poll::Pending => break Ok(()),
+            }
+        };
+        self.datagram_buf = buf;
+        result
+    }
+
+    /// Borrow the datagram buffer while `self` is in use; put it back after.
+    fn take_datagram_buf(&mut self) -> Vec<u8> {
+        let mut buf = std::mem::take(&mut self.datagram_buf);
+        buf.resize(MAX_DATAGRAM_SIZE, 0);
+        buf
+    }
+
+    /// Decrypt a datagram from the peer, received from `from`, into the
+    /// inbox.
+    fn ingest(&mut self, datagram: &[u8], from: SocketAddr) -> Result<(), TransportError> {
+        let StreamState::Established(session) = &self.state else {
+            return Err(TransportError::HandshakeIncomplete);
+        };
+        let mut session = lock_session(session);
+        let session = &mut *session;
+        let ingested = ingest_from(&mut self.inbox, &mut session.transport, &mut session.outbox, &mut session.remote_addr, datagram, from);
+        self.remote_addr = session.remote_addr;
+        if !ingested? {
+            return Ok(());
+        }
+        self.liveness.last_received = Instant::now();
+        Ok(())
+    }
+
+    /// Encrypt `data` as a length-prefixed message and queue its frames.
+    fn queue_message(transport: &mut TransportState, outbox: &mut Outbox, data: &[u8]) -> Result<(), TransportError> {
+        let prefix = length_prefix(data)?;
+        for parts in data_frames(&prefix, data) {
+            outbox.push_frame(transport, FRAME_DATA, &parts)?;
+        }
+        Ok(())
+    }
+
+    /// Queue what was written through `AsyncWrite`, followed by a close
+    /// frame unless one was sent already.
+    fn queue_close(&mut self) -> Result<(), TransportError> {
+        self.pack_write_buf()?;
+        if !self.close_sent {
+            self.session()?.push_frame(FRAME_CLOSE, &[])?;
+            self.close_sent = true;
+        }
+        Ok(())
+    }
+
+    /// Turn bytes buffered by `AsyncWrite` into a queued message.
+    fn pack_write_buf(&mut self) -> Result<(), TransportError> {
+        if self.write_buf.is_empty() {
+            return Ok(());
+        }
+        let StreamState::Established(session) = &self.state else {
+            return Err(TransportError::HandshakeIncomplete);
+        };
+        let mut session = lock_session(session);
+        let session = &mut *session;
+        Self::queue_message(&mut session.transport, &mut session.outbox, &self.write_buf)?;
+        self.write_buf.clear();
+        Ok(())
+    }
+
+    /// Split an established stream into halves that receive and send
+    /// independently, for use from separate tasks.
+    ///
+    /// Only encrypting or decrypting a frame takes the halves' shared lock on
+    /// the Noise session, so neither waits while the other is blocked on the
+    /// socket. Keepalives keep going out as before, and the read half
+    /// reports the idle timeout. Closing the write half sends a close frame
+    /// but leaves the read half reading until the peer closes too.
+    ///
+    /// Fails with [`TransportError::HandshakeIncomplete`] before the handshake.
+    pub fn split(self) -> Result<(ReadHalf, WriteHalf), TransportError> {
+        let StreamState::Established(session) = self.state else {
+            return Err(TransportError::HandshakeIncomplete);
+        };
+        let max_message_size = self.inbox.max_message_size;
+        let read = ReadHalf {
+            socket: self.socket.clone(),
+            source: self.source,
+            session: session.clone(),
+            inbox: self.inbox,
+            datagram_buf: self.datagram_buf,
+            liveness: self.liveness,
+            address_migration: self.address_migration,
+        };
+        let write = WriteHalf {
+            socket: self.socket,
+            session,
+            write_buf: self.write_buf,
+            close_sent: self.close_sent,
+            max_message_size,
+        };
+        Ok((read, write))
+    }
+
+    /// Send queued datagrams in order.
+    fn poll_send_outbox(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &self.state {
+            StreamState::Established(session) => lock_session(session).poll_flush(&*self.socket, cx),
+            StreamState::Handshaking(_) => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+/// The 4-byte big-endian length that precedes `data` on the wire.
+fn length_prefix(data: &[u8]) -> Result<[u8; LENGTH_PREFIX_SIZE], TransportError> {
+    let len = u32::try_from(data.len()).map_err(|_| TransportError::InvalidMessage)?;
+    Ok(len.to_be_bytes())
+}
+
+/// Split `prefix || data` into the bodies of consecutive data frames, each
+/// given as the parts to concatenate, without copying `data`.
+fn data_frames<'a>(prefix: &'a [u8; LENGTH_PREFIX_SIZE], data: &'a [u8]) -> impl Iterator<Item = [&'a [u8]; 2]> {
+    let (first, rest) = data.split_at(data.len().min(MAX_CHUNK_SIZE - LENGTH_PREFIX_SIZE));
+    std::iter::once([prefix.as_slice(), first]).chain(rest.chunks(MAX_CHUNK_SIZE).map(|chunk| [&[][..], chunk]))
+}
+
+/// Encrypted datagrams waiting to be sent, and the sending key's schedule.
+struct Outbox {
+    datagrams: VecDeque<Vec<u8>>,
+    /// Buffers of sent datagrams, to seal later frames into.
+    spare: Vec<Vec<u8>>,
+    /// Plaintext of the frame being sealed.
+    plaintext: Vec<u8>,
+    rekey_after: u64,
+    /// Messages sealed with the current sending key.
+    sealed_with_key: u64,
+    /// When the last frame was sealed, for keepalive scheduling.
+    last_sealed: Instant,
+}
+
+impl Outbox {
+    fn new(rekey_after: u64) -> Self {
+        Self {
+            datagrams: VecDeque::new(),
+            spare: Vec::new(),
+            plaintext: Vec::new(),
+            rekey_after,
+            sealed_with_key: 0,
+            last_sealed: Instant::now(),
+        }
+    }
+
+    /// Encrypt and queue one frame, whose body is the concatenation of
+    /// `body`, rotating the sending key when due.
+    fn push_frame(&mut self, transport: &mut TransportState, frame_type: u8, body: &[&[u8]]) -> Result<(), TransportError> {
+        let datagram = self.seal_frame(transport, frame_type, body)?;
+        self.datagrams.push_back(datagram);
+        self.sealed_with_key += 1;
+        self.last_sealed = Instant::now();
+        if self.rekey_after > 0 && self.sealed_with_key >= self.rekey_after {
+            // Still sealed with the old key; everything after uses the new one
+            let datagram = self.seal_frame(transport, FRAME_REKEY, &[])?;
+            self.datagrams.push_back(datagram);
+            transport.rekey_outgoing();
+            self.sealed_with_key = 0;
+        }
+        Ok(())
+    }
+
+    /// Encrypt one frame into a datagram, reusing a spare buffer.
+    fn seal_frame(&mut self, transport: &mut TransportState, frame_type: u8, body: &[&[u8]]) -> Result<Vec<u8>, TransportError> {
+        self.plaintext.clear();
+        self.plaintext.push(frame_type);
+        for part in body {
+            self.plaintext.extend_from_slice(part);
+        }
+        
+        let mut buf = self.spare.pop().unwrap_or_default();
+        buf.resize(self.plaintext.len() + NOISE_TAG_SIZE, 0);
+        let len = transport
+            .write_message(&self.plaintext, &mut buf)
+            .map_err(noise_error)?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    /// Drop the datagram at the front of the queue once it is sent, keeping
+    /// its buffer.
+    fn sent(&mut self) {
+        if let Some(datagram) = self.datagrams.pop_front() {
+            if self.spare.len() < SPARE_DATAGRAMS {
+                self.spare.push(datagram);
+            }
+        }
+    }
+}
+
+/// Keepalive interval and idle-timeout bookkeeping.
+struct Liveness {
+    keepalive_interval: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    /// When the last frame arrived from the peer.
+    last_received: Instant,
+    /// Fires at the idle deadline.
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Self {
+            keepalive_interval: None,
+            idle_timeout: None,
+            last_received: Instant::now(),
+            timer: None,
+        }
+    }
+}
+
+impl Liveness {
+    /// Fail if the peer has been silent for the idle timeout.
+    fn check_idle(&self) -> Result<(), TransportError> {
+        match self.idle_timeout {
+            Some(timeout) if self.last_received.elapsed() >= timeout => Err(TransportError::IdleTimeout),
+            _ => Ok(()),
+        }
+    }
+
+    /// Resolve with [`TransportError::IdleTimeout`] once the peer has been
+    /// idle too long.
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<TransportError> {
+        loop {
+            if let Err(e) = self.check_idle() {
+                return Poll::Ready(e);
+            }
+            let Some(timeout) = self.idle_timeout else {
+                return Poll::Pending;
+            };
+            let deadline = self.last_received + timeout;
+            let timer = self.timer.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+            timer.as_mut().reset(deadline);
+            ready!(timer.as_mut().poll(cx));
+        }
+    }
+}
+
+/// Received plaintext that has not been handed to the application yet.
+struct Inbox {
+    /// Decrypted frame being filed.
+    plaintext: Vec<u8>,
+    /// Data frames of logical messages still being reassembled. Messages are
+    /// split off without copying, and the space they took is reclaimed once
+    /// the application has dropped them.
+    recv_buf: BytesMut,
+    /// Remainder of the current message, for `AsyncRead`.
+    read_buf: Bytes,
+    /// Whether the peer sent a close frame, or we closed the stream.
+    closed: bool,
+    /// Longest message accepted, see [`TransportConfig::max_message_size`].
+    max_message_size: usize,
+    /// As initiator, our answer to the responder's handshake message, sent
+    /// again if the responder resends it; dropped with its first frame.
+    handshake_answer: Option<HandshakeAnswer>,
+}
+
+impl Default for Inbox {
+    fn default() -> Self {
+        Self {
+            plaintext: Vec::new(),
+            recv_buf: BytesMut::new(),
+            read_buf: Bytes::new(),
+            closed: false,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            handshake_answer: None,
+        }
+    }
+}
+
+/// Whether a datagram of `len` bytes could be a Noise message we send: long
+/// enough for a frame type and tag, and no longer than the largest frame.
+/// Handshake messages fall in the same range.
+pub(crate) fn could_be_noise_datagram(len: usize) -> bool {
+    (FRAME_TYPE_SIZE + NOISE_TAG_SIZE..=MAX_NOISE_PLAINTEXT + NOISE_TAG_SIZE).contains(&len)
+}
+
+/// Decrypt a datagram received from `from` into `inbox`, returning whether
+/// it was taken in.
+///
+/// A datagram from `remote_addr` that fails to decrypt is an error, unless
+/// it is left over from the handshake: a late cookie packet is ignored, and
+/// the responder's last handshake message, resent because our answer was
+/// lost, is answered again through `outbox`. One from anywhere else is only received with address migration
+/// on: if it decrypts, the peer has moved and `remote_addr` follows it;
+/// otherwise it is ignored. Decryption failures leave the Noise nonce where
+/// it was, and a replayed frame reuses a spent nonce, so only the peer's
+/// next fresh frame can move the stream.
+fn ingest_from(
+    inbox: &mut Inbox,
+    transport: &mut TransportState,
+    outbox: &mut Outbox,
+    remote_addr: &mut SocketAddr,
+    datagram: &[u8],
+    from: SocketAddr,
+) -> Result<bool, TransportError> {
+    if from == *remote_addr {
+        return match inbox.ingest(transport, datagram) {
+            Ok(()) => Ok(true),
+            Err(_) if inbox.handshake_retransmit(datagram, outbox) => Ok(false),
+            Err(e) => Err(e),
+        };
+    }
+    match inbox.ingest(transport, datagram) {
+        Ok(()) => {
+            tracing::debug!("Peer moved from {} to {}", remote_addr, from);
+            *remote_addr = from;
+            Ok(true)
+        }
+        Err(e) => {
+            tracing::debug!("Ignoring packet from {}: {}", from, e);
+            Ok(false)
+        }
+    }
+}
+
+impl Inbox {
+    /// Fail for a message longer than the configured maximum.
+    fn check_message_size(&self, len: usize) -> Result<(), TransportError> {
+        if len > self.max_message_size {
+            return Err(TransportError::InvalidMessage);
+        }
+        Ok(())
+    }
+
+    /// Decrypt a datagram and file its frame.
+    ///
+    /// A datagram too short to hold a frame, or longer than any we send
+    /// (such as one truncated to fill the receive buffer), is
+    /// [`TransportError::InvalidMessage`] without being decrypted.
+    fn ingest(&mut self, transport: &mut TransportState, datagram: &[u8]) -> Result<(), TransportError> {
+        if !could_be_noise_datagram(datagram.len()) {
+            return Err(TransportError::InvalidMessage);
+        }
+        self.plaintext.resize(datagram.len(), 0);
+        let len = transport
+            .read_message(datagram, &mut self.plaintext)
+            .map_err(noise_error)?;
+        
+        // The responder has finished its handshake
+        self.handshake_answer = None;
+        match self.plaintext[..len].split_first() {
+            Some((&FRAME_DATA, chunk)) => self.recv_buf.extend_from_slice(chunk),
+            Some((&FRAME_CLOSE, _)) => self.closed = true,
+            Some((&FRAME_REKEY, _)) => transport.rekey_incoming(),
+            Some((&FRAME_KEEPALIVE, _)) => {}
+            _ => return Err(TransportError::InvalidMessage),
+        }
+        Ok(())
+    }
+
+    /// Whether `datagram` is a handshake packet arriving after the
+    /// handshake, queueing our answer again if it is the responder's last
+    /// message.
+    fn handshake_retransmit(&self, datagram: &[u8], outbox: &mut Outbox) -> bool {
+        if let Some(answer) = &self.handshake_answer {
+            if datagram == answer.message {
+                outbox.datagrams.push_back(answer.reply.clone());
+                return true;
+            }
+        }
+        datagram.starts_with(COOKIE_REPLY) || datagram.starts_with(COOKIE_ECHO)
+    }
+
+    /// Drop whatever has not been read and take nothing more.
+    fn close(&mut self) {
+        self.recv_buf.clear();
+        self.read_buf.clear();
+        self.closed = true;
+    }
+
+    /// Split a complete length-prefixed message off the front of `recv_buf`.
+    ///
+    /// A message announced as longer than the maximum is
+    /// [`TransportError::InvalidMessage`], and what was received of it is
+    /// dropped rather than buffered further.
+    fn take_message(&mut self) -> Result<Option<Bytes>, TransportError> {
+        let Some(prefix) = self.recv_buf.get(..LENGTH_PREFIX_SIZE) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(prefix.try_into().expect("prefix is 4 bytes")) as usize;
+        if let Err(e) = self.check_message_size(len) {
+            self.recv_buf.clear();
+            return Err(e);
+        }
+        let end = LENGTH_PREFIX_SIZE + len;
+        if self.recv_buf.len() < end {
+            return Ok(None);
+        }
+        let mut message = self.recv_buf.split_to(end);
+        message.advance(LENGTH_PREFIX_SIZE);
+        Ok(Some(message.freeze()))
+    }
+}
+
+impl AsyncRead for EncryptedStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, out: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !matches!(this.state, StreamState::Established(_)) {
+                return Poll::Ready(Err(TransportError::HandshakeIncomplete.into()));
+            }
+            // Serve what is left of the current message first
+            if !this.inbox.read_buf.is_empty() {
+                let n = out.remaining().min(this.inbox.read_buf.len());
+                out.put_slice(&this.inbox.read_buf.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(message) = this.inbox.take_message()? {
+                this.inbox.read_buf = message;
+                continue;
+            }
+            if this.inbox.closed {
+                // End of stream
+                return Poll::Ready(Ok(()));
+            }
+            
+            ready!(this.poll_ingest(cx))?;
+        }
+    }
+}
+
+impl AsyncWrite for EncryptedStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.close_sent {
+            return Poll::Ready(Err(TransportError::Closed.into()));
+        }
+        if !matches!(this.state, StreamState::Established(_)) {
+            return Poll::Ready(Err(TransportError::HandshakeIncomplete.into()));
+        }
+        let batch = MAX_WRITE_BATCH.min(this.inbox.max_message_size);
+        if this.write_buf.len() >= batch {
+            // A full frame is buffered: send it before taking more
+            this.pack_write_buf()?;
+            ready!(this.poll_send_outbox(cx))?;
+        }
+        let n = data.len().min(batch - this.write_buf.len());
+        this.write_buf.extend_from_slice(&data[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        this.pack_write_buf()?;
+        this.poll_send_outbox(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        this.queue_close()?;
+        this.poll_send_outbox(cx)
+    }
+}
+
+/// The Noise session and send queue of an established stream, shared with
+/// its keepalive task and, once split, between its halves.
+struct Session {
+    transport: TransportState,
+    /// The peer's address, shared so both halves follow it if it moves.
+    remote_addr: SocketAddr,
+    outbox: Outbox,
+    /// Halves waiting for the socket to take the queued datagrams.
+    flush_waiters: Vec<Waker>,
+}
+
+impl Session {
+    /// Seal one frame into the send queue.
+    fn push_frame(&mut self, frame_type: u8, body: &[&[u8]]) -> Result<(), TransportError> {
+        self.outbox.push_frame(&mut self.transport, frame_type, body)
+    }
+
+    /// Send queued datagrams in order, from either half.
+    ///
+    /// The socket only wakes the task that polled it last, so whichever half
+    /// empties the queue wakes any other half that was left waiting.
+    fn poll_flush(&mut self, socket: &dyn PacketTransport, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let result = loop {
+            let Some(datagram) = self.outbox.datagrams.front() else {
+                break Ok(());
+            };
+            match socket.poll_send_to(cx, datagram, self.remote_addr) {
+                Poll::Ready(Ok(_)) => self.outbox.sent(),
+                Poll::Ready(Err(e)) => break Err(e),
+                Poll::Pending => {
+                    if !self.flush_waiters.iter().any(|waker| waker.will_wake(cx.waker())) {
+                        self.flush_waiters.push(cx.waker().clone());
+                    }
+                    return Poll::Pending;
+                }
+            }
+        };
+        for waker in self.flush_waiters.drain(..) {
+            waker.wake();
+        }
+        Poll::Ready(result)
+    }
+}
+
+fn lock_session(session: &Mutex<Session>) -> MutexGuard<'_, Session> {
+    session.lock().expect("session lock poisoned")
+}
+
+/// The session keys of an established stream, sealing and opening one
+/// packet at a time for a layer that orders packets itself, such as
+/// [`ReliableStream`](crate::reliable::ReliableStream).
+///
+/// Each packet carries the nonce it was sealed with ahead of the Noise
+/// message, so packets open in whatever order they arrive and a lost one
+/// holds none of the others up. Opening does not remember nonces: a
+/// replayed packet opens again, and the layer above must recognise it.
+pub(crate) struct PacketCipher {
+    transport: TransportState,
+    /// As for a stream, our answer to the responder's handshake message,
+    /// until the responder's first packet shows it got through.
+    handshake_answer: Option<HandshakeAnswer>,
+}
+
+impl PacketCipher {
+    /// Seal `plaintext` into a packet.
+    pub(crate) fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, TransportError> {
+        let mut packet = vec![0u8; plaintext.len() + PACKET_OVERHEAD];
+        packet[..PACKET_NONCE_SIZE].copy_from_slice(&self.transport.sending_nonce().to_be_bytes());
+        let len = self
+            .transport
+            .write_message(plaintext, &mut packet[PACKET_NONCE_SIZE..])
+            .map_err(noise_error)?;
+        packet.truncate(PACKET_NONCE_SIZE + len);
+        Ok(packet)
+    }
+
+    /// Open a packet the peer sealed, returning its plaintext. A packet
+    /// that does not authenticate is [`TransportError::InvalidMessage`].
+    pub(crate) fn open(&mut self, packet: &[u8]) -> Result<Vec<u8>, TransportError> {
+        let (nonce, message) = packet
+            .split_first_chunk::<PACKET_NONCE_SIZE>()
+            .ok_or(TransportError::InvalidMessage)?;
+        if message.len() < NOISE_TAG_SIZE {
+            return Err(TransportError::InvalidMessage);
+        }
+        self.transport.set_receiving_nonce(u64::from_be_bytes(*nonce));
+        let mut plaintext = vec![0u8; message.len()];
+        let len = self
+            .transport
+            .read_message(message, &mut plaintext)
+            .map_err(|_| TransportError::InvalidMessage)?;
+        plaintext.truncate(len);
+        self.handshake_answer = None;
+        Ok(plaintext)
+    }
+
+    /// Our last handshake message again, if `packet` is the responder's
+    /// message it answered, resent because the answer was lost.
+    pub(crate) fn handshake_reply(&self, packet: &[u8]) -> Option<&[u8]> {
+        self.handshake_answer
+            .as_ref()
+            .filter(|answer| answer.message == packet)
+            .map(|answer| answer.reply.as_slice())
+    }
+}
+
+/// Send a keepalive frame whenever nothing was sent for `interval`, until
+/// the stream is dropped.
+async fn send_keepalives(socket: Arc<dyn PacketTransport>, session: Weak<Mutex<Session>>, interval: Duration) {
+    loop {
+        let Some(shared) = session.upgrade() else {
+            return;
+        };
+        let due = lock_session(&shared).outbox.last_sealed + interval;
+        drop(shared);
+        tokio::time::sleep_until(due).await;
+
+        let Some(shared) = session.upgrade() else {
+            return;
+        };
+        {
+            let mut session = lock_session(&shared);
+            if session.outbox.last_sealed + interval > Instant::now() {
+                continue;
+            }
+            if let Err(e) = session.push_frame(FRAME_KEEPALIVE, &[]) {
+                tracing::debug!("Stopping keepalives: {}", e);
+                return;
+            }
+        }
+        if let Err(e) = std::future::poll_fn(|cx| lock_session(&shared).poll_flush(&*socket, cx)).await {
+            tracing::debug!("Sending keepalive failed: {}", e);
+        }
+    }
+}
+
+/// The receiving half of a split [`EncryptedStream`].
+pub struct ReadHalf {
+    socket: Arc<dyn PacketTransport>,
+    source: PacketSource,
+    session: Arc<Mutex<Session>>,
+    inbox: Inbox,
+    datagram_buf: Vec<u8>,
+    liveness: Liveness,
+    address_migration: bool,
+}
+
+impl ReadHalf {
+    /// The address this stream exchanges datagrams with.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.session().remote_addr
+    }
+
+    /// Receive the next message, as [`EncryptedStream::recv`] does.
+    pub async fn recv(&mut self) -> Result<Bytes, TransportError> {
+        loop {
+            if let Some(message) = self.inbox.take_message()? {
+                return Ok(message);
+            }
+            if self.inbox.closed {
+                return Err(TransportError::Closed);
+            }
+            std::future::poll_fn(|cx| self.poll_ingest(cx)).await?;
+        }
+    }
+
+    fn session(&self) -> MutexGuard<'_, Session> {
+        lock_session(&self.session)
+    }
+
+    /// Wait for the next datagram from the peer, watching the idle timeout
+    /// meanwhile.
+    fn poll_recv_datagram(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<(usize, SocketAddr), TransportError>> {
+        let remote_addr = self.remote_addr();
+        if let Poll::Ready(received) =
+            EncryptedStream::poll_recv_from_remote(&mut self.source, &*self.socket, remote_addr, self.address_migration, cx, buf)?
+        {
+            return Poll::Ready(Ok(received));
+        }
+        self.liveness.poll_idle(cx).map(Err)
+    }
+
+    /// Wait for the next datagram from the peer and take it in.
+    fn poll_ingest(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), TransportError>> {
+        let mut buf = std::mem::take(&mut self.datagram_buf);
+        buf.resize(MAX_DATAGRAM_SIZE, 0);
+        let result = match self.poll_recv_datagram(cx, &mut buf) {
+            Poll::Ready(Ok((len, from))) => {
+                let mut session = lock_session(&self.session);
+                let session = &mut *session;
+                let ingested = ingest_from(
+                    &mut self.inbox,
+                    &mut session.transport,
+                    &mut session.outbox,
+                    &mut session.remote_addr,
+                    &buf[..len],
+                    from,
+                );
+                if let Ok(true) = ingested {
+                    self.liveness.last_received = Instant::now();
+                }
+                // Send a handshake answer now; the write half may be idle
+                let flushed = if session.outbox.datagrams.is_empty() {
+                    Ok(())
+                } else {
+                    match session.poll_flush(&*self.socket, cx) {
+                        Poll::Ready(Err(e)) => Err(e.into()),
+                        _ => Ok(()),
+                    }
+                };
+                Poll::Ready(ingested.and(flushed))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        };
+        self.datagram_buf = buf;
+        result
+    }
+}
+
+impl AsyncRead for ReadHalf {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, out: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.inbox.read_buf.is_empty() {
+                let n = out.remaining().min(this.inbox.read_buf.len());
+                out.put_slice(&this.inbox.read_buf.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            if let Some(message) = this.inbox.take_message()? {
+                this.inbox.read_buf = message;
+                continue;
+            }
+            if this.inbox.closed {
+                return Poll::Ready(Ok(()));
+            }
+            ready!(this.poll_ingest(cx))?;
+        }
+    }
+}
+
+/// The sending half of a split [`EncryptedStream`].
+pub struct WriteHalf {
+    socket: Arc<dyn PacketTransport>,
+    session: Arc<Mutex<Session>>,
+    write_buf: Vec<u8>,
+    close_sent: bool,
+    max_message_size: usize,
+}
+
+impl WriteHalf {
+    /// The address this stream exchanges datagrams with.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.session().remote_addr
+    }
+
+    /// Send `data` as one message, as [`EncryptedStream::send`] does.
+    pub async fn send(&mut self, data: Bytes) -> Result<(), TransportError> {
+        if self.close_sent {
+            return Err(TransportError::Closed);
+        }
+        if data.len() > self.max_message_size {
+            return Err(TransportError::InvalidMessage);
+        }
+        self.pack_write_buf()?;
+        std::future::poll_fn(|cx| self.poll_flush_session(cx)).await?;
+
+        let prefix = length_prefix(&data)?;
+        for parts in data_frames(&prefix, &data) {
+            self.session().push_frame(FRAME_DATA, &parts)?;
+            std::future::poll_fn(|cx| self.poll_flush_session(cx)).await?;
+        }
+        Ok(())
+    }
+
+    /// Send what was written through `AsyncWrite`, then a close frame. The
+    /// peer's reads end once it has read everything sent before.
+    pub async fn close(&mut self) -> Result<(), TransportError> {
+        self.queue_close()?;
+        std::future::poll_fn(|cx| self.poll_flush_session(cx)).await?;
+        Ok(())
+    }
+
+    fn session(&self) -> MutexGuard<'_, Session> {
+        lock_session(&self.session)
+    }
+
+    fn poll_flush_session(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.session().poll_flush(&*self.socket, cx)
+    }
+
+    /// Turn bytes buffered by `AsyncWrite` into a queued message.
+    fn pack_write_buf(&mut self) -> Result<(), TransportError> {
+        if self.write_buf.is_empty() {
+            return Ok(());
+        }
+        let mut session = lock_session(&self.session);
+        let session = &mut *session;
+        EncryptedStream::queue_message(&mut session.transport, &mut session.outbox, &self.write_buf)?;
+        self.write_buf.clear();
+        Ok(())
+    }
+
+    fn queue_close(&mut self) -> Result<(), TransportError> {
+        self.pack_write_buf()?;
+        if !self.close_sent {
+            self.session().push_frame(FRAME_CLOSE, &[])?;
+            self.close_sent = true;
+        }
+        Ok(())
+    }
+}
+
+impl AsyncWrite for WriteHalf {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.close_sent {
+            return Poll::Ready(Err(TransportError::Closed.into()));
+        }
+        let batch = MAX_WRITE_BATCH.min(this.max_message_size);
+        if this.write_buf.len() >= batch {
+            this.pack_write_buf()?;
+            ready!(this.poll_flush_session(cx))?;
+        }
+        let n = data.len().min(batch - this.write_buf.len());
+        this.write_buf.extend_from_slice(&data[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        this.pack_write_buf()?;
+        this.poll_flush_session(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        this.queue_close()?;
+        this.poll_flush_session(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn test_encrypted_stream_creation() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let remote_addr = "127.0.0.1:8080".parse().unwrap();
+        
+        let stream = EncryptedStream::new(socket, remote_addr).await;
+        assert!(stream.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_secret_keys_are_held_in_zeroizing() {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let peer = socket.local_addr().unwrap();
+        let stream = EncryptedStream::with_psk(socket, peer, [7u8; 32]).unwrap();
+        let _: &Zeroizing<[u8; 32]> = &stream.local_static_privkey;
+        let _: Option<&Zeroizing<[u8; 32]>> = stream.psk.as_ref();
+        assert_eq!(public_key_from_private(&stream.local_static_privkey).unwrap(), stream.local_static_pubkey());
+    }
+
+    #[test]
+    fn test_take_message_waits_for_full_frame() {
+        let mut inbox = Inbox::default();
+        assert!(inbox.take_message().unwrap().is_none());
+        
+        // Zero-length message
+        inbox.recv_buf.extend_from_slice(&0u32.to_be_bytes());
+        assert_eq!(inbox.take_message().unwrap(), Some(Bytes::new()));
+        assert!(inbox.recv_buf.is_empty());
+        
+        // Partial final chunk: nothing until the last byte arrives
+        inbox.recv_buf.extend_from_slice(&3u32.to_be_bytes());
+        inbox.recv_buf.extend_from_slice(b"ab");
+        assert!(inbox.take_message().unwrap().is_none());
+        inbox.recv_buf.extend_from_slice(b"c");
+        assert_eq!(inbox.take_message().unwrap(), Some(Bytes::from_static(b"abc")));
+        assert!(inbox.recv_buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_messages_over_max_message_size_are_rejected() {
+        let (sender, receiver) = handshaked_pair().await;
+        let config = TransportConfig {
+            max_message_size: 1000,
+            ..Default::default()
+        };
+        let mut sender = sender.with_config(config.clone()).unwrap();
+        let mut receiver = receiver.with_config(config).unwrap();
+        assert_eq!(TransportConfig::default().max_message_size, 65535);
+
+        // One byte over the cap is refused before anything is sent
+        let over = Bytes::from(vec![1u8; 1001]);
+        assert!(matches!(sender.send(over.clone()).await, Err(TransportError::InvalidMessage)));
+        let at_cap = Bytes::from(vec![2u8; 1000]);
+        sender.send(at_cap.clone()).await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), at_cap);
+
+        // A peer ignoring the cap gets its message refused on arrival, after
+        // just the first chunk: the length prefix is enough to tell
+        let mut sender = sender
+            .with_config(TransportConfig {
+                max_message_size: 3 * MAX_CHUNK_SIZE,
+                ..Default::default()
+            })
+            .unwrap();
+        let huge = Bytes::from(vec![3u8; 3 * MAX_CHUNK_SIZE]);
+        let (sent, received) = tokio::join!(sender.send(huge), receiver.recv());
+        sent.unwrap();
+        assert!(matches!(received, Err(TransportError::InvalidMessage)), "got {:?}", received);
+        assert!(receiver.inbox.recv_buf.is_empty(), "the oversized message should not be buffered");
+    }
+
+    #[tokio::test]
+    async fn test_zero_max_message_size_is_rejected() {
+        let (stream, _) = handshaked_pair().await;
+        let config = TransportConfig {
+            max_message_size: 0,
+            ..Default::default()
+        };
+        assert!(matches!(stream.with_config(config), Err(TransportError::InvalidConfig(_))));
+    }
+
+    async fn handshaked_pair() -> (EncryptedStream, EncryptedStream) {
+        let s1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let s2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let a1 = s1.local_addr().unwrap();
+        let a2 = s2.local_addr().unwrap();
+        let mut initiator = EncryptedStream::new(s1, a2).await.unwrap();
+        let mut responder = EncryptedStream::new(s2, a1).await.unwrap();
+        let (r1, r2) = tokio::join!(initiator.handshake_initiator(None), responder.handshake_responder(None));
+        r1.unwrap();
+        r2.unwrap();
+        (initiator, responder)
+    }
+
+    /// A memory endpoint that loses the sends whose index is in `lose`.
+    struct LossyTransport {
+        inner: Arc<crate::packet::MemoryTransport>,
+        lose: &'static [usize],
+        sends: std::sync::atomic::AtomicUsize,
     }
 
-    /// Build a responder handshake state reusing the stored static keypair.
-    fn make_responder_state(&self) -> Result<HandshakeState, TransportError> {
-        Builder::new(
-            NOISE_PARAMS.parse().map_err(|e| TransportError::Noise(format!("{:?}", e)))?,
-        )
-        .local_private_key(self.local_static_privkey.as_ref())
-        .build_responder()
-        .map_err(|e| TransportError::Noise(format!("{:?}", e)))
+    impl PacketTransport for LossyTransport {
+        fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<std::io::Result<usize>> {
+            let index = self.sends.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            if self.lose.contains(&index) {
+                return Poll::Ready(Ok(buf.len()));
+            }
+            self.inner.poll_send_to(cx, buf, target)
+        }
+
+        fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<SocketAddr>> {
+            self.inner.poll_recv_from(cx, buf)
+        }
+
+        fn local_addr(&self) -> std::io::Result<SocketAddr> {
+            self.inner.local_addr()
+        }
     }
 
-    /// Returns the local static public key for this stream.
-    ///
-    /// This key is stable for the lifetime of the `EncryptedStream` and can be
-    /// shared with a peer out-of-band so the peer can authenticate this end.
-    pub fn local_static_pubkey(&self) -> [u8; 32] {
-        self.local_static_pubkey
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_retransmits_lost_messages() {
+        // Initiator sends: -> e, -> cookie + e, -> s, se
+        // Responder sends: -> cookie, -> e, ee, s, es
+        let cases: [(&'static [usize], &'static [usize]); 5] = [(&[0], &[]), (&[1], &[]), (&[2], &[]), (&[], &[0]), (&[], &[1])];
+        for (initiator_loses, responder_loses) in cases {
+            let network = crate::packet::MemoryNetwork::new();
+            let a1: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+            let a2: SocketAddr = "10.0.0.2:1000".parse().unwrap();
+            let lossy = |addr, lose| {
+                Arc::new(LossyTransport {
+                    inner: network.bind(addr).unwrap(),
+                    lose,
+                    sends: Default::default(),
+                })
+            };
+            let mut initiator = EncryptedStream::new(lossy(a1, initiator_loses), a2).await.unwrap();
+            let mut responder = EncryptedStream::new(lossy(a2, responder_loses), a1).await.unwrap();
+
+            // The initiator only learns its last message was lost from the
+            // responder's retransmission, which arrives while it reads
+            let started = Instant::now();
+            let (received, sent) = tokio::join!(
+                async {
+                    initiator.handshake_initiator(None).await?;
+                    initiator.recv().await
+                },
+                async {
+                    responder.handshake_responder(None).await?;
+                    responder.send(Bytes::from_static(b"through")).await
+                },
+            );
+            sent.unwrap_or_else(|e| panic!("responder losing {:?}: {:?}", responder_loses, e));
+            assert_eq!(received.unwrap(), Bytes::from_static(b"through"), "initiator losing {:?}", initiator_loses);
+            assert_eq!(started.elapsed(), HANDSHAKE_RETRANSMIT_INTERVAL);
+            assert_eq!(initiator.handshake_hash(), responder.handshake_hash());
+            assert!(initiator.inbox.handshake_answer.is_none());
+        }
     }
 
-    /// Copy the remote static public key out of a completed `HandshakeState`.
-    ///
-    /// Returns `None` if the handshake has not yet revealed the remote key (which
-    /// should not happen at the expected call sites in the XX flow).
-    fn extract_remote_static(handshake: &snow::HandshakeState) -> Option<[u8; 32]> {
-        handshake.get_remote_static().and_then(|k| {
-            if k.len() >= 32 {
-                let mut arr = [0u8; 32];
-                arr.copy_from_slice(&k[..32]);
-                Some(arr)
+    #[tokio::test]
+    async fn test_address_migration_follows_a_rebound_peer() {
+        for address_migration in [true, false] {
+            let (initiator, mut responder) = handshaked_pair().await;
+            let mut initiator = initiator
+                .with_config(TransportConfig {
+                    address_migration,
+                    ..Default::default()
+                })
+                .unwrap();
+            let initiator_addr = initiator.socket.local_addr().unwrap();
+            let old_addr = initiator.remote_addr();
+            responder.send(Bytes::from_static(b"before")).await.unwrap();
+            assert_eq!(initiator.recv().await.unwrap(), Bytes::from_static(b"before"));
+
+            // Junk from a stranger never moves the stream, nor breaks it
+            let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            stranger.send_to(&[0x42; 64], initiator_addr).await.unwrap();
+
+            // The responder's NAT remaps it to another port
+            responder.socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let new_addr = responder.socket.local_addr().unwrap();
+            responder.send(Bytes::from_static(b"after")).await.unwrap();
+
+            let received = initiator.recv_timeout(Duration::from_millis(500)).await;
+            if address_migration {
+                assert_eq!(received.unwrap(), Bytes::from_static(b"after"));
+                assert_eq!(initiator.remote_addr(), new_addr);
+                initiator.send(Bytes::from_static(b"reply")).await.unwrap();
+                assert_eq!(responder.recv().await.unwrap(), Bytes::from_static(b"reply"));
             } else {
-                None
+                assert!(matches!(received, Err(TransportError::Timeout)), "got {:?}", received);
+                assert_eq!(initiator.remote_addr(), old_addr);
             }
-        })
+        }
     }
 
-    /// Perform Noise XX handshake as initiator.
-    ///
-    /// If `remote_static_pubkey` is provided, the handshake will verify that the
-    /// responder's static public key (obtained from the `<- e, ee, s, es` message)
-    /// matches the supplied value, and return [`TransportError::PeerAuthenticationFailed`]
-    /// if it does not.  This defends against man-in-the-middle attacks.
-    ///
-    /// After a successful handshake the peer's static key is stored and accessible via
-    /// [`EncryptedStream::remote_static_key`].
-    pub async fn handshake_initiator(&mut self, remote_static_pubkey: Option<[u8; 32]>) -> Result<(), TransportError> {
-        // Extract the handshake state temporarily
-        let handshake = {
-            let mut state = self.state.lock().await;
-            match &*state {
-                StreamState::Established(_) => return Ok(()),
-                StreamState::Handshaking(_) => {
-                    // Replace with a placeholder so the lock can be released while
-                    // we perform network I/O.
-                    match std::mem::replace(&mut *state, StreamState::Handshaking(self.make_initiator_state()?)) {
-                        StreamState::Handshaking(h) => h,
-                        _ => unreachable!(),
-                    }
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_split_halves_send_and_receive_concurrently() {
+        // Few enough bytes that a burst fits the sockets' receive buffers
+        const MESSAGES: usize = 200;
+        let (initiator, responder) = handshaked_pair().await;
+
+        // Each side reads in one task while writing in another
+        let mut tasks = Vec::new();
+        for (side, stream) in [(1u8, initiator), (2u8, responder)] {
+            let (mut read, mut write) = stream.split().unwrap();
+            tasks.push(tokio::spawn(async move {
+                for i in 0..MESSAGES {
+                    let message = vec![side; 1 + i * 37 % 300];
+                    write.send(Bytes::from(message)).await.unwrap();
                 }
-            }
+                write.close().await.unwrap();
+            }));
+            tasks.push(tokio::spawn(async move {
+                let peer = 3 - side;
+                for i in 0..MESSAGES {
+                    let message = read.recv().await.unwrap();
+                    assert_eq!(message.len(), 1 + i * 37 % 300);
+                    assert!(message.iter().all(|&byte| byte == peer));
+                }
+                assert!(matches!(read.recv().await, Err(TransportError::Closed)));
+            }));
+        }
+        tokio::time::timeout(std::time::Duration::from_secs(10), futures::future::try_join_all(tasks))
+            .await
+            .expect("split halves deadlocked")
+            .unwrap();
+
+        let s = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let peer = s.local_addr().unwrap();
+        let fresh = EncryptedStream::new(s, peer).await.unwrap();
+        assert!(matches!(fresh.split(), Err(TransportError::HandshakeIncomplete)));
+    }
+
+    #[tokio::test]
+    async fn test_message_filling_exact_chunks() {
+        let (sender, receiver) = handshaked_pair().await;
+        let config = TransportConfig {
+            max_message_size: 2 * MAX_CHUNK_SIZE,
+            ..Default::default()
         };
+        let mut sender = sender.with_config(config.clone()).unwrap();
+        let mut receiver = receiver.with_config(config).unwrap();
         
-        let mut handshake = handshake;
-        
-        // -> e
-        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
-        let len = handshake
-            .write_message(&[], &mut buf)
-            .map_err(|e| TransportError::Noise(format!("{:?}", e)))?;
+        // Length prefix plus data fill exactly two Noise messages
+        let data = Bytes::from(vec![7u8; 2 * MAX_CHUNK_SIZE - LENGTH_PREFIX_SIZE]);
+        let send = sender.send(data.clone());
+        let recv = receiver.recv();
+        let (sent, received) = tokio::join!(send, recv);
+        sent.unwrap();
+        assert_eq!(received.unwrap(), data);
         
-        self.socket.send_to(&buf[..len], self.remote_addr).await?;
+        // The next message starts cleanly on a fresh chunk
+        sender.send(Bytes::from_static(b"next")).await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), Bytes::from_static(b"next"));
+    }
 
-        // <- e, ee, s, es
-        // Apply the same shared deadline as the responder to prevent an adversary
-        // from stalling the initiator indefinitely by flooding from wrong addresses.
-        let deadline = tokio::time::Instant::now() + HANDSHAKE_TIMEOUT;
-        let recv_len = loop {
-            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
-            if remaining.is_zero() {
-                return Err(TransportError::HandshakeIncomplete);
-            }
-            match tokio::time::timeout(remaining, self.socket.recv_from(&mut buf)).await {
-                Ok(Ok((len, addr))) if addr == self.remote_addr => break len,
-                Ok(Ok(_)) => {} // ignore packets from unexpected sources
-                _ => return Err(TransportError::HandshakeIncomplete),
-            }
+    #[tokio::test]
+    async fn test_impossible_frames_are_invalid_messages() {
+        let (mut initiator, mut responder) = handshaked_pair().await;
+        // Truncated to fill the receive buffer
+        let mut inbox = Inbox::default();
+        let ingested = inbox.ingest(&mut responder.session().unwrap().transport, &[0; MAX_DATAGRAM_SIZE]);
+        assert!(matches!(ingested, Err(TransportError::InvalidMessage)));
+
+        // Too short to hold a frame, from the peer's address
+        initiator.socket.send_to(&[1, 2, 3], initiator.remote_addr()).await.unwrap();
+        assert!(matches!(responder.recv().await, Err(TransportError::InvalidMessage)));
+
+        // The stream is still usable
+        initiator.send(Bytes::from_static(b"after")).await.unwrap();
+        assert_eq!(responder.recv().await.unwrap(), Bytes::from_static(b"after"));
+    }
+
+    #[tokio::test]
+    async fn test_outbox_rekeys_after_threshold() {
+        let (stream, _) = handshaked_pair().await;
+        let transport = &mut stream.session().unwrap().transport;
+        let mut outbox = Outbox::new(3);
+        for _ in 0..7 {
+            outbox.push_frame(transport, FRAME_DATA, &[b"x"]).unwrap();
+        }
+        // Two rekey frames: after the third and the sixth data frame
+        assert_eq!(outbox.datagrams.len(), 9);
+        assert_eq!(outbox.sealed_with_key, 1);
+    }
+
+    #[tokio::test]
+    async fn test_data_flows_across_rekeys() {
+        let (initiator, responder) = handshaked_pair().await;
+        let config = TransportConfig {
+            rekey_after: 2,
+            max_message_size: 3 * MAX_CHUNK_SIZE,
+            ..Default::default()
         };
+        let mut a = initiator.with_config(config.clone()).unwrap();
+        let mut b = responder.with_config(config).unwrap();
+        
+        for i in 0..10u8 {
+            // Both sides send at once so rekey frames cross on the wire
+            let (sent_a, sent_b) = tokio::join!(a.send(Bytes::from(vec![i; 16])), b.send(Bytes::from(vec![i + 100; 16])));
+            sent_a.unwrap();
+            sent_b.unwrap();
+            assert_eq!(b.recv().await.unwrap(), Bytes::from(vec![i; 16]));
+            assert_eq!(a.recv().await.unwrap(), Bytes::from(vec![i + 100; 16]));
+        }
         
-        let _ = handshake
-            .read_message(&buf[..recv_len], &mut [])
-            .map_err(|e| TransportError::Noise(format!("{:?}", e)))?;
+        // A message spanning several frames rekeys partway through
+        let data = Bytes::from(vec![9u8; 3 * MAX_CHUNK_SIZE]);
+        let (sent, received) = tokio::join!(a.send(data.clone()), b.recv());
+        sent.unwrap();
+        assert_eq!(received.unwrap(), data);
+    }
 
-        // The remote static key ('s') is now revealed by the XX handshake.
-        // Copy it out before consuming the handshake state.
-        let remote_static = Self::extract_remote_static(&handshake);
+    #[tokio::test]
+    async fn test_noise_handshake_state_creation() {
+        let private_key = EncryptedStream::generate_private_key().unwrap();
+        for cipher_suite in [CipherSuite::ChaChaPoly, CipherSuite::AesGcm] {
+            assert!(build_handshake_state(&private_key, None, cipher_suite, true).is_ok());
+            assert!(build_handshake_state(&private_key, Some(&[7u8; 32]), cipher_suite, false).is_ok());
+        }
+    }
 
-        // Validate the remote key if the caller supplied an expected value.
-        if let Some(expected) = remote_static_pubkey {
-            match remote_static {
-                Some(actual) if actual == expected => {}
-                _ => return Err(TransportError::PeerAuthenticationFailed),
-            }
+    /// The default config with a handshake `timeout`.
+    fn timeout_config(timeout: Duration) -> TransportConfig {
+        TransportConfig {
+            handshake_timeout: timeout,
+            ..Default::default()
         }
+    }
 
-        // -> s, se
-        let len = handshake
-            .write_message(&[], &mut buf)
-            .map_err(|e| TransportError::Noise(format!("{:?}", e)))?;
-        
-        self.socket.send_to(&buf[..len], self.remote_addr).await?;
+    /// Run a handshake between streams built with the given PSKs, returning
+    /// the initiator's result once the responder has finished or given up.
+    async fn psk_handshake(initiator_psk: [u8; 32], responder_psk: [u8; 32]) -> Result<(), TransportError> {
+        let s1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let s2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let a1 = s1.local_addr().unwrap();
+        let a2 = s2.local_addr().unwrap();
 
-        // Transition to transport mode
-        let transport = handshake
-            .into_transport_mode()
-            .map_err(|e| TransportError::Noise(format!("{:?}", e)))?;
-        
-        // Update state and store the authenticated remote key
-        let mut state = self.state.lock().await;
-        *state = StreamState::Established(transport);
-        self.remote_static_key = remote_static;
-        
-        Ok(())
+        let timeout = Duration::from_secs(2);
+        let mut initiator = EncryptedStream::with_psk(s1, a2, initiator_psk).unwrap().with_config(timeout_config(timeout)).unwrap();
+        let mut responder = EncryptedStream::with_psk(s2, a1, responder_psk).unwrap().with_config(timeout_config(timeout)).unwrap();
+
+        let responder = tokio::spawn(async move {
+            // On a mismatch the initiator never sends the third message
+            let _ = tokio::time::timeout(
+                std::time::Duration::from_secs(3),
+                responder.handshake_responder(None),
+            )
+            .await;
+            responder
+        });
+        let result = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            initiator.handshake_initiator(None),
+        )
+        .await
+        .expect("initiator timed out");
+        let responder = responder.await.expect("task panicked");
+
+        if result.is_ok() {
+            assert_eq!(responder.remote_static_key(), Some(initiator.local_static_pubkey()));
+        }
+        result
     }
 
-    /// Perform Noise XX handshake as responder.
-    ///
-    /// After a successful handshake the initiator's static public key is stored
-    /// and accessible via [`EncryptedStream::remote_static_key`].
-    pub async fn handshake_responder(&mut self) -> Result<(), TransportError> {
-        // Build a responder state reusing the stored static keypair so that
-        // local_static_pubkey() remains consistent regardless of which role
-        // this stream takes.
-        let mut handshake = self.make_responder_state()?;
+    #[tokio::test]
+    async fn test_responder_requires_cookie_echo() {
+        let attacker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let responder_addr = s2.local_addr().unwrap();
+        let mut responder = EncryptedStream::new(s2, attacker.local_addr().unwrap()).await.unwrap();
+        let responder = tokio::spawn(async move { responder.handshake_responder(None).await });
+
+        // A captured first message, replayed without ever echoing a cookie
+        let private_key = EncryptedStream::generate_private_key().unwrap();
+        let mut initiator = build_handshake_state(&private_key, None, CipherSuite::default(), true).unwrap();
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let len = initiator.write_message(&[], &mut buf).unwrap();
+        let hello = buf[..len].to_vec();
+        let wait = std::time::Duration::from_millis(300);
+        for _ in 0..3 {
+            attacker.send_to(&hello, responder_addr).await.unwrap();
+            let (len, _) = tokio::time::timeout(wait, attacker.recv_from(&mut buf)).await.unwrap().unwrap();
+            assert!(buf[..len].starts_with(COOKIE_REPLY), "responder should only hand out a cookie");
+        }
+        let cookie = buf[COOKIE_REPLY.len()..COOKIE_REPLY.len() + COOKIE_SIZE].to_vec();
+
+        // A forged cookie gets no answer at all
+        let forged = [COOKIE_ECHO.as_slice(), &[0u8; COOKIE_SIZE], &hello].concat();
+        attacker.send_to(&forged, responder_addr).await.unwrap();
+        assert!(tokio::time::timeout(wait, attacker.recv_from(&mut buf)).await.is_err());
+        assert!(!responder.is_finished(), "no session without a valid cookie echo");
+
+        // Echoing the real cookie moves the handshake on to `<- e, ee, s, es`
+        let echo = [COOKIE_ECHO.as_slice(), &cookie, &hello].concat();
+        attacker.send_to(&echo, responder_addr).await.unwrap();
+        let (len, _) = tokio::time::timeout(wait, attacker.recv_from(&mut buf)).await.unwrap().unwrap();
+        initiator.read_message(&buf[..len], &mut []).unwrap();
+        responder.abort();
+    }
 
-        // <- e
-        // Use a shared deadline so continuous packets from unexpected sources cannot
-        // stall the handshake indefinitely (DoS mitigation).
-        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
-        let deadline = tokio::time::Instant::now() + HANDSHAKE_TIMEOUT;
-        let recv_len = loop {
-            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
-            if remaining.is_zero() {
-                return Err(TransportError::HandshakeIncomplete);
-            }
-            match tokio::time::timeout(remaining, self.socket.recv_from(&mut buf)).await {
-                Ok(Ok((len, addr))) if addr == self.remote_addr => break len,
-                Ok(Ok(_)) => {} // ignore packets from unexpected sources
-                _ => return Err(TransportError::HandshakeIncomplete),
-            }
+    #[tokio::test]
+    async fn test_nonce_exhaustion_is_its_own_error() {
+        let (initiator, mut responder) = handshaked_pair().await;
+        let datagram = {
+            let mut sender = initiator.session().unwrap();
+            let sender = &mut *sender;
+            sender.outbox.seal_frame(&mut sender.transport, FRAME_KEEPALIVE, &[]).unwrap()
         };
-        let _ = handshake
-            .read_message(&buf[..recv_len], &mut [])
-            .map_err(|e| TransportError::Noise(format!("{:?}", e)))?;
 
-        // -> e, ee, s, es
-        let len = handshake
-            .write_message(&[], &mut buf)
-            .map_err(|e| TransportError::Noise(format!("{:?}", e)))?;
-        
-        self.socket.send_to(&buf[..len], self.remote_addr).await?;
+        // The last nonce is reserved, so this one cannot be used
+        let StreamState::Established(session) = &responder.state else {
+            panic!("handshake incomplete");
+        };
+        let mut receiver = lock_session(session);
+        receiver.transport.set_receiving_nonce(u64::MAX);
+        let result = responder.inbox.ingest(&mut receiver.transport, &datagram);
+        assert!(matches!(result, Err(TransportError::NonceExhausted)), "got {:?}", result);
+        let io: std::io::Error = TransportError::NonceExhausted.into();
+        assert_eq!(io.kind(), std::io::ErrorKind::ConnectionAborted);
+
+        assert!(matches!(noise_error(snow::Error::Decrypt), TransportError::Noise(_)));
+    }
 
-        // <- s, se
-        let recv_len = loop {
-            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
-            if remaining.is_zero() {
-                return Err(TransportError::HandshakeIncomplete);
+    #[tokio::test]
+    async fn test_handshake_timeout_is_configurable() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let stream = EncryptedStream::new(socket.clone(), silent.local_addr().unwrap()).await.unwrap();
+        assert_eq!(stream.handshake_timeout(), DEFAULT_HANDSHAKE_TIMEOUT);
+        assert!(matches!(stream.with_config(timeout_config(Duration::ZERO)), Err(TransportError::InvalidConfig(_))));
+
+        let timeout = Duration::from_millis(200);
+        let mut initiator = EncryptedStream::new(socket.clone(), silent.local_addr().unwrap())
+            .await
+            .unwrap()
+            .with_config(timeout_config(timeout))
+            .unwrap();
+        let started = Instant::now();
+        let result = initiator.handshake_initiator(None).await;
+        assert!(matches!(result, Err(TransportError::HandshakeIncomplete)), "got {:?}", result);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= timeout && elapsed < Duration::from_secs(1), "took {:?}", elapsed);
+
+        let mut responder = EncryptedStream::new(socket, silent.local_addr().unwrap())
+            .await
+            .unwrap()
+            .with_config(timeout_config(timeout))
+            .unwrap();
+        let started = Instant::now();
+        let result = responder.handshake_responder(None).await;
+        assert!(matches!(result, Err(TransportError::HandshakeIncomplete)), "got {:?}", result);
+        assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_initiator_gives_up_when_responder_goes_quiet() {
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let initiator_addr = s1.local_addr().unwrap();
+        let mut initiator = EncryptedStream::new(s1, peer.local_addr().unwrap()).await.unwrap();
+
+        // The peer hands out a cookie and then never answers again
+        let quiet = tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            let (_, from) = peer.recv_from(&mut buf).await.unwrap();
+            let reply = [COOKIE_REPLY.as_slice(), &[0u8; COOKIE_SIZE]].concat();
+            peer.send_to(&reply, from).await.unwrap();
+            loop {
+                let _ = peer.recv_from(&mut buf).await;
             }
-            match tokio::time::timeout(remaining, self.socket.recv_from(&mut buf)).await {
-                Ok(Ok((len, addr))) if addr == self.remote_addr => break len,
-                Ok(Ok(_)) => {} // ignore packets from unexpected sources
-                _ => return Err(TransportError::HandshakeIncomplete),
+        });
+        // Meanwhile someone else keeps sending from the wrong address
+        let flood = tokio::spawn(async move {
+            loop {
+                let _ = stranger.send_to(COOKIE_REPLY, initiator_addr).await;
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
             }
-        };
-        let _ = handshake
-            .read_message(&buf[..recv_len], &mut [])
-            .map_err(|e| TransportError::Noise(format!("{:?}", e)))?;
+        });
 
-        // The initiator's static key ('s') is now revealed by the XX handshake.
-        let remote_static = Self::extract_remote_static(&handshake);
+        let started = Instant::now();
+        let result = initiator.handshake_initiator(None).await;
+        let elapsed = started.elapsed();
+        assert!(matches!(result, Err(TransportError::HandshakeIncomplete)), "got {:?}", result);
+        assert!(elapsed >= DEFAULT_HANDSHAKE_TIMEOUT, "gave up early, after {:?}", elapsed);
+        assert!(elapsed < DEFAULT_HANDSHAKE_TIMEOUT + std::time::Duration::from_secs(1), "overran, {:?}", elapsed);
+        quiet.abort();
+        flood.abort();
+    }
 
-        // Transition to transport mode
-        let transport = handshake
-            .into_transport_mode()
-            .map_err(|e| TransportError::Noise(format!("{:?}", e)))?;
-        
-        let mut state = self.state.lock().await;
-        *state = StreamState::Established(transport);
-        self.remote_static_key = remote_static;
-        
-        Ok(())
+    #[tokio::test]
+    async fn test_psk_handshake_with_matching_keys() {
+        psk_handshake([7u8; 32], [7u8; 32]).await.unwrap();
     }
 
-    /// Returns the remote peer's static public key.
-    ///
-    /// This is available only after a successful handshake (either as initiator or
-    /// responder).  Returns `None` if the handshake has not yet completed.
-    pub fn remote_static_key(&self) -> Option<[u8; 32]> {
-        self.remote_static_key
+    #[test]
+    fn test_undecryptable_handshake_message_is_ignored() {
+        let key = |byte| [byte; 32];
+        let mut initiator = build_handshake_state(&key(1), None, CipherSuite::default(), true).unwrap();
+        let mut responder = build_handshake_state(&key(2), None, CipherSuite::default(), false).unwrap();
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let len = initiator.write_message(&[], &mut buf).unwrap();
+        EncryptedStream::read_handshake_message(&mut responder, &buf[..len]).unwrap().unwrap();
+        let len = responder.write_message(b"payload", &mut buf).unwrap();
+
+        // A forged `<- e, ee, s, es` is skipped, and the genuine one still reads
+        let mut forged = buf[..len].to_vec();
+        *forged.last_mut().unwrap() ^= 1;
+        assert_eq!(EncryptedStream::read_handshake_message(&mut initiator, &forged).unwrap(), None);
+        let payload = EncryptedStream::read_handshake_message(&mut initiator, &buf[..len]).unwrap();
+        assert_eq!(payload.as_deref(), Some(&b"payload"[..]));
+
+        // Timing out after one is blamed on the peer's keys
+        assert!(matches!(
+            handshake_failure(TransportError::HandshakeIncomplete, true),
+            TransportError::PeerAuthenticationFailed
+        ));
+        assert!(matches!(
+            handshake_failure(TransportError::HandshakeIncomplete, false),
+            TransportError::HandshakeIncomplete
+        ));
     }
 
-    /// Send encrypted data
-    pub async fn send(&mut self, data: Bytes) -> Result<(), TransportError> {
-        let mut state = self.state.lock().await;
-        
-        match &mut *state {
-            StreamState::Established(transport) => {
-                let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
-                let len = transport
-                    .write_message(&data, &mut buf)
-                    .map_err(|e| TransportError::Noise(format!("{:?}", e)))?;
-                
-                self.socket.send_to(&buf[..len], self.remote_addr).await?;
-                Ok(())
-            }
-            StreamState::Handshaking(_) => Err(TransportError::HandshakeIncomplete),
+    #[test]
+    fn test_packet_cipher_opens_packets_in_any_order() {
+        let mut initiator = build_handshake_state(&[1; 32], None, CipherSuite::default(), true).unwrap();
+        let mut responder = build_handshake_state(&[2; 32], None, CipherSuite::default(), false).unwrap();
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        for turn in 0..3 {
+            let (from, to) = if turn % 2 == 0 { (&mut initiator, &mut responder) } else { (&mut responder, &mut initiator) };
+            let len = from.write_message(&[], &mut buf).unwrap();
+            to.read_message(&buf[..len], &mut vec![0u8; len]).unwrap();
         }
-    }
+        let cipher = |handshake: HandshakeState| PacketCipher {
+            transport: handshake.into_transport_mode().unwrap(),
+            handshake_answer: None,
+        };
+        let (mut sender, mut receiver) = (cipher(initiator), cipher(responder));
 
-    /// Receive encrypted data
-    pub async fn recv(&mut self) -> Result<Bytes, TransportError> {
-        let mut state = self.state.lock().await;
-        
-        match &mut *state {
-            StreamState::Established(transport) => {
-                let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
-                // Only accept packets from the expected remote_addr
-                let len = loop {
-                    let (len, addr) = self.socket.recv_from(&mut buf).await?;
-                    if addr == self.remote_addr {
-                        break len;
-                    }
-                    // Ignore packets from unexpected peers and wait for the correct one
-                };
-                
-                let mut plaintext = vec![0u8; MAX_MESSAGE_SIZE];
-                let plaintext_len = transport
-                    .read_message(&buf[..len], &mut plaintext)
-                    .map_err(|e| TransportError::Noise(format!("{:?}", e)))?;
-                
-                Ok(Bytes::copy_from_slice(&plaintext[..plaintext_len]))
-            }
-            StreamState::Handshaking(_) => Err(TransportError::HandshakeIncomplete),
+        let packets: Vec<Vec<u8>> = (0..3u8).map(|i| sender.seal(&[i]).unwrap()).collect();
+        assert!(packets.iter().all(|packet| packet.len() == 1 + PACKET_OVERHEAD));
+        for i in [2, 0, 1, 2] {
+            assert_eq!(receiver.open(&packets[i]).unwrap(), [i as u8]);
+        }
+
+        // Tampering with the nonce or the message fails authentication
+        for byte in [0, PACKET_NONCE_SIZE] {
+            let mut forged = packets[1].clone();
+            forged[byte] ^= 1;
+            assert!(matches!(receiver.open(&forged), Err(TransportError::InvalidMessage)));
         }
+        assert!(matches!(receiver.open(&packets[1][..PACKET_OVERHEAD - 1]), Err(TransportError::InvalidMessage)));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::sync::Arc;
-    use tokio::net::UdpSocket;
+    #[tokio::test]
+    async fn test_psk_handshake_with_mismatched_keys_fails() {
+        let result = psk_handshake([7u8; 32], [8u8; 32]).await;
+        assert!(
+            matches!(result, Err(TransportError::PeerAuthenticationFailed)),
+            "expected PeerAuthenticationFailed, got {:?}",
+            result
+        );
+        let error = crate::connection::ConnectionError::from(result.unwrap_err());
+        assert_eq!(error.failure(), Some(crate::connection::ConnectionFailure::PeerAuthFailed));
+    }
+
+    /// Run a handshake between streams using the given cipher suites,
+    /// returning the initiator's result.
+    async fn cipher_suite_handshake(initiator_suite: CipherSuite, responder_suite: CipherSuite) -> Result<(), TransportError> {
+        let s1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let s2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let a1 = s1.local_addr().unwrap();
+        let a2 = s2.local_addr().unwrap();
+
+        let timeout = Duration::from_secs(2);
+        let initiator = EncryptedStream::new(s1, a2).await.unwrap().with_cipher_suite(initiator_suite).unwrap();
+        let mut initiator = initiator.with_config(timeout_config(timeout)).unwrap();
+        let responder = EncryptedStream::new(s2, a1).await.unwrap().with_cipher_suite(responder_suite).unwrap();
+        let mut responder = responder.with_config(timeout_config(timeout)).unwrap();
+        assert_eq!(initiator.cipher_suite(), initiator_suite);
+
+        let responder = tokio::spawn(async move {
+            let result = tokio::time::timeout(std::time::Duration::from_secs(3), responder.handshake_responder(None)).await;
+            (responder, result)
+        });
+        let result = tokio::time::timeout(std::time::Duration::from_secs(5), initiator.handshake_initiator(None))
+            .await
+            .expect("initiator timed out");
+        let (mut responder, responded) = responder.await.expect("task panicked");
+        result?;
+
+        // Data flows both ways under the agreed suite
+        responded.expect("responder timed out").expect("responder failed");
+        initiator.send(Bytes::from_static(b"hello")).await.unwrap();
+        assert_eq!(&responder.recv().await.unwrap()[..], b"hello");
+        responder.send(Bytes::from_static(b"world")).await.unwrap();
+        assert_eq!(&initiator.recv().await.unwrap()[..], b"world");
+        Ok(())
+    }
 
     #[tokio::test]
-    async fn test_encrypted_stream_creation() {
-        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
-        let remote_addr = "127.0.0.1:8080".parse().unwrap();
-        
-        let stream = EncryptedStream::new(socket, remote_addr).await;
-        assert!(stream.is_ok());
+    async fn test_aes_gcm_handshake_succeeds() {
+        cipher_suite_handshake(CipherSuite::AesGcm, CipherSuite::AesGcm).await.unwrap();
     }
 
     #[tokio::test]
-    async fn test_noise_handshake_state_creation() {
-        let result = EncryptedStream::generate_keypair_and_initiator();
-        assert!(result.is_ok());
+    async fn test_mismatched_cipher_suites_fail() {
+        let result = cipher_suite_handshake(CipherSuite::ChaChaPoly, CipherSuite::AesGcm).await;
+        assert!(
+            matches!(result, Err(TransportError::PeerAuthenticationFailed)),
+            "expected PeerAuthenticationFailed, got {:?}",
+            result
+        );
     }
 
     #[tokio::test]
@@ -406,7 +2580,7 @@ mod tests {
         });
         
         let responder_handshake = tokio::spawn(async move {
-            responder.handshake_responder().await
+            responder.handshake_responder(None).await
         });
         
         // Both handshakes should complete successfully
@@ -453,7 +2627,7 @@ mod tests {
             (initiator, r)
         });
         let h2 = tokio::spawn(async move {
-            let r = responder.handshake_responder().await;
+            let r = responder.handshake_responder(None).await;
             (responder, r)
         });
 
@@ -499,7 +2673,7 @@ mod tests {
         });
         let h2 = tokio::spawn(async move {
             let mut responder = responder;
-            responder.handshake_responder().await
+            responder.handshake_responder(None).await
         });
 
         let result = tokio::time::timeout(
@@ -538,7 +2712,7 @@ mod tests {
             // the initiator aborts.  We don't assert on its result here.
             let _ = tokio::time::timeout(
                 std::time::Duration::from_secs(3),
-                responder.handshake_responder(),
+                responder.handshake_responder(None),
             )
             .await;
         });
@@ -560,6 +2734,48 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_responder_rejects_unexpected_initiator_key() {
+        let s1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let s2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let a1 = s1.local_addr().unwrap();
+        let a2 = s2.local_addr().unwrap();
+
+        // A responder expecting someone else refuses the initiator
+        let mut initiator = EncryptedStream::new(s1.clone(), a2).await.unwrap();
+        let mut responder = EncryptedStream::new(s2.clone(), a1).await.unwrap();
+        let (_, resp_result) = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            async { tokio::join!(initiator.handshake_initiator(None), responder.handshake_responder(Some([0xdeu8; 32]))) },
+        )
+        .await
+        .expect("handshake timed out");
+        assert!(
+            matches!(resp_result, Err(TransportError::PeerAuthenticationFailed)),
+            "expected PeerAuthenticationFailed, got {:?}",
+            resp_result
+        );
+        assert!(responder.remote_static_key().is_none(), "rejected key should not be stored");
+        assert!(matches!(
+            responder.send(Bytes::from_static(b"unwanted")).await,
+            Err(TransportError::HandshakeIncomplete)
+        ));
+
+        // Expecting the initiator's actual key, it completes
+        let mut initiator = EncryptedStream::new(s1, a2).await.unwrap();
+        let mut responder = EncryptedStream::new(s2, a1).await.unwrap();
+        let initiator_key = initiator.local_static_pubkey();
+        let (init_result, resp_result) = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            async { tokio::join!(initiator.handshake_initiator(None), responder.handshake_responder(Some(initiator_key))) },
+        )
+        .await
+        .expect("handshake timed out");
+        init_result.expect("initiator handshake failed");
+        resp_result.expect("responder should accept the expected key");
+        assert_eq!(responder.remote_static_key(), Some(initiator_key));
+    }
+
     #[tokio::test]
     async fn test_remote_static_key_not_set_before_handshake() {
         let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
@@ -571,6 +2787,63 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_handshake_hash_matches_on_both_ends() {
+        let s1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let s2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let a1 = s1.local_addr().unwrap();
+        let a2 = s2.local_addr().unwrap();
+
+        let mut initiator = EncryptedStream::new(s1.clone(), a2).await.unwrap();
+        let mut responder = EncryptedStream::new(s2.clone(), a1).await.unwrap();
+        assert!(initiator.handshake_hash().is_none());
+        assert!(responder.handshake_hash().is_none());
+
+        let (r1, r2) = tokio::join!(initiator.handshake_initiator(None), responder.handshake_responder(None));
+        r1.unwrap();
+        r2.unwrap();
+        let hash = initiator.handshake_hash().expect("hash after handshake");
+        assert_eq!(responder.handshake_hash(), Some(hash));
+
+        // Another session between the same keys has its own hash
+        let mut again = EncryptedStream::new(s1, a2).await.unwrap();
+        let mut responder = EncryptedStream::new(s2, a1).await.unwrap();
+        let (r1, r2) = tokio::join!(again.handshake_initiator(None), responder.handshake_responder(None));
+        r1.unwrap();
+        r2.unwrap();
+        assert_ne!(again.handshake_hash(), Some(hash));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_payloads_reach_the_peer() {
+        let s1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let s2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let a1 = s1.local_addr().unwrap();
+        let a2 = s2.local_addr().unwrap();
+
+        let mut initiator = EncryptedStream::new(s1.clone(), a2)
+            .await
+            .unwrap()
+            .with_handshake_payload(b"from the initiator")
+            .unwrap();
+        let mut responder = EncryptedStream::new(s2, a1)
+            .await
+            .unwrap()
+            .with_handshake_payload(b"from the responder")
+            .unwrap();
+        let (r1, r2) = tokio::join!(initiator.handshake_initiator(None), responder.handshake_responder(None));
+        r1.unwrap();
+        r2.unwrap();
+        assert_eq!(initiator.remote_handshake_payload(), b"from the responder");
+        assert_eq!(responder.remote_handshake_payload(), b"from the initiator");
+
+        let oversized = EncryptedStream::new(s1, a2)
+            .await
+            .unwrap()
+            .with_handshake_payload(&[0; MAX_HANDSHAKE_PAYLOAD_SIZE + 1]);
+        assert!(matches!(oversized, Err(TransportError::InvalidConfig(_))));
+    }
+
     #[tokio::test]
     async fn test_local_static_pubkey_consistent_across_roles() {
         // The same EncryptedStream's local_static_pubkey should be the key
@@ -591,7 +2864,7 @@ mod tests {
             (initiator, r)
         });
         let h2 = tokio::spawn(async move {
-            let r = responder.handshake_responder().await;
+            let r = responder.handshake_responder(None).await;
             (responder, r)
         });
 
@@ -0,0 +1,94 @@
+//! Integration test: Steady-state allocations
+//!
+//! This test counts heap allocations made while an established encrypted
+//! stream sends and receives, and verifies that:
+//! 1. After warming up, send/recv of small messages allocates nothing
+//! 2. The same holds for messages spanning several Noise frames
+
+use bytes::Bytes;
+use hyperswarm::transport::{EncryptedStream, DEFAULT_MAX_MESSAGE_SIZE};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+
+/// The system allocator, counting allocations made on threads that ask for it.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.with(Cell::get) {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if COUNTING.with(Cell::get) {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Allocations made on this thread while `f` runs.
+async fn count_allocations<F: std::future::Future>(f: F) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    COUNTING.with(|counting| counting.set(true));
+    f.await;
+    COUNTING.with(|counting| counting.set(false));
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+async fn handshaked_pair() -> (EncryptedStream, EncryptedStream) {
+    let socket1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.expect("Failed to bind socket1"));
+    let socket2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.expect("Failed to bind socket2"));
+    let addr1 = socket1.local_addr().expect("Failed to get addr1");
+    let addr2 = socket2.local_addr().expect("Failed to get addr2");
+
+    let mut stream1 = EncryptedStream::new(socket1, addr2).await.expect("Failed to create stream1");
+    let mut stream2 = EncryptedStream::new(socket2, addr1).await.expect("Failed to create stream2");
+    let (result1, result2) = tokio::join!(stream1.handshake_initiator(None), stream2.handshake_responder(None));
+    result1.expect("Handshake 1 failed");
+    result2.expect("Handshake 2 failed");
+    (stream1, stream2)
+}
+
+/// Send `message` back and forth `rounds` times, dropping what is received.
+async fn ping_pong(stream1: &mut EncryptedStream, stream2: &mut EncryptedStream, message: &Bytes, rounds: usize) {
+    for _ in 0..rounds {
+        stream1.send(message.clone()).await.expect("Failed to send");
+        assert_eq!(stream2.recv().await.expect("Failed to receive").len(), message.len());
+        stream2.send(message.clone()).await.expect("Failed to reply");
+        assert_eq!(stream1.recv().await.expect("Failed to receive reply").len(), message.len());
+    }
+}
+
+#[tokio::test(flavor = "current_thread")]
+async fn test_steady_state_send_recv_does_not_allocate() {
+    let (mut stream1, mut stream2) = handshaked_pair().await;
+
+    // Small messages, and the largest allowed, which spans two Noise frames
+    for (size, rounds) in [(64, 64), (DEFAULT_MAX_MESSAGE_SIZE, 8)] {
+        let message = Bytes::from(vec![7u8; size]);
+        // The first rounds size the reused buffers, and the runtime's own
+        ping_pong(&mut stream1, &mut stream2, &message, rounds).await;
+
+        let allocations = count_allocations(ping_pong(&mut stream1, &mut stream2, &message, rounds)).await;
+        assert_eq!(allocations, 0, "{} byte messages allocated", size);
+    }
+}
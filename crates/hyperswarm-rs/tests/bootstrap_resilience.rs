@@ -7,7 +7,7 @@
 
 mod common;
 
-use hyperswarm::dht::{DhtClient, DhtConfig};
+use hyperswarm::dht::{DhtClient, DhtConfig, DhtError};
 use hyperswarm::Topic;
 use std::time::Duration;
 
@@ -21,6 +21,7 @@ async fn test_bootstrap_with_unreachable_nodes() {
             "203.0.113.1:6881".to_string(),   // TEST-NET-3 (unreachable)
         ],
         bind_port: 0,
+        ..Default::default()
     };
     
     let client = DhtClient::new(config).await.expect("Failed to create client");
@@ -47,6 +48,7 @@ async fn test_announce_without_bootstrap() {
     let config = DhtConfig {
         bootstrap: vec!["192.0.2.1:6881".to_string()], // Unreachable node (TEST-NET-1)
         bind_port: 0,
+        ..Default::default()
     };
     
     let client = DhtClient::new(config).await.expect("Failed to create client");
@@ -74,6 +76,7 @@ async fn test_lookup_without_bootstrap() {
     let config = DhtConfig {
         bootstrap: vec!["192.0.2.1:6881".to_string()], // Unreachable node (TEST-NET-1)
         bind_port: 0,
+        ..Default::default()
     };
     
     let client = DhtClient::new(config).await.expect("Failed to create client");
@@ -105,6 +108,7 @@ async fn test_mixed_bootstrap_nodes() {
     let bootstrap_config = DhtConfig {
         bootstrap: vec![],
         bind_port: 0,
+        ..Default::default()
     };
     let bootstrap_node = DhtClient::new(bootstrap_config).await.expect("Failed to create bootstrap node");
     let bootstrap_addr = bootstrap_node.local_addr().expect("Failed to get bootstrap address");
@@ -116,6 +120,7 @@ async fn test_mixed_bootstrap_nodes() {
             "192.0.2.1:6881".to_string(),        // Unreachable
         ],
         bind_port: 0,
+        ..Default::default()
     };
     
     let client = DhtClient::new(config).await.expect("Failed to create client");
@@ -140,6 +145,7 @@ async fn test_concurrent_operations_during_bootstrap() {
             "192.0.2.1:6881".to_string(),  // Unreachable
         ],
         bind_port: 0,
+        ..Default::default()
     };
     
     let client = std::sync::Arc::new(DhtClient::new(config).await.expect("Failed to create client"));
@@ -184,6 +190,7 @@ async fn test_client_shutdown_after_failed_bootstrap() {
     let config = DhtConfig {
         bootstrap: vec!["192.0.2.1:6881".to_string()],
         bind_port: 0,
+        ..Default::default()
     };
     
     let client = DhtClient::new(config).await.expect("Failed to create client");
@@ -200,3 +207,41 @@ async fn test_client_shutdown_after_failed_bootstrap() {
     
     println!("✓ Client shutdown after failed bootstrap test passed");
 }
+
+#[tokio::test]
+async fn test_bootstrap_entry_without_port_is_rejected() {
+    let config = DhtConfig {
+        bootstrap: vec!["127.0.0.1:6881".to_string(), "node1.hyperdht.org".to_string()],
+        bind_port: 0,
+        ..Default::default()
+    };
+    
+    match DhtClient::new(config).await {
+        Err(DhtError::InvalidBootstrap(entries)) => assert_eq!(entries, "node1.hyperdht.org"),
+        Err(e) => panic!("Expected InvalidBootstrap, got {}", e),
+        Ok(_) => panic!("Expected InvalidBootstrap, client was created"),
+    }
+}
+
+#[tokio::test]
+async fn test_bootstrap_ipv6_literal_needs_brackets() {
+    let config = DhtConfig {
+        bootstrap: vec!["::1:6881".to_string(), "[::1:6881".to_string()],
+        bind_port: 0,
+        ..Default::default()
+    };
+    
+    match DhtClient::new(config).await {
+        Err(DhtError::InvalidBootstrap(entries)) => assert_eq!(entries, "::1:6881, [::1:6881"),
+        Err(e) => panic!("Expected InvalidBootstrap, got {}", e),
+        Ok(_) => panic!("Expected InvalidBootstrap, client was created"),
+    }
+    
+    // Bracketed IPv6 and names that may not resolve are fine until bootstrap
+    let config = DhtConfig {
+        bootstrap: vec!["[::1]:6881".to_string(), "no-such-host.invalid:6881".to_string()],
+        bind_port: 0,
+        ..Default::default()
+    };
+    assert!(DhtClient::new(config).await.is_ok());
+}
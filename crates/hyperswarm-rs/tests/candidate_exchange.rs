@@ -0,0 +1,88 @@
+//! Integration test: candidate exchange through the DHT
+//!
+//! Two swarms share a bootstrap node:
+//! 1. The server joins, announcing the key its holepunch candidates are
+//!    published under, and publishes them
+//! 2. The client looks up the topic and fetches them with that key
+//! 3. The client joins, fetches them and connects, with no candidates
+//!    handed over by the test
+
+mod common;
+
+use std::time::Duration;
+
+use hyperswarm::discovery::candidates;
+use hyperswarm::holepunch::CandidateKind;
+use hyperswarm::{Hyperswarm, JoinOpts, SwarmConfig, Topic};
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_swarms_exchange_candidates_and_connect() {
+    let bootstrap = common::create_test_dht_client().await.expect("Failed to create bootstrap node");
+    let bootstrap_port = bootstrap.local_addr().expect("Failed to get bootstrap address").port();
+    let config = SwarmConfig::builder()
+        .bootstrap(vec![format!("127.0.0.1:{}", bootstrap_port)])
+        .max_peers(8)
+        .build();
+    let topic = Topic::from_key(b"candidate-exchange");
+
+    let server = Hyperswarm::new(config.clone()).await.expect("Failed to create server");
+    let mut inbound = server.connections().expect("Events already taken");
+    let server_opts = JoinOpts {
+        server: true,
+        client: false,
+    };
+    server.join(topic, server_opts).await.expect("Server join failed");
+
+    // The announcement is recorded under the loopback address the DHT saw
+    let announced = format!("127.0.0.1:{}", server.local_addr().expect("No server address").port())
+        .parse()
+        .unwrap();
+    let client = Hyperswarm::new(config).await.expect("Failed to create client");
+    let peers = client.dht().lookup(topic).await.expect("Lookup failed");
+    let peer = peers.iter().find(|peer| peer.addr == announced).expect("Server not announced");
+    let key: [u8; 32] = peer
+        .value
+        .as_deref()
+        .and_then(|value| value.try_into().ok())
+        .expect("Server announced no candidates key");
+    let published = candidates::fetch(client.dht(), &key).await.expect("Fetching candidates failed");
+    assert!(
+        published.iter().any(|c| c.addr == announced && c.kind == CandidateKind::Wan),
+        "Announced address missing from {:?}",
+        published
+    );
+    assert!(
+        published.iter().all(|c| c.addr.port() == announced.port()),
+        "Candidates should all be on the swarm socket: {:?}",
+        published
+    );
+
+    let mut outbound = client.connections().expect("Events already taken");
+    let client_opts = JoinOpts {
+        server: false,
+        client: true,
+    };
+    client.join(topic, client_opts).await.expect("Client join failed");
+
+    let connected = tokio::time::timeout(Duration::from_secs(5), outbound.recv())
+        .await
+        .expect("No outbound connection")
+        .expect("Event channel closed");
+    assert!(connected.initiator);
+    assert_eq!(connected.topic, Some(topic));
+    assert!(
+        published.iter().any(|c| c.addr == connected.remote_addr),
+        "Connected to {} which is not a published candidate",
+        connected.remote_addr
+    );
+
+    let accepted = tokio::time::timeout(Duration::from_secs(5), inbound.recv())
+        .await
+        .expect("No inbound connection")
+        .expect("Event channel closed");
+    assert!(!accepted.initiator);
+    assert_eq!(
+        accepted.stream.remote_static_key(),
+        Some(connected.stream.local_static_pubkey())
+    );
+}
@@ -8,6 +8,9 @@ pub async fn create_test_dht_client() -> Result<hyperswarm::dht::DhtClient, Box<
     let config = DhtConfig {
         bootstrap: vec![], // No external bootstrap for local tests
         bind_port: 0, // OS-assigned port
+        // Every node of a test queries from 127.0.0.1, so one IP speaks for them all
+        max_queries_per_ip: DhtConfig::default().max_queries_total,
+        ..Default::default()
     };
     
     Ok(hyperswarm::dht::DhtClient::new(config).await?)
@@ -26,3 +29,98 @@ pub async fn create_test_socket() -> Result<(std::sync::Arc<tokio::net::UdpSocke
 pub async fn wait_for_setup() {
     tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
 }
+
+/// Spawn a scripted KRPC responder on localhost.
+///
+/// Every query received is passed to `respond`; when it returns a response,
+/// that response is sent back with the query's transaction id.
+#[allow(dead_code)]
+pub async fn spawn_mock_krpc_node<F>(respond: F) -> std::net::SocketAddr
+where
+    F: Fn(&hyperswarm::protocol::KrpcMessage) -> Option<hyperswarm::protocol::KrpcResponse>
+        + Send
+        + 'static,
+{
+    spawn_mock_krpc_node_echoing(None, respond).await
+}
+
+/// Like [`spawn_mock_krpc_node`], but every response claims the querier is
+/// at `ip` (the BEP 42 `ip` key).
+#[allow(dead_code)]
+pub async fn spawn_mock_krpc_node_echoing<F>(ip: Option<std::net::SocketAddr>, respond: F) -> std::net::SocketAddr
+where
+    F: Fn(&hyperswarm::protocol::KrpcMessage) -> Option<hyperswarm::protocol::KrpcResponse>
+        + Send
+        + 'static,
+{
+    use hyperswarm::protocol::{decode_krpc, encode_krpc, KrpcMessage, KrpcMessageType};
+
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.expect("bind mock node");
+    let addr = socket.local_addr().expect("mock node address");
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 2048];
+        loop {
+            let Ok((len, from)) = socket.recv_from(&mut buf).await else { break };
+            let Ok(query) = decode_krpc(&buf[..len]) else { continue };
+            if let Some(r) = respond(&query) {
+                let reply = KrpcMessage {
+                    t: query.t.clone(),
+                    y: KrpcMessageType::Response,
+                    q: None,
+                    a: None,
+                    r: Some(r),
+                    e: None,
+                    ip: ip.map(compact_peer),
+                };
+                let _ = socket.send_to(&encode_krpc(&reply).unwrap(), from).await;
+            }
+        }
+    });
+    addr
+}
+
+/// Encode a node as BEP 5 compact node info (20-byte id + IPv4 + port).
+#[allow(dead_code)]
+pub fn compact_node(node_id: [u8; 20], addr: std::net::SocketAddr) -> Vec<u8> {
+    let mut out = node_id.to_vec();
+    match addr.ip() {
+        std::net::IpAddr::V4(ip) => out.extend_from_slice(&ip.octets()),
+        std::net::IpAddr::V6(_) => panic!("compact node info is IPv4 only"),
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+    out
+}
+
+/// Encode a peer address as BEP 5 compact peer info.
+#[allow(dead_code)]
+pub fn compact_peer(addr: std::net::SocketAddr) -> Vec<u8> {
+    let mut out = match addr.ip() {
+        std::net::IpAddr::V4(ip) => ip.octets().to_vec(),
+        std::net::IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    out.extend_from_slice(&addr.port().to_be_bytes());
+    out
+}
+
+/// Two localhost `EncryptedStream`s that have completed the Noise handshake.
+///
+/// The first stream was the initiator, the second the responder.
+#[allow(dead_code)]
+pub async fn handshaked_stream_pair() -> (hyperswarm::transport::EncryptedStream, hyperswarm::transport::EncryptedStream) {
+    use hyperswarm::transport::EncryptedStream;
+    
+    let (socket1, addr1) = create_test_socket().await.expect("Failed to bind socket1");
+    let (socket2, addr2) = create_test_socket().await.expect("Failed to bind socket2");
+    let mut initiator = EncryptedStream::new(socket1, addr2).await.expect("Failed to create stream1");
+    let mut responder = EncryptedStream::new(socket2, addr1).await.expect("Failed to create stream2");
+    
+    let (init_result, resp_result) = tokio::time::timeout(std::time::Duration::from_secs(3), async {
+        tokio::join!(initiator.handshake_initiator(None), responder.handshake_responder(None))
+    })
+    .await
+    .expect("Handshake timed out");
+    init_result.expect("Initiator handshake failed");
+    resp_result.expect("Responder handshake failed");
+    
+    (initiator, responder)
+}
@@ -7,6 +7,8 @@
 mod common;
 
 use hyperswarm::dht::{DhtClient, DhtConfig};
+use hyperswarm::packet::MemoryNetwork;
+use hyperswarm::protocol::{decode_krpc, KrpcMessage, KrpcMessageType};
 use hyperswarm::Topic;
 use std::time::Duration;
 
@@ -16,10 +18,12 @@ async fn test_two_node_localhost_discovery() {
     let config1 = DhtConfig {
         bootstrap: vec![], // Will use mainline DHT defaults, but we'll manually add nodes
         bind_port: 0,
+        ..Default::default()
     };
     let config2 = DhtConfig {
         bootstrap: vec![],
         bind_port: 0,
+        ..Default::default()
     };
     
     let client1 = DhtClient::new(config1).await.expect("Failed to create client1");
@@ -78,6 +82,7 @@ async fn test_announce_and_lookup_same_client() {
     let config = DhtConfig {
         bootstrap: vec!["192.0.2.1:6881".to_string()], // Unreachable TEST-NET-1
         bind_port: 0,
+        ..Default::default()
     };
     
     let client = DhtClient::new(config).await.expect("Failed to create client");
@@ -103,3 +108,508 @@ async fn test_announce_and_lookup_same_client() {
     
     println!("✓ Announce and lookup on same client test passed");
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_iterative_lookup_converges_over_multiple_hops() {
+    use hyperswarm::dht::LookupOptions;
+    use hyperswarm::protocol::KrpcResponse;
+
+    let topic = Topic::from_key(b"multi-hop-lookup");
+    let mut target = [0u8; 20];
+    target.copy_from_slice(&topic.0[..20]);
+
+    // Node ids that get progressively closer to the topic: far -> mid -> near
+    let with_prefix = |shared: usize| {
+        let mut id = target;
+        id[shared] ^= 0xFF;
+        id
+    };
+    let (far_id, mid_id, near_id) = (with_prefix(0), with_prefix(4), with_prefix(12));
+    let announced_peer: std::net::SocketAddr = "10.1.2.3:4567".parse().unwrap();
+
+    // The nearest node is the only one storing the peer
+    let near_addr = common::spawn_mock_krpc_node(move |_| {
+        Some(KrpcResponse {
+            id: Some(near_id.to_vec()),
+            values: Some(vec![common::compact_peer(announced_peer)]),
+            token: Some(b"tok".to_vec()),
+            ..Default::default()
+        })
+    })
+    .await;
+    // Each other node only knows the next node closer to the topic
+    let mid_addr = common::spawn_mock_krpc_node(move |_| {
+        Some(KrpcResponse {
+            id: Some(mid_id.to_vec()),
+            nodes: Some(common::compact_node(near_id, near_addr)),
+            ..Default::default()
+        })
+    })
+    .await;
+    let far_addr = common::spawn_mock_krpc_node(move |_| {
+        Some(KrpcResponse {
+            id: Some(far_id.to_vec()),
+            nodes: Some(common::compact_node(mid_id, mid_addr)),
+            ..Default::default()
+        })
+    })
+    .await;
+
+    let client = common::create_test_dht_client().await.expect("Failed to create client");
+    client.add_node_to_routing_table(far_id, far_addr).await;
+
+    let peers = tokio::time::timeout(
+        Duration::from_secs(3),
+        client.lookup_with(topic, LookupOptions::default()),
+    )
+    .await
+    .expect("Lookup should not timeout")
+    .expect("Lookup should succeed");
+
+    assert_eq!(peers.len(), 1, "Peer stored two hops away should be found");
+    assert_eq!(peers[0].addr, announced_peer);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_lookup_stream_yields_the_lookup_peers() {
+    use futures::StreamExt;
+    use hyperswarm::protocol::KrpcResponse;
+
+    let topic = Topic::from_key(b"streamed-lookup");
+    // Two nodes know overlapping peers; one also points at the other
+    let shared: std::net::SocketAddr = "10.0.0.1:1000".parse().unwrap();
+    let second_addr = common::spawn_mock_krpc_node(move |_| {
+        Some(KrpcResponse {
+            id: Some(vec![2u8; 20]),
+            values: Some(vec![common::compact_peer(shared), common::compact_peer("10.0.0.3:3000".parse().unwrap())]),
+            ..Default::default()
+        })
+    })
+    .await;
+    let first_addr = common::spawn_mock_krpc_node(move |_| {
+        Some(KrpcResponse {
+            id: Some(vec![1u8; 20]),
+            values: Some(vec![common::compact_peer(shared), common::compact_peer("10.0.0.2:2000".parse().unwrap())]),
+            nodes: Some(common::compact_node([2u8; 20], second_addr)),
+            ..Default::default()
+        })
+    })
+    .await;
+
+    let client = common::create_test_dht_client().await.expect("Failed to create client");
+    client.add_node_to_routing_table([1u8; 20], first_addr).await;
+
+    let streamed: Vec<_> = tokio::time::timeout(Duration::from_secs(3), client.lookup_stream(topic).collect::<Vec<_>>())
+        .await
+        .expect("Stream should end");
+    let batch = client.lookup(topic).await.expect("Lookup should succeed");
+
+    let addrs = |peers: &[hyperswarm::dht::PeerAddress]| peers.iter().map(|p| p.addr).collect::<Vec<_>>();
+    assert_eq!(streamed.len(), 3, "Each peer should be yielded once: {:?}", addrs(&streamed));
+    assert_eq!(addrs(&streamed), addrs(&batch));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_iterative_lookup_stops_at_max_peers() {
+    use hyperswarm::dht::LookupOptions;
+    use hyperswarm::protocol::KrpcResponse;
+
+    let topic = Topic::from_key(b"max-peers-lookup");
+    let peers_stored: Vec<Vec<u8>> = (1..=5u8)
+        .map(|i| common::compact_peer(format!("10.0.0.{}:1000", i).parse().unwrap()))
+        .collect();
+
+    let node_addr = common::spawn_mock_krpc_node(move |_| {
+        Some(KrpcResponse {
+            id: Some(vec![9u8; 20]),
+            values: Some(peers_stored.clone()),
+            ..Default::default()
+        })
+    })
+    .await;
+
+    let client = common::create_test_dht_client().await.expect("Failed to create client");
+    client.add_node_to_routing_table([9u8; 20], node_addr).await;
+
+    let opts = LookupOptions {
+        max_peers: Some(2),
+        ..Default::default()
+    };
+    let peers = client.lookup_with(topic, opts).await.expect("Lookup should succeed");
+    assert_eq!(peers.len(), 2);
+}
+
+#[tokio::test]
+async fn test_announce_is_served_to_third_node() {
+    // A announces to B; C asks B and learns A's address. The nodes talk over
+    // an in-memory network, so nothing depends on real sockets.
+    let network = MemoryNetwork::new();
+    let node = |addr: &str| {
+        let transport = network.bind(addr.parse().unwrap()).expect("Failed to bind");
+        DhtClient::with_transport(DhtConfig::default(), transport).expect("Failed to create node")
+    };
+    let node_a = node("10.0.0.1:6881");
+    let node_b = node("10.0.0.2:6881");
+    let node_c = node("10.0.0.3:6881");
+    let addr_b = node_b.local_addr().expect("Failed to get B address");
+
+    node_a.add_node_to_routing_table(node_b.node_id(), addr_b).await;
+    node_c.add_node_to_routing_table(node_b.node_id(), addr_b).await;
+
+    let topic = Topic::from_key(b"served-announce");
+    let announced_port = 41234;
+    tokio::time::timeout(Duration::from_secs(2), node_a.announce(topic, announced_port))
+        .await
+        .expect("Announce should not timeout")
+        .expect("Announce should succeed");
+
+    let peers = tokio::time::timeout(Duration::from_secs(2), node_c.lookup(topic))
+        .await
+        .expect("Lookup should not timeout")
+        .expect("Lookup should succeed");
+
+    let expected: std::net::SocketAddr = format!("10.0.0.1:{}", announced_port).parse().unwrap();
+    assert!(
+        peers.iter().any(|p| p.addr == expected),
+        "C should learn A's announced address from B, got {:?}",
+        peers
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_dual_stack_announce_and_lookup_over_ipv6() {
+    let node_a = common::create_test_dht_client().await.expect("Failed to create A");
+    let node_b = common::create_test_dht_client().await.expect("Failed to create B");
+    let node_c = common::create_test_dht_client().await.expect("Failed to create C");
+    let Some(b_v6) = node_b.local_addr_v6() else {
+        println!("IPv6 unavailable, skipping dual-stack test");
+        return;
+    };
+    // B listens on both families; talk to it over IPv6 loopback only
+    let addr_b: std::net::SocketAddr = format!("[::1]:{}", b_v6.port()).parse().unwrap();
+
+    node_a.add_node_to_routing_table(node_b.node_id(), addr_b).await;
+    node_c.add_node_to_routing_table(node_b.node_id(), addr_b).await;
+
+    let topic = Topic::from_key(b"dual-stack-announce");
+    let announced_port = 41235;
+    tokio::time::timeout(Duration::from_secs(2), node_a.announce(topic, announced_port))
+        .await
+        .expect("Announce should not timeout")
+        .expect("Announce should succeed");
+
+    let peers = tokio::time::timeout(Duration::from_secs(2), node_c.lookup(topic))
+        .await
+        .expect("Lookup should not timeout")
+        .expect("Lookup should succeed");
+
+    let expected: std::net::SocketAddr = format!("[::1]:{}", announced_port).parse().unwrap();
+    assert!(
+        peers.iter().any(|p| p.addr == expected),
+        "C should learn A's IPv6 address from B, got {:?}",
+        peers
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_lookup_dedupes_peers_from_several_nodes() {
+    use hyperswarm::dht::LookupOptions;
+    use hyperswarm::protocol::KrpcResponse;
+
+    let topic = Topic::from_key(b"dedupe-lookup");
+    let peer = |i: u8| -> std::net::SocketAddr { format!("10.0.0.{}:1000", i).parse().unwrap() };
+
+    // Two nodes storing overlapping peer sets: {1, 2, 3} and {2, 3, 4}
+    let spawn_node = |id: [u8; 20], peers: Vec<std::net::SocketAddr>| {
+        let values: Vec<Vec<u8>> = peers.into_iter().map(common::compact_peer).collect();
+        common::spawn_mock_krpc_node(move |_| {
+            Some(KrpcResponse {
+                id: Some(id.to_vec()),
+                values: Some(values.clone()),
+                ..Default::default()
+            })
+        })
+    };
+    let addr_a = spawn_node([1u8; 20], vec![peer(1), peer(2), peer(3)]).await;
+    let addr_b = spawn_node([2u8; 20], vec![peer(2), peer(3), peer(4)]).await;
+
+    let client = common::create_test_dht_client().await.expect("Failed to create client");
+    client.add_node_to_routing_table([1u8; 20], addr_a).await;
+    client.add_node_to_routing_table([2u8; 20], addr_b).await;
+
+    let peers = client.lookup(topic).await.expect("Lookup should succeed");
+    let mut addrs: Vec<_> = peers.iter().map(|p| p.addr).collect();
+    addrs.sort();
+    assert_eq!(addrs, vec![peer(1), peer(2), peer(3), peer(4)]);
+
+    // The cap counts distinct peers
+    let opts = LookupOptions {
+        max_peers: Some(3),
+        ..Default::default()
+    };
+    let peers = client.lookup_with(topic, opts).await.expect("Lookup should succeed");
+    let distinct: std::collections::HashSet<_> = peers.iter().map(|p| p.addr).collect();
+    assert_eq!(peers.len(), 3);
+    assert_eq!(distinct.len(), 3);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_announce_tolerates_black_hole_nodes() {
+    use hyperswarm::protocol::KrpcResponse;
+
+    // Two nodes that hand out tokens and accept announces
+    let mut responsive = Vec::new();
+    for i in 1..=2u8 {
+        let addr = common::spawn_mock_krpc_node(move |_| {
+            Some(KrpcResponse {
+                id: Some(vec![i; 20]),
+                token: Some(b"tok".to_vec()),
+                ..Default::default()
+            })
+        })
+        .await;
+        responsive.push(([i; 20], addr));
+    }
+    // Three nodes that never answer
+    let mut black_holes = Vec::new();
+    for i in 3..=5u8 {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        black_holes.push(([i; 20], socket.local_addr().unwrap(), socket));
+    }
+
+    let query_timeout = Duration::from_millis(300);
+    let client = DhtClient::new(DhtConfig {
+        query_timeout,
+        ..Default::default()
+    })
+    .await
+    .expect("Failed to create client");
+    for (id, addr) in &responsive {
+        client.add_node_to_routing_table(*id, *addr).await;
+    }
+    for (id, addr, _) in &black_holes {
+        client.add_node_to_routing_table(*id, *addr).await;
+    }
+
+    let started = std::time::Instant::now();
+    let announced = client
+        .announce(Topic::from_key(b"mixed-announce"), 4000)
+        .await
+        .expect("Announce should succeed when some nodes accept");
+    let elapsed = started.elapsed();
+
+    let mut announced_sorted = announced.clone();
+    announced_sorted.sort();
+    let mut expected: Vec<_> = responsive.iter().map(|(_, addr)| *addr).collect();
+    expected.sort();
+    assert_eq!(announced_sorted, expected, "Only the responsive nodes should acknowledge");
+    // Sequentially the black holes alone would cost three query timeouts
+    assert!(elapsed < 3 * query_timeout, "announce should not wait on each dead node in turn, took {:?}", elapsed);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_announce_fails_when_no_node_accepts() {
+    let black_hole = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = DhtClient::new(DhtConfig {
+        query_timeout: Duration::from_millis(100),
+        ..Default::default()
+    })
+    .await
+    .expect("Failed to create client");
+    client.add_node_to_routing_table([1u8; 20], black_hole.local_addr().unwrap()).await;
+
+    let result = client.announce(Topic::from_key(b"nobody-home"), 4000).await;
+    assert!(matches!(result, Err(hyperswarm::dht::DhtError::Timeout)), "got {:?}", result);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_lookup_keeps_peer_public_keys() {
+    use hyperswarm::protocol::KrpcResponse;
+
+    let keyed: std::net::SocketAddr = "10.0.0.1:1000".parse().unwrap();
+    let plain: std::net::SocketAddr = "10.0.0.2:1000".parse().unwrap();
+    let key = [0x5Au8; 32];
+    let mut keyed_value = key.to_vec();
+    keyed_value.extend(common::compact_peer(keyed));
+
+    // The keyed peer is also listed in plain `values`, as a mixed node would
+    let node_addr = common::spawn_mock_krpc_node(move |_| {
+        Some(KrpcResponse {
+            id: Some(vec![7u8; 20]),
+            values: Some(vec![common::compact_peer(keyed), common::compact_peer(plain)]),
+            peers: Some(vec![keyed_value.clone()]),
+            ..Default::default()
+        })
+    })
+    .await;
+
+    let client = common::create_test_dht_client().await.expect("Failed to create client");
+    client.add_node_to_routing_table([7u8; 20], node_addr).await;
+
+    let peers = client.lookup(Topic::from_key(b"keyed-peers")).await.expect("Lookup should succeed");
+    assert_eq!(peers.len(), 2);
+    let find = |addr| peers.iter().find(|p| p.addr == addr).expect("peer should be returned");
+    assert_eq!(find(keyed).node_id, Some(key));
+    assert_eq!(find(plain).node_id, None);
+}
+
+/// A client that bootstraps from `nodes`, pinging each of them.
+async fn client_bootstrapping_from(nodes: &[std::net::SocketAddr]) -> DhtClient {
+    let config = DhtConfig {
+        bootstrap: nodes.iter().map(|n| n.to_string()).collect(),
+        bind_port: 0,
+        ..Default::default()
+    };
+    DhtClient::new(config).await.expect("Failed to create client")
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_observed_address_from_responses() {
+    use hyperswarm::protocol::KrpcResponse;
+
+    let external: std::net::SocketAddr = "203.0.113.5:4000".parse().unwrap();
+    let mut responders = Vec::new();
+    for i in 1..=2u8 {
+        responders.push(
+            common::spawn_mock_krpc_node_echoing(Some(external), move |_| {
+                Some(KrpcResponse {
+                    id: Some(vec![i; 20]),
+                    ..Default::default()
+                })
+            })
+            .await,
+        );
+    }
+
+    let client = client_bootstrapping_from(&responders).await;
+    assert_eq!(client.observed_address(), None);
+    client.bootstrap().await.expect("Bootstrap should succeed");
+    assert_eq!(client.observed_address(), Some(external));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_nodes_echo_the_querier_address() {
+    let node_b = common::create_test_dht_client().await.expect("Failed to create B");
+    let port_b = node_b.local_addr().expect("Failed to get B address").port();
+    let node_a = client_bootstrapping_from(&[format!("127.0.0.1:{}", port_b).parse().unwrap()]).await;
+    node_a.bootstrap().await.expect("Bootstrap should succeed");
+
+    let port_a = node_a.local_addr().expect("Failed to get A address").port();
+    let expected: std::net::SocketAddr = format!("127.0.0.1:{}", port_a).parse().unwrap();
+    assert_eq!(node_a.observed_address(), Some(expected));
+}
+
+/// A transport that tracks how many of its queries are awaiting an answer.
+struct InFlightCounter {
+    inner: std::sync::Arc<hyperswarm::packet::MemoryTransport>,
+    in_flight: std::sync::atomic::AtomicUsize,
+    max_in_flight: std::sync::atomic::AtomicUsize,
+}
+
+impl InFlightCounter {
+    fn max_in_flight(&self) -> usize {
+        self.max_in_flight.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+impl hyperswarm::packet::PacketTransport for InFlightCounter {
+    fn poll_send_to(
+        &self,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+        target: std::net::SocketAddr,
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        use std::sync::atomic::Ordering;
+
+        let sent = self.inner.poll_send_to(cx, buf, target);
+        if let Ok(KrpcMessage { y: KrpcMessageType::Query, .. }) = decode_krpc(buf) {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+        }
+        sent
+    }
+
+    fn poll_recv_from(
+        &self,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<std::net::SocketAddr>> {
+        use std::sync::atomic::Ordering;
+
+        let received = self.inner.poll_recv_from(cx, buf);
+        if let std::task::Poll::Ready(Ok(_)) = &received {
+            if let Ok(KrpcMessage {
+                y: KrpcMessageType::Response | KrpcMessageType::Error,
+                ..
+            }) = decode_krpc(buf.filled())
+            {
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        received
+    }
+
+    fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+/// Look `topic` up from a fresh node that knows `nodes`, with `lookup_alpha`,
+/// returning the peers found and the most queries it had in flight at once.
+async fn lookup_counting_in_flight(
+    network: &MemoryNetwork,
+    nodes: &[&DhtClient],
+    topic: Topic,
+    lookup_alpha: usize,
+) -> (Vec<std::net::SocketAddr>, usize) {
+    let transport = std::sync::Arc::new(InFlightCounter {
+        inner: network.bind("10.0.1.1:0".parse().unwrap()).expect("Failed to bind"),
+        in_flight: Default::default(),
+        max_in_flight: Default::default(),
+    });
+    let config = DhtConfig {
+        lookup_alpha,
+        ..Default::default()
+    };
+    let client = DhtClient::with_transport(config, transport.clone()).expect("Failed to create client");
+    for node in nodes {
+        client
+            .add_node_to_routing_table(node.node_id(), node.local_addr().unwrap())
+            .await;
+    }
+
+    let peers = tokio::time::timeout(Duration::from_secs(2), client.lookup(topic))
+        .await
+        .expect("Lookup should not timeout")
+        .expect("Lookup should succeed");
+    (peers.into_iter().map(|p| p.addr).collect(), transport.max_in_flight())
+}
+
+#[tokio::test]
+async fn test_lower_lookup_alpha_still_completes_lookup() {
+    let network = MemoryNetwork::new();
+    let node = |addr: String| {
+        let transport = network.bind(addr.parse().unwrap()).expect("Failed to bind");
+        DhtClient::with_transport(DhtConfig::default(), transport).expect("Failed to create node")
+    };
+    let announcer = node("10.0.0.1:6881".to_string());
+    // In separate /24s, as the routing table takes few nodes from each
+    let nodes: Vec<DhtClient> = (2..=7).map(|i| node(format!("10.{}.0.1:6881", i))).collect();
+    for n in &nodes {
+        announcer.add_node_to_routing_table(n.node_id(), n.local_addr().unwrap()).await;
+    }
+    let topic = Topic::from_key(b"lookup-alpha");
+    announcer.announce(topic, 41234).await.expect("Announce should succeed");
+    let expected: std::net::SocketAddr = "10.0.0.1:41234".parse().unwrap();
+    let nodes: Vec<&DhtClient> = nodes.iter().collect();
+
+    // One query at a time still reaches the peer...
+    let (peers, max_in_flight) = lookup_counting_in_flight(&network, &nodes, topic, 1).await;
+    assert!(peers.contains(&expected), "Lookup with alpha 1 should find the peer, got {:?}", peers);
+    assert_eq!(max_in_flight, 1);
+
+    // ...where the default keeps three in flight
+    let (peers, max_in_flight) = lookup_counting_in_flight(&network, &nodes, topic, 3).await;
+    assert!(peers.contains(&expected), "Lookup with alpha 3 should find the peer, got {:?}", peers);
+    assert_eq!(max_in_flight, 3);
+}
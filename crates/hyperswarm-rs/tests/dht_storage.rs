@@ -0,0 +1,90 @@
+//! Integration test: BEP 44 storage through an in-process node
+//!
+//! Verifies that records put on one node can be fetched by another:
+//! 1. Immutable items round trip under the SHA-1 of their value
+//! 2. Mutable items round trip, and the storing node only accepts a newer
+//!    sequence number
+//! 3. Oversized values are rejected before anything is sent
+
+mod common;
+
+use ed25519_dalek::SigningKey;
+use hyperswarm::dht::{DhtClient, DhtError};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// A writer and a reader that both know only a storing node.
+async fn writer_and_reader() -> (DhtClient, DhtClient, DhtClient) {
+    let writer = common::create_test_dht_client().await.expect("Failed to create writer");
+    let store = common::create_test_dht_client().await.expect("Failed to create storing node");
+    let reader = common::create_test_dht_client().await.expect("Failed to create reader");
+    // The storing node is bound to the wildcard address; reach it over loopback
+    let port = store.local_addr().expect("Failed to get storing node address").port();
+    let store_addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+    writer.add_node_to_routing_table(store.node_id(), store_addr).await;
+    reader.add_node_to_routing_table(store.node_id(), store_addr).await;
+    (writer, store, reader)
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_immutable_item_round_trip() {
+    let (writer, _store, reader) = writer_and_reader().await;
+
+    let target = tokio::time::timeout(Duration::from_secs(2), writer.put_immutable(b"Hello World!"))
+        .await
+        .expect("Put should not timeout")
+        .expect("Put should succeed");
+
+    let value = tokio::time::timeout(Duration::from_secs(2), reader.get_immutable(target))
+        .await
+        .expect("Get should not timeout")
+        .expect("Get should succeed");
+    assert_eq!(value.as_deref(), Some(&b"Hello World!"[..]));
+
+    let missing = reader.get_immutable([0u8; 20]).await.expect("Get should succeed");
+    assert_eq!(missing, None);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_mutable_item_round_trip_and_sequence_numbers() {
+    let (writer, _store, reader) = writer_and_reader().await;
+    let key = SigningKey::from_bytes(&[42u8; 32]);
+    let public_key = key.verifying_key().to_bytes();
+    let salt = Some(&b"pluresdb-meta"[..]);
+
+    writer.put_mutable(&key, salt, 1, b"first", None).await.expect("Put seq 1 should succeed");
+    writer.put_mutable(&key, salt, 2, b"second", Some(1)).await.expect("Put seq 2 should succeed");
+
+    let item = reader
+        .get_mutable(&public_key, salt)
+        .await
+        .expect("Get should succeed")
+        .expect("Item should be found");
+    assert_eq!(item.seq, 2);
+    assert_eq!(item.value, b"second");
+    assert!(item.verify());
+
+    // Going back to an older sequence number is refused by the storing node
+    match writer.put_mutable(&key, salt, 1, b"stale", None).await {
+        Err(DhtError::KrpcError { code: 302, .. }) => {}
+        other => panic!("expected a sequence number error, got {:?}", other),
+    }
+    let item = reader.get_mutable(&public_key, salt).await.unwrap().unwrap();
+    assert_eq!(item.value, b"second");
+
+    // The same key without the salt is a different item
+    assert_eq!(reader.get_mutable(&public_key, None).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_oversized_value_is_rejected() {
+    let (writer, _store, _reader) = writer_and_reader().await;
+    let key = SigningKey::from_bytes(&[1u8; 32]);
+    let value = vec![0u8; 1000];
+
+    assert!(matches!(writer.put_immutable(&value).await, Err(DhtError::ValueTooLarge(_))));
+    assert!(matches!(
+        writer.put_mutable(&key, None, 1, &value, None).await,
+        Err(DhtError::ValueTooLarge(_))
+    ));
+}
@@ -4,6 +4,7 @@
 //! 1. Establish a Noise XX encrypted stream
 //! 2. Send encrypted payloads in both directions
 //! 3. Verify payload integrity
+//! 4. Close the stream, which the other side observes
 
 mod common;
 
@@ -40,7 +41,7 @@ async fn test_encrypted_stream_round_trip() {
     });
     
     let handshake2 = tokio::spawn(async move {
-        let result = stream2.handshake_responder().await;
+        let result = stream2.handshake_responder(None).await;
         (stream2, result)
     });
     
@@ -106,7 +107,7 @@ async fn test_encrypted_stream_multiple_messages() {
         (stream1, result)
     });
     let handshake2 = tokio::spawn(async move {
-        let result = stream2.handshake_responder().await;
+        let result = stream2.handshake_responder(None).await;
         (stream2, result)
     });
     
@@ -136,3 +137,221 @@ async fn test_encrypted_stream_multiple_messages() {
     
     println!("✓ Multiple encrypted messages test passed");
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_encrypted_stream_large_payload_round_trip() {
+    let (stream1, stream2) = common::handshaked_stream_pair().await;
+    // Above the default cap, so both ends raise it
+    let config = hyperswarm::transport::TransportConfig {
+        max_message_size: 1024 * 1024,
+        ..Default::default()
+    };
+    let mut stream1 = stream1.with_config(config.clone()).unwrap();
+    let mut stream2 = stream2.with_config(config).unwrap();
+    
+    // 1 MiB spans many Noise messages
+    let payload: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let expected = Bytes::from(payload);
+    
+    let sender = tokio::spawn({
+        let payload = expected.clone();
+        async move {
+            stream1.send(payload).await.expect("Failed to send 1 MiB");
+            stream1
+        }
+    });
+    let received = tokio::time::timeout(Duration::from_secs(5), stream2.recv())
+        .await
+        .expect("Receive timed out")
+        .expect("Failed to receive 1 MiB");
+    let mut stream1 = sender.await.expect("Sender panicked");
+    
+    assert_eq!(received.len(), expected.len());
+    assert_eq!(received, expected);
+    
+    // Messages before and after keep their boundaries: empty and tiny
+    stream1.send(Bytes::new()).await.expect("Failed to send empty message");
+    stream1.send(Bytes::from_static(b"x")).await.expect("Failed to send byte");
+    assert_eq!(stream2.recv().await.expect("Failed to receive"), Bytes::new());
+    assert_eq!(stream2.recv().await.expect("Failed to receive"), Bytes::from_static(b"x"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_encrypted_stream_tokio_io_copy() {
+    use tokio::io::AsyncWriteExt;
+    
+    let (mut writer, mut reader) = common::handshaked_stream_pair().await;
+    
+    // More than one write batch, streamed as bytes rather than messages
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 253) as u8).collect();
+    let expected = data.clone();
+    
+    let send = tokio::spawn(async move {
+        let mut source = &data[..];
+        let copied = tokio::io::copy(&mut source, &mut writer).await.expect("copy into stream failed");
+        // Signals end of stream to the reader
+        writer.shutdown().await.expect("shutdown failed");
+        copied
+    });
+    
+    let mut received = Vec::new();
+    let read = tokio::time::timeout(Duration::from_secs(5), tokio::io::copy(&mut reader, &mut received))
+        .await
+        .expect("copy out of stream timed out")
+        .expect("copy out of stream failed");
+    
+    assert_eq!(send.await.expect("sender panicked"), expected.len() as u64);
+    assert_eq!(read, expected.len() as u64);
+    assert_eq!(received, expected);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_encrypted_stream_with_persistent_keypair() {
+    use hyperswarm::transport::public_key_from_private;
+
+    // X25519 test vectors from RFC 7748, section 6.1
+    let alice_private = hex32("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+    let alice_public = hex32("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+    let bob_private = hex32("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+    let bob_public = hex32("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+    assert_eq!(public_key_from_private(&alice_private).unwrap(), alice_public);
+    assert_eq!(public_key_from_private(&bob_private).unwrap(), bob_public);
+
+    let socket1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.expect("Failed to bind socket1"));
+    let socket2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.expect("Failed to bind socket2"));
+    let addr1 = socket1.local_addr().unwrap();
+    let addr2 = socket2.local_addr().unwrap();
+
+    let mut alice = EncryptedStream::with_keypair(socket1, addr2, alice_private).expect("Failed to create alice");
+    let mut bob = EncryptedStream::with_keypair(socket2, addr1, bob_private).expect("Failed to create bob");
+    assert_eq!(alice.local_static_pubkey(), alice_public);
+    assert_eq!(bob.local_static_pubkey(), bob_public);
+
+    // Alice pins Bob's well-known key
+    let (result1, result2) = tokio::time::timeout(Duration::from_secs(3), async {
+        tokio::join!(alice.handshake_initiator(Some(bob_public)), bob.handshake_responder(None))
+    })
+    .await
+    .expect("Handshake timed out");
+    result1.expect("Initiator handshake failed");
+    result2.expect("Responder handshake failed");
+
+    assert_eq!(alice.remote_static_key(), Some(bob_public));
+    assert_eq!(bob.remote_static_key(), Some(alice_public));
+}
+
+fn hex32(s: &str) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    out
+}
+
+fn keepalive_config() -> hyperswarm::transport::TransportConfig {
+    hyperswarm::transport::TransportConfig {
+        keepalive_interval: Some(Duration::from_millis(50)),
+        idle_timeout: Some(Duration::from_millis(300)),
+        ..Default::default()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_keepalives_hold_idle_stream_open() {
+    let (a, b) = common::handshaked_stream_pair().await;
+    let mut a = a.with_config(keepalive_config()).unwrap();
+    let mut b = b.with_config(keepalive_config()).unwrap();
+
+    // Both sides sit idle for several idle timeouts, living on keepalives
+    let idle = tokio::time::timeout(Duration::from_secs(1), async {
+        tokio::select! {
+            r = a.recv() => r,
+            r = b.recv() => r,
+        }
+    })
+    .await;
+    assert!(idle.is_err(), "keepalives should be filtered out, not fail or surface: {:?}", idle);
+
+    a.send(Bytes::from_static(b"after idle")).await.expect("Send after idle period");
+    assert_eq!(b.recv().await.expect("Receive after idle period"), Bytes::from_static(b"after idle"));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_keepalives_go_out_while_the_application_does_not_read() {
+    use hyperswarm::transport::TransportError;
+
+    let (a, b) = common::handshaked_stream_pair().await;
+    // `a` is never polled again; only its keepalive task runs
+    let a = a.with_config(keepalive_config()).unwrap();
+    let mut b = b.with_config(keepalive_config()).unwrap();
+
+    let idle = tokio::time::timeout(Duration::from_secs(1), b.recv()).await;
+    assert!(idle.is_err(), "keepalives should hold the stream open: {:?}", idle);
+
+    // Once `a` is gone, `b` notices
+    drop(a);
+    let result = tokio::time::timeout(Duration::from_secs(2), b.recv())
+        .await
+        .expect("recv should give up after the idle timeout");
+    assert!(matches!(result, Err(TransportError::IdleTimeout)), "got {:?}", result);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_torn_down_peer_triggers_idle_timeout() {
+    use hyperswarm::transport::TransportError;
+
+    let (a, b) = common::handshaked_stream_pair().await;
+    let mut a = a.with_config(keepalive_config()).unwrap();
+    drop(b);
+
+    let result = tokio::time::timeout(Duration::from_secs(2), a.recv())
+        .await
+        .expect("recv should give up after the idle timeout");
+    assert!(matches!(result, Err(TransportError::IdleTimeout)), "got {:?}", result);
+    assert!(matches!(a.send(Bytes::from_static(b"x")).await, Err(TransportError::IdleTimeout)));
+}
+
+#[tokio::test]
+async fn test_recv_timeout_on_silent_peer() {
+    use hyperswarm::transport::TransportError;
+
+    let (mut a, mut b) = common::handshaked_stream_pair().await;
+
+    let started = std::time::Instant::now();
+    let result = a.recv_timeout(Duration::from_millis(200)).await;
+    let elapsed = started.elapsed();
+    assert!(matches!(result, Err(TransportError::Timeout)), "got {:?}", result);
+    assert!(elapsed >= Duration::from_millis(200), "gave up early after {:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(1), "gave up late after {:?}", elapsed);
+
+    // The stream is still usable after a timeout
+    b.send_timeout(Bytes::from_static(b"late"), Duration::from_secs(1))
+        .await
+        .expect("send_timeout");
+    assert_eq!(
+        a.recv_timeout(Duration::from_secs(1)).await.expect("recv_timeout"),
+        Bytes::from_static(b"late")
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_close_is_reported_to_the_peer() {
+    use hyperswarm::transport::TransportError;
+
+    let (mut closer, mut peer) = common::handshaked_stream_pair().await;
+
+    closer.send(Bytes::from_static(b"last words")).await.expect("Failed to send");
+    closer.close().await.expect("Failed to close");
+    closer.close().await.expect("Closing twice should be harmless");
+
+    // The peer gets what was sent before the close, then the close itself
+    assert_eq!(peer.recv().await.expect("Failed to receive"), Bytes::from_static(b"last words"));
+    let closed = tokio::time::timeout(Duration::from_secs(2), peer.recv())
+        .await
+        .expect("recv should report the close rather than hang");
+    assert!(matches!(closed, Err(TransportError::Closed)), "got {:?}", closed);
+
+    // The closed side neither sends nor receives any more
+    assert!(matches!(closer.send(Bytes::from_static(b"more")).await, Err(TransportError::Closed)));
+    assert!(matches!(closer.recv().await, Err(TransportError::Closed)));
+}
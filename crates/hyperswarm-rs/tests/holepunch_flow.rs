@@ -68,8 +68,8 @@ async fn test_holepunch_initiate_and_respond() {
     let initiate_result = results.0.expect("Initiate task failed");
     let respond_result = results.1.expect("Respond task failed");
     
-    let established_addr1 = initiate_result.expect("Initiate failed");
-    let established_addr2 = respond_result.expect("Respond failed");
+    let established_addr1 = initiate_result.expect("Initiate failed").addr;
+    let established_addr2 = respond_result.expect("Respond failed").addr;
     
     println!("Session 1 established connection to: {}", established_addr1);
     println!("Session 2 established connection from: {}", established_addr2);
@@ -171,10 +171,12 @@ async fn test_holepunch_with_multiple_candidates() {
     let respond_result = results.1.expect("Task failed");
     
     // Should successfully establish connection using the correct candidate
-    let established_addr = initiate_result.expect("Initiate failed");
+    let established = initiate_result.expect("Initiate failed");
     let _ = respond_result.expect("Respond failed");
     
-    assert_eq!(established_addr, addr2, "Should connect to correct candidate");
+    assert_eq!(established.addr, addr2, "Should connect to correct candidate");
+    assert_eq!(established.kind, CandidateKind::Lan, "Should report the winning candidate's kind");
+    assert!(established.rtt < Duration::from_secs(1), "Loopback RTT should be small: {:?}", established.rtt);
     
     println!("✓ Holepunch with multiple candidates test passed");
 }
@@ -247,3 +249,244 @@ async fn test_holepunch_mismatched_keys_fail() {
 
     println!("✓ Holepunch with mismatched keys correctly fails");
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_dead_candidate_does_not_delay_live_one() {
+    let mut session1 = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_KEY)
+        .await
+        .expect("Failed to create session1");
+    let mut session2 = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_KEY)
+        .await
+        .expect("Failed to create session2");
+    let addr1 = session1.local_addr().expect("Failed to get addr1");
+    let addr2 = session2.local_addr().expect("Failed to get addr2");
+
+    // A port nothing listens on, listed before the live candidate
+    let dead_addr = {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap()
+    };
+    let candidates_for_2 = vec![
+        Candidate { addr: dead_addr, kind: CandidateKind::Wan },
+        Candidate { addr: addr2, kind: CandidateKind::Lan },
+    ];
+    let candidates_for_1 = vec![Candidate { addr: addr1, kind: CandidateKind::Lan }];
+
+    let respond_task = tokio::spawn(async move { session2.respond(candidates_for_1).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let started = std::time::Instant::now();
+    let established = tokio::time::timeout(Duration::from_secs(3), session1.initiate(candidates_for_2))
+        .await
+        .expect("Initiate timed out")
+        .expect("Initiate failed");
+    let elapsed = started.elapsed();
+    respond_task.abort();
+
+    assert_eq!(established.addr, addr2);
+    assert_eq!(established.kind, CandidateKind::Lan);
+    assert!(
+        elapsed < Duration::from_millis(1000),
+        "live candidate should win well before the 2 s punch deadline, took {:?}",
+        elapsed
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_initiate_reports_progress_events() {
+    use hyperswarm::holepunch::HolepunchEvent;
+
+    let mut session1 = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_KEY)
+        .await
+        .expect("Failed to create session1");
+    let mut session2 = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_KEY)
+        .await
+        .expect("Failed to create session2");
+    let addr1 = session1.local_addr().expect("Failed to get addr1");
+    let addr2 = session2.local_addr().expect("Failed to get addr2");
+    let dead_addr = {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap()
+    };
+    let candidates_for_2 = vec![
+        Candidate { addr: dead_addr, kind: CandidateKind::Wan },
+        Candidate { addr: addr2, kind: CandidateKind::Lan },
+    ];
+    let candidates_for_1 = vec![Candidate { addr: addr1, kind: CandidateKind::Lan }];
+
+    let respond_task = tokio::spawn(async move { session2.respond(candidates_for_1).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let mut events = Vec::new();
+    let established = tokio::time::timeout(
+        Duration::from_secs(3),
+        session1.initiate_with_events(candidates_for_2, |event| events.push(event)),
+    )
+    .await
+    .expect("Initiate timed out")
+    .expect("Initiate failed");
+    respond_task.abort();
+    assert_eq!(established.addr, addr2);
+
+    // Both candidates are probed and punched; the live one wins and ends it
+    for addr in [dead_addr, addr2] {
+        assert!(events.contains(&HolepunchEvent::Probing(addr)), "{:?}", events);
+    }
+    let punching = events
+        .iter()
+        .position(|event| *event == HolepunchEvent::Punching(addr2))
+        .expect("no Punching for the winner");
+    let established_at = events
+        .iter()
+        .position(|event| *event == HolepunchEvent::Established(addr2))
+        .expect("no Established for the winner");
+    assert!(punching < established_at, "{:?}", events);
+    assert_eq!(established_at, events.len() - 1, "Established should be last: {:?}", events);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_port_prediction_finds_shifted_port() {
+    use hyperswarm::holepunch::PortPrediction;
+
+    // The responder's NAT mapped it 3 ports above the one it reported
+    let mut responder = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_KEY)
+        .await
+        .expect("Failed to create responder");
+    let actual_addr = responder.local_addr().expect("Failed to get responder address");
+    let reported_addr: std::net::SocketAddr = format!("127.0.0.1:{}", actual_addr.port() - 3).parse().unwrap();
+
+    let mut initiator = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_KEY)
+        .await
+        .expect("Failed to create initiator")
+        .with_port_prediction(PortPrediction { window: 4, stride: 1 });
+    let initiator_addr = initiator.local_addr().expect("Failed to get initiator address");
+
+    let respond_task = tokio::spawn(async move {
+        responder.respond(vec![Candidate { addr: initiator_addr, kind: CandidateKind::Wan }]).await
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    let established = tokio::time::timeout(
+        Duration::from_secs(3),
+        initiator.initiate(vec![Candidate { addr: reported_addr, kind: CandidateKind::Wan }]),
+    )
+    .await
+    .expect("Initiate timed out")
+    .expect("Prediction window should cover the shifted port");
+    respond_task.abort();
+
+    assert_eq!(established.addr, actual_addr);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_relay_fallback_when_direct_punch_fails() {
+    use hyperswarm::holepunch::relay::RelayServer;
+
+    let relay = RelayServer::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .expect("Failed to bind relay");
+    let relay_addr = relay.local_addr().expect("Failed to get relay address");
+    let relay_task = tokio::spawn(relay.run());
+
+    let mut session1 = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_KEY)
+        .await
+        .expect("Failed to create session1")
+        .with_relay(relay_addr);
+    let mut session2 = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_KEY)
+        .await
+        .expect("Failed to create session2")
+        .with_relay(relay_addr);
+
+    // Neither peer can reach the other directly
+    let dead_addr = {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap()
+    };
+    let dead = vec![Candidate { addr: dead_addr, kind: CandidateKind::Wan }];
+
+    let respond_task = tokio::spawn({
+        let dead = dead.clone();
+        async move { session2.respond(dead).await }
+    });
+    let initiated = tokio::time::timeout(Duration::from_secs(5), session1.initiate(dead))
+        .await
+        .expect("Initiate timed out")
+        .expect("Initiate should succeed through the relay");
+    let responded = tokio::time::timeout(Duration::from_secs(1), respond_task)
+        .await
+        .expect("Respond timed out")
+        .unwrap()
+        .expect("Respond should succeed through the relay");
+    relay_task.abort();
+
+    assert_eq!(initiated.addr, relay_addr);
+    assert_eq!(initiated.kind, CandidateKind::Relay);
+    assert_eq!(responded.addr, relay_addr);
+    assert_eq!(responded.kind, CandidateKind::Relay);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_cancelled_attempts_leave_sessions_reusable() {
+    let mut session1 = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_KEY)
+        .await
+        .expect("Failed to create session1");
+    let mut session2 = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_KEY)
+        .await
+        .expect("Failed to create session2");
+    let addr1 = session1.local_addr().expect("Failed to get addr1");
+    let addr2 = session2.local_addr().expect("Failed to get addr2");
+    let lan = |addr| vec![Candidate { addr, kind: CandidateKind::Lan }];
+
+    // Punch a peer that never answers, and give up partway through
+    let (_silent, silent_addr) = common::create_test_socket().await.expect("Failed to bind silent peer");
+    tokio::select! {
+        result = session1.initiate(lan(silent_addr)) => panic!("silent peer answered: {:?}", result),
+        _ = tokio::time::sleep(Duration::from_millis(300)) => {}
+    }
+    // Likewise wait for a punch that never comes
+    tokio::select! {
+        result = session2.respond(lan(silent_addr)) => panic!("nobody punched: {:?}", result),
+        _ = tokio::time::sleep(Duration::from_millis(300)) => {}
+    }
+
+    // Fresh attempts on the same sessions succeed
+    let (initiated, responded) = tokio::time::timeout(
+        Duration::from_secs(3),
+        async { tokio::join!(session1.initiate(lan(addr2)), session2.respond(lan(addr1))) },
+    )
+    .await
+    .expect("Holepunch timed out");
+    assert_eq!(initiated.expect("Initiate failed").addr, addr2);
+    assert_eq!(responded.expect("Respond failed").addr, addr1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_simultaneous_initiate_settles_roles_by_tiebreak() {
+    let mut session1 = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_KEY)
+        .await
+        .expect("Failed to create session1")
+        .with_tiebreak([2; 32]);
+    let mut session2 = HolepunchSession::new("127.0.0.1:0".parse().unwrap(), TEST_KEY)
+        .await
+        .expect("Failed to create session2")
+        .with_tiebreak([1; 32]);
+    let addr1 = session1.local_addr().expect("Failed to get addr1");
+    let addr2 = session2.local_addr().expect("Failed to get addr2");
+    let lan = |addr| vec![Candidate { addr, kind: CandidateKind::Lan }];
+
+    // Both sides initiate towards each other at once
+    let (punched1, punched2) = tokio::time::timeout(
+        Duration::from_secs(3),
+        async { tokio::join!(session1.initiate(lan(addr2)), session2.initiate(lan(addr1))) },
+    )
+    .await
+    .expect("Holepunch timed out");
+    let punched1 = punched1.expect("Session 1 failed");
+    let punched2 = punched2.expect("Session 2 failed");
+
+    // The lower tiebreak stays initiator, the other answers it
+    assert!(!punched1.initiator);
+    assert!(punched2.initiator);
+    assert_eq!(punched1.addr, addr2);
+    assert_eq!(punched2.addr, addr1);
+}
@@ -0,0 +1,127 @@
+//! Integration test: server-only and client-only joins
+//!
+//! A swarm joins a topic through a scripted DHT node that records every
+//! query it receives:
+//! 1. A client-only join looks the topic up but never sends `announce_peer`
+//! 2. A server-only join announces but never looks the topic up
+//! 3. An explicit `announce_port` is the port announced and accepted on
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyperswarm::protocol::{KrpcQueryKind, KrpcResponse};
+use tokio::net::UdpSocket;
+use hyperswarm::{Hyperswarm, JoinOpts, SwarmConfig, Topic};
+
+/// Join `topic` with `opts` through a mock node and return the kinds of the
+/// queries the node received.
+async fn queries_seen_on_join(opts: JoinOpts) -> Vec<KrpcQueryKind> {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let recorder = seen.clone();
+    let node = common::spawn_mock_krpc_node(move |query| {
+        recorder.lock().unwrap().extend(query.q.clone());
+        Some(KrpcResponse {
+            id: Some(vec![7u8; 20]),
+            token: Some(b"tok".to_vec()),
+            ..Default::default()
+        })
+    })
+    .await;
+
+    let config = SwarmConfig::builder().bootstrap([node.to_string()]).max_peers(8).build();
+    let swarm = Hyperswarm::new(config).await.expect("Failed to create swarm");
+    swarm
+        .join(Topic::from_key(b"join-modes"), opts)
+        .await
+        .expect("Join failed");
+
+    // Give the background lookup time to run
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let seen = seen.lock().unwrap().clone();
+    seen
+}
+
+fn count(seen: &[KrpcQueryKind], kind: KrpcQueryKind) -> usize {
+    seen.iter().filter(|q| **q == kind).count()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_client_only_join_looks_up_without_announcing() {
+    let seen = queries_seen_on_join(JoinOpts {
+        server: false,
+        client: true,
+    })
+    .await;
+
+    assert!(count(&seen, KrpcQueryKind::GetPeers) >= 1, "Expected a lookup, saw {:?}", seen);
+    assert_eq!(count(&seen, KrpcQueryKind::AnnouncePeer), 0, "Client-only join announced: {:?}", seen);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_server_only_join_announces_without_looking_up() {
+    let seen = queries_seen_on_join(JoinOpts {
+        server: true,
+        client: false,
+    })
+    .await;
+
+    assert_eq!(count(&seen, KrpcQueryKind::AnnouncePeer), 1, "Expected one announce, saw {:?}", seen);
+    // The only get_peers is the one fetching the announce token
+    assert_eq!(count(&seen, KrpcQueryKind::GetPeers), 1, "Server-only join looked up: {:?}", seen);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_default_join_announces_and_looks_up() {
+    let seen = queries_seen_on_join(JoinOpts::default()).await;
+
+    assert_eq!(count(&seen, KrpcQueryKind::AnnouncePeer), 1, "Expected one announce, saw {:?}", seen);
+    assert_eq!(count(&seen, KrpcQueryKind::GetPeers), 2, "Expected token fetch and lookup: {:?}", seen);
+}
+
+#[tokio::test]
+async fn test_join_needs_a_mode() {
+    let config = SwarmConfig::builder().bootstrap(Vec::<String>::new()).build();
+    let swarm = Hyperswarm::new(config).await.expect("Failed to create swarm");
+    let opts = JoinOpts {
+        server: false,
+        client: false,
+    };
+    assert!(swarm.join(Topic::from_key(b"no-mode"), opts).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_announce_port_is_announced_and_accepted_on() {
+    let announced = Arc::new(Mutex::new(Vec::new()));
+    let recorder = announced.clone();
+    let node = common::spawn_mock_krpc_node(move |query| {
+        if query.q == Some(KrpcQueryKind::AnnouncePeer) {
+            let args = query.a.clone().unwrap_or_default();
+            recorder.lock().unwrap().push((args.port, args.implied_port));
+        }
+        Some(KrpcResponse {
+            id: Some(vec![7u8; 20]),
+            token: Some(b"tok".to_vec()),
+            ..Default::default()
+        })
+    })
+    .await;
+
+    // A port that is free now
+    let port = UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+    let config = SwarmConfig::builder()
+        .bootstrap([node.to_string()])
+        .bind_addr(std::net::Ipv4Addr::LOCALHOST.into())
+        .announce_port(port)
+        .build();
+    let swarm = Hyperswarm::new(config).await.expect("Failed to create swarm");
+    assert_eq!(swarm.local_addr().expect("Failed to get swarm address").port(), port);
+
+    let opts = JoinOpts {
+        server: true,
+        client: false,
+    };
+    swarm.join(Topic::from_key(b"announce-port"), opts).await.expect("Join failed");
+    assert_eq!(announced.lock().unwrap().clone(), vec![(Some(port), None)]);
+}
@@ -0,0 +1,63 @@
+//! Integration test: Lookups across an in-process DHT
+//!
+//! This test starts a ten node `LocalSwarm` and verifies that:
+//! 1. Each node joins knowing only its parent and children in the tree
+//! 2. An announce from a leaf is stored on the only node it knows
+//! 3. A lookup from another branch walks the tree to that node and finds
+//!    the peer
+
+use hyperswarm::metrics::SwarmMetrics;
+use hyperswarm::protocol::KrpcQueryKind;
+use hyperswarm::testing::LocalSwarm;
+use hyperswarm::Topic;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Records the nodes `get_peers` queries went to.
+#[derive(Default)]
+struct QueriedNodes(Mutex<HashSet<SocketAddr>>);
+
+impl SwarmMetrics for QueriedNodes {
+    fn on_query_sent(&self, kind: &KrpcQueryKind, to: SocketAddr) {
+        if matches!(kind, KrpcQueryKind::GetPeers) {
+            self.0.lock().unwrap().insert(to);
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_peer_is_found_across_a_ten_node_dht() {
+    let swarm = LocalSwarm::new(10).await.expect("Failed to start the swarm");
+    assert_eq!(swarm.node(0).routing_table_len().await, 2, "Node 0 knows nodes 1 and 2");
+    assert_eq!(swarm.node(4).routing_table_len().await, 2, "Node 4 knows nodes 1 and 9");
+    assert_eq!(swarm.node(6).routing_table_len().await, 1, "Node 6 knows node 2");
+
+    // Node 9 only knows node 4, which stores the announce
+    let topic = Topic::from_key(b"local-swarm");
+    let announced = swarm.node(9).announce(topic, 40000).await.expect("Announce failed");
+    assert_eq!(announced, vec![LocalSwarm::node_addr(4)]);
+
+    // Node 6 has to go 6 -> 2 -> 0 -> 1 -> 4 to find it
+    let queried = Arc::new(QueriedNodes::default());
+    let searcher = swarm.node(6);
+    searcher.set_metrics(queried.clone());
+    let peers = tokio::time::timeout(Duration::from_secs(5), searcher.lookup(topic))
+        .await
+        .expect("Lookup timed out")
+        .expect("Lookup failed");
+
+    let expected = SocketAddr::new(LocalSwarm::node_addr(9).ip(), 40000);
+    assert!(
+        peers.iter().any(|p| p.addr == expected),
+        "Expected {} among {:?}",
+        expected,
+        peers
+    );
+    let queried = queried.0.lock().unwrap().clone();
+    for hop in [2, 0, 1, 4] {
+        assert!(queried.contains(&LocalSwarm::node_addr(hop)), "Node {} not queried: {:?}", hop, queried);
+    }
+    swarm.shutdown().await;
+}
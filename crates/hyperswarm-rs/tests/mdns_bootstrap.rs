@@ -0,0 +1,53 @@
+//! Integration test: Bootstrapping from nodes on the local network
+//!
+//! This test answers mDNS queries from a responder on the loopback
+//! multicast group and verifies that:
+//! 1. A client whose only bootstrap source is mDNS finds the advertised node
+//! 2. The node answers the client's ping and joins its routing table
+//! 3. The client does not bootstrap from its own advertisement
+
+use hyperswarm::dht::mdns::MdnsResponder;
+use hyperswarm::dht::{BootstrapSource, DhtClient, DhtConfig};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+/// Not the real service name, so that nodes on the host running the test
+/// stay out of it.
+const SERVICE: &str = "_hyperswarm-test._udp.local";
+
+fn loopback_config() -> DhtConfig {
+    DhtConfig {
+        bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        ipv6: false,
+        bootstrap_dns_timeout: Duration::from_millis(500),
+        bootstrap_ping_timeout: Duration::from_millis(500),
+        ..Default::default()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_mdns_source_discovers_a_lan_node() {
+    // A node advertised by a responder of its own
+    let node = DhtClient::new(loopback_config()).await.expect("Failed to create node");
+    let node_addr = node.local_addr().unwrap();
+    let _responder = MdnsResponder::bind(SERVICE, "test-node", node_addr.port()).expect("Failed to bind responder");
+
+    let client = DhtClient::new(DhtConfig {
+        bootstrap_sources: vec![BootstrapSource::Mdns { service_name: SERVICE.to_string() }],
+        ..loopback_config()
+    })
+    .await
+    .expect("Failed to create client");
+    tokio::time::timeout(Duration::from_secs(5), client.bootstrap())
+        .await
+        .expect("Bootstrap timed out")
+        .expect("Bootstrap failed");
+
+    let known = client.known_nodes().await;
+    let expected = SocketAddr::from((Ipv4Addr::LOCALHOST, node_addr.port()));
+    assert!(known.contains(&(node.node_id(), expected)), "node not discovered: {:?}", known);
+    assert!(
+        known.iter().all(|(id, _)| *id != client.node_id()),
+        "client bootstrapped from itself"
+    );
+}
@@ -0,0 +1,111 @@
+//! Integration test: metrics hooks
+//!
+//! A counting `SwarmMetrics` installed on a swarm sees the queries, peers,
+//! punches and handshakes of a localhost announce + lookup.
+
+mod common;
+
+use hyperswarm::dht::{DhtClient, DhtConfig, PeerAddress};
+use hyperswarm::metrics::SwarmMetrics;
+use hyperswarm::packet::MemoryNetwork;
+use hyperswarm::protocol::KrpcQueryKind;
+use hyperswarm::{Hyperswarm, JoinOpts, SwarmConfig, Topic};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Default)]
+struct CountingMetrics {
+    queries_sent: AtomicUsize,
+    query_timeouts: AtomicUsize,
+    peers_discovered: AtomicUsize,
+    punches_succeeded: AtomicUsize,
+    punches_failed: AtomicUsize,
+    handshakes_completed: AtomicUsize,
+    handshakes_failed: AtomicUsize,
+}
+
+impl SwarmMetrics for CountingMetrics {
+    fn on_query_sent(&self, _kind: &KrpcQueryKind, _to: SocketAddr) {
+        self.queries_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_query_timeout(&self, _kind: &KrpcQueryKind, _to: SocketAddr) {
+        self.query_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_peer_discovered(&self, _topic: &Topic, _peer: &PeerAddress) {
+        self.peers_discovered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_punch_result(&self, _peer: SocketAddr, success: bool) {
+        let counter = if success { &self.punches_succeeded } else { &self.punches_failed };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_handshake_complete(&self, _peer: SocketAddr) {
+        self.handshakes_completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_handshake_failed(&self, _peer: SocketAddr) {
+        self.handshakes_failed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_metrics_see_announce_lookup_and_connect() {
+    let bootstrap = common::create_test_dht_client().await.expect("Failed to create bootstrap node");
+    let bootstrap_port = bootstrap.local_addr().expect("Failed to get bootstrap address").port();
+    let config = SwarmConfig::builder()
+        .bootstrap(vec![format!("127.0.0.1:{}", bootstrap_port)])
+        .max_peers(8)
+        .build();
+    let topic = Topic::from_key(b"metrics");
+
+    let announced = Hyperswarm::new(config.clone()).await.expect("Failed to create swarm1");
+    let _inbound = announced.connections().expect("Events already taken");
+    announced.join(topic, JoinOpts::default()).await.expect("Join failed");
+
+    let joining = Hyperswarm::new(config).await.expect("Failed to create swarm2");
+    let metrics = Arc::new(CountingMetrics::default());
+    joining.set_metrics(metrics.clone());
+    let mut events = joining.connections().expect("Events already taken");
+    joining.join(topic, JoinOpts::default()).await.expect("Join failed");
+
+    tokio::time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .expect("No connection event")
+        .expect("Event channel closed");
+
+    assert!(metrics.queries_sent.load(Ordering::Relaxed) > 0);
+    assert_eq!(metrics.query_timeouts.load(Ordering::Relaxed), 0);
+    assert!(metrics.peers_discovered.load(Ordering::Relaxed) >= 1);
+    assert_eq!(metrics.punches_succeeded.load(Ordering::Relaxed), 1);
+    assert_eq!(metrics.punches_failed.load(Ordering::Relaxed), 0);
+    assert_eq!(metrics.handshakes_completed.load(Ordering::Relaxed), 1);
+    assert_eq!(metrics.handshakes_failed.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn test_metrics_count_query_timeouts() {
+    let network = MemoryNetwork::new();
+    let transport = network.bind("10.0.0.1:6881".parse().unwrap()).expect("Failed to bind");
+    let client = DhtClient::with_transport(
+        DhtConfig {
+            // Nobody listens there, so bootstrapping times out
+            bootstrap: vec!["10.0.0.2:6881".to_string()],
+            query_timeout: Duration::from_millis(50),
+            bootstrap_ping_timeout: Duration::from_secs(1),
+            ..Default::default()
+        },
+        transport,
+    )
+    .expect("Failed to create client");
+    let metrics = Arc::new(CountingMetrics::default());
+    client.set_metrics(metrics.clone());
+
+    client.bootstrap().await.expect("Bootstrap should tolerate dead nodes");
+    assert_eq!(metrics.queries_sent.load(Ordering::Relaxed), 1);
+    assert_eq!(metrics.query_timeouts.load(Ordering::Relaxed), 1);
+}
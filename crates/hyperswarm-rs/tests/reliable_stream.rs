@@ -0,0 +1,118 @@
+//! Integration test: Reliable stream over a lossy path
+//!
+//! This test runs two ReliableStreams over an in-memory network that drops
+//! every third packet, and verifies that:
+//! 1. The Noise handshake completes despite the loss
+//! 2. Every message arrives, in order, including ones spanning many segments
+//! 3. A close reaches the peer
+
+use bytes::Bytes;
+use hyperswarm::packet::{MemoryNetwork, MemoryTransport, PacketTransport};
+use hyperswarm::reliable::{ReliableConfig, ReliableStream};
+use hyperswarm::transport::TransportError;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::ReadBuf;
+
+/// A transport that silently drops every third packet it sends.
+struct LossyTransport {
+    inner: Arc<MemoryTransport>,
+    sent: AtomicUsize,
+}
+
+impl LossyTransport {
+    fn dropped(&self) -> usize {
+        self.sent.load(Ordering::SeqCst) / 3
+    }
+}
+
+impl PacketTransport for LossyTransport {
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<std::io::Result<usize>> {
+        if self.sent.fetch_add(1, Ordering::SeqCst) % 3 == 2 {
+            return Poll::Ready(Ok(buf.len()));
+        }
+        self.inner.poll_send_to(cx, buf, target)
+    }
+
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<SocketAddr>> {
+        self.inner.poll_recv_from(cx, buf)
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+}
+
+#[tokio::test]
+async fn test_messages_arrive_in_order_when_every_third_packet_is_dropped() {
+    let network = MemoryNetwork::new();
+    let lossy = |addr: &str| {
+        Arc::new(LossyTransport {
+            inner: network.bind(addr.parse().unwrap()).expect("Failed to bind"),
+            sent: AtomicUsize::new(0),
+        })
+    };
+    let (socket_a, socket_b) = (lossy("10.0.0.1:5000"), lossy("10.0.0.2:5000"));
+    let config = ReliableConfig {
+        rto: Duration::from_millis(20),
+        max_rto: Duration::from_millis(200),
+        max_retransmits: 20,
+        max_message_size: 128 * 1024,
+        ..Default::default()
+    };
+    let mut a = ReliableStream::new(socket_a.clone(), "10.0.0.2:5000".parse().unwrap(), config.clone())
+        .await
+        .expect("Failed to create stream A");
+    let mut b = ReliableStream::new(socket_b.clone(), "10.0.0.1:5000".parse().unwrap(), config)
+        .await
+        .expect("Failed to create stream B");
+
+    let (init, resp) = tokio::time::timeout(
+        Duration::from_secs(5),
+        async { tokio::join!(a.handshake_initiator(None), b.handshake_responder(None)) },
+    )
+    .await
+    .expect("Handshake timed out");
+    init.expect("Initiator handshake failed");
+    resp.expect("Responder handshake failed");
+    assert_eq!(a.remote_static_key(), Some(b.local_static_pubkey()));
+
+    // Small messages, ones spanning several segments, and one larger than any datagram
+    let mut messages: Vec<Bytes> = (0..40u32)
+        .map(|i| Bytes::from(vec![i as u8; (i as usize * 397) % 5000]))
+        .collect();
+    messages.push(Bytes::from((0..70_000u32).map(|i| i as u8).collect::<Vec<u8>>()));
+
+    let sending = async {
+        for message in &messages {
+            a.send(message.clone()).await.expect("Failed to send");
+        }
+    };
+    let receiving = async {
+        let mut received = Vec::new();
+        for _ in 0..messages.len() {
+            received.push(b.recv().await.expect("Failed to receive"));
+        }
+        received
+    };
+    let ((), received) = tokio::time::timeout(Duration::from_secs(10), async { tokio::join!(sending, receiving) })
+        .await
+        .expect("Messages were not all delivered");
+    assert_eq!(received, messages);
+
+    // The other way, then a close the peer observes
+    b.send(Bytes::from_static(b"reply")).await.expect("Failed to reply");
+    let reply = tokio::time::timeout(Duration::from_secs(5), a.recv()).await.expect("Reply timed out");
+    assert_eq!(reply.expect("Failed to receive reply"), Bytes::from_static(b"reply"));
+    tokio::time::timeout(Duration::from_secs(5), a.close())
+        .await
+        .expect("Close timed out")
+        .expect("Failed to close");
+    let closed = tokio::time::timeout(Duration::from_secs(5), b.recv()).await.expect("recv should see the close");
+    assert!(matches!(closed, Err(TransportError::Closed)), "got {:?}", closed);
+
+    assert!(socket_a.dropped() > 0 && socket_b.dropped() > 0, "Both directions should have lost packets");
+}
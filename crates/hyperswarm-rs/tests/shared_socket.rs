@@ -0,0 +1,113 @@
+//! Integration test: One socket for the DHT, holepunching and transport
+//!
+//! This test binds a single UDP socket and verifies that:
+//! 1. A DHT node running on it is observed at the socket's address
+//! 2. A holepunch from it reaches the peer from that same address
+//! 3. The encrypted stream that follows talks from that address as well
+//! 4. Behind a `Demux`, the DHT keeps answering while the socket punches
+
+use hyperswarm::dht::{DhtClient, DhtConfig};
+use hyperswarm::holepunch::{Candidate, CandidateKind, HolepunchSession};
+use hyperswarm::packet::Demux;
+use hyperswarm::transport::EncryptedStream;
+use hyperswarm::Topic;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const TEST_KEY: [u8; 32] = [0x5Au8; 32];
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_dht_holepunch_and_transport_share_one_port() {
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.expect("Failed to bind"));
+    let shared_addr = socket.local_addr().unwrap();
+
+    // The DHT runs on the shared socket, and a node it queries sees it there
+    let node = DhtClient::new(DhtConfig {
+        bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        ipv6: false,
+        ..Default::default()
+    })
+    .await
+    .expect("Failed to create node");
+    let dht = DhtClient::with_socket(DhtConfig::default(), socket.clone())
+        .await
+        .expect("Failed to create DHT client");
+    assert_eq!(dht.local_addr().unwrap(), shared_addr);
+    dht.add_node_to_routing_table(node.node_id(), node.local_addr().unwrap()).await;
+    tokio::time::timeout(Duration::from_secs(2), dht.lookup(Topic::from_key(b"shared-socket")))
+        .await
+        .expect("Lookup timed out")
+        .expect("Lookup should succeed");
+    assert_eq!(dht.observed_address(), Some(shared_addr));
+
+    // Hand the socket over to holepunching
+    dht.shutdown().await.unwrap();
+    let peer_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let peer_addr = peer_socket.local_addr().unwrap();
+    let mut ours = HolepunchSession::with_socket(socket.clone(), TEST_KEY);
+    let mut theirs = HolepunchSession::with_socket(peer_socket.clone(), TEST_KEY);
+    assert_eq!(ours.local_addr().unwrap(), shared_addr);
+    let (initiated, responded) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(
+            ours.initiate(vec![Candidate { addr: peer_addr, kind: CandidateKind::Lan }]),
+            theirs.respond(vec![Candidate { addr: shared_addr, kind: CandidateKind::Lan }]),
+        )
+    })
+    .await
+    .expect("Holepunch timed out");
+    assert_eq!(initiated.expect("Initiator failed").addr, peer_addr);
+    assert_eq!(responded.expect("Responder failed").addr, shared_addr, "punched from the DHT's port");
+
+    // Then to the encrypted stream
+    let mut stream = EncryptedStream::new(socket, peer_addr).await.unwrap();
+    let mut peer_stream = EncryptedStream::new(peer_socket, shared_addr).await.unwrap();
+    let (init, resp) = tokio::time::timeout(
+        Duration::from_secs(5),
+        async { tokio::join!(stream.handshake_initiator(None), peer_stream.handshake_responder(None)) },
+    )
+    .await
+    .expect("Handshake timed out");
+    init.expect("Initiator handshake failed");
+    resp.expect("Responder handshake failed");
+    stream.send(bytes::Bytes::from_static(b"same port")).await.unwrap();
+    assert_eq!(peer_stream.recv().await.unwrap(), bytes::Bytes::from_static(b"same port"));
+    assert_eq!(peer_stream.remote_addr(), shared_addr);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_demux_keeps_the_dht_running_while_punching() {
+    let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.expect("Failed to bind"));
+    let shared_addr = socket.local_addr().unwrap();
+    let demux = Demux::new(socket);
+
+    let node = DhtClient::new(DhtConfig {
+        bind_addr: IpAddr::V4(Ipv4Addr::LOCALHOST),
+        ipv6: false,
+        ..Default::default()
+    })
+    .await
+    .expect("Failed to create node");
+    let dht = DhtClient::with_transport(DhtConfig::default(), demux.dht()).expect("Failed to create DHT client");
+    dht.add_node_to_routing_table(node.node_id(), node.local_addr().unwrap()).await;
+
+    let peer_socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    let peer_addr = peer_socket.local_addr().unwrap();
+    let mut ours = HolepunchSession::with_transport(demux.holepunch(), TEST_KEY);
+    let mut theirs = HolepunchSession::with_socket(peer_socket, TEST_KEY);
+    let (initiated, responded, looked_up) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(
+            ours.initiate(vec![Candidate { addr: peer_addr, kind: CandidateKind::Lan }]),
+            theirs.respond(vec![Candidate { addr: shared_addr, kind: CandidateKind::Lan }]),
+            dht.lookup(Topic::from_key(b"demux")),
+        )
+    })
+    .await
+    .expect("Timed out");
+    assert_eq!(initiated.expect("Initiator failed").addr, peer_addr);
+    assert_eq!(responded.expect("Responder failed").addr, shared_addr);
+    looked_up.expect("Lookup over the shared socket failed");
+    assert_eq!(dht.observed_address(), Some(shared_addr));
+    assert_eq!(demux.dropped_packets(), 0);
+}
@@ -0,0 +1,117 @@
+//! Integration test: channel multiplexing over one encrypted stream
+//!
+//! Verifies that channels opened on a `Muxer`:
+//! 1. Deliver only their own bytes, in order, when writes are interleaved
+//! 2. Keep flowing while another channel's reader is stalled
+//! 3. Reach end of stream when the peer closes the channel
+//! 4. Are refused past `MAX_CHANNELS`, until one is dropped
+
+mod common;
+
+use hyperswarm::mux::{MuxError, Muxer, CHANNEL_WINDOW, MAX_CHANNELS};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+async fn muxer_pair() -> (Muxer, Muxer) {
+    let (a, b) = common::handshaked_stream_pair().await;
+    (Muxer::new(a), Muxer::new(b))
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_interleaved_channels_stay_separate() {
+    let (left, right) = muxer_pair().await;
+    let ids = [0u64, 1, 300];
+    let mut writers: Vec<_> = ids.iter().map(|&id| left.open_channel(id).unwrap()).collect();
+    let readers: Vec<_> = ids.iter().map(|&id| right.open_channel(id).unwrap()).collect();
+
+    // Round-robin small writes across all three channels
+    for round in 0..50u8 {
+        for (i, writer) in writers.iter_mut().enumerate() {
+            writer.write_all(&[i as u8, round]).await.unwrap();
+        }
+    }
+    for writer in &mut writers {
+        writer.shutdown().await.unwrap();
+    }
+
+    let read_all = readers.into_iter().map(|mut reader| {
+        tokio::spawn(async move {
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await.unwrap();
+            received
+        })
+    });
+    let received = tokio::time::timeout(Duration::from_secs(5), futures::future::join_all(read_all))
+        .await
+        .expect("Channels should reach end of stream");
+
+    for (i, received) in received.into_iter().enumerate() {
+        let expected: Vec<u8> = (0..50u8).flat_map(|round| [i as u8, round]).collect();
+        assert_eq!(received.unwrap(), expected, "channel {} got foreign or reordered bytes", ids[i]);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_stalled_channel_does_not_block_others() {
+    let (left, right) = muxer_pair().await;
+    let mut slow_writer = left.open_channel(1).unwrap();
+    let _slow_reader = right.open_channel(1).unwrap();
+    let mut fast_writer = left.open_channel(2).unwrap();
+    let mut fast_reader = right.open_channel(2).unwrap();
+
+    // Nobody reads channel 1, so its writer stalls once the window is used up
+    let data = vec![1u8; CHANNEL_WINDOW as usize + 1];
+    let stalled = tokio::time::timeout(Duration::from_millis(500), slow_writer.write_all(&data)).await;
+    assert!(stalled.is_err(), "writer should stall on a full window");
+
+    fast_writer.write_all(b"still moving").await.unwrap();
+    let mut buf = [0u8; 12];
+    tokio::time::timeout(Duration::from_secs(2), fast_reader.read_exact(&mut buf))
+        .await
+        .expect("Other channels should keep flowing")
+        .unwrap();
+    assert_eq!(&buf, b"still moving");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_window_refills_as_reader_consumes() {
+    let (left, right) = muxer_pair().await;
+    let mut writer = left.open_channel(5).unwrap();
+    let mut reader = right.open_channel(5).unwrap();
+    assert!(left.open_channel(5).is_err(), "an open id cannot be opened twice");
+
+    // Several windows' worth only gets through if window updates flow back
+    let data: Vec<u8> = (0..3 * CHANNEL_WINDOW as usize).map(|i| i as u8).collect();
+    let expected = data.clone();
+    let write = tokio::spawn(async move {
+        writer.write_all(&data).await.unwrap();
+        writer.shutdown().await.unwrap();
+    });
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), reader.read_to_end(&mut received))
+        .await
+        .expect("Transfer should complete")
+        .unwrap();
+    write.await.unwrap();
+    assert_eq!(received, expected);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_channels_past_the_limit_are_refused() {
+    let (left, right) = muxer_pair().await;
+    let mut channels: Vec<_> = (0..MAX_CHANNELS as u64).map(|id| left.open_channel(id).unwrap()).collect();
+    let next = MAX_CHANNELS as u64;
+    assert!(matches!(left.open_channel(next), Err(MuxError::TooManyChannels)));
+
+    // A dropped channel only frees its slot once the peer closes it too
+    drop(channels.pop());
+    assert!(matches!(left.open_channel(next), Err(MuxError::TooManyChannels)));
+    drop(right.open_channel(next - 1).unwrap());
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while left.open_channel(next).is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("Slot should free up once both sides closed the channel");
+}
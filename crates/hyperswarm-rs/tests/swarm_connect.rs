@@ -0,0 +1,442 @@
+//! Integration test: Swarm-level connect
+//!
+//! This test verifies that one Hyperswarm can connect to another on localhost:
+//! 1. Holepunch over the swarm sockets
+//! 2. Noise XX handshake
+//! 3. Exchange data over the resulting encrypted stream
+//! 4. `Hyperswarm::public_key` is the static key the remote end observes
+
+mod common;
+
+use bytes::Bytes;
+use hyperswarm::connection::ConnectionError;
+use hyperswarm::dht::PeerAddress;
+use hyperswarm::transport::TransportError;
+use hyperswarm::{ConnectionFailure, Hyperswarm, JoinOpts, SwarmConfig, SwarmError};
+use std::time::Duration;
+
+fn local_config(max_peers: usize) -> SwarmConfig {
+    SwarmConfig {
+        bootstrap: vec![], // No external bootstrap for local tests
+        bind_addr: std::net::Ipv4Addr::UNSPECIFIED.into(),
+        port: 0,
+        announce_port: None,
+        max_peers,
+        backoff: Default::default(),
+        refresh_jitter: hyperswarm::discovery::DEFAULT_REFRESH_JITTER,
+        seed: None,
+        transport: Default::default(),
+        app_id: None,
+    }
+}
+
+/// The swarm's connection address, reachable over loopback.
+fn loopback_addr(swarm: &Hyperswarm) -> std::net::SocketAddr {
+    let port = swarm.local_addr().expect("Failed to get swarm address").port();
+    format!("127.0.0.1:{}", port).parse().unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_swarm_connect_exchanges_a_byte() {
+    let swarm1 = Hyperswarm::new(local_config(8)).await.expect("Failed to create swarm1");
+    let swarm2 = Hyperswarm::new(local_config(8)).await.expect("Failed to create swarm2");
+    let addr2 = loopback_addr(&swarm2);
+
+    let (connected, accepted) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(
+            swarm1.connect(PeerAddress {
+                addr: addr2,
+                node_id: None,
+                value: None,
+            }),
+            swarm2.accept(),
+        )
+    })
+    .await
+    .expect("Connect timed out");
+
+    let mut stream1 = connected.expect("Connect failed");
+    let mut stream2 = accepted.expect("Accept failed");
+    assert_eq!(stream1.remote_static_key(), Some(stream2.local_static_pubkey()));
+
+    stream1.send(Bytes::from_static(&[42])).await.expect("Failed to send");
+    let received = stream2.recv().await.expect("Failed to receive");
+    assert_eq!(received, Bytes::from_static(&[42]));
+
+    stream2.send(Bytes::from_static(&[7])).await.expect("Failed to reply");
+    assert_eq!(stream1.recv().await.expect("Failed to receive reply"), Bytes::from_static(&[7]));
+}
+
+/// Connect `from` to `to` and return the static key `to` presented.
+async fn observed_static_key(from: &Hyperswarm, to: &Hyperswarm) -> [u8; 32] {
+    let (connected, accepted) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(
+            from.connect(PeerAddress {
+                addr: loopback_addr(to),
+                node_id: None,
+                value: None,
+            }),
+            to.accept(),
+        )
+    })
+    .await
+    .expect("Connect timed out");
+    accepted.expect("Accept failed");
+    connected.expect("Connect failed").remote_static_key().expect("Handshake incomplete")
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_seeded_swarms_share_identity() {
+    let seeded = |seed: u8| SwarmConfig {
+        seed: Some([seed; 32]),
+        ..local_config(8)
+    };
+    let swarm1 = Hyperswarm::new(seeded(1)).await.expect("Failed to create swarm1");
+    let swarm2 = Hyperswarm::new(seeded(1)).await.expect("Failed to create swarm2");
+    let swarm3 = Hyperswarm::new(seeded(2)).await.expect("Failed to create swarm3");
+    let client = Hyperswarm::new(local_config(8)).await.expect("Failed to create client");
+
+    assert_eq!(swarm1.dht().node_id(), swarm2.dht().node_id());
+    assert_ne!(swarm1.dht().node_id(), swarm3.dht().node_id());
+
+    let key1 = observed_static_key(&client, &swarm1).await;
+    let key2 = observed_static_key(&client, &swarm2).await;
+    let key3 = observed_static_key(&client, &swarm3).await;
+    assert_eq!(key1, key2, "Same seed should present the same static key");
+    assert_ne!(key1, key3, "Different seeds should present different static keys");
+
+    // The key is the same on every connection, not just the first
+    assert_eq!(observed_static_key(&swarm3, &swarm1).await, key1);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_public_key_is_the_presented_identity() {
+    use hyperswarm::Topic;
+
+    let bootstrap = common::create_test_dht_client().await.expect("Failed to create bootstrap node");
+    let bootstrap_port = bootstrap.local_addr().expect("Failed to get bootstrap address").port();
+    let config = SwarmConfig {
+        bootstrap: vec![format!("127.0.0.1:{}", bootstrap_port)],
+        bind_addr: std::net::Ipv4Addr::LOCALHOST.into(),
+        seed: Some([9; 32]),
+        ..local_config(8)
+    };
+    let swarm = Hyperswarm::new(config.clone()).await.expect("Failed to create swarm");
+    let key = swarm.public_key();
+
+    // Joining and leaving topics leaves the identity alone
+    let topic = Topic::from_key(b"public-key");
+    swarm.join(topic, JoinOpts::default()).await.expect("Join failed");
+    assert_eq!(swarm.public_key(), key);
+    swarm.leave(topic).await.expect("Leave failed");
+    assert_eq!(swarm.public_key(), key);
+
+    // It comes from the seed
+    let restarted = Hyperswarm::new(config).await.expect("Failed to create second swarm");
+    assert_eq!(restarted.public_key(), key);
+
+    // And it is what connecting peers see, so they can pin it
+    let client = Hyperswarm::new(local_config(8)).await.expect("Failed to create client");
+    assert_eq!(observed_static_key(&client, &swarm).await, key);
+    let (pinned, accepted) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(
+            client.connect(PeerAddress {
+                addr: loopback_addr(&restarted),
+                node_id: Some(key),
+                value: None,
+            }),
+            restarted.accept(),
+        )
+    })
+    .await
+    .expect("Pinned connect timed out");
+    accepted.expect("Accept failed");
+    assert_eq!(pinned.expect("Pinned connect failed").remote_static_key(), Some(key));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_swarm_connect_rejects_wrong_static_key() {
+    let swarm1 = Hyperswarm::new(local_config(8)).await.expect("Failed to create swarm1");
+    let swarm2 = Hyperswarm::new(local_config(8)).await.expect("Failed to create swarm2");
+    let addr2 = loopback_addr(&swarm2);
+
+    let connect = swarm1.connect(PeerAddress {
+        addr: addr2,
+        node_id: Some([0xde; 32]),
+        value: None,
+    });
+    // The responder waits for a final handshake message that never comes
+    let accept = tokio::time::timeout(Duration::from_secs(2), swarm2.accept());
+
+    let (connected, _) = tokio::join!(connect, accept);
+    let error = connected.map(|_| ()).expect_err("connect should fail");
+    assert!(
+        matches!(
+            error,
+            SwarmError::Connection(ConnectionError::Transport(TransportError::PeerAuthenticationFailed))
+        ),
+        "expected an authentication failure, got {:?}",
+        error
+    );
+    assert_eq!(error.failure(), Some(ConnectionFailure::PeerAuthFailed));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_swarm_connect_respects_max_peers() {
+    let swarm1 = Hyperswarm::new(local_config(0)).await.expect("Failed to create swarm");
+
+    let result = swarm1
+        .connect(PeerAddress {
+            addr: "127.0.0.1:9".parse().unwrap(),
+            node_id: None,
+            value: None,
+        })
+        .await;
+    let error = result.map(|_| ()).expect_err("connect should fail");
+    assert!(matches!(error, SwarmError::Connection(ConnectionError::PeerLimit(0))), "got {:?}", error);
+    assert_eq!(error.failure(), Some(ConnectionFailure::CapacityReached));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_join_with_announced_peer_yields_one_connection_event() {
+    use hyperswarm::Topic;
+
+    // A plain DHT node both swarms bootstrap from
+    let bootstrap = common::create_test_dht_client().await.expect("Failed to create bootstrap node");
+    let bootstrap_port = bootstrap.local_addr().expect("Failed to get bootstrap address").port();
+    let config = SwarmConfig {
+        bootstrap: vec![format!("127.0.0.1:{}", bootstrap_port)],
+        bind_addr: std::net::Ipv4Addr::UNSPECIFIED.into(),
+        port: 0,
+        announce_port: None,
+        max_peers: 8,
+        backoff: Default::default(),
+        refresh_jitter: hyperswarm::discovery::DEFAULT_REFRESH_JITTER,
+        seed: None,
+        transport: Default::default(),
+        app_id: None,
+    };
+    let topic = Topic::from_key(b"connection-events");
+
+    // The first swarm is already announced when the second one joins
+    let announced = Hyperswarm::new(config.clone()).await.expect("Failed to create swarm1");
+    let mut announced_events = announced.connections().expect("Events already taken");
+    announced.join(topic, JoinOpts::default()).await.expect("Join failed");
+
+    let joining = Hyperswarm::new(config).await.expect("Failed to create swarm2");
+    let mut events = joining.connections().expect("Events already taken");
+    assert!(joining.connections().is_none(), "Events can only be taken once");
+    joining.join(topic, JoinOpts::default()).await.expect("Join failed");
+
+    let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .expect("No connection event")
+        .expect("Event channel closed");
+    assert!(event.initiator);
+    assert_eq!(event.topic, Some(topic));
+    assert_eq!(event.remote_addr, loopback_addr(&announced));
+
+    let inbound = tokio::time::timeout(Duration::from_secs(5), announced_events.recv())
+        .await
+        .expect("No inbound connection event")
+        .expect("Event channel closed");
+    assert!(!inbound.initiator);
+    assert_eq!(inbound.topic, None);
+
+    // Nothing else shows up: one peer, one connection
+    let extra = tokio::time::timeout(Duration::from_millis(500), events.recv()).await;
+    assert!(extra.is_err(), "Expected exactly one connection event");
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_swarm_binds_the_configured_interface() {
+    let v4 = Hyperswarm::new(SwarmConfig {
+        bind_addr: std::net::Ipv4Addr::LOCALHOST.into(),
+        ..local_config(8)
+    })
+    .await
+    .expect("Failed to create IPv4 swarm");
+    assert_eq!(v4.local_addr().expect("No swarm address").ip(), std::net::Ipv4Addr::LOCALHOST);
+    assert_eq!(v4.dht().local_addr().expect("No DHT address").ip(), std::net::Ipv4Addr::LOCALHOST);
+
+    let v6_config = SwarmConfig {
+        bind_addr: std::net::Ipv6Addr::LOCALHOST.into(),
+        ..local_config(8)
+    };
+    let swarm1 = Hyperswarm::new(v6_config.clone()).await.expect("Failed to create IPv6 swarm");
+    let swarm2 = Hyperswarm::new(v6_config).await.expect("Failed to create IPv6 swarm");
+    let addr2 = swarm2.local_addr().expect("No swarm address");
+    assert_eq!(addr2.ip(), std::net::Ipv6Addr::LOCALHOST);
+    assert_eq!(swarm2.dht().local_addr().expect("No DHT address").ip(), std::net::Ipv6Addr::LOCALHOST);
+
+    // Peers on the same interface still connect
+    let (connected, accepted) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(
+            swarm1.connect(PeerAddress {
+                addr: addr2,
+                node_id: None,
+                value: None,
+            }),
+            swarm2.accept(),
+        )
+    })
+    .await
+    .expect("Connect timed out");
+    let mut stream1 = connected.expect("Connect failed");
+    let mut stream2 = accepted.expect("Accept failed");
+    stream1.send(Bytes::from_static(&[42])).await.expect("Failed to send");
+    assert_eq!(stream2.recv().await.expect("Failed to receive"), Bytes::from_static(&[42]));
+}
+
+/// Whether `stream` is still open: a closed one fails to read at once.
+async fn is_open(stream: &mut hyperswarm::transport::EncryptedStream) -> bool {
+    tokio::time::timeout(Duration::from_millis(200), stream.recv()).await.is_err()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_simultaneous_connect_leaves_one_connection() {
+    let swarm1 = Hyperswarm::new(local_config(8)).await.expect("Failed to create swarm1");
+    let swarm2 = Hyperswarm::new(local_config(8)).await.expect("Failed to create swarm2");
+    let mut events1 = swarm1.connections().expect("Events already taken");
+    let mut events2 = swarm2.connections().expect("Events already taken");
+
+    let (connected1, connected2) = tokio::time::timeout(Duration::from_secs(5), async {
+        tokio::join!(
+            swarm1.connect(PeerAddress {
+                addr: loopback_addr(&swarm2),
+                node_id: None,
+                value: None,
+            }),
+            swarm2.connect(PeerAddress {
+                addr: loopback_addr(&swarm1),
+                node_id: None,
+                value: None,
+            }),
+        )
+    })
+    .await
+    .expect("Connect timed out");
+
+    // Whatever each side ended up with: its own connect, or the peer's
+    let mut streams1: Vec<_> = connected1.into_iter().collect();
+    let mut streams2: Vec<_> = connected2.into_iter().collect();
+    while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(500), events1.recv()).await {
+        streams1.push(event.stream);
+    }
+    while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(500), events2.recv()).await {
+        streams2.push(event.stream);
+    }
+    let mut open1 = Vec::new();
+    for mut stream in streams1 {
+        if is_open(&mut stream).await {
+            open1.push(stream);
+        }
+    }
+    let mut open2 = Vec::new();
+    for mut stream in streams2 {
+        if is_open(&mut stream).await {
+            open2.push(stream);
+        }
+    }
+    assert_eq!(open1.len(), 1, "Expected one surviving connection on swarm1");
+    assert_eq!(open2.len(), 1, "Expected one surviving connection on swarm2");
+
+    // Both ends agree on who initiated, and the connection works
+    let (mut stream1, mut stream2) = (open1.remove(0), open2.remove(0));
+    assert_ne!(stream1.is_initiator(), stream2.is_initiator());
+    assert_eq!(stream1.remote_static_key(), Some(stream2.local_static_pubkey()));
+    stream1.send(Bytes::from_static(&[42])).await.expect("Failed to send");
+    assert_eq!(stream2.recv().await.expect("Failed to receive"), Bytes::from_static(&[42]));
+    stream2.send(Bytes::from_static(&[7])).await.expect("Failed to reply");
+    assert_eq!(stream1.recv().await.expect("Failed to receive reply"), Bytes::from_static(&[7]));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_flush_waits_for_discovered_connections() {
+    use hyperswarm::Topic;
+
+    let bootstrap = common::create_test_dht_client().await.expect("Failed to create bootstrap node");
+    let bootstrap_port = bootstrap.local_addr().expect("Failed to get bootstrap address").port();
+    let config = SwarmConfig {
+        bootstrap: vec![format!("127.0.0.1:{}", bootstrap_port)],
+        // One path between the swarms, so no attempt is left punching a
+        // second one when flushing
+        bind_addr: std::net::Ipv4Addr::LOCALHOST.into(),
+        ..local_config(8)
+    };
+    let topic = Topic::from_key(b"flush-connections");
+
+    let announced = Hyperswarm::new(config.clone()).await.expect("Failed to create swarm1");
+    let _inbound = announced.connections().expect("Events already taken");
+    let server = JoinOpts {
+        server: true,
+        client: false,
+    };
+    announced.join(topic, server).await.expect("Join failed");
+
+    let joining = Hyperswarm::new(config).await.expect("Failed to create swarm2");
+    let mut events = joining.connections().expect("Events already taken");
+    joining.join(topic, JoinOpts::default()).await.expect("Join failed");
+
+    // Once flushed, the connection to the announced peer is already delivered
+    joining.flush(Duration::from_secs(10)).await.expect("Flush failed");
+    let event = events.try_recv().expect("Connection event not delivered before flush returned");
+    assert_eq!(event.topic, Some(topic));
+    assert!(event.initiator);
+}
+
+#[tokio::test]
+async fn test_peers_lists_connections_per_topic() {
+    use hyperswarm::Topic;
+
+    let bootstrap = common::create_test_dht_client().await.expect("Failed to create bootstrap node");
+    let bootstrap_port = bootstrap.local_addr().expect("Failed to get bootstrap address").port();
+    let config = SwarmConfig {
+        bootstrap: vec![format!("127.0.0.1:{}", bootstrap_port)],
+        bind_addr: std::net::Ipv4Addr::LOCALHOST.into(),
+        ..local_config(8)
+    };
+    let topic = Topic::from_key(b"peers-per-topic");
+
+    let first = Hyperswarm::new(config.clone()).await.expect("Failed to create swarm1");
+    let mut first_events = first.connections().expect("Events already taken");
+    first.join(topic, JoinOpts::default()).await.expect("Join failed");
+    first.flush(Duration::from_secs(10)).await.expect("Flush failed");
+    assert!(first.peers(topic).is_empty());
+
+    // The second swarm finds the first and connects; the first learns the
+    // topic of the inbound connection from the second's handshake payload
+    let second = Hyperswarm::new(config).await.expect("Failed to create swarm2");
+    let _second_events = second.connections().expect("Events already taken");
+    second.join(topic, JoinOpts::default()).await.expect("Join failed");
+    second.flush(Duration::from_secs(10)).await.expect("Flush failed");
+    let accepted = tokio::time::timeout(Duration::from_secs(10), first_events.recv())
+        .await
+        .expect("Inbound connection timed out")
+        .expect("Events closed");
+    let first_key = accepted.stream.local_static_pubkey();
+    let second_key = accepted.stream.remote_static_key().expect("Handshake complete");
+    let outbound = second.peers(topic);
+    assert_eq!(outbound.len(), 1, "Expected one peer, got {:?}", outbound);
+    assert_eq!(outbound[0].public_key, first_key);
+    assert!(outbound[0].initiator);
+    assert_eq!(outbound[0].remote_addr, first.local_addr().unwrap());
+
+    let inbound = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let peers = first.peers(topic);
+            if !peers.is_empty() {
+                break peers;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .expect("Inbound connection never listed under the topic");
+    assert_eq!(inbound.len(), 1);
+    assert_eq!(inbound[0].public_key, second_key);
+    assert!(!inbound[0].initiator);
+    assert!(inbound[0].connected_since <= std::time::SystemTime::now());
+
+    assert!(second.peers(Topic::from_key(b"another-topic")).is_empty());
+}